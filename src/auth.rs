@@ -0,0 +1,118 @@
+use std::sync::OnceLock;
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+
+use argon2::Argon2;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use sha2::{Sha256, Digest};
+
+/// How many remembered logins a single user can have at once. The oldest is forgotten first.
+pub const MAX_LOGIN_TOKENS: usize = 8;
+/// How many passwords can be waiting to be checked or hashed before more are turned away.
+const MAX_PASSWORD_JOBS: usize = 32;
+
+/// A password checked when the username doesn't exist, so that takes as long as a wrong password.
+static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+
+/// Hashes a password with Argon2 and a random salt, returning a PHC string that
+/// contains everything needed to verify it later.
+pub fn hash_password(password: &str) -> Result<String, String> {
+    let salt = SaltString::generate(&mut OsRng);
+    match Argon2::default().hash_password(password.as_bytes(), &salt) {
+        Ok(hash) => Ok(hash.to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Checks a password against a hash made with [`hash_password`].
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(parsed) => Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Checks a password against a user's hash, like [`verify_password`]. Without a hash, a made up
+/// one is checked instead and the answer is always no, so how long it takes doesn't give away
+/// whether the user exists.
+pub fn check_password(password: &str, hash: Option<&str>) -> bool {
+    match hash {
+        Some(hash) => verify_password(password, hash),
+        None => {
+            let dummy = DUMMY_HASH.get_or_init(|| hash_password("not anyone's password").unwrap_or_default());
+            let _ = verify_password(password, dummy);
+            false
+        },
+    }
+}
+
+/// Whether a stored password has already been hashed. Save files from before hashing was added
+/// store passwords in plaintext.
+pub fn is_hashed(stored: &str) -> bool {
+    match PasswordHash::new(stored) {
+        Ok(parsed) => parsed.algorithm.as_str().starts_with("argon2") && parsed.salt.is_some() && parsed.hash.is_some(),
+        Err(_) => false,
+    }
+}
+
+/// Generates a random token a client can store instead of the user's password.
+pub fn new_login_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Hashes a login token to be stored. Tokens are long and random, so unlike passwords they don't
+/// need a slow hash to be hard to guess, and checking one costs next to nothing.
+pub fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Checks a login token against a hash made with [`hash_token`].
+pub fn verify_token(token: &str, hash: &str) -> bool {
+    tokens_match(&hash_token(token), hash)
+}
+
+/// Compares two tokens, taking just as long however much of them matches so the time taken
+/// gives nothing away.
+pub fn tokens_match(a: &str, b: &str) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut difference = 0;
+    for (x, y) in a.bytes().zip(b.bytes()) {
+        difference |= x ^ y;
+    }
+    difference == 0
+}
+
+/// Something slow to do with a password, run on the [`PasswordWorker`].
+type PasswordJob = Box<dyn FnOnce() + Send>;
+
+/// A thread that checks and hashes passwords. Argon2 is slow on purpose, so it runs here instead
+/// of on the thread handling everyone's packets. Jobs run one at a time in the order they were
+/// queued, so an account is always created before a log in sent after it is checked.
+pub struct PasswordWorker {
+    jobs: SyncSender<PasswordJob>,
+}
+
+impl PasswordWorker {
+    /// Starts the worker, which stops once this is dropped.
+    pub fn start() -> Self {
+        let (jobs, receiver) = mpsc::sync_channel::<PasswordJob>(MAX_PASSWORD_JOBS);
+        std::thread::Builder::new().name("password_worker".to_owned()).spawn(move || {
+            for job in receiver {
+                job();
+            }
+        }).unwrap();
+        Self { jobs }
+    }
+
+    /// Queues a job. Returns false if too many are already waiting.
+    pub fn run<F: FnOnce() + Send + 'static>(&self, job: F) -> bool {
+        match self.jobs.try_send(Box::new(job)) {
+            Ok(_) => true,
+            Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => false,
+        }
+    }
+}
@@ -0,0 +1,455 @@
+use std::collections::HashMap;
+use std::io::BufRead;
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::AppPreferences;
+use crate::character::PlayerCharacter;
+use crate::combat::{Combatant, PreRoundAction, MovementAction, AttackAction, SavingThrowType};
+use crate::common_ui::{ChatMessage, command_tokens};
+use crate::network::{Connection, Handshake, NetEvent, NetEventKind, PinCheck, HANDSHAKE_TIMEOUT, CERTIFICATE_CHANGED, decode_frame, connect, check_pin};
+use crate::packets::{ClientBoundPacket, ServerBoundPacket, Request};
+use crate::player_app::{CombatState, CombatRoundState};
+use crate::storage::write_atomic;
+
+/// How the bot should connect, read from the command line.
+pub struct BotOptions {
+    pub address: String,
+    pub username: String,
+    pub password: String,
+    /// Create the account before logging in.
+    pub register: bool,
+    pub encrypt: bool,
+    /// A file of commands to run, one per line, before reading more from stdin.
+    pub script: Option<String>,
+    /// Play combat turns automatically.
+    pub autopilot: bool,
+}
+
+/// Everything the bot knows, shared between the thread reading commands and the one handling
+/// packets from the server.
+struct Bot {
+    options: BotOptions,
+    connection: Connection,
+    logged_in: bool,
+    /// Set when the bot should stop, with the reason if it failed.
+    finished: Option<Result<(), String>>,
+    known_servers: HashMap<String, String>,
+    characters: HashMap<String, PlayerCharacter>,
+    pending_characters: Vec<PlayerCharacter>,
+    combat_state: Option<CombatState>,
+}
+
+impl Bot {
+    fn send(&mut self, packet: ServerBoundPacket) {
+        if let Err(e) = self.connection.send(&packet) {
+            self.finish(Err(format!("Lost connection to the server: {}", e)));
+        }
+    }
+
+    fn finish(&mut self, result: Result<(), String>) {
+        if self.finished.is_none() {
+            self.finished = Some(result);
+        }
+    }
+
+    fn log_in(&mut self) {
+        self.send(ServerBoundPacket::AttemptLogIn(self.options.username.clone(), self.options.password.clone(), false));
+    }
+}
+
+/// Prints a message the way it would appear in the chat window.
+fn print(msg: ChatMessage) {
+    println!("{}", msg.to_log_entry().job.text);
+}
+
+/// Runs a terminal client. Incoming chat is printed, and typed lines are sent as chat or, if they
+/// start with `/`, run as commands (see `/help`).
+pub fn run(prefs: AppPreferences, options: BotOptions) -> Result<(), String> {
+    let (connection, events) = connect(options.address.trim(), options.encrypt).map_err(|e| format!("Could not connect to server: {}", e))?;
+    let script = match &options.script {
+        Some(path) => Some(std::fs::read_to_string(path).map_err(|e| format!("Could not read {}: {}", path, e))?),
+        None => None,
+    };
+    let bot = Arc::new(Mutex::new(Bot {
+        options,
+        connection,
+        logged_in: false,
+        finished: None,
+        known_servers: prefs.known_servers,
+        characters: HashMap::new(),
+        pending_characters: Vec::new(),
+        combat_state: None,
+    }));
+
+    let bot_clone = Arc::clone(&bot);
+    std::thread::Builder::new().name(String::from("bot_events")).spawn(move || {
+        handle_events(bot_clone, events);
+    }).unwrap();
+
+    // nothing but logging in works until we have
+    loop {
+        std::thread::sleep(Duration::from_millis(50));
+        let bot = bot.lock().unwrap();
+        if let Some(result) = &bot.finished {
+            return result.clone();
+        }
+        if bot.logged_in {
+            break;
+        }
+        if !bot.connection.handshake_done && bot.connection.opened_at.elapsed() > HANDSHAKE_TIMEOUT {
+            return Err("The server did not answer the version handshake. It may be running an older version; please update so both match.".to_owned());
+        }
+    }
+
+    let script_lines = script.iter().flat_map(|script| script.lines().map(|line| Ok(line.to_owned()))).collect::<Vec<_>>();
+    for line in script_lines.into_iter().chain(std::io::stdin().lock().lines()) {
+        let line = match line {
+            Ok(line) => line.trim().to_owned(),
+            Err(_) => break,
+        };
+        if command_tokens(&line).next() == Some("/wait") {
+            // waiting has to happen without the lock, so the bot keeps handling packets
+            match command_tokens(&line).nth(1).and_then(|secs| secs.parse::<f64>().ok()).map(Duration::try_from_secs_f64) {
+                Some(Ok(duration)) => std::thread::sleep(duration),
+                _ => print(ChatMessage::no_sender("Expected a number of seconds.").light_red()),
+            }
+            continue;
+        }
+        let bot = &mut *bot.lock().unwrap();
+        if bot.finished.is_some() {
+            break;
+        }
+        match line.as_str() {
+            "/quit" | "/exit" => break,
+            "" => {},
+            // comments, for scripts
+            comment if comment.starts_with('#') => {},
+            command if command.starts_with('/') => {
+                parse_command(bot, command);
+            },
+            _ => {
+                let msg = ChatMessage::player(bot.options.username.clone(), line);
+                bot.send(ServerBoundPacket::ChatMessage(msg));
+            },
+        }
+    }
+    let bot = &mut *bot.lock().unwrap();
+    bot.finish(Ok(()));
+    bot.connection.shutdown();
+    bot.finished.clone().unwrap_or(Ok(()))
+}
+
+/// Handles everything the server sends until the connection closes.
+fn handle_events(bot: Arc<Mutex<Bot>>, events: Receiver<NetEvent>) {
+    for event in events {
+        let bot = &mut *bot.lock().unwrap();
+        match event {
+            NetEvent { kind: NetEventKind::Frame(payload), .. } => {
+                if bot.connection.handshake_done {
+                    match decode_frame::<ClientBoundPacket>(&payload) {
+                        Ok(packet) => handle_packet(bot, packet),
+                        Err(e) => print(ChatMessage::no_sender(format!("Could not read a packet from the server: {}", e)).red()),
+                    }
+                } else {
+                    handle_handshake(bot, decode_frame::<Handshake>(&payload));
+                }
+            },
+            NetEvent { kind: NetEventKind::Closed(error), .. } => {
+                let reason = match error {
+                    Some(e) => format!("Lost connection to the server: {}", e),
+                    None => "The server closed the connection.".to_owned(),
+                };
+                // the other thread may be waiting on stdin, so don't wait for it to notice
+                if bot.logged_in && bot.finished.is_none() {
+                    eprintln!("{}", reason);
+                    std::process::exit(1);
+                }
+                bot.finish(Err(reason));
+                return;
+            },
+        }
+    }
+}
+
+fn handle_handshake(bot: &mut Bot, hello: Result<Handshake, String>) {
+    let hello = match hello {
+        Ok(hello) => hello,
+        Err(_) => {
+            bot.finish(Err("The server sent an unexpected reply. It may be running an older version; please update so both match.".to_owned()));
+            return;
+        },
+    };
+    if !hello.is_compatible() {
+        bot.finish(Err(hello.mismatch_message("server")));
+        return;
+    }
+    if let Some(reason) = hello.refusal {
+        bot.finish(Err(reason));
+        return;
+    }
+    if let Some(fingerprint) = bot.connection.peer_fingerprint() {
+        let server = bot.options.address.trim().to_owned();
+        match check_pin(&mut bot.known_servers, &server, &fingerprint) {
+            PinCheck::Trusted => {},
+            PinCheck::FirstUse => {
+                print(ChatMessage::no_sender(format!("Connected to this server for the first time. Its certificate fingerprint is {}.", fingerprint)).blue());
+                save_known_servers(&bot.known_servers);
+            },
+            PinCheck::Changed => {
+                bot.finish(Err(format!("{} New fingerprint: {}", CERTIFICATE_CHANGED, fingerprint)));
+                return;
+            },
+        }
+    }
+    bot.connection.handshake_done = true;
+    if bot.options.register {
+        bot.send(ServerBoundPacket::CreateAccount(bot.options.username.clone(), bot.options.password.clone()));
+    } else {
+        bot.log_in();
+    }
+}
+
+/// Remembers newly pinned certificates, the same way the player app does when it closes.
+fn save_known_servers(known_servers: &HashMap<String, String>) {
+    if let Ok(s) = std::fs::read_to_string("preferences.ron") {
+        if let Ok(mut prefs) = ron::from_str::<AppPreferences>(&s) {
+            prefs.known_servers = known_servers.clone();
+            let _ = write_atomic("preferences.ron", ron::to_string(&prefs).unwrap_or(s).as_bytes());
+        }
+    }
+}
+
+fn handle_packet(bot: &mut Bot, packet: ClientBoundPacket) {
+    match packet {
+        ClientBoundPacket::ChatMessage(msg) => print(msg),
+        ClientBoundPacket::CreateAccountResult(success, username) => {
+            if success {
+                print(ChatMessage::no_sender(format!("Created account \"{}\".", username)).blue());
+                bot.log_in();
+            } else {
+                bot.finish(Err("Could not create that account. The name may be taken.".to_owned()));
+            }
+        },
+        ClientBoundPacket::LogInResult(success, _) => {
+            if success {
+                bot.logged_in = true;
+                print(ChatMessage::no_sender(format!("Logged in as {}. Type /help for commands.", bot.options.username)).blue());
+            } else {
+                bot.finish(Err("Incorrect username or password.".to_owned()));
+            }
+        },
+        ClientBoundPacket::CreateNewCharacterResult(result, name) => {
+            match result {
+                Ok(()) => {
+                    print(ChatMessage::no_sender(format!("Created {}.", name)).blue());
+                    bot.send(ServerBoundPacket::RequestCharacterUpdate(name));
+                },
+                Err(e) => print(ChatMessage::no_sender(e.to_string()).light_red()),
+            }
+        },
+        ClientBoundPacket::UpdateCharacter(name, sheet) => {
+            bot.characters.insert(name, sheet);
+        },
+        ClientBoundPacket::PatchCharacter(name, patch) => {
+            match bot.characters.get_mut(&name) {
+                Some(sheet) => patch.apply(sheet),
+                None => bot.send(ServerBoundPacket::RequestCharacterUpdate(name)),
+            }
+        },
+        ClientBoundPacket::RespondToRequest(request, approved) => {
+            let msg = format!("The DM {} your request: {}", if approved { "approved" } else { "denied" }, request);
+            print(ChatMessage::no_sender(msg).blue());
+        },
+        ClientBoundPacket::PendingCharacters(pending) => {
+            bot.pending_characters = pending;
+            if !bot.pending_characters.is_empty() {
+                print(ChatMessage::no_sender("New characters are available. Type /characters to see them.").blue());
+            }
+        },
+        ClientBoundPacket::UpdateCombatState(state) => {
+            if let Some(state) = &state {
+                announce_combat_state(bot, state);
+            }
+            bot.combat_state = state;
+        },
+        ClientBoundPacket::ProtocolError(e) => print(ChatMessage::no_sender(e).red()),
+        ClientBoundPacket::Error(e) => print(ChatMessage::no_sender(e.to_string()).light_red()),
+        // the bot doesn't need the registries, notes, macros or parties
+        _ => {},
+    }
+}
+
+/// Says what the bot is waiting on in a fight, and plays the turn if it is on autopilot.
+fn announce_combat_state(bot: &mut Bot, state: &CombatState) {
+    let changed = match &bot.combat_state {
+        Some(old) => old.round_state != state.round_state,
+        None => true,
+    };
+    match &state.round_state {
+        CombatRoundState::PreRound => {
+            if changed {
+                let names: Vec<String> = state.your_combatants.keys().map(|c| c.to_string()).collect();
+                print(ChatMessage::no_sender(format!("A new round is starting. Declare actions for {} with /declare.", names.join(", "))).combat());
+            }
+        },
+        CombatRoundState::NotYourTurn => {},
+        CombatRoundState::MovementAction { combatant, .. } => {
+            if bot.options.autopilot {
+                bot.send(ServerBoundPacket::DecideMovementAction(MovementAction::Move));
+            } else if changed {
+                print(ChatMessage::no_sender(format!("It is {}'s turn to move. Use /move.", combatant)).combat());
+            }
+        },
+        CombatRoundState::AttackAction { combatant, .. } => {
+            let mut targets: Vec<&Combatant> = state.valid_targets.iter().collect();
+            targets.sort();
+            if bot.options.autopilot {
+                let action = match targets.first() {
+                    Some(target) => AttackAction::Attack((*target).clone(), 0),
+                    None => AttackAction::None,
+                };
+                bot.send(ServerBoundPacket::DecideAttackAction(action));
+            } else if changed {
+                let names: Vec<String> = targets.iter().map(|c| c.to_string()).collect();
+                print(ChatMessage::no_sender(format!("It is {}'s turn to attack. Use /attack. Targets: {}", combatant, names.join(", "))).combat());
+            }
+        },
+    }
+}
+
+fn parse_command(bot: &mut Bot, command: &str) {
+    let mut tree = command_tokens(&command[1..]);
+    match tree.next() {
+        Some("m") => {
+            match tree.next() {
+                Some(name) => {
+                    let character = tree.next().map(|c| c.to_owned());
+                    bot.send(ServerBoundPacket::RollMacro(character, name.to_owned()));
+                },
+                None => print(ChatMessage::no_sender("You must specify a macro.").light_red()),
+            }
+        },
+        Some("request") => {
+            match tree.next() {
+                Some("characters") => bot.send(ServerBoundPacket::MakeRequest(Request::GenerateCharacters)),
+                _ => print(ChatMessage::no_sender("Expected /request characters.").light_red()),
+            }
+        },
+        Some("characters") => {
+            let mut msg = "Your characters:".to_owned();
+            let mut names: Vec<&String> = bot.characters.keys().collect();
+            names.sort();
+            for name in names {
+                let sheet = &bot.characters[name];
+                msg.push_str(&format!("\n- {}: level {} {} {}, {}/{} hp", name, sheet.level, sheet.race, sheet.class.name, sheet.combat_stats.health.current_hp, sheet.combat_stats.health.max_hp));
+            }
+            if !bot.pending_characters.is_empty() {
+                msg.push_str("\nCharacters to pick from (/create <number> <class> <name>):");
+                for (i, candidate) in bot.pending_characters.iter().enumerate() {
+                    let a = &candidate.combat_stats.attributes;
+                    msg.push_str(&format!("\n{}: {} STR {} DEX {} CON {} INT {} WIS {} CHA {}", i, candidate.race, a.strength, a.dexterity, a.constitution, a.intelligence, a.wisdom, a.charisma));
+                }
+            }
+            print(ChatMessage::no_sender(msg));
+        },
+        Some("create") => {
+            match (tree.next().and_then(|i| i.parse::<usize>().ok()), tree.next(), tree.next()) {
+                (Some(index), Some(class), Some(name)) => {
+                    bot.send(ServerBoundPacket::CreateNewCharacter(name.to_owned(), index, class.to_owned()));
+                },
+                _ => print(ChatMessage::no_sender("Expected /create <number> <class> <name>.").light_red()),
+            }
+        },
+        Some("save") => {
+            let character = tree.next().map(|c| c.to_owned());
+            let save = match tree.next() {
+                Some("paralysis") => Some(SavingThrowType::PetrificationParalysis),
+                Some("poison") => Some(SavingThrowType::PoisonDeath),
+                Some("breath") => Some(SavingThrowType::BlastBreath),
+                Some("wands") => Some(SavingThrowType::StaffsWands),
+                Some("spells") => Some(SavingThrowType::Spells),
+                _ => None,
+            };
+            match (character, save) {
+                (Some(character), Some(save)) => bot.send(ServerBoundPacket::SavingThrow(character, save)),
+                _ => print(ChatMessage::no_sender("Expected /save <character> <paralysis|poison|breath|wands|spells>.").light_red()),
+            }
+        },
+        Some("declare") => {
+            let character = tree.next().map(|c| c.to_owned());
+            let action = match tree.next() {
+                Some("none") => Some(PreRoundAction::None),
+                Some("withdraw") => Some(PreRoundAction::FightingWithdrawal),
+                Some("retreat") => Some(PreRoundAction::FullRetreat),
+                _ => None,
+            };
+            match (character, action) {
+                (Some(character), Some(action)) => {
+                    let combatant = Combatant::pc(bot.options.username.clone(), character);
+                    bot.send(ServerBoundPacket::MakePreRoundDeclaration(combatant, action));
+                },
+                _ => print(ChatMessage::no_sender("Expected /declare <character> <none|withdraw|retreat>.").light_red()),
+            }
+        },
+        Some("move") => {
+            let action = match tree.next() {
+                Some("none") => Some(MovementAction::None),
+                Some("move") => Some(MovementAction::Move),
+                Some("run") => Some(MovementAction::Run),
+                Some("charge") => Some(MovementAction::Charge),
+                Some("withdraw") => Some(MovementAction::FightingWithdrawal),
+                Some("retreat") => Some(MovementAction::FullRetreat),
+                Some("simple") => Some(MovementAction::SimpleAction),
+                _ => None,
+            };
+            match action {
+                Some(action) => bot.send(ServerBoundPacket::DecideMovementAction(action)),
+                None => print(ChatMessage::no_sender("Expected /move <none|move|run|charge|withdraw|retreat|simple>.").light_red()),
+            }
+        },
+        Some("attack") => {
+            let action = match tree.next() {
+                Some("none") => Some(AttackAction::None),
+                Some("spell") => Some(AttackAction::CastSpell),
+                Some("other") => Some(AttackAction::OtherAction),
+                Some(target) => {
+                    let targets = bot.combat_state.as_ref().map(|state| state.valid_targets.clone()).unwrap_or_default();
+                    targets.into_iter().find(|t| t.to_string().eq_ignore_ascii_case(target)).map(|t| AttackAction::Attack(t, 0))
+                },
+                None => None,
+            };
+            match action {
+                Some(action) => bot.send(ServerBoundPacket::DecideAttackAction(action)),
+                None => print(ChatMessage::no_sender("Expected /attack <target|none|spell|other>. The target must be one you can attack.").light_red()),
+            }
+        },
+        Some("auto") => {
+            match tree.next() {
+                Some("on") => bot.options.autopilot = true,
+                Some("off") => bot.options.autopilot = false,
+                _ => print(ChatMessage::no_sender("Expected /auto <on|off>.").light_red()),
+            }
+        },
+        Some("help") => {
+            let mut msg = "List of all commands:".to_owned();
+            msg.push_str("\n/m <macro> <character>: Rolls a saved macro.");
+            msg.push_str("\n/request characters: Asks the DM for new characters to pick from.");
+            msg.push_str("\n/characters: Lists your characters, and any you can pick from.");
+            msg.push_str("\n/create <number> <class> <name>: Picks one of the characters you were given. <class> is the class's registry path.");
+            msg.push_str("\n/save <character> <paralysis|poison|breath|wands|spells>: Rolls a saving throw.");
+            msg.push_str("\n/declare <character> <none|withdraw|retreat>: Declares what a character will do this round.");
+            msg.push_str("\n/move <none|move|run|charge|withdraw|retreat|simple>: Decides a movement action on your turn.");
+            msg.push_str("\n/attack <target|none|spell|other>: Decides an attack action on your turn.");
+            msg.push_str("\n/auto <on|off>: Plays your combat turns automatically, moving and attacking the first target.");
+            msg.push_str("\n/wait <seconds>: Does nothing for a while. Useful in scripts.");
+            msg.push_str("\n/quit: Disconnects.");
+            msg.push_str("\nAnything else is sent as a chat message. Lines starting with # are ignored.");
+            print(ChatMessage::no_sender(msg));
+        },
+        t => {
+            print(ChatMessage::no_sender(format!("Unknown command \"{}\". Run /help for a list of commands.", t.unwrap_or(""))).light_red());
+        },
+    }
+}
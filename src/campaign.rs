@@ -0,0 +1,121 @@
+use std::path::{Path, PathBuf};
+
+use crate::storage::{self, StoreKind};
+
+/// Where every campaign but the default one is kept, each in a folder named after it.
+pub const CAMPAIGNS_DIR: &str = "campaigns";
+
+/// The campaign kept directly in the working directory, like everything was before there were
+/// campaigns. Its registry folders are the shared ones, so it has no overrides.
+pub const DEFAULT_CAMPAIGN: &str = "Default";
+
+/// The folder a campaign's save data, maps and registry overrides are kept in. Paths inside the
+/// default campaign are relative to the working directory.
+pub fn dir(name: &str) -> PathBuf {
+    if name == DEFAULT_CAMPAIGN {
+        PathBuf::new()
+    } else {
+        Path::new(CAMPAIGNS_DIR).join(name)
+    }
+}
+
+/// The names of every campaign, with the default one first.
+pub fn list() -> Vec<String> {
+    let mut names = Vec::new();
+    if let Ok(entries) = std::fs::read_dir(CAMPAIGNS_DIR) {
+        for entry in entries.flatten() {
+            if entry.path().is_dir() {
+                names.push(entry.file_name().to_string_lossy().into_owned());
+            }
+        }
+    }
+    names.sort();
+    names.insert(0, DEFAULT_CAMPAIGN.to_owned());
+    names
+}
+
+/// Whether a campaign with this name exists.
+pub fn exists(name: &str) -> bool {
+    name == DEFAULT_CAMPAIGN || dir(name).is_dir()
+}
+
+/// Checks that a new campaign can be made with this name.
+fn check_new_name(name: &str) -> Result<(), String> {
+    if name.trim().is_empty() {
+        return Err("The campaign needs a name.".to_owned());
+    }
+    if name != name.trim() || name.starts_with('.') || name.contains(['/', '\\', ':']) {
+        return Err("Campaign names can't start with a dot, have spaces at either end or contain slashes or colons.".to_owned());
+    }
+    if name.eq_ignore_ascii_case(DEFAULT_CAMPAIGN) || exists(name) {
+        return Err(format!("There is already a campaign called \"{}\".", name));
+    }
+    Ok(())
+}
+
+/// Makes an empty campaign, stored in the given kind of store.
+pub fn create(name: &str, kind: StoreKind) -> Result<(), String> {
+    check_new_name(name)?;
+    std::fs::create_dir_all(dir(name).join("maps")).map_err(|e| format!("Could not create the campaign: {}", e))?;
+    if let Err(e) = storage::create_store(&dir(name), kind) {
+        let _ = std::fs::remove_dir_all(dir(name));
+        return Err(format!("Could not create the campaign: {}", e));
+    }
+    Ok(())
+}
+
+/// Makes a new campaign with a copy of another's save data, maps and registry overrides, but not
+/// its backups. It is stored in the same kind of store.
+pub fn clone(from: &str, to: &str) -> Result<(), String> {
+    check_new_name(to)?;
+    if !exists(from) {
+        return Err(format!("There is no campaign called \"{}\".", from));
+    }
+    let source = dir(from);
+    let target = dir(to);
+    let result = if from == DEFAULT_CAMPAIGN {
+        // the rest of the working directory is shared by every campaign
+        copy_if_exists(&source.join(storage::SAVE_FILE), &target.join(storage::SAVE_FILE))
+            .and_then(|_| copy_if_exists(&source.join(storage::DATABASE_FILE), &target.join(storage::DATABASE_FILE)))
+            .and_then(|_| copy_if_exists(&source.join("maps"), &target.join("maps")))
+    } else {
+        copy_dir(&source, &target, &[storage::BACKUP_DIR])
+    };
+    match result {
+        Ok(_) => std::fs::create_dir_all(target.join("maps")).map_err(|e| format!("Could not create the campaign: {}", e)),
+        Err(e) => {
+            let _ = std::fs::remove_dir_all(&target);
+            Err(format!("Could not copy \"{}\": {}", from, e))
+        },
+    }
+}
+
+fn copy_if_exists(from: &Path, to: &Path) -> std::io::Result<()> {
+    if from.is_dir() {
+        copy_dir(from, to, &[])
+    } else if from.is_file() {
+        if let Some(parent) = to.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::copy(from, to).map(|_| ())
+    } else {
+        Ok(())
+    }
+}
+
+/// Copies a folder and everything in it, except for the top level entries named in `skip`.
+fn copy_dir(from: &Path, to: &Path, skip: &[&str]) -> std::io::Result<()> {
+    std::fs::create_dir_all(to)?;
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        if skip.iter().any(|s| entry.file_name() == *s) {
+            continue;
+        }
+        if entry.path().is_dir() {
+            copy_dir(&entry.path(), &to.join(entry.file_name()), &[])?;
+        } else {
+            std::fs::copy(entry.path(), to.join(entry.file_name()))?;
+        }
+    }
+    Ok(())
+}
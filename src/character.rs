@@ -1,7 +1,8 @@
-use std::collections::HashSet;
+use std::collections::{HashSet, BTreeMap};
 
 use crate::{dice::{roll, DiceRoll}, class::{Class, SavingThrowProgressionType, HitDie, DivineValue, ArcaneValue}, race::{Race, RaceTable}, combat::{CombatantStats, DamageRoll, StatModifiers, StatusEffects}, item::{Item, ItemType, Encumbrance, WeaponDamage, MeleeDamage}, enemy::AttackRoutine, proficiency::{Proficiencies, ProficiencyInstance, PROF_CODE_MAP}};
 use array_macro::array;
+use rand::Rng;
 use serde::{Deserialize, Serialize};
 use simple_enum_macro::simple_enum;
 
@@ -22,15 +23,19 @@ pub struct PlayerCharacter {
     pub arcane_spells: Option<ArcaneSpellcaster>,
     pub notes: String,
     pub party: Option<String>,
+    /// Saved rolls for this character, by name. These may use the character's attribute
+    /// modifiers (e.g. `1d8+STR`).
+    #[serde(default)]
+    pub macros: BTreeMap<String, String>,
 }
 
 impl PlayerCharacter {
-    pub fn random() -> Self {
-        let race = RaceTable::StandardFantasy.random_race();
+    pub fn random(rng: &mut impl Rng) -> Self {
+        let race = RaceTable::StandardFantasy.random_race(rng);
         let class = Class::default();
         Self {
             combat_stats: CombatantStats { 
-                attributes: race.roll_attrs(), 
+                attributes: race.roll_attrs(rng), 
                 health: Health::new(), 
                 attack_throw: 10, 
                 armor_class: 0, 
@@ -52,10 +57,17 @@ impl PlayerCharacter {
             arcane_spells: None,
             notes: String::new(),
             party: None,
+            macros: BTreeMap::new(),
         }
     }
 
-    pub fn initialize(&mut self) {
+    /// Whether a new character may take this class: it must be for their race, and they need at
+    /// least 9 in each of its prime requisites.
+    pub fn can_be(&self, class: &Class) -> bool {
+        class.race == self.race && class.prime_reqs.iter().all(|attr| self.combat_stats.attributes.get(*attr) >= 9)
+    }
+
+    pub fn initialize(&mut self, rng: &mut impl Rng) {
         self.combat_stats.saving_throws = SavingThrows::calculate_simple(self.class.saving_throw_progression_type, self.level);
         self.combat_stats.modifiers.melee_attack.add("strength", self.combat_stats.attributes.modifier(Attr::STR));
         self.combat_stats.modifiers.melee_damage.add("strength", self.combat_stats.attributes.modifier(Attr::STR));
@@ -82,15 +94,15 @@ impl PlayerCharacter {
                 spell_repertoire: array![(HashSet::new(), 0); 6],
             });
         }
-        self.level_up();
+        self.level_up(rng);
     }
 
-    pub fn level_up(&mut self) {
+    pub fn level_up(&mut self, rng: &mut impl Rng) {
         if self.level >= self.class.maximum_level {
             return;
         }
         self.level += 1;
-        let hp = self.roll_hit_die();
+        let hp = self.roll_hit_die(rng);
         self.combat_stats.health.max_hp += hp;
         self.combat_stats.health.current_hp = self.combat_stats.health.max_hp as i32;
         self.combat_stats.saving_throws = SavingThrows::calculate_simple(self.class.saving_throw_progression_type, self.level);
@@ -143,7 +155,7 @@ impl PlayerCharacter {
         }
     }
 
-    pub fn roll_hit_die(&self) -> u32 {
+    pub fn roll_hit_die(&self, rng: &mut impl Rng) -> u32 {
         if self.level > 9 {
             match self.class.saving_throw_progression_type {
                 SavingThrowProgressionType::Cleric |
@@ -162,16 +174,16 @@ impl PlayerCharacter {
                     HitDie::D12 => 12,
                 }, 
                 self.combat_stats.attributes.modifier(Attr::CON),
-            ).roll() as u32
+            ).roll(rng) as u32
         }
     }
 
-    pub fn add_xp(&mut self, mut amount: u32) {
+    pub fn add_xp(&mut self, mut amount: u32, rng: &mut impl Rng) {
         amount = (amount as i32 + (amount as f64 * self.combat_stats.modifiers.xp_gain.total()).round() as i32) as u32;
         self.xp += amount;
         let mut iterations = 0;
         while self.xp >= self.xp_to_level && iterations < 15 {
-            self.level_up();
+            self.level_up(rng);
             iterations += 1;
         }
     }
@@ -330,6 +342,138 @@ impl PlayerCharacter {
     }
 }
 
+/// The parts of a character sheet that changed, so a client that already has the sheet only
+/// needs to be sent those. Each field is `None` if it didn't change.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct CharacterPatch {
+    pub combat_stats: Option<CombatantStats>,
+    pub race: Option<Race>,
+    pub class: Option<Class>,
+    pub title: Option<String>,
+    pub level: Option<u8>,
+    pub xp: Option<u32>,
+    pub xp_to_level: Option<u32>,
+    pub inventory: Option<PlayerInventory>,
+    pub proficiencies: Option<Proficiencies>,
+    pub divine_spells: Option<Option<DivineSpellcaster>>,
+    pub arcane_spells: Option<Option<ArcaneSpellcaster>>,
+    pub notes: Option<String>,
+    pub party: Option<Option<String>>,
+    pub macros: Option<BTreeMap<String, String>>,
+}
+
+impl CharacterPatch {
+    /// Everything that is different in `new`. Fields are compared by their serialized form, so
+    /// something may be sent that didn't really change (like a map in a new order), but nothing
+    /// that did change is ever missed.
+    pub fn diff(old: &PlayerCharacter, new: &PlayerCharacter) -> Self {
+        // destructured so that adding a field to the sheet without adding it here won't compile
+        let PlayerCharacter {
+            combat_stats,
+            race,
+            class,
+            title,
+            level,
+            xp,
+            xp_to_level,
+            inventory,
+            proficiencies,
+            divine_spells,
+            arcane_spells,
+            notes,
+            party,
+            macros,
+        } = new;
+        Self {
+            combat_stats: changed(&old.combat_stats, combat_stats),
+            race: changed(&old.race, race),
+            class: changed(&old.class, class),
+            title: changed(&old.title, title),
+            level: changed(&old.level, level),
+            xp: changed(&old.xp, xp),
+            xp_to_level: changed(&old.xp_to_level, xp_to_level),
+            inventory: changed(&old.inventory, inventory),
+            proficiencies: changed(&old.proficiencies, proficiencies),
+            divine_spells: changed(&old.divine_spells, divine_spells),
+            arcane_spells: changed(&old.arcane_spells, arcane_spells),
+            notes: changed(&old.notes, notes),
+            party: changed(&old.party, party),
+            macros: changed(&old.macros, macros),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.combat_stats.is_none()
+            && self.race.is_none()
+            && self.class.is_none()
+            && self.title.is_none()
+            && self.level.is_none()
+            && self.xp.is_none()
+            && self.xp_to_level.is_none()
+            && self.inventory.is_none()
+            && self.proficiencies.is_none()
+            && self.divine_spells.is_none()
+            && self.arcane_spells.is_none()
+            && self.notes.is_none()
+            && self.party.is_none()
+            && self.macros.is_none()
+    }
+
+    /// Applies the changes to a sheet.
+    pub fn apply(self, sheet: &mut PlayerCharacter) {
+        if let Some(combat_stats) = self.combat_stats {
+            sheet.combat_stats = combat_stats;
+        }
+        if let Some(race) = self.race {
+            sheet.race = race;
+        }
+        if let Some(class) = self.class {
+            sheet.class = class;
+        }
+        if let Some(title) = self.title {
+            sheet.title = title;
+        }
+        if let Some(level) = self.level {
+            sheet.level = level;
+        }
+        if let Some(xp) = self.xp {
+            sheet.xp = xp;
+        }
+        if let Some(xp_to_level) = self.xp_to_level {
+            sheet.xp_to_level = xp_to_level;
+        }
+        if let Some(inventory) = self.inventory {
+            sheet.inventory = inventory;
+        }
+        if let Some(proficiencies) = self.proficiencies {
+            sheet.proficiencies = proficiencies;
+        }
+        if let Some(divine_spells) = self.divine_spells {
+            sheet.divine_spells = divine_spells;
+        }
+        if let Some(arcane_spells) = self.arcane_spells {
+            sheet.arcane_spells = arcane_spells;
+        }
+        if let Some(notes) = self.notes {
+            sheet.notes = notes;
+        }
+        if let Some(party) = self.party {
+            sheet.party = party;
+        }
+        if let Some(macros) = self.macros {
+            sheet.macros = macros;
+        }
+    }
+}
+
+/// `Some` copy of `new` if it differs from `old`.
+fn changed<T: Serialize + Clone>(old: &T, new: &T) -> Option<T> {
+    match (ron::to_string(old), ron::to_string(new)) {
+        (Ok(a), Ok(b)) if a == b => None,
+        _ => Some(new.clone()),
+    }
+}
+
 /// The six basic attributes (ability scores).
 #[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 pub struct Attributes {
@@ -342,28 +486,31 @@ pub struct Attributes {
 }
 
 impl Attributes {
-    pub fn random() -> Self {
+    pub fn random(rng: &mut impl Rng) -> Self {
         let r = DiceRoll::simple(3, 6);
         Self {
-            strength: roll(r) as u8,
-            dexterity: roll(r) as u8,
-            constitution: roll(r) as u8,
-            intelligence: roll(r) as u8,
-            wisdom: roll(r) as u8,
-            charisma: roll(r) as u8,
+            strength: roll(r, rng) as u8,
+            dexterity: roll(r, rng) as u8,
+            constitution: roll(r, rng) as u8,
+            intelligence: roll(r, rng) as u8,
+            wisdom: roll(r, rng) as u8,
+            charisma: roll(r, rng) as u8,
         }
     }
-    /// Gets the attribute modifier for the specified attribute.
-    pub fn modifier(&self, attr: Attr) -> i32 {
-        let a = match attr {
+    /// Gets the score of the specified attribute.
+    pub fn get(&self, attr: Attr) -> u8 {
+        match attr {
             Attr::STR => self.strength,
             Attr::DEX => self.dexterity,
             Attr::CON => self.constitution,
             Attr::INT => self.intelligence,
             Attr::WIS => self.wisdom,
             Attr::CHA => self.charisma,
-        };
-        match a {
+        }
+    }
+    /// Gets the attribute modifier for the specified attribute.
+    pub fn modifier(&self, attr: Attr) -> i32 {
+        match self.get(attr) {
             18.. => 3,
             16..=17 => 2,
             13..=15 => 1,
@@ -373,6 +520,20 @@ impl Attributes {
             ..=3 => -3,
         }
     }
+    /// Gets an attribute modifier by its short name (e.g. `STR`), for use in dice expressions. 
+    /// Case insensitive.
+    pub fn named_modifier(&self, name: &str) -> Option<i32> {
+        let attr = match name.to_uppercase().as_str() {
+            "STR" => Attr::STR,
+            "DEX" => Attr::DEX,
+            "CON" => Attr::CON,
+            "INT" => Attr::INT,
+            "WIS" => Attr::WIS,
+            "CHA" => Attr::CHA,
+            _ => return None,
+        };
+        Some(self.modifier(attr))
+    }
 }
 
 #[simple_enum(display)]
@@ -1,10 +1,11 @@
 use std::{collections::{HashMap, hash_map::Iter, HashSet, BTreeSet}, ops::{AddAssign, SubAssign}, cmp::Ordering};
 
 use displaydoc::Display;
+use rand::Rng;
 use serde::{Serialize, Deserialize};
 use simple_enum_macro::simple_enum;
 
-use crate::{character::{Attributes, Health, SavingThrows}, dm_app::DMAppData, packets::ClientBoundPacket, dice::{self, DiceRoll}, enemy::AttackRoutine, common_ui::ChatMessage, spell::MagicType, player_app::{CombatRoundState, CombatState}};
+use crate::{character::{Attributes, Health, SavingThrows}, dm_app::DMAppData, packets::ClientBoundPacket, dice::{self, DiceRoll, RollResult, ModifierType}, enemy::AttackRoutine, common_ui::ChatMessage, spell::MagicType, player_app::{CombatRoundState, CombatState}};
 
 /// All the stats required for something to engage in combat. All of these are *base* stats, before
 /// any modifiers! This means `armor_class` will be zero for most characters, unless they have 
@@ -79,7 +80,7 @@ impl CombatantStats {
         }
     }
 
-    pub fn saving_throw(&self, save: SavingThrowType) -> bool {
+    pub fn saving_throw(&self, save: SavingThrowType, rng: &mut impl Rng) -> bool {
         let modifier = match save {
             SavingThrowType::PetrificationParalysis => {
                 self.saving_throws.petrification_paralysis + self.modifiers.save_petrification_paralysis.total()
@@ -97,7 +98,7 @@ impl CombatantStats {
                 self.saving_throws.spells + self.modifiers.save_spells.total()
             },
         };
-        let nat = DiceRoll::simple(1, 20).roll();
+        let nat = DiceRoll::simple(1, 20).roll(rng);
         nat >= 20 || nat + modifier >= 20
     }
 
@@ -278,8 +279,14 @@ impl DamageRoll {
         }
     }
 
-    pub fn roll(&self) -> i32 {
-        self.as_diceroll().roll()
+    pub fn roll(&self, rng: &mut impl Rng) -> i32 {
+        self.as_diceroll().roll(rng)
+    }
+
+    pub fn roll_detailed(&self, rng: &mut impl Rng) -> RollResult {
+        let mut result = self.as_diceroll().roll_detailed(rng);
+        result.label = format!("Damage: {}", self.to_notation());
+        result
     }
 }
 
@@ -499,7 +506,7 @@ impl Fight {
         let mut list = vec![];
         for (owner, ctype) in &self.combatants {
             if !data.get_combatant_stats_alt(ctype, |c| c.status_effects.is_incapacitated()).unwrap_or(true) {
-                let mut r = dice::roll(DiceRoll::simple(1, 6)) as i32;
+                let mut r = dice::roll(DiceRoll::simple(1, 6), &mut data.rng) as i32;
                 r += data.get_combatant_stats_alt(ctype, |c| c.modifiers.initiative.total()).unwrap_or(0);
 
                 list.push((owner.clone(), ctype.clone(), self.declarations.get(ctype).cloned().unwrap_or(PreRoundAction::None), r));
@@ -757,9 +764,10 @@ impl Fight {
     }
 
     pub fn make_attack(&mut self, data: &mut DMAppData, attacker: &Combatant, target: &Combatant, modifier: i32) {
-        match attack_roll(data, attacker, target, modifier) {
+        let (result, attack) = attack_roll(data, attacker, target, modifier);
+        match result {
             AttackResult::CriticalFail => {
-                let msg = match dice::roll(DiceRoll::simple(1, 6)) {
+                let msg = match dice::roll(DiceRoll::simple(1, 6), &mut data.rng) {
                     ..=1 => format!("{} failed miserably when attacking {}!", attacker, target),
                     2 => format!("{} critically missed {}!", attacker, target),
                     3 => format!("{} utterly whiffed an attempt to hit {}!", attacker, target),
@@ -767,33 +775,35 @@ impl Fight {
                     5 => format!("Whatever {} tried to do to {}, it didn\'t work very well.", attacker, target),
                     6.. => format!("{} lands a devastating warning blow toward {}! It did absolutely nothing.", attacker, target),
                 };
-                data.log(ChatMessage::no_sender(msg).combat().dice_roll());
+                data.log(ChatMessage::no_sender(msg).combat().dice_roll().with_roll(attack));
             },
             AttackResult::Fail => {
-                data.log(ChatMessage::no_sender(format!("{} missed {}!", attacker, target)).combat().dice_roll());
+                data.log(ChatMessage::no_sender(format!("{} missed {}!", attacker, target)).combat().dice_roll().with_roll(attack));
             },
             AttackResult::Success => {
-                let damage = damage_roll(data, attacker, false);
+                let damage_result = damage_roll(data, attacker, false);
+                let damage = damage_result.total;
                 let mut killed = false;
                 data.get_combatant_stats(target, |stats| {
                     if let Some(stats) = stats {
                         killed = stats.hurt(damage as u32);
                     }
                 });
-                data.log(ChatMessage::no_sender(format!("{} hit {} for {} damage!", attacker, target, damage)).combat().dice_roll());
+                data.log(ChatMessage::no_sender(format!("{} hit {} for {} damage!", attacker, target, damage)).combat().dice_roll().with_roll(attack).with_roll(damage_result));
                 if killed {
                     data.log(ChatMessage::no_sender(format!("{} was killed!", target)).combat().red());
                 }
             },
             AttackResult::CriticalSuccess => {
-                let damage = damage_roll(data, attacker, true);
+                let damage_result = damage_roll(data, attacker, true);
+                let damage = damage_result.total;
                 let mut killed = false;
                 data.get_combatant_stats(target, |stats| {
                     if let Some(stats) = stats {
                         killed = stats.hurt(damage as u32);
                     }
                 });
-                let msg = match dice::roll(DiceRoll::simple(1, 6)) {
+                let msg = match dice::roll(DiceRoll::simple(1, 6), &mut data.rng) {
                     ..=1 => format!("{} critically hit {} for a whopping {} damage!", attacker, target, damage),
                     2 => format!("{} absolutely devastated {} for {} damage!", attacker, target, damage),
                     3 => format!("{} expertly struck {} for {} damage!", attacker, target, damage),
@@ -801,7 +811,7 @@ impl Fight {
                     5 => format!("{} obliterated {} for a staggering {} damage!", attacker, target, damage),
                     6.. => format!("{} asked nicely for {} to go away. With force. It did {} damage!", attacker, target, damage),
                 };
-                data.log(ChatMessage::no_sender(msg).combat().dice_roll());
+                data.log(ChatMessage::no_sender(msg).combat().dice_roll().with_roll(attack).with_roll(damage_result));
                 if killed {
                     data.log(ChatMessage::no_sender(format!("{} was killed!", target)).combat().red());
                 }
@@ -827,31 +837,37 @@ impl Fight {
     }
 }
 
-pub fn damage_roll(data: &mut DMAppData, attacker: &Combatant, critical: bool) -> i32 {
-    data.get_combatant_stats_alt(attacker, |stats| {
+pub fn damage_roll(data: &mut DMAppData, attacker: &Combatant, critical: bool) -> RollResult {
+    let stats = data.get_combatant_stats_alt(attacker, |stats| {
         let damage = stats.current_damage().unwrap_or(DamageRoll::default());
-        let mut nat = damage.roll();
-        if critical {
-            nat *= 2;
-        }
-        match damage.attack_type {
-            AttackType::Melee => {
-                match nat + stats.modifiers.melee_damage.total() {
-                    i if i < 1 => 1,
-                    i => i,
-                }
-            },
-            AttackType::Missile => {
-                match nat + stats.modifiers.missile_damage.total() {
-                    i if i < 1 => 1,
-                    i => i,
-                }
-            },
-        }
-    }).unwrap_or(1)
+        let bonus = match damage.attack_type {
+            AttackType::Melee => stats.modifiers.melee_damage.total(),
+            AttackType::Missile => stats.modifiers.missile_damage.total(),
+        };
+        (damage, bonus)
+    });
+    let (damage, bonus) = match stats {
+        Some(stats) => stats,
+        None => {
+            let mut result = RollResult::new("Damage");
+            result.total = 1;
+            return result;
+        },
+    };
+    let mut result = damage.roll_detailed(&mut data.rng);
+    if critical {
+        result.push_step("critical", 2, ModifierType::Multiply);
+    }
+    match damage.attack_type {
+        AttackType::Melee => result.push_step("melee damage", bonus, ModifierType::Add),
+        AttackType::Missile => result.push_step("missile damage", bonus, ModifierType::Add),
+    }
+    result.clamp_min(1);
+    result
 }
 
-pub fn attack_roll(data: &mut DMAppData, attacker: &Combatant, target: &Combatant, modifiers: i32) -> AttackResult {
+/// Makes an attack roll, returning the outcome and the details of the roll.
+pub fn attack_roll(data: &mut DMAppData, attacker: &Combatant, target: &Combatant, modifiers: i32) -> (AttackResult, RollResult) {
     let attack_throw = data.get_combatant_stats_alt(attacker, |s| {
         match s.current_damage().unwrap_or(DamageRoll::default()).attack_type {
             AttackType::Melee => {
@@ -863,22 +879,20 @@ pub fn attack_roll(data: &mut DMAppData, attacker: &Combatant, target: &Combatan
         }
     }).unwrap_or(10);
     let armor_class = data.get_combatant_stats_alt(target, |s| s.armor_class + s.modifiers.armor_class.total()).unwrap_or(0);
-    let r = d20_exploding();
-    if r <= 1 {
-        return AttackResult::CriticalFail;
+    let mut result = DiceRoll::simple_exploding(1, 20).roll_detailed(&mut data.rng);
+    result.label = "Attack: 1d20!".to_owned();
+    if result.total <= 1 {
+        return (AttackResult::CriticalFail, result);
     }
-    match r + attack_throw - armor_class + modifiers {
+    result.push_step("attack throw", attack_throw, ModifierType::Add);
+    result.push_step("armor class", -armor_class, ModifierType::Add);
+    result.push_step("situational", modifiers, ModifierType::Add);
+    let outcome = match result.total {
         ..=19 => AttackResult::Fail,
         20..=29 => AttackResult::Success,
         30.. => AttackResult::CriticalSuccess,
-    }
-}
-
-fn d20_exploding() -> i32 {
-    match dice::roll(DiceRoll::simple(1, 20)) {
-        20 => 20 + d20_exploding(),
-        i => i as i32,
-    }
+    };
+    (outcome, result)
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, Copy)]
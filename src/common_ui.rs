@@ -1,6 +1,6 @@
 use std::hash::Hash;
 
-use crate::{dice::{DiceRoll, ModifierType, Drop}, combat::{DamageRoll, StatMod}, dm_app::{Registry, RegistryNode}, enemy::EnemyType, item::{ItemType, Item, WeaponDamage, MeleeDamage, ContainerStats}};
+use crate::{dice::{DiceRoll, ModifierType, Drop, RollResult, MAX_EXPR_DICE, MAX_DICE_SIDES}, combat::{DamageRoll, StatMod}, dm_app::{Registry, RegistryNode}, enemy::EnemyType, item::{ItemType, Item, WeaponDamage, MeleeDamage, ContainerStats}};
 use eframe::{egui::{self, Ui, RichText, Button, TextFormat}, epaint::{text::LayoutJob, Color32}, emath::Align};
 use egui::{FontId, Stroke, Id, WidgetText, InnerResponse};
 use egui_dock::Tree;
@@ -91,6 +91,9 @@ impl TabValue for CharacterSheetTab {
 pub fn dice_roll_editor(ui: &mut Ui, roll: &mut DiceRoll) {
     ui.add(egui::Slider::new(&mut roll.amount, 1..=10).text("Amount").clamp_to_range(false));
     ui.add(egui::Slider::new(&mut roll.sides, 1..=20).text("Sides").clamp_to_range(false));
+    // larger values can still be typed in, but not more than a roll can handle
+    roll.amount = roll.amount.min(MAX_EXPR_DICE);
+    roll.sides = roll.sides.min(MAX_DICE_SIDES);
     ui.add(egui::Slider::new(&mut roll.modifier, -10..=10).text("Modifier").clamp_to_range(false));
     egui::ComboBox::from_label("Modifier type")
         .selected_text(roll.modifier_type.to_string())
@@ -119,6 +122,10 @@ pub fn dice_roll_editor(ui: &mut Ui, roll: &mut DiceRoll) {
                 Drop::DropHighest(0), Drop::DropHighest(0).to_string());
             ui.selectable_value(&mut roll.drop,
                 Drop::DropLowest(0),  Drop::DropLowest(0).to_string());
+            ui.selectable_value(&mut roll.drop,
+                Drop::KeepHighest(0), Drop::KeepHighest(0).to_string());
+            ui.selectable_value(&mut roll.drop,
+                Drop::KeepLowest(0),  Drop::KeepLowest(0).to_string());
         });
     match &mut roll.drop {
         Drop::None => {},
@@ -128,6 +135,29 @@ pub fn dice_roll_editor(ui: &mut Ui, roll: &mut DiceRoll) {
         Drop::DropLowest(amount) => {
             ui.add(egui::Slider::new(amount, 0..=10).text("Drop amount").clamp_to_range(false));
         },
+        Drop::KeepHighest(amount) => {
+            ui.add(egui::Slider::new(amount, 0..=10).text("Keep amount").clamp_to_range(false));
+        },
+        Drop::KeepLowest(amount) => {
+            ui.add(egui::Slider::new(amount, 0..=10).text("Keep amount").clamp_to_range(false));
+        },
+    }
+    ui.checkbox(&mut roll.explode, "Exploding")
+        .on_hover_text("Roll another die whenever a die rolls its highest value");
+    let mut reroll = roll.reroll_below.is_some();
+    if ui.checkbox(&mut reroll, "Reroll low dice").changed() {
+        roll.reroll_below = if reroll {Some(1)} else {None};
+    }
+    if let Some(below) = &mut roll.reroll_below {
+        ui.add(egui::Slider::new(below, 1..=10).text("Reroll at or below").clamp_to_range(false));
+    }
+    let mut count_successes = roll.success_target.is_some();
+    if ui.checkbox(&mut count_successes, "Count successes").changed() {
+        roll.success_target = if count_successes {Some(roll.sides)} else {None};
+        roll.min_value = if count_successes {0} else {1};
+    }
+    if let Some(target) = &mut roll.success_target {
+        ui.add(egui::Slider::new(target, 1..=20).text("Success target").clamp_to_range(false));
     }
     ui.add(egui::Slider::new(&mut roll.min_value, -10..=10).text("Minimum value").clamp_to_range(false));
 }
@@ -135,6 +165,9 @@ pub fn dice_roll_editor(ui: &mut Ui, roll: &mut DiceRoll) {
 pub fn dice_roll_editor_simple(ui: &mut Ui, roll: &mut DiceRoll) {
     ui.add(egui::Slider::new(&mut roll.amount, 1..=10).text("Amount").clamp_to_range(false));
     ui.add(egui::Slider::new(&mut roll.sides, 1..=20).text("Sides").clamp_to_range(false));
+    // larger values can still be typed in, but not more than a roll can handle
+    roll.amount = roll.amount.min(MAX_EXPR_DICE);
+    roll.sides = roll.sides.min(MAX_DICE_SIDES);
     ui.add(egui::Slider::new(&mut roll.modifier, -10..=10).text("Modifier").clamp_to_range(false));
 }
 
@@ -293,9 +326,19 @@ pub struct ChatMessage {
     pub strikethrough: bool,
     pub underline: bool,
     pub valign: Align,
+    /// The details of any dice rolled for this message. Shown when hovering over it.
+    #[serde(default)]
+    pub rolls: Vec<RollResult>,
 }
 
 impl ChatMessage {
+    pub fn to_log_entry(&self) -> ChatLogEntry {
+        ChatLogEntry {
+            job: self.to_layout_job(),
+            rolls: self.rolls.clone(),
+        }
+    }
+
     pub fn to_layout_job(&self) -> LayoutJob {
         let mut job = LayoutJob::default();
         job.append(&format!("{}{}{}{}", 
@@ -447,6 +490,52 @@ impl ChatMessage {
     pub fn blue(self) -> Self {
         self.color(Color32::BLUE)
     }
+
+    /// Attaches the details of a roll to this message.
+    pub fn with_roll(mut self, roll: RollResult) -> Self {
+        self.rolls.push(roll);
+        self
+    }
+
+    /// Attaches the details of several rolls to this message.
+    pub fn with_rolls(mut self, rolls: Vec<RollResult>) -> Self {
+        self.rolls.extend(rolls);
+        self
+    }
+}
+
+/// Splits a chat command into tokens on whitespace, keeping "quoted sections" together.
+pub fn command_tokens(command: &str) -> impl Iterator<Item = &str> {
+    let mut in_quotes = false;
+    command.split(move |c: char| {
+        if c == '\'' || c == '\"' {
+            in_quotes = !in_quotes;
+        }
+        !in_quotes && c.is_whitespace()
+    }).map(|s| s.trim_matches(|c| c == '\'' || c == '\"'))
+}
+
+/// A message in the chat log, ready to be displayed.
+#[derive(Debug, Clone)]
+pub struct ChatLogEntry {
+    pub job: LayoutJob,
+    pub rolls: Vec<RollResult>,
+}
+
+/// Displays a chat log entry. If any dice were rolled for it, hovering shows how each roll
+/// came about.
+pub fn chat_log_entry(ui: &mut Ui, entry: &ChatLogEntry) {
+    let response = ui.label(entry.job.clone());
+    if !entry.rolls.is_empty() {
+        response.on_hover_ui(|ui| {
+            for (i, roll) in entry.rolls.iter().enumerate() {
+                if i > 0 {
+                    ui.separator();
+                }
+                ui.label(roll.breakdown());
+            }
+        });
+    }
 }
 
 impl Default for ChatMessage {
@@ -461,6 +550,7 @@ impl Default for ChatMessage {
             strikethrough: false,
             underline: false,
             valign: Align::Max,
+            rolls: Vec::new(),
         }
     }
 }
@@ -0,0 +1,416 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::time::Duration;
+
+use rusqlite::{Connection, OptionalExtension, params};
+
+use crate::common_ui::ChatMessage;
+use crate::dm_app::{Role, SaveData, UserData};
+use crate::map::Map;
+use crate::migration::{self, FileKind, Loaded, RonValue};
+use crate::party::Party;
+use crate::storage::{self, CampaignStore, StoreKind, UnsavedChanges};
+
+/// Every value is stored as the RON it would have in the save file, so the same upgrades can be
+/// applied to it. Characters are kept apart from the rest of their user's data, so one can be
+/// written without the others.
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS meta (key TEXT PRIMARY KEY, value TEXT NOT NULL);
+    CREATE TABLE IF NOT EXISTS users (name TEXT PRIMARY KEY, password TEXT, role TEXT, banned INTEGER NOT NULL, data TEXT);
+    CREATE TABLE IF NOT EXISTS characters (user TEXT NOT NULL, name TEXT NOT NULL, data TEXT NOT NULL, PRIMARY KEY (user, name));
+    CREATE TABLE IF NOT EXISTS parties (name TEXT PRIMARY KEY, data TEXT NOT NULL);
+    CREATE TABLE IF NOT EXISTS maps (id TEXT PRIMARY KEY, name TEXT NOT NULL, data TEXT NOT NULL);
+    CREATE TABLE IF NOT EXISTS chat (id INTEGER PRIMARY KEY AUTOINCREMENT, time TEXT NOT NULL, message TEXT NOT NULL);
+";
+
+/// A row of the users table. Names can have a role or be banned without having an account.
+#[derive(Debug, Clone, PartialEq)]
+struct UserRow {
+    password: Option<String>,
+    role: Option<String>,
+    banned: bool,
+    /// Their [`UserData`] without any characters.
+    data: Option<String>,
+}
+
+/// Stores a campaign in an SQLite database. The rows as they were last loaded or saved are kept
+/// in memory, so saving only writes the ones that changed.
+pub struct SqliteStore {
+    conn: Connection,
+    /// The version of the stored rows, or `None` if nothing has been saved yet.
+    version: Option<u32>,
+    /// Whether the rows below have been read from the database yet.
+    rows_read: bool,
+    meta: HashMap<String, String>,
+    users: HashMap<String, UserRow>,
+    characters: HashMap<(String, String), String>,
+    parties: HashMap<String, String>,
+}
+
+impl SqliteStore {
+    /// Opens the database, creating it if it doesn't exist.
+    pub fn open(path: &Path) -> Result<Self, String> {
+        if let Some(parent) = path.parent() {
+            if !parent.as_os_str().is_empty() {
+                std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+            }
+        }
+        let conn = Connection::open(path).map_err(|e| e.to_string())?;
+        conn.execute_batch(SCHEMA).map_err(|e| e.to_string())?;
+        let version: Option<String> = conn.query_row("SELECT value FROM meta WHERE key = 'version'", [], |row| row.get(0)).optional().map_err(|e| e.to_string())?;
+        let version = match version {
+            Some(version) => Some(version.parse::<u32>().map_err(|e| format!("the stored version \"{}\" is not a number: {}", version, e))?),
+            None => None,
+        };
+        Ok(Self {
+            conn,
+            version,
+            rows_read: false,
+            meta: HashMap::new(),
+            users: HashMap::new(),
+            characters: HashMap::new(),
+            parties: HashMap::new(),
+        })
+    }
+
+    /// Reads every row of the save data into memory.
+    fn read_rows(&mut self) -> Result<(), String> {
+        self.meta.clear();
+        self.users.clear();
+        self.characters.clear();
+        self.parties.clear();
+        let mut statement = self.conn.prepare("SELECT key, value FROM meta").map_err(|e| e.to_string())?;
+        let rows = statement.query_map([], |row| Ok((row.get(0)?, row.get(1)?))).map_err(|e| e.to_string())?;
+        for row in rows {
+            let (key, value) = row.map_err(|e| e.to_string())?;
+            self.meta.insert(key, value);
+        }
+        let mut statement = self.conn.prepare("SELECT name, password, role, banned, data FROM users").map_err(|e| e.to_string())?;
+        let rows = statement.query_map([], |row| {
+            Ok((row.get(0)?, UserRow { password: row.get(1)?, role: row.get(2)?, banned: row.get(3)?, data: row.get(4)? }))
+        }).map_err(|e| e.to_string())?;
+        for row in rows {
+            let (name, user) = row.map_err(|e| e.to_string())?;
+            self.users.insert(name, user);
+        }
+        let mut statement = self.conn.prepare("SELECT user, name, data FROM characters").map_err(|e| e.to_string())?;
+        let rows = statement.query_map([], |row| Ok(((row.get(0)?, row.get(1)?), row.get(2)?))).map_err(|e| e.to_string())?;
+        for row in rows {
+            let (key, data) = row.map_err(|e| e.to_string())?;
+            self.characters.insert(key, data);
+        }
+        let mut statement = self.conn.prepare("SELECT name, data FROM parties").map_err(|e| e.to_string())?;
+        let rows = statement.query_map([], |row| Ok((row.get(0)?, row.get(1)?))).map_err(|e| e.to_string())?;
+        for row in rows {
+            let (name, data) = row.map_err(|e| e.to_string())?;
+            self.parties.insert(name, data);
+        }
+        self.rows_read = true;
+        Ok(())
+    }
+
+    /// Puts the rows back together as the save file they came from.
+    fn rows_to_text(&self) -> Result<String, String> {
+        let mut known_users = Vec::new();
+        let mut user_data = Vec::new();
+        let mut roles = Vec::new();
+        let mut banned = Vec::new();
+        let mut names: Vec<&String> = self.users.keys().collect();
+        names.sort();
+        for name in names {
+            let user = &self.users[name];
+            let key = ron::to_string(name).map_err(|e| e.to_string())?;
+            if let Some(password) = &user.password {
+                known_users.push(format!("{}:{}", key, ron::to_string(password).map_err(|e| e.to_string())?));
+            }
+            if let Some(role) = &user.role {
+                roles.push(format!("{}:{}", key, role));
+            }
+            if user.banned {
+                banned.push(key.clone());
+            }
+            if let Some(data) = &user.data {
+                let mut tree = RonValue::parse(data).map_err(|e| format!("user \"{}\": {}", name, e))?;
+                let mut characters = Vec::new();
+                for ((user, character), data) in &self.characters {
+                    if user == name {
+                        let value = RonValue::parse(data).map_err(|e| format!("character \"{}\" of user \"{}\": {}", character, user, e))?;
+                        characters.push((RonValue::Literal(ron::to_string(character).map_err(|e| e.to_string())?), value));
+                    }
+                }
+                tree.insert_field("characters", RonValue::Map(characters)).map_err(|e| format!("user \"{}\": {}", name, e))?;
+                user_data.push(format!("{}:{}", key, tree));
+            }
+        }
+        let mut parties = Vec::new();
+        for (name, data) in &self.parties {
+            parties.push(format!("{}:{}", ron::to_string(name).map_err(|e| e.to_string())?, data));
+        }
+        Ok(format!(
+            "(version:{},known_users:{{{}}},user_data:{{{}}},parties:{{{}}},roles:{{{}}},banned:[{}],registration_closed:{},campaign_name:{})",
+            self.version.unwrap_or(0),
+            known_users.join(","),
+            user_data.join(","),
+            parties.join(","),
+            roles.join(","),
+            banned.join(","),
+            self.meta.get("registration_closed").map_or("false", |s| s.as_str()),
+            self.meta.get("campaign_name").map_or("\"\"", |s| s.as_str()),
+        ))
+    }
+
+    /// Reads the rows straight into their types, which only works if they were written by this
+    /// version of the app.
+    fn rows_to_save_data(&self) -> Result<SaveData, String> {
+        let mut data = SaveData {
+            version: FileKind::Save.version(),
+            known_users: HashMap::new(),
+            user_data: HashMap::new(),
+            parties: HashMap::new(),
+            roles: HashMap::new(),
+            admins: HashSet::new(),
+            banned: HashSet::new(),
+            registration_closed: false,
+            campaign_name: String::new(),
+        };
+        for (name, user) in &self.users {
+            if let Some(password) = &user.password {
+                data.known_users.insert(name.clone(), password.clone());
+            }
+            if let Some(role) = &user.role {
+                data.roles.insert(name.clone(), ron::from_str::<Role>(role).map_err(|e| format!("the role of user \"{}\": {}", name, e))?);
+            }
+            if user.banned {
+                data.banned.insert(name.clone());
+            }
+            if let Some(user_data) = &user.data {
+                data.user_data.insert(name.clone(), ron::from_str::<UserData>(user_data).map_err(|e| format!("user \"{}\": {}", name, e))?);
+            }
+        }
+        for ((user, name), character) in &self.characters {
+            let character = ron::from_str(character).map_err(|e| format!("character \"{}\" of user \"{}\": {}", name, user, e))?;
+            data.user_data.entry(user.clone()).or_insert_with(UserData::new).characters.insert(name.clone(), character);
+        }
+        for (name, party) in &self.parties {
+            data.parties.insert(name.clone(), ron::from_str::<Party>(party).map_err(|e| format!("party \"{}\": {}", name, e))?);
+        }
+        if let Some(closed) = self.meta.get("registration_closed") {
+            data.registration_closed = ron::from_str(closed).map_err(|e| e.to_string())?;
+        }
+        if let Some(name) = self.meta.get("campaign_name") {
+            data.campaign_name = ron::from_str(name).map_err(|e| e.to_string())?;
+        }
+        Ok(data)
+    }
+}
+
+/// The rows that change when users or their characters do.
+struct UserRows {
+    meta: HashMap<String, String>,
+    users: HashMap<String, UserRow>,
+    characters: HashMap<(String, String), String>,
+}
+
+/// Splits the save data into the rows it is stored as, except for parties.
+fn user_rows(data: &SaveData) -> Result<UserRows, String> {
+    let mut meta = HashMap::new();
+    meta.insert("version".to_owned(), FileKind::Save.version().to_string());
+    meta.insert("registration_closed".to_owned(), ron::to_string(&data.registration_closed).map_err(|e| e.to_string())?);
+    meta.insert("campaign_name".to_owned(), ron::to_string(&data.campaign_name).map_err(|e| e.to_string())?);
+    let mut users: HashMap<String, UserRow> = HashMap::new();
+    let mut characters = HashMap::new();
+    for (name, password) in &data.known_users {
+        users.entry(name.clone()).or_insert(UserRow { password: None, role: None, banned: false, data: None }).password = Some(password.clone());
+    }
+    for (name, role) in &data.roles {
+        users.entry(name.clone()).or_insert(UserRow { password: None, role: None, banned: false, data: None }).role = Some(ron::to_string(role).map_err(|e| e.to_string())?);
+    }
+    for name in &data.banned {
+        users.entry(name.clone()).or_insert(UserRow { password: None, role: None, banned: false, data: None }).banned = true;
+    }
+    for (name, user_data) in &data.user_data {
+        for (character_name, character) in &user_data.characters {
+            characters.insert((name.clone(), character_name.clone()), ron::to_string(character).map_err(|e| e.to_string())?);
+        }
+        let without_characters = UserData { characters: HashMap::new(), ..user_data.clone() };
+        users.entry(name.clone()).or_insert(UserRow { password: None, role: None, banned: false, data: None }).data = Some(ron::to_string(&without_characters).map_err(|e| e.to_string())?);
+    }
+    Ok(UserRows { meta, users, characters })
+}
+
+impl CampaignStore for SqliteStore {
+    fn kind(&self) -> StoreKind {
+        StoreKind::Sqlite
+    }
+
+    fn load(&mut self) -> Result<Option<Loaded<SaveData>>, String> {
+        let version = match self.version {
+            Some(version) => version,
+            None => return Ok(None),
+        };
+        self.read_rows()?;
+        if version == FileKind::Save.version() {
+            Ok(Some(Loaded { value: self.rows_to_save_data()?, version, upgrades: Vec::new() }))
+        } else {
+            // upgrades are written for the whole save file
+            migration::from_str::<SaveData>(FileKind::Save, &self.rows_to_text()?).map(Some)
+        }
+    }
+
+    fn saved_text(&mut self) -> Result<Option<String>, String> {
+        if self.version.is_none() {
+            return Ok(None);
+        }
+        self.read_rows()?;
+        self.rows_to_text().map(Some)
+    }
+
+    fn save(&mut self, data: &SaveData, unsaved: UnsavedChanges) -> Result<(), String> {
+        // rows written by another version are all rewritten, so none are left in the old format
+        let everything = self.version != Some(FileKind::Save.version());
+        if !everything && !self.rows_read {
+            self.read_rows()?;
+        }
+        let tx = self.conn.transaction().map_err(|e| e.to_string())?;
+        if everything {
+            tx.execute_batch("DELETE FROM meta; DELETE FROM users; DELETE FROM characters; DELETE FROM parties;").map_err(|e| e.to_string())?;
+            self.meta.clear();
+            self.users.clear();
+            self.characters.clear();
+            self.parties.clear();
+        }
+        let mut new_rows = None;
+        if everything || unsaved.users {
+            let rows = user_rows(data)?;
+            for (key, value) in &rows.meta {
+                if self.meta.get(key) != Some(value) {
+                    tx.execute("INSERT OR REPLACE INTO meta (key, value) VALUES (?1, ?2)", params![key, value]).map_err(|e| e.to_string())?;
+                }
+            }
+            for (name, user) in &rows.users {
+                if self.users.get(name) != Some(user) {
+                    tx.execute("INSERT OR REPLACE INTO users (name, password, role, banned, data) VALUES (?1, ?2, ?3, ?4, ?5)", params![name, user.password, user.role, user.banned, user.data]).map_err(|e| e.to_string())?;
+                }
+            }
+            for name in self.users.keys() {
+                if !rows.users.contains_key(name) {
+                    tx.execute("DELETE FROM users WHERE name = ?1", params![name]).map_err(|e| e.to_string())?;
+                }
+            }
+            for ((user, name), character) in &rows.characters {
+                if self.characters.get(&(user.clone(), name.clone())) != Some(character) {
+                    tx.execute("INSERT OR REPLACE INTO characters (user, name, data) VALUES (?1, ?2, ?3)", params![user, name, character]).map_err(|e| e.to_string())?;
+                }
+            }
+            for (user, name) in self.characters.keys() {
+                if !rows.characters.contains_key(&(user.clone(), name.clone())) {
+                    tx.execute("DELETE FROM characters WHERE user = ?1 AND name = ?2", params![user, name]).map_err(|e| e.to_string())?;
+                }
+            }
+            new_rows = Some(rows);
+        }
+        let mut new_parties = None;
+        if everything || unsaved.parties {
+            let mut parties = HashMap::new();
+            for (name, party) in &data.parties {
+                parties.insert(name.clone(), ron::to_string(party).map_err(|e| e.to_string())?);
+            }
+            for (name, party) in &parties {
+                if self.parties.get(name) != Some(party) {
+                    tx.execute("INSERT OR REPLACE INTO parties (name, data) VALUES (?1, ?2)", params![name, party]).map_err(|e| e.to_string())?;
+                }
+            }
+            for name in self.parties.keys() {
+                if !parties.contains_key(name) {
+                    tx.execute("DELETE FROM parties WHERE name = ?1", params![name]).map_err(|e| e.to_string())?;
+                }
+            }
+            new_parties = Some(parties);
+        }
+        tx.commit().map_err(|e| e.to_string())?;
+        // only once everything is written, so a failed save is tried again in full
+        if let Some(rows) = new_rows {
+            self.meta = rows.meta;
+            self.users = rows.users;
+            self.characters = rows.characters;
+            self.version = Some(FileKind::Save.version());
+        }
+        if let Some(parties) = new_parties {
+            self.parties = parties;
+        }
+        Ok(())
+    }
+
+    fn list_maps(&mut self) -> Vec<(String, Result<String, String>)> {
+        let mut maps = Vec::new();
+        let result = self.conn.prepare("SELECT id, name FROM maps").and_then(|mut statement| {
+            let rows = statement.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?;
+            for row in rows {
+                let (id, name) = row?;
+                maps.push((id, Ok(name)));
+            }
+            Ok(())
+        });
+        if let Err(e) = result {
+            maps.push(("maps".to_owned(), Err(e.to_string())));
+        }
+        maps
+    }
+
+    fn load_map(&mut self, id: &str) -> Result<Map, String> {
+        let data: Option<String> = self.conn.query_row("SELECT data FROM maps WHERE id = ?1", params![id], |row| row.get(0)).optional().map_err(|e| e.to_string())?;
+        match data {
+            Some(data) => migration::from_str::<Map>(FileKind::Map, &data).map(|loaded| loaded.value),
+            None => Err(format!("there is no map \"{}\"", id)),
+        }
+    }
+
+    fn save_map(&mut self, id: &str, map: &Map) -> Result<(), String> {
+        let data = migration::to_string(FileKind::Map, map)?;
+        self.conn.execute("INSERT OR REPLACE INTO maps (id, name, data) VALUES (?1, ?2, ?3)", params![id, map.name, data]).map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    fn backup(&mut self, dir: &Path, keep: usize) -> Result<Option<String>, String> {
+        if keep == 0 {
+            return Ok(None);
+        }
+        std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+        let name = storage::new_backup_name(dir);
+        let temp = dir.join(format!("{}.sqlite.tmp", name));
+        // VACUUM INTO refuses to overwrite, so clear out anything left by a backup that failed
+        let _ = std::fs::remove_file(&temp);
+        self.conn.execute("VACUUM INTO ?1", params![temp.to_string_lossy()]).map_err(|e| e.to_string())?;
+        std::fs::rename(&temp, dir.join(format!("{}.sqlite", name))).map_err(|e| e.to_string())?;
+        storage::remove_old_backups(dir, keep).map_err(|e| e.to_string())?;
+        Ok(Some(name))
+    }
+
+    fn backup_interval(&self) -> Duration {
+        storage::DATABASE_BACKUP_INTERVAL
+    }
+
+    fn append_chat(&mut self, messages: &[ChatMessage]) -> Result<(), String> {
+        let time = chrono::Local::now().to_rfc3339();
+        let tx = self.conn.transaction().map_err(|e| e.to_string())?;
+        for message in messages {
+            let message = ron::to_string(message).map_err(|e| e.to_string())?;
+            tx.execute("INSERT INTO chat (time, message) VALUES (?1, ?2)", params![time, message]).map_err(|e| e.to_string())?;
+        }
+        tx.commit().map_err(|e| e.to_string())
+    }
+
+    fn recent_chat(&mut self, count: usize) -> Result<Vec<ChatMessage>, String> {
+        let mut statement = self.conn.prepare("SELECT message FROM chat ORDER BY id DESC LIMIT ?1").map_err(|e| e.to_string())?;
+        let rows = statement.query_map(params![i64::try_from(count).unwrap_or(i64::MAX)], |row| row.get::<_, String>(0)).map_err(|e| e.to_string())?;
+        let mut messages = Vec::new();
+        for row in rows {
+            // a message that can't be read anymore isn't worth failing over
+            if let Ok(message) = ron::from_str::<ChatMessage>(&row.map_err(|e| e.to_string())?) {
+                messages.push(message);
+            }
+        }
+        messages.reverse();
+        Ok(messages)
+    }
+}
@@ -1,4 +1,4 @@
-use std::{iter::Peekable, str::Chars};
+use std::{collections::BTreeMap, iter::Peekable, str::Chars};
 
 use rand::prelude::*;
 use serde::{Serialize, Deserialize};
@@ -20,6 +20,16 @@ pub struct DiceRoll {
     pub drop: Drop,
     /// The minimum value that this roll will evaluate to.
     pub min_value: i32,
+    /// Whether dice that roll their highest face 'explode', adding another die to the roll.
+    #[serde(default)]
+    pub explode: bool,
+    /// Dice that roll this value or lower are rerolled until they roll higher.
+    #[serde(default)]
+    pub reroll_below: Option<u32>,
+    /// If present, the roll counts how many dice rolled at least this value instead of adding 
+    /// them together.
+    #[serde(default)]
+    pub success_target: Option<u32>,
 }
 
 impl DiceRoll {
@@ -32,11 +42,14 @@ impl DiceRoll {
             apply_modifier_to_all,
             drop,
             min_value,
+            explode: false,
+            reroll_below: None,
+            success_target: None,
         }
     }
 
     pub fn from_notation(s: impl Into<String>) -> Result<Self, String> {
-        // N'd'M('&')(['+'|'-'|['x'|'*']|'/'(['u'|'d'])]A)('_'[['L'|'l'](X)|['H'|'h'](Y)])('>'('=')B)
+        // N'd'M('!')('r'R)('&')(['+'|'-'|['x'|'*']|'/'(['u'|'d'])]A)(['_'['L'|'l'|'H'|'h']|'k'['l'|'h']](X))('s>'('=')T)('>'('=')B)
         let s: String = s.into();
         let mut iter = s.chars().peekable();
         let read_num = |iter: &mut Peekable<Chars>| -> Option<u32> {
@@ -50,7 +63,8 @@ impl DiceRoll {
                 }
             }
             if !num.is_empty() {
-                Some(num.parse::<u32>().unwrap_or(0))
+                // anything larger couldn't be used as a modifier
+                Some(num.parse::<i32>().map_or(0, |n| n as u32))
             } else {
                 None
             }
@@ -67,14 +81,38 @@ impl DiceRoll {
             return Err("Missing 'd' character.".to_owned());
         }
         if let Some(sides) = read_num(&mut iter) {
-            if sides > 0 {
-                roll.sides = sides;
-            } else {
+            if sides == 0 {
                 return Err("Cannot roll a zero-sided dice.".to_owned());
+            } else if sides > MAX_DICE_SIDES {
+                return Err(format!("Dice can't have more than {} sides.", MAX_DICE_SIDES));
+            } else {
+                roll.sides = sides;
             }
         } else {
             return Err("You must specifiy a number of sides.".to_owned());
         }
+        loop {
+            match *iter.peek().unwrap_or(&'q') {
+                '!' if !roll.explode => {
+                    iter.next();
+                    if roll.sides == 1 {
+                        return Err("A one-sided dice cannot explode.".to_owned());
+                    }
+                    roll.explode = true;
+                },
+                'r' if roll.reroll_below.is_none() => {
+                    iter.next();
+                    match read_num(&mut iter) {
+                        Some(n) if n >= roll.sides => {
+                            return Err(format!("Cannot reroll {} or lower on a d{}, every roll would be rerolled!", n, roll.sides));
+                        },
+                        Some(n) => roll.reroll_below = Some(n),
+                        None => return Err("Unexpected 'r' without value.".to_owned()),
+                    }
+                },
+                _ => break,
+            }
+        }
         if *iter.peek().unwrap_or(&'q') == '&' {
             roll.apply_modifier_to_all = true;
             iter.next();
@@ -162,6 +200,38 @@ impl DiceRoll {
                     return Err("Unexpected '_' without 'l' or 'h'.".to_owned());
                 },
             }
+        } else if *iter.peek().unwrap_or(&'q') == 'k' {
+            iter.next();
+            let highest = match iter.next() {
+                Some('h') => true,
+                Some('l') => false,
+                _ => return Err("Unexpected 'k' without 'l' or 'h'.".to_owned()),
+            };
+            let n = read_num(&mut iter).unwrap_or(1);
+            if n == 0 {
+                return Err("Cannot keep zero dice.".to_owned());
+            }
+            if n > roll.amount {
+                return Err(format!("Cannot keep {} dice when only rolling {}!", n, roll.amount));
+            }
+            roll.drop = if highest {Drop::KeepHighest(n)} else {Drop::KeepLowest(n)};
+        }
+        if *iter.peek().unwrap_or(&'q') == 's' {
+            iter.next();
+            if iter.next() != Some('>') {
+                return Err("Unexpected 's' without '>' or '>='.".to_owned());
+            }
+            let inclusive = *iter.peek().unwrap_or(&'q') == '=';
+            if inclusive {
+                iter.next();
+            }
+            match read_num(&mut iter) {
+                Some(target) => {
+                    roll.success_target = Some(if inclusive {target} else {target.saturating_add(1)});
+                    roll.min_value = 0;
+                },
+                None => return Err("Unexpected 's>' without a target.".to_owned()),
+            }
         }
         if *iter.peek().unwrap_or(&'q') == '>' {
             iter.next();
@@ -194,18 +264,42 @@ impl DiceRoll {
         Ok(roll)
     }
 
+    /// The notation for this roll, which `from_notation` reads back as the same roll.
     pub fn to_notation(&self) -> String {
-        format!("{}d{}{}", self.amount, self.sides, match self.modifier_type {
-            ModifierType::Add => format!("{:+}", self.modifier),
-            ModifierType::DivideCeil |
-            ModifierType::DivideFloor |
-            ModifierType::DivideRound => format!("÷{}", self.modifier),
-            ModifierType::Multiply => format!("x{}", self.modifier),
-        })
+        let mut s = format!("{}d{}", self.amount, self.sides);
+        if self.explode {
+            s.push('!');
+        }
+        if let Some(below) = self.reroll_below {
+            s.push_str(&format!("r{}", below));
+        }
+        if self.apply_modifier_to_all {
+            s.push('&');
+        }
+        if self.modifier_type != ModifierType::Add || self.modifier != 0 {
+            s.push_str(&match self.modifier_type {
+                ModifierType::Add => format!("{:+}", self.modifier),
+                ModifierType::DivideCeil => format!("/u{}", self.modifier),
+                ModifierType::DivideFloor => format!("/d{}", self.modifier),
+                ModifierType::DivideRound => format!("/{}", self.modifier),
+                ModifierType::Multiply => format!("x{}", self.modifier),
+            });
+        }
+        s.push_str(&self.drop_notation());
+        s.push_str(&self.target_notation());
+        let default_min = if self.success_target.is_some() {0} else {1};
+        if self.min_value != default_min {
+            s.push_str(&format!(">={}", self.min_value));
+        }
+        s
+    }
+
+    pub fn roll(&self, rng: &mut impl Rng) -> i32 {
+        roll(*self, rng)
     }
 
-    pub fn roll(&self) -> i32 {
-        roll(*self)
+    pub fn roll_detailed(&self, rng: &mut impl Rng) -> RollResult {
+        roll_detailed(*self, rng)
     }
 
     pub fn simple(amount: u32, sides: u32) -> Self {
@@ -217,42 +311,71 @@ impl DiceRoll {
             apply_modifier_to_all: false,
             drop: Drop::None,
             min_value: 1,
+            explode: false,
+            reroll_below: None,
+            success_target: None,
         }
     }
 
     pub fn simple_modifier(amount: u32, sides: u32, modifier: i32) -> Self {
         Self {
-            amount,
-            sides,
             modifier,
-            modifier_type: ModifierType::Add,
-            apply_modifier_to_all: false,
-            drop: Drop::None,
-            min_value: 1,
+            ..Self::simple(amount, sides)
         }
     }
 
     pub fn simple_drop_highest(amount: u32, sides: u32) -> Self {
         Self {
-            amount,
-            sides,
-            modifier: 0,
-            modifier_type: ModifierType::Add,
-            apply_modifier_to_all: false,
             drop: Drop::DropHighest(1),
-            min_value: 1,
+            ..Self::simple(amount, sides)
         }
     }
 
     pub fn simple_drop_lowest(amount: u32, sides: u32) -> Self {
         Self {
-            amount,
-            sides,
-            modifier: 0,
-            modifier_type: ModifierType::Add,
-            apply_modifier_to_all: false,
             drop: Drop::DropLowest(1),
-            min_value: 1,
+            ..Self::simple(amount, sides)
+        }
+    }
+
+    pub fn simple_exploding(amount: u32, sides: u32) -> Self {
+        Self {
+            explode: true,
+            ..Self::simple(amount, sides)
+        }
+    }
+
+    /// The notation for everything that comes after `NdM`: exploding, rerolls, keep/drop and
+    /// success counting.
+    fn suffix_notation(&self) -> String {
+        let mut s = String::new();
+        if self.explode {
+            s.push('!');
+        }
+        if let Some(below) = self.reroll_below {
+            s.push_str(&format!("r{}", below));
+        }
+        s.push_str(&self.drop_notation());
+        s.push_str(&self.target_notation());
+        s
+    }
+
+    fn drop_notation(&self) -> String {
+        match self.drop {
+            Drop::None => String::new(),
+            Drop::DropHighest(1) => "_h".to_owned(),
+            Drop::DropLowest(1) => "_l".to_owned(),
+            Drop::DropHighest(n) => format!("_h{}", n),
+            Drop::DropLowest(n) => format!("_l{}", n),
+            Drop::KeepHighest(n) => format!("kh{}", n),
+            Drop::KeepLowest(n) => format!("kl{}", n),
+        }
+    }
+
+    fn target_notation(&self) -> String {
+        match self.success_target {
+            Some(target) => format!("s>={}", target),
+            None => String::new(),
         }
     }
 }
@@ -283,6 +406,8 @@ pub enum Drop {
     None,
     DropHighest(u32),
     DropLowest(u32),
+    KeepHighest(u32),
+    KeepLowest(u32),
 }
 
 impl Drop {
@@ -291,60 +416,282 @@ impl Drop {
             Drop::None => "None".to_owned(),
             Drop::DropHighest(_) => "Drop highest".to_owned(),
             Drop::DropLowest(_) => "Drop lowest".to_owned(),
+            Drop::KeepHighest(_) => "Keep highest".to_owned(),
+            Drop::KeepLowest(_) => "Keep lowest".to_owned(),
         }
     }
 }
 
+/// The most extra dice an exploding roll may add, so a one-sided (or very lucky) roll can't go on
+/// forever.
+pub const MAX_EXPLOSIONS: u32 = 100;
+
+/// The source of randomness for every roll the DM makes. It is seeded, so the same seed will
+/// produce the same sequence of rolls (as long as the same actions are taken in the same order).
+#[derive(Debug, Clone)]
+pub struct DiceRng {
+    seed: u64,
+    rng: StdRng,
+}
+
+impl DiceRng {
+    /// Creates a new RNG with a random seed.
+    pub fn new() -> Self {
+        Self::seeded(thread_rng().gen())
+    }
+
+    /// Creates a new RNG with the given seed.
+    pub fn seeded(seed: u64) -> Self {
+        Self {
+            seed,
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    /// The seed this RNG was last (re)started from.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Restarts the RNG from the given seed.
+    pub fn reseed(&mut self, seed: u64) {
+        *self = Self::seeded(seed);
+    }
+}
+
+impl Default for DiceRng {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RngCore for DiceRng {
+    fn next_u32(&mut self) -> u32 {
+        self.rng.next_u32()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.rng.next_u64()
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.rng.fill_bytes(dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.rng.try_fill_bytes(dest)
+    }
+}
+
 /// Rolls a `DiceRoll` and returns the result.
-pub fn roll(roll: DiceRoll) -> i32 {
+pub fn roll(roll: DiceRoll, rng: &mut impl Rng) -> i32 {
+    roll_detailed(roll, rng).total
+}
+
+/// Rolls a `DiceRoll`, keeping track of every die and modifier that went into the result.
+pub fn roll_detailed(roll: DiceRoll, rng: &mut impl Rng) -> RollResult {
+    let mut result = RollResult::new(roll.to_notation());
     if roll.amount == 0 || roll.sides == 0 {
-        return 0;
-    }
-    let mut rng = thread_rng();
-    let mut result: i32 = 0;
-    let mut raw_rolls: Vec<i32> = Vec::new();
-    for _ in 0..roll.amount {
-        raw_rolls.push(rng.gen_range(1..=roll.sides) as i32);
+        return result;
     }
-    if let Drop::DropHighest(i) = roll.drop {
-        if i >= roll.amount {
-            return 0;
+    // rolls read from files aren't checked against `MAX_DICE_SIDES`, but every face has to fit
+    let sides = roll.sides.min(i32::MAX as u32);
+    let mut to_roll = roll.amount;
+    let mut i = 0;
+    while i < to_roll {
+        let mut r = rng.gen_range(1..=sides) as i32;
+        if let Some(below) = roll.reroll_below {
+            // rerolling every face would never finish
+            if below < sides {
+                while r <= below as i32 {
+                    result.rerolled.push(r);
+                    r = rng.gen_range(1..=sides) as i32;
+                }
+            }
         }
-        raw_rolls.sort();
-        for _ in 0..i {
-            raw_rolls.pop();
+        result.raw.push(r);
+        if roll.explode && sides > 1 && r == sides as i32 && to_roll - roll.amount < MAX_EXPLOSIONS {
+            to_roll += 1;
         }
+        i += 1;
+    }
+    let mut kept = result.raw.clone();
+    let count = kept.len() as u32;
+    let (drop_highest, drop_lowest) = match roll.drop {
+        Drop::None => (0, 0),
+        Drop::DropHighest(i) => (i, 0),
+        Drop::DropLowest(i) => (0, i),
+        Drop::KeepHighest(i) => (0, count.saturating_sub(i)),
+        Drop::KeepLowest(i) => (count.saturating_sub(i), 0),
+    };
+    if drop_highest + drop_lowest >= count && roll.drop != Drop::None {
+        return result;
     }
-    if let Drop::DropLowest(i) = roll.drop {
-        if i >= roll.amount {
-            return 0;
+    kept.sort();
+    for _ in 0..drop_highest {
+        result.dropped.extend(kept.pop());
+    }
+    kept.reverse();
+    for _ in 0..drop_lowest {
+        result.dropped.extend(kept.pop());
+    }
+    if let Some(target) = roll.success_target {
+        result.success_target = Some(target as i32);
+        result.total = kept.iter().filter(|r| **r >= target as i32).count() as i32;
+        result.push_step("", roll.modifier, roll.modifier_type);
+    } else if roll.apply_modifier_to_all {
+        result.total = kept.iter().fold(0, |total: i32, r| total.saturating_add(apply_modifier(*r, roll.modifier, roll.modifier_type)));
+        if !is_noop(roll.modifier, roll.modifier_type) {
+            result.steps.push(RollStep {
+                label: "each die".to_owned(),
+                modifier: roll.modifier,
+                modifier_type: roll.modifier_type,
+                result: result.total,
+            });
         }
-        raw_rolls.sort();
-        raw_rolls.reverse();
-        for _ in 0..i {
-            raw_rolls.pop();
+    } else {
+        result.total = kept.iter().fold(0, |total: i32, r| total.saturating_add(*r));
+        result.push_step("", roll.modifier, roll.modifier_type);
+    }
+    result.clamp_min(roll.min_value);
+    result
+}
+
+/// Whether applying this modifier would have no effect.
+fn is_noop(modifier: i32, modifier_type: ModifierType) -> bool {
+    match modifier_type {
+        ModifierType::Add => modifier == 0,
+        ModifierType::Multiply => modifier == 1,
+        _ => modifier == 0 || modifier == 1,
+    }
+}
+
+/// The full record of a roll: every die rolled, which were dropped, each modifier applied and the
+/// final result. Sent along with chat messages so players can see how a number came about.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RollResult {
+    /// What was rolled, e.g. `4d6` or `Attack roll`.
+    pub label: String,
+    /// Every die rolled, in the order they were rolled (including dropped dice).
+    pub raw: Vec<i32>,
+    /// The dice that were dropped.
+    pub dropped: Vec<i32>,
+    /// Dice that were rolled too low and rolled again, in order. These don't count towards the 
+    /// result.
+    #[serde(default)]
+    pub rerolled: Vec<i32>,
+    /// If the roll counted successes, the value each die needed to meet.
+    #[serde(default)]
+    pub success_target: Option<i32>,
+    /// Each modifier applied after summing the dice, in order.
+    pub steps: Vec<RollStep>,
+    /// If the result was raised to a minimum value, what it was before.
+    pub clamped_from: Option<i32>,
+    /// The final result.
+    pub total: i32,
+}
+
+impl RollResult {
+    pub fn new(label: impl Into<String>) -> Self {
+        Self {
+            label: label.into(),
+            raw: Vec::new(),
+            dropped: Vec::new(),
+            rerolled: Vec::new(),
+            success_target: None,
+            steps: Vec::new(),
+            clamped_from: None,
+            total: 0,
         }
     }
-    for r in raw_rolls {
-        if roll.apply_modifier_to_all {
-            result += apply_modifier(r, roll.modifier, roll.modifier_type);
-        } else {
-            result += r;
+
+    /// Applies a modifier to the total and records it. Modifiers that would do nothing (such as
+    /// adding zero) are skipped.
+    pub fn push_step(&mut self, label: impl Into<String>, modifier: i32, modifier_type: ModifierType) {
+        if is_noop(modifier, modifier_type) {
+            return;
         }
+        self.total = apply_modifier(self.total, modifier, modifier_type);
+        self.steps.push(RollStep {
+            label: label.into(),
+            modifier,
+            modifier_type,
+            result: self.total,
+        });
     }
-    if !roll.apply_modifier_to_all {
-        result = apply_modifier(result, roll.modifier, roll.modifier_type);
+
+    /// Raises the total to `min` if it is lower.
+    pub fn clamp_min(&mut self, min: i32) {
+        if self.total < min {
+            self.clamped_from = Some(self.total);
+            self.total = min;
+        }
     }
-    if result < roll.min_value {
-        result = roll.min_value;
+
+    /// A multi-line, human readable explanation of the roll.
+    pub fn breakdown(&self) -> String {
+        let mut dropped = self.dropped.clone();
+        let dice: Vec<String> = self.raw.iter().map(|r| {
+            if let Some(i) = dropped.iter().position(|d| d == r) {
+                dropped.remove(i);
+                format!("({})", r)
+            } else {
+                r.to_string()
+            }
+        }).collect();
+        let mut text = format!("{}: [{}]", self.label, dice.join(", "));
+        if !self.dropped.is_empty() {
+            text.push_str(" (dropped dice in parentheses)");
+        }
+        if !self.rerolled.is_empty() {
+            text.push_str(&format!("\nRerolled: [{}]", self.rerolled.iter().map(|r| r.to_string()).collect::<Vec<String>>().join(", ")));
+        }
+        if let Some(target) = self.success_target {
+            text.push_str(&format!("\nCounting dice of {} or higher as successes", target));
+        }
+        for step in &self.steps {
+            text.push_str(&format!("\n{}", step));
+        }
+        if let Some(clamped) = self.clamped_from {
+            text.push_str(&format!("\n{} raised to minimum of {}", clamped, self.total));
+        }
+        text.push_str(&format!("\nTotal: {}", self.total));
+        text
     }
-    result
 }
 
+/// A single modifier applied to a roll.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RollStep {
+    /// What the modifier represents, e.g. `Strength`. May be empty.
+    pub label: String,
+    pub modifier: i32,
+    pub modifier_type: ModifierType,
+    /// The total after this step was applied.
+    pub result: i32,
+}
+
+impl std::fmt::Display for RollStep {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.modifier_type {
+            ModifierType::Add => write!(f, "{:+}", self.modifier)?,
+            ModifierType::Multiply => write!(f, "x{}", self.modifier)?,
+            ModifierType::DivideFloor => write!(f, "÷{} (round down)", self.modifier)?,
+            ModifierType::DivideCeil => write!(f, "÷{} (round up)", self.modifier)?,
+            ModifierType::DivideRound => write!(f, "÷{}", self.modifier)?,
+        }
+        if !self.label.is_empty() {
+            write!(f, " ({})", self.label)?;
+        }
+        write!(f, " = {}", self.result)
+    }
+}
+
+/// Applies a modifier to a value. Results that don't fit are capped rather than overflowing.
 fn apply_modifier(initial: i32, modifier: i32, modifier_type: ModifierType) -> i32 {
     match modifier_type {
-        ModifierType::Add => initial + modifier,
-        ModifierType::Multiply => initial * modifier,
+        ModifierType::Add => initial.saturating_add(modifier),
+        ModifierType::Multiply => initial.saturating_mul(modifier),
         ModifierType::DivideFloor => {
             if modifier == 0 {
                 initial
@@ -367,4 +714,876 @@ fn apply_modifier(initial: i32, modifier: i32, modifier_type: ModifierType) -> i
             }
         },
     }
-}
\ No newline at end of file
+}
+
+/// The most dice a single term of a `DiceExpr` may roll. Prevents someone from typing `99999999d6`
+/// and freezing the server.
+pub const MAX_EXPR_DICE: u32 = 1000;
+
+/// The most sides a die may have. Keeps every face well within an `i32`.
+pub const MAX_DICE_SIDES: u32 = 1_000_000;
+
+/// A full dice expression, such as `2d6+1d4+3`, `(1d20+STR)>=14` or `4d6_l/2`. Any number of dice
+/// terms, constants and named values can be combined with arithmetic, parentheses and (at most)
+/// one comparison.
+/// 
+/// ## Grammar
+/// ```text
+/// expr    := sum (('>' | '>=' | '<' | '<=' | '=') sum)?
+/// sum     := product (('+' | '-') product)*
+/// product := unary (('*' | 'x' | '/' | '/u' | '/d') unary)*
+/// unary   := '-' unary | atom
+/// atom    := '(' expr ')' | dice | number | name
+/// dice    := (N)'d'M('!')('r'R)(['_h'|'_l'|'kh'|'kl'](X))('s'['>'|'>=']T)
+/// ```
+/// Names (like `STR`) are looked up when the expression is evaluated. Division rounds normally, 
+/// unless `/u` (round up) or `/d` (round down) is used.
+/// 
+/// Dice can explode (`!`, roll again on the highest face), reroll values of `R` or lower (`r1`),
+/// drop or keep the `X` highest or lowest dice, and count how many dice meet a target (`s>=5`).
+/// Without the `s`, `1d20>=14` is a comparison.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DiceExpr {
+    /// A constant.
+    Number(i32),
+    /// A single dice term.
+    Roll(DiceRoll),
+    /// A named value, like an attribute modifier. Resolved at evaluation time.
+    Variable(String),
+    /// A negated expression.
+    Negate(Box<DiceExpr>),
+    /// An expression wrapped in parentheses.
+    Group(Box<DiceExpr>),
+    /// Two expressions combined arithmetically.
+    Binary(Box<DiceExpr>, BinaryOp, Box<DiceExpr>),
+    /// Two expressions compared. Evaluates to 1 if the comparison holds, or 0 otherwise.
+    Compare(Box<DiceExpr>, CompareOp, Box<DiceExpr>),
+}
+
+impl DiceExpr {
+    /// Parses a dice expression. If the string isn't a valid expression, but *is* valid legacy
+    /// `DiceRoll` notation (e.g. `1d6&+1`), that is used instead.
+    pub fn parse(s: impl Into<String>) -> Result<Self, String> {
+        let s: String = s.into();
+        let mut parser = ExprParser {
+            chars: s.chars().collect(),
+            pos: 0,
+        };
+        match parser.parse() {
+            Ok(expr) => Ok(expr),
+            Err(e) => {
+                match DiceRoll::from_notation(s) {
+                    Ok(roll) if roll.amount <= MAX_EXPR_DICE => Ok(Self::Roll(roll)),
+                    _ => Err(e),
+                }
+            },
+        }
+    }
+
+    /// Rolls all of the dice in this expression and evaluates it. `variables` is used to look up
+    /// any names in the expression, returning `None` if the name is unknown.
+    pub fn evaluate(&self, variables: &dyn Fn(&str) -> Option<i32>, rng: &mut impl Rng) -> Result<ExprResult, String> {
+        let mut rolls = Vec::new();
+        let (value, breakdown) = self.eval_inner(variables, &mut rolls, rng)?;
+        Ok(ExprResult {
+            value,
+            breakdown,
+            rolls,
+            success: match self {
+                Self::Compare(..) => Some(value != 0),
+                _ => None,
+            },
+        })
+    }
+
+    /// Like `evaluate()`, but for expressions that aren't allowed to refer to any names.
+    pub fn evaluate_simple(&self, rng: &mut impl Rng) -> Result<ExprResult, String> {
+        self.evaluate(&|_| None, rng)
+    }
+
+    fn eval_inner<R: Rng>(&self, variables: &dyn Fn(&str) -> Option<i32>, rolls: &mut Vec<RollResult>, rng: &mut R) -> Result<(i32, String), String> {
+        match self {
+            Self::Number(n) => Ok((*n, n.to_string())),
+            Self::Roll(r) => {
+                let mut result = r.roll_detailed(rng);
+                let total = result.total;
+                result.label = self.to_string();
+                rolls.push(result);
+                Ok((total, format!("{} ({})", self, total)))
+            },
+            Self::Variable(name) => {
+                match variables(name) {
+                    Some(value) => Ok((value, format!("{} ({:+})", name, value))),
+                    None => Err(format!("Unknown value \"{}\". Attribute modifiers (like STR) can only be used when rolling for a character.", name)),
+                }
+            },
+            Self::Negate(inner) => {
+                let (value, text) = inner.eval_inner(variables, rolls, rng)?;
+                Ok((value.checked_neg().ok_or(OVERFLOW_ERROR)?, format!("-{}", text)))
+            },
+            Self::Group(inner) => {
+                let (value, text) = inner.eval_inner(variables, rolls, rng)?;
+                Ok((value, format!("({})", text)))
+            },
+            Self::Binary(left, op, right) => {
+                let (a, left_text) = left.eval_inner(variables, rolls, rng)?;
+                let (b, right_text) = right.eval_inner(variables, rolls, rng)?;
+                Ok((op.apply(a, b)?, format!("{} {} {}", left_text, op, right_text)))
+            },
+            Self::Compare(left, op, right) => {
+                let (a, left_text) = left.eval_inner(variables, rolls, rng)?;
+                let (b, right_text) = right.eval_inner(variables, rolls, rng)?;
+                Ok((op.compare(a, b) as i32, format!("{} = {} {} {}", left_text, a, op, right_text)))
+            },
+        }
+    }
+}
+
+/// How large a named value is assumed to be at most when working out the bounds of an expression.
+/// Attribute modifiers are far smaller than this.
+const VARIABLE_BOUND: i64 = 100;
+
+impl DiceExpr {
+    /// A range that every result of this expression falls in. It may be wider than the results
+    /// really go, but never narrower. Worked out without rolling anything.
+    pub fn bounds(&self) -> (i64, i64) {
+        match self {
+            Self::Number(n) => (*n as i64, *n as i64),
+            Self::Roll(r) => r.bounds(),
+            Self::Variable(_) => (-VARIABLE_BOUND, VARIABLE_BOUND),
+            Self::Negate(inner) => {
+                let (lo, hi) = inner.bounds();
+                (hi.saturating_neg(), lo.saturating_neg())
+            },
+            Self::Group(inner) => inner.bounds(),
+            Self::Binary(left, op, right) => {
+                let (a_lo, a_hi) = left.bounds();
+                let (b_lo, b_hi) = right.bounds();
+                match op {
+                    BinaryOp::Add => (a_lo.saturating_add(b_lo), a_hi.saturating_add(b_hi)),
+                    BinaryOp::Subtract => (a_lo.saturating_sub(b_hi), a_hi.saturating_sub(b_lo)),
+                    BinaryOp::Multiply => span(&[a_lo.saturating_mul(b_lo), a_lo.saturating_mul(b_hi), a_hi.saturating_mul(b_lo), a_hi.saturating_mul(b_hi)]),
+                    // dividing by a whole number never makes anything further from zero
+                    _ => {
+                        let largest = a_lo.saturating_abs().max(a_hi.saturating_abs());
+                        (-largest, largest)
+                    },
+                }
+            },
+            Self::Compare(..) => (0, 1),
+        }
+    }
+
+    /// Whether every result this expression could have fits in an `i32`. Expressions that don't
+    /// can still be rolled, but might give an error instead of a result.
+    pub fn always_fits(&self) -> bool {
+        let (lo, hi) = self.bounds();
+        lo >= i32::MIN as i64 && hi <= i32::MAX as i64
+    }
+}
+
+impl DiceRoll {
+    /// A range that every result of this roll falls in, like [`DiceExpr::bounds`].
+    pub fn bounds(&self) -> (i64, i64) {
+        let most_dice = self.amount as i64 + if self.explode {MAX_EXPLOSIONS as i64} else {0};
+        let (lo, hi) = if self.success_target.is_some() {
+            (0, most_dice)
+        } else {
+            let lowest = match self.reroll_below {
+                Some(below) if below < self.sides => below as i64 + 1,
+                _ => 1,
+            };
+            let (die_lo, die_hi) = if self.apply_modifier_to_all {
+                modifier_bounds(lowest, self.sides as i64, self.modifier, self.modifier_type)
+            } else {
+                (lowest, self.sides as i64)
+            };
+            // any number of dice could be dropped
+            span(&[0, most_dice.saturating_mul(die_lo), most_dice.saturating_mul(die_hi)])
+        };
+        let (lo, hi) = if self.success_target.is_some() || !self.apply_modifier_to_all {
+            modifier_bounds(lo, hi, self.modifier, self.modifier_type)
+        } else {
+            (lo, hi)
+        };
+        (lo.max(self.min_value as i64), hi.max(self.min_value as i64))
+    }
+}
+
+/// The lowest and highest of some values.
+fn span(values: &[i64]) -> (i64, i64) {
+    (values.iter().copied().min().unwrap_or(0), values.iter().copied().max().unwrap_or(0))
+}
+
+/// The bounds of applying a modifier to anything between `lo` and `hi`.
+fn modifier_bounds(lo: i64, hi: i64, modifier: i32, modifier_type: ModifierType) -> (i64, i64) {
+    let modifier = modifier as i64;
+    match modifier_type {
+        ModifierType::Add => (lo.saturating_add(modifier), hi.saturating_add(modifier)),
+        ModifierType::Multiply => span(&[lo.saturating_mul(modifier), hi.saturating_mul(modifier)]),
+        // rounding can move the result by one either way
+        _ if modifier != 0 => {
+            let (lo, hi) = span(&[lo / modifier, hi / modifier]);
+            (lo - 1, hi + 1)
+        },
+        _ => (lo, hi),
+    }
+}
+
+impl std::fmt::Display for DiceExpr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Number(n) => write!(f, "{}", n),
+            Self::Roll(r) => {
+                let default_min = if r.success_target.is_some() {0} else {1};
+                if r.modifier != 0 || r.apply_modifier_to_all || r.min_value != default_min {
+                    return write!(f, "{}", r.to_notation());
+                }
+                write!(f, "{}d{}{}", r.amount, r.sides, r.suffix_notation())
+            },
+            Self::Variable(name) => write!(f, "{}", name),
+            Self::Negate(inner) => write!(f, "-{}", inner),
+            Self::Group(inner) => write!(f, "({})", inner),
+            Self::Binary(left, op, right) => write!(f, "{} {} {}", left, op, right),
+            Self::Compare(left, op, right) => write!(f, "{} {} {}", left, op, right),
+        }
+    }
+}
+
+impl From<DiceRoll> for DiceExpr {
+    fn from(roll: DiceRoll) -> Self {
+        Self::Roll(roll)
+    }
+}
+
+const OVERFLOW_ERROR: &str = "The result is too large.";
+
+/// An arithmetic operator in a `DiceExpr`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Subtract,
+    Multiply,
+    DivideFloor,
+    DivideCeil,
+    DivideRound,
+}
+
+impl BinaryOp {
+    pub fn apply(&self, a: i32, b: i32) -> Result<i32, String> {
+        match self {
+            Self::Add => a.checked_add(b).ok_or(OVERFLOW_ERROR.to_owned()),
+            Self::Subtract => a.checked_sub(b).ok_or(OVERFLOW_ERROR.to_owned()),
+            Self::Multiply => a.checked_mul(b).ok_or(OVERFLOW_ERROR.to_owned()),
+            Self::DivideFloor |
+            Self::DivideCeil |
+            Self::DivideRound => {
+                if b == 0 {
+                    return Err("Cannot divide by zero.".to_owned());
+                }
+                Ok(apply_modifier(a, b, match self {
+                    Self::DivideFloor => ModifierType::DivideFloor,
+                    Self::DivideCeil => ModifierType::DivideCeil,
+                    _ => ModifierType::DivideRound,
+                }))
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for BinaryOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", match self {
+            Self::Add => "+",
+            Self::Subtract => "-",
+            Self::Multiply => "x",
+            Self::DivideFloor => "/d",
+            Self::DivideCeil => "/u",
+            Self::DivideRound => "/",
+        })
+    }
+}
+
+/// A comparison operator in a `DiceExpr`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompareOp {
+    Greater,
+    GreaterOrEqual,
+    Less,
+    LessOrEqual,
+    Equal,
+}
+
+impl CompareOp {
+    pub fn compare(&self, a: i32, b: i32) -> bool {
+        match self {
+            Self::Greater => a > b,
+            Self::GreaterOrEqual => a >= b,
+            Self::Less => a < b,
+            Self::LessOrEqual => a <= b,
+            Self::Equal => a == b,
+        }
+    }
+}
+
+impl std::fmt::Display for CompareOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", match self {
+            Self::Greater => ">",
+            Self::GreaterOrEqual => ">=",
+            Self::Less => "<",
+            Self::LessOrEqual => "<=",
+            Self::Equal => "=",
+        })
+    }
+}
+
+/// The outcome of evaluating a `DiceExpr`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExprResult {
+    /// The final value. For comparisons, this is 1 on success and 0 on failure.
+    pub value: i32,
+    /// A human readable explanation of how the value was reached, e.g. `2d6 (7) + 3`.
+    pub breakdown: String,
+    /// Whether the comparison succeeded, if the expression was a comparison.
+    pub success: Option<bool>,
+    /// The details of every dice term that was rolled.
+    pub rolls: Vec<RollResult>,
+}
+
+impl std::fmt::Display for ExprResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.success {
+            Some(true) => write!(f, "{}: Success!", self.breakdown),
+            Some(false) => write!(f, "{}: Failure!", self.breakdown),
+            None => write!(f, "{} = {}", self.breakdown, self.value),
+        }
+    }
+}
+
+/// A simple recursive descent parser for `DiceExpr`.
+struct ExprParser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl ExprParser {
+    fn parse(&mut self) -> Result<DiceExpr, String> {
+        let expr = self.comparison()?;
+        self.skip_whitespace();
+        if let Some(c) = self.peek() {
+            return Err(format!("Unexpected token '{}'.", c));
+        }
+        Ok(expr)
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.pos + offset).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(|c| c.is_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    fn read_num(&mut self) -> Result<Option<u32>, String> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            return Ok(None);
+        }
+        self.chars[start..self.pos].iter().collect::<String>().parse::<u32>()
+            .map(Some)
+            .map_err(|_| "That number is too large.".to_owned())
+    }
+
+    fn comparison(&mut self) -> Result<DiceExpr, String> {
+        let left = self.sum()?;
+        self.skip_whitespace();
+        let op = match (self.peek(), self.peek_at(1)) {
+            (Some('>'), Some('=')) => CompareOp::GreaterOrEqual,
+            (Some('>'), _) => CompareOp::Greater,
+            (Some('<'), Some('=')) => CompareOp::LessOrEqual,
+            (Some('<'), _) => CompareOp::Less,
+            (Some('='), _) => CompareOp::Equal,
+            _ => return Ok(left),
+        };
+        self.pos += match op {
+            CompareOp::GreaterOrEqual | CompareOp::LessOrEqual => 2,
+            _ => 1,
+        };
+        if op == CompareOp::Equal && self.peek() == Some('=') {
+            self.pos += 1;
+        }
+        let right = self.sum()?;
+        self.skip_whitespace();
+        if matches!(self.peek(), Some('>' | '<' | '=')) {
+            return Err("Only one comparison is allowed (use parentheses to compare more than once).".to_owned());
+        }
+        Ok(DiceExpr::Compare(Box::new(left), op, Box::new(right)))
+    }
+
+    fn sum(&mut self) -> Result<DiceExpr, String> {
+        let mut left = self.product()?;
+        loop {
+            self.skip_whitespace();
+            let op = match self.peek() {
+                Some('+') => BinaryOp::Add,
+                Some('-') => BinaryOp::Subtract,
+                _ => return Ok(left),
+            };
+            self.pos += 1;
+            let right = self.product()?;
+            left = DiceExpr::Binary(Box::new(left), op, Box::new(right));
+        }
+    }
+
+    fn product(&mut self) -> Result<DiceExpr, String> {
+        let mut left = self.unary()?;
+        loop {
+            self.skip_whitespace();
+            let op = match self.peek() {
+                Some('*' | 'x') => {
+                    self.pos += 1;
+                    BinaryOp::Multiply
+                },
+                Some('/') => {
+                    self.pos += 1;
+                    match self.peek() {
+                        Some('u') => {
+                            self.pos += 1;
+                            BinaryOp::DivideCeil
+                        },
+                        Some('d') => {
+                            self.pos += 1;
+                            BinaryOp::DivideFloor
+                        },
+                        _ => BinaryOp::DivideRound,
+                    }
+                },
+                _ => return Ok(left),
+            };
+            let right = self.unary()?;
+            left = DiceExpr::Binary(Box::new(left), op, Box::new(right));
+        }
+    }
+
+    fn unary(&mut self) -> Result<DiceExpr, String> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some('-') => {
+                self.pos += 1;
+                Ok(DiceExpr::Negate(Box::new(self.unary()?)))
+            },
+            Some('+') => {
+                self.pos += 1;
+                self.unary()
+            },
+            _ => self.atom(),
+        }
+    }
+
+    fn atom(&mut self) -> Result<DiceExpr, String> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some('(') => {
+                self.pos += 1;
+                let inner = self.comparison()?;
+                self.skip_whitespace();
+                if self.peek() != Some(')') {
+                    return Err("Missing closing parenthesis.".to_owned());
+                }
+                self.pos += 1;
+                Ok(DiceExpr::Group(Box::new(inner)))
+            },
+            Some(c) if c.is_ascii_digit() => {
+                let n = self.read_num()?.unwrap_or(0);
+                if self.peek() == Some('d') && self.peek_at(1).is_some_and(|c| c.is_ascii_digit()) {
+                    self.pos += 1;
+                    self.dice(n)
+                } else {
+                    i32::try_from(n).map(DiceExpr::Number).map_err(|_| "That number is too large.".to_owned())
+                }
+            },
+            Some('d') if self.peek_at(1).is_some_and(|c| c.is_ascii_digit()) => {
+                self.pos += 1;
+                self.dice(1)
+            },
+            Some(c) if c.is_ascii_alphabetic() => {
+                let start = self.pos;
+                while self.peek().is_some_and(|c| c.is_ascii_alphanumeric() || c == '_') {
+                    self.pos += 1;
+                }
+                Ok(DiceExpr::Variable(self.chars[start..self.pos].iter().collect::<String>().to_uppercase()))
+            },
+            Some(c) => Err(format!("Unexpected token '{}'.", c)),
+            None => Err("Unexpected end of expression.".to_owned()),
+        }
+    }
+
+    /// Reads the rest of a dice term, after the 'd'.
+    fn dice(&mut self, amount: u32) -> Result<DiceExpr, String> {
+        if amount == 0 {
+            return Err("Cannot roll zero dice.".to_owned());
+        }
+        if amount > MAX_EXPR_DICE {
+            return Err(format!("Cannot roll more than {} dice at once.", MAX_EXPR_DICE));
+        }
+        let sides = self.read_num()?.unwrap_or(0);
+        if sides == 0 {
+            return Err("Cannot roll a zero-sided dice.".to_owned());
+        }
+        if sides > MAX_DICE_SIDES {
+            return Err(format!("Dice can't have more than {} sides.", MAX_DICE_SIDES));
+        }
+        let mut roll = DiceRoll::simple(amount, sides);
+        loop {
+            match (self.peek(), self.peek_at(1)) {
+                (Some('!'), _) if !roll.explode => {
+                    self.pos += 1;
+                    if sides == 1 {
+                        return Err("A one-sided dice cannot explode.".to_owned());
+                    }
+                    roll.explode = true;
+                },
+                (Some('r'), Some(c)) if c.is_ascii_digit() && roll.reroll_below.is_none() => {
+                    self.pos += 1;
+                    let n = self.read_num()?.unwrap_or(0);
+                    if n >= sides {
+                        return Err(format!("Cannot reroll {} or lower on a d{}, every roll would be rerolled!", n, sides));
+                    }
+                    roll.reroll_below = Some(n);
+                },
+                (Some('_'), _) if roll.drop == Drop::None => {
+                    self.pos += 1;
+                    let highest = match self.peek() {
+                        Some('h' | 'H') => true,
+                        Some('l' | 'L') => false,
+                        _ => return Err("Unexpected '_' without 'l' or 'h'.".to_owned()),
+                    };
+                    self.pos += 1;
+                    let n = self.read_num()?.unwrap_or(1);
+                    if n >= amount {
+                        return Err(format!("Cannot drop {} dice when only rolling {}!", n, amount));
+                    }
+                    roll.drop = if highest {Drop::DropHighest(n)} else {Drop::DropLowest(n)};
+                },
+                (Some('k'), Some('h' | 'l')) if roll.drop == Drop::None => {
+                    let highest = self.peek_at(1) == Some('h');
+                    self.pos += 2;
+                    let n = self.read_num()?.unwrap_or(1);
+                    if n == 0 {
+                        return Err("Cannot keep zero dice.".to_owned());
+                    }
+                    if n > amount {
+                        return Err(format!("Cannot keep {} dice when only rolling {}!", n, amount));
+                    }
+                    roll.drop = if highest {Drop::KeepHighest(n)} else {Drop::KeepLowest(n)};
+                },
+                _ => break,
+            }
+        }
+        // a target after an 's' counts successes; without it, it's a comparison
+        if self.peek() == Some('s') && self.peek_at(1) == Some('>') {
+            self.pos += 2;
+            let inclusive = self.peek() == Some('=');
+            if inclusive {
+                self.pos += 1;
+            }
+            match self.read_num()? {
+                Some(target) => {
+                    roll.success_target = Some(if inclusive {target} else {target.saturating_add(1)});
+                    roll.min_value = 0;
+                },
+                None => return Err("Unexpected 's>' without a target.".to_owned()),
+            }
+        }
+        Ok(DiceExpr::Roll(roll))
+    }
+}
+
+/// The most outcomes the probability calculator will work through before giving up.
+pub const MAX_ODDS_OUTCOMES: u64 = 500_000;
+
+/// How many times an exploding die is followed when calculating odds. The chance of exploding
+/// this many times is small enough to not matter.
+const MAX_ODDS_EXPLOSIONS: u32 = 20;
+
+fn gcd(a: i64, b: i64) -> i64 {
+    if b == 0 {a.abs()} else {gcd(b, a % b)}
+}
+
+/// The exact probability of every possible result of a roll or expression.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Distribution {
+    /// Maps each possible result to its probability (between 0 and 1).
+    pub probabilities: BTreeMap<i32, f64>,
+}
+
+impl Distribution {
+    /// A "roll" that always gives the same result.
+    pub fn constant(value: i32) -> Self {
+        Self {
+            probabilities: BTreeMap::from([(value, 1.0)]),
+        }
+    }
+
+    /// Calculates the distribution of a `DiceRoll`, taking into account everything `roll()` does.
+    pub fn of_roll(roll: &DiceRoll) -> Result<Self, String> {
+        if roll.amount == 0 || roll.sides == 0 {
+            return Ok(Self::constant(0));
+        }
+        if roll.sides as u64 > MAX_ODDS_OUTCOMES {
+            return Err("That roll has too many outcomes to calculate odds for.".to_owned());
+        }
+        let lowest = match roll.reroll_below {
+            Some(below) if below < roll.sides => below as i32 + 1,
+            _ => 1,
+        };
+        let faces: Vec<i32> = (lowest..=roll.sides as i32).collect();
+        let per_die = |face: i32| -> i32 {
+            match roll.success_target {
+                Some(target) => (face >= target as i32) as i32,
+                None if roll.apply_modifier_to_all => apply_modifier(face, roll.modifier, roll.modifier_type),
+                None => face,
+            }
+        };
+        let kept_total = if roll.drop == Drop::None {
+            let mut chain = BTreeMap::new();
+            let mut pending = BTreeMap::from([(0, 1.0)]);
+            for depth in 0..=MAX_ODDS_EXPLOSIONS {
+                let mut next = BTreeMap::new();
+                for (acc, p) in pending {
+                    for face in &faces {
+                        let value = acc + per_die(*face);
+                        let p = p / faces.len() as f64;
+                        if roll.explode && roll.sides > 1 && *face == roll.sides as i32 && depth < MAX_ODDS_EXPLOSIONS {
+                            *next.entry(value).or_insert(0.0) += p;
+                        } else {
+                            *chain.entry(value).or_insert(0.0) += p;
+                        }
+                    }
+                }
+                pending = next;
+                if pending.is_empty() {
+                    break;
+                }
+            }
+            Self { probabilities: chain }.sum_of(roll.amount)?
+        } else {
+            if roll.explode {
+                return Err("Can't calculate odds for exploding dice that also keep or drop dice.".to_owned());
+            }
+            Self::of_kept_dice(roll, &faces, &per_die)?
+        };
+        let modify_total = roll.success_target.is_some() || !roll.apply_modifier_to_all;
+        let dropped_everything = match roll.drop {
+            Drop::DropHighest(i) | Drop::DropLowest(i) => i >= roll.amount,
+            Drop::KeepHighest(i) | Drop::KeepLowest(i) => i == 0,
+            Drop::None => false,
+        };
+        if dropped_everything {
+            return Ok(Self::constant(0));
+        }
+        kept_total.map(|total| {
+            let total = if modify_total {apply_modifier(total, roll.modifier, roll.modifier_type)} else {total};
+            Ok(total.max(roll.min_value))
+        })
+    }
+
+    /// Works through every combination of dice (ignoring order) to find the distribution of the
+    /// dice that are kept.
+    fn of_kept_dice(roll: &DiceRoll, faces: &[i32], per_die: &dyn Fn(i32) -> i32) -> Result<Self, String> {
+        let n = roll.amount as usize;
+        // number of ways to pick n dice from the faces, ignoring order: (n + f - 1) choose n
+        let mut combinations: u64 = 1;
+        for i in 0..faces.len().saturating_sub(1) as u64 {
+            combinations = combinations.saturating_mul(n as u64 + i + 1) / (i + 1);
+            if combinations > MAX_ODDS_OUTCOMES {
+                return Err("That roll has too many outcomes to calculate odds for.".to_owned());
+            }
+        }
+        let (drop_highest, drop_lowest) = match roll.drop {
+            Drop::None => (0, 0),
+            Drop::DropHighest(i) => (i as usize, 0),
+            Drop::DropLowest(i) => (0, i as usize),
+            Drop::KeepHighest(i) => (0, n.saturating_sub(i as usize)),
+            Drop::KeepLowest(i) => (n.saturating_sub(i as usize), 0),
+        };
+        if drop_highest + drop_lowest >= n {
+            return Ok(Self::constant(0));
+        }
+        let mut ln_factorial = vec![0.0; n + 1];
+        for i in 1..=n {
+            ln_factorial[i] = ln_factorial[i - 1] + (i as f64).ln();
+        }
+        // every ordering is equally likely, so a combination's chance is how many orderings it
+        // has multiplied by the chance of any one ordering
+        let ln_each = -(n as f64) * (faces.len() as f64).ln();
+        let mut probabilities = BTreeMap::new();
+        let mut counts = vec![0; faces.len()];
+        fn visit(face: usize, remaining: usize, counts: &mut Vec<usize>, f: &mut dyn FnMut(&[usize])) {
+            if face == counts.len() - 1 {
+                counts[face] = remaining;
+                f(counts);
+                return;
+            }
+            for c in 0..=remaining {
+                counts[face] = c;
+                visit(face + 1, remaining - c, counts, f);
+            }
+        }
+        visit(0, n, &mut counts, &mut |counts| {
+            let ln_p = ln_factorial[n] - counts.iter().map(|c| ln_factorial[*c]).sum::<f64>() + ln_each;
+            // faces are in ascending order, so skip the lowest and highest dice as needed
+            let mut skip_low = drop_lowest;
+            let mut keep = n - drop_lowest - drop_highest;
+            let mut total = 0;
+            for (face, count) in faces.iter().zip(counts) {
+                let skipped = skip_low.min(*count);
+                skip_low -= skipped;
+                let kept = (count - skipped).min(keep);
+                keep -= kept;
+                total += per_die(*face) * kept as i32;
+            }
+            *probabilities.entry(total).or_insert(0.0) += ln_p.exp();
+        });
+        Ok(Self { probabilities })
+    }
+
+    /// The distribution of adding up `amount` independent results of this one. Results are kept
+    /// in an array indexed from the lowest total, which is much faster than combining maps.
+    fn sum_of(&self, amount: u32) -> Result<Self, String> {
+        if amount <= 1 {
+            return Ok(self.clone());
+        }
+        let lowest = self.min() as i64;
+        // results are often spaced out (e.g. each die multiplied by 10), so index them by the
+        // largest step that divides every gap
+        let mut step = 0;
+        for value in self.probabilities.keys() {
+            step = gcd(step, *value as i64 - lowest);
+        }
+        let step = step.max(1);
+        let span = ((self.max() as i64 - lowest) / step + 1) as u64;
+        let amount = amount as u64;
+        // adding the nth die takes about n * span * span steps
+        let work = amount.saturating_mul(amount).saturating_mul(span).saturating_mul(span) / 2;
+        if (amount - 1) * (span - 1) + 1 > MAX_ODDS_OUTCOMES || work > MAX_ODDS_OUTCOMES * 200 {
+            return Err("That roll has too many outcomes to calculate odds for.".to_owned());
+        }
+        // whether each total can happen is tracked separately, as the chance of the most extreme
+        // totals can be too small to store
+        let mut single = vec![None; span as usize];
+        for (value, p) in &self.probabilities {
+            single[((*value as i64 - lowest) / step) as usize] = Some(*p);
+        }
+        let mut total = single.clone();
+        for _ in 1..amount {
+            let mut next: Vec<Option<f64>> = vec![None; total.len() + single.len() - 1];
+            for (i, pa) in total.iter().enumerate() {
+                if let Some(pa) = pa {
+                    for (j, pb) in single.iter().enumerate() {
+                        if let Some(pb) = pb {
+                            next[i + j] = Some(next[i + j].unwrap_or(0.0) + pa * pb);
+                        }
+                    }
+                }
+            }
+            total = next;
+        }
+        let mut probabilities = BTreeMap::new();
+        for (i, p) in total.iter().enumerate() {
+            if let Some(p) = p {
+                let value = i as i64 * step + lowest * amount as i64;
+                probabilities.insert(i32::try_from(value).map_err(|_| OVERFLOW_ERROR.to_owned())?, *p);
+            }
+        }
+        Ok(Self { probabilities })
+    }
+
+    /// Applies a function to every possible result, failing if it fails for any of them.
+    pub fn map(&self, f: impl Fn(i32) -> Result<i32, String>) -> Result<Self, String> {
+        let mut probabilities = BTreeMap::new();
+        for (value, p) in &self.probabilities {
+            *probabilities.entry(f(*value)?).or_insert(0.0) += p;
+        }
+        Ok(Self { probabilities })
+    }
+
+    /// Combines two independent distributions.
+    pub fn combine(&self, other: &Self, f: impl Fn(i32, i32) -> Result<i32, String>) -> Result<Self, String> {
+        if (self.probabilities.len() as u64).saturating_mul(other.probabilities.len() as u64) > MAX_ODDS_OUTCOMES * 20 {
+            return Err("That roll has too many outcomes to calculate odds for.".to_owned());
+        }
+        let mut probabilities = BTreeMap::new();
+        for (a, pa) in &self.probabilities {
+            for (b, pb) in &other.probabilities {
+                *probabilities.entry(f(*a, *b)?).or_insert(0.0) += pa * pb;
+            }
+        }
+        Ok(Self { probabilities })
+    }
+
+    /// The average result.
+    pub fn mean(&self) -> f64 {
+        self.probabilities.iter().map(|(value, p)| *value as f64 * p).sum()
+    }
+
+    /// The middle result: at least half of all rolls are this or lower.
+    pub fn median(&self) -> i32 {
+        let mut total = 0.0;
+        for (value, p) in &self.probabilities {
+            total += p;
+            if total >= 0.5 - 1e-9 {
+                return *value;
+            }
+        }
+        self.max()
+    }
+
+    /// The chance of rolling `target` or higher.
+    pub fn at_least(&self, target: i32) -> f64 {
+        self.probabilities.range(target..).map(|(_, p)| p).sum::<f64>().min(1.0)
+    }
+
+    pub fn min(&self) -> i32 {
+        self.probabilities.keys().next().copied().unwrap_or(0)
+    }
+
+    pub fn max(&self) -> i32 {
+        self.probabilities.keys().next_back().copied().unwrap_or(0)
+    }
+}
+
+impl DiceExpr {
+    /// Calculates the exact distribution of this expression. Every dice term is independent, so
+    /// terms are combined one pair at a time.
+    pub fn distribution(&self, variables: &dyn Fn(&str) -> Option<i32>) -> Result<Distribution, String> {
+        match self {
+            Self::Number(n) => Ok(Distribution::constant(*n)),
+            Self::Roll(r) => Distribution::of_roll(r),
+            Self::Variable(name) => {
+                match variables(name) {
+                    Some(value) => Ok(Distribution::constant(value)),
+                    None => Err(format!("Unknown value \"{}\".", name)),
+                }
+            },
+            Self::Negate(inner) => inner.distribution(variables)?.map(|v| v.checked_neg().ok_or(OVERFLOW_ERROR.to_owned())),
+            Self::Group(inner) => inner.distribution(variables),
+            Self::Binary(left, op, right) => {
+                left.distribution(variables)?.combine(&right.distribution(variables)?, |a, b| op.apply(a, b))
+            },
+            Self::Compare(left, op, right) => {
+                left.distribution(variables)?.combine(&right.distribution(variables)?, |a, b| Ok(op.compare(a, b) as i32))
+            },
+        }
+    }
+}
@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+use std::net::{UdpSocket, SocketAddr, Ipv4Addr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::{Serialize, Deserialize};
+
+use crate::network::PROTOCOL_VERSION;
+
+/// The UDP port servers announce themselves on, and players listen on.
+pub const DISCOVERY_PORT: u16 = 38517;
+/// How often a hosting server announces itself.
+pub const BEACON_INTERVAL: Duration = Duration::from_secs(2);
+/// How long a server is listed after its last beacon, so games that stopped hosting disappear.
+pub const BEACON_TIMEOUT: Duration = Duration::from_secs(7);
+/// Sent in front of every beacon, so stray packets from other programs on the port are ignored.
+const BEACON_MAGIC: &str = "ACKS";
+/// The largest beacon that can be received, in bytes. Longer campaign names are cut short to fit.
+const MAX_BEACON_SIZE: usize = 1024;
+/// How long listening waits after failing to receive, so a socket that keeps failing doesn't
+/// spin.
+const RECEIVE_ERROR_DELAY: Duration = Duration::from_millis(500);
+
+/// What a server broadcasts on the LAN while it is hosting.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Beacon {
+    pub magic: String,
+    pub protocol_version: u32,
+    pub campaign: String,
+    /// The TCP port the server is hosting on.
+    pub port: u16,
+    /// Whether the server only accepts encrypted connections.
+    pub require_encryption: bool,
+}
+
+impl Beacon {
+    /// Makes a beacon, cutting the campaign name short if the beacon would be too large to receive.
+    pub fn new(mut campaign: String, port: u16, require_encryption: bool) -> Self {
+        let mut end = campaign.len().min(MAX_BEACON_SIZE);
+        while !campaign.is_char_boundary(end) {
+            end -= 1;
+        }
+        campaign.truncate(end);
+        let mut beacon = Self {
+            magic: BEACON_MAGIC.to_owned(),
+            protocol_version: PROTOCOL_VERSION,
+            campaign,
+            port,
+            require_encryption,
+        };
+        // some characters take more space once written out, so check the whole thing
+        while ron::to_string(&beacon).is_ok_and(|msg| msg.len() > MAX_BEACON_SIZE) {
+            beacon.campaign.pop();
+        }
+        beacon
+    }
+}
+
+/// Broadcasts a beacon every [`BEACON_INTERVAL`] on its own thread, for as long as the program
+/// runs. `beacon` is asked for the current one each time, and nothing is sent while it returns
+/// `None`.
+pub fn announce<F: FnMut() -> Option<Beacon> + Send + 'static>(mut beacon: F) -> std::io::Result<()> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_broadcast(true)?;
+    std::thread::Builder::new().name("announce".to_owned()).spawn(move || {
+        loop {
+            if let Some(beacon) = beacon() {
+                if let Ok(msg) = ron::to_string(&beacon) {
+                    // nobody may be listening, and that's fine
+                    let _ = socket.send_to(msg.as_bytes(), (Ipv4Addr::BROADCAST, DISCOVERY_PORT));
+                }
+            }
+            std::thread::sleep(BEACON_INTERVAL);
+        }
+    })?;
+    Ok(())
+}
+
+/// Listens for servers announcing themselves on the LAN.
+pub struct Discovery {
+    /// Each server's beacon by the address to connect to, with when it was last heard from.
+    servers: Arc<Mutex<HashMap<String, (Beacon, Instant)>>>,
+}
+
+impl Discovery {
+    /// Starts listening on its own thread. Fails if something else on this machine is already
+    /// listening, like another copy of the player app.
+    pub fn start() -> std::io::Result<Self> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, DISCOVERY_PORT))?;
+        let servers = Arc::new(Mutex::new(HashMap::new()));
+        let found = Arc::clone(&servers);
+        std::thread::Builder::new().name("discovery".to_owned()).spawn(move || {
+            let mut buf = [0u8; MAX_BEACON_SIZE];
+            loop {
+                let (n, from) = match socket.recv_from(&mut buf) {
+                    Ok(received) => received,
+                    Err(_) => {
+                        std::thread::sleep(RECEIVE_ERROR_DELAY);
+                        continue;
+                    },
+                };
+                if let Some((address, beacon)) = read_beacon(&buf[..n], from) {
+                    found.lock().unwrap().insert(address, (beacon, Instant::now()));
+                }
+            }
+        })?;
+        Ok(Self { servers })
+    }
+
+    /// The servers heard from recently, with the address to connect to, sorted by campaign name.
+    pub fn servers(&self) -> Vec<(String, Beacon)> {
+        let mut servers = self.servers.lock().unwrap();
+        servers.retain(|_, (_, last_seen)| last_seen.elapsed() < BEACON_TIMEOUT);
+        let mut list: Vec<(String, Beacon)> = servers.iter().map(|(address, (beacon, _))| (address.clone(), beacon.clone())).collect();
+        list.sort_by(|(a_address, a), (b_address, b)| a.campaign.cmp(&b.campaign).then(a_address.cmp(b_address)));
+        list
+    }
+}
+
+/// Reads a beacon, returning it with the address its server can be reached at. Anything that
+/// isn't a beacon, or is from a server running a different protocol, is ignored.
+fn read_beacon(bytes: &[u8], from: SocketAddr) -> Option<(String, Beacon)> {
+    let beacon = ron::from_str::<Beacon>(std::str::from_utf8(bytes).ok()?).ok()?;
+    if beacon.magic != BEACON_MAGIC || beacon.protocol_version != PROTOCOL_VERSION {
+        return None;
+    }
+    Some((SocketAddr::new(from.ip(), beacon.port).to_string(), beacon))
+}
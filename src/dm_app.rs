@@ -1,55 +1,93 @@
 use crate::map::{Map, Room, RoomContainer, RoomTrap, RoomConnection};
 use crate::party::Party;
 use crate::{AppPreferences, WindowPreferences};
-use crate::character::{PlayerCharacter, SavingThrows, Attr, PlayerEquipSlot};
+use crate::auth::{PasswordWorker, hash_password, is_hashed};
+use crate::character::{PlayerCharacter, CharacterPatch, SavingThrows, Attr, PlayerEquipSlot};
 use crate::class::{SavingThrowProgressionType, Class, ClassDamageBonus, Cleaves, HitDie, AttackThrowProgression, WeaponSelection, BroadWeapons, NarrowWeapons, RestrictedWeapons, ArmorSelection, THIEF_SKILLS};
 use crate::combat::{Fight, Owner, Combatant, CombatantStats, DamageRoll, PreRoundAction, TurnType, MovementAction, AttackAction, SpecialManeuver, StatusEffect};
 use crate::common_ui::*;
-use crate::dice::{ModifierType, Drop, DiceRoll, roll};
+use crate::dice::{ModifierType, Drop, DiceRoll, DiceExpr, DiceRng, Distribution, ExprResult, roll_detailed};
 use crate::enemy::{Enemy, EnemyType, EnemyHitDice, EnemyCategory, Alignment, AttackRoutine};
 use crate::item::{ItemType, Encumbrance, WeaponStats, WeaponDamage, MeleeDamage, ContainerStats, Item};
 use crate::proficiency::{Proficiency, ProficiencyInstance};
 use crate::race::Race;
+use crate::remote::RemoteConsole;
 use crate::spell::{Spell, MagicType, SpellRange, SpellDuration, SpellRegistry};
 use eframe::egui::{self, Ui, RichText, WidgetText, Color32};
 use egui::collapsing_header::CollapsingState;
 use egui::{Label, Sense, TextEdit, Id, Layout, Align};
-use egui::text::LayoutJob;
 use egui_dock::{DockArea, Tree, TabViewer};
 use simple_enum_macro::simple_enum;
 use thousands::Separable;
-use crate::packets::{ClientBoundPacket, ServerBoundPacket, Request};
-use std::collections::{HashMap, HashSet};
-use std::fs::File;
-use std::net::{TcpListener, TcpStream, SocketAddr, SocketAddrV4, Ipv4Addr};
+use crate::network::{Connection, Handshake, NetEvent, NetEventKind, PROTOCOL_VERSION, HANDSHAKE_TIMEOUT, RECONNECT_GRACE, IDLE_CHECK_INTERVAL, encode_frame, decode_frame, server_tls_config};
+use crate::packets::{ClientBoundPacket, ServerBoundPacket, ClientFacingError, Request, sync_user, validate_username};
+use crate::discovery::{self, Beacon};
+use crate::rate_limit::{ConnectionLimits, TokenBucket, MAX_STRIKES, MAX_CONNECTIONS_PER_IP};
+use crate::storage::{self, write_atomic, CampaignStore, Fingerprints, StoreKind, UnsavedChanges};
+use crate::migration::{self, FileKind};
+use crate::campaign::{self, DEFAULT_CAMPAIGN};
+use std::collections::{HashMap, HashSet, BTreeMap};
+use std::net::{TcpListener, SocketAddr, IpAddr, Ipv4Addr};
 use std::io::{prelude::*, ErrorKind};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
-use serde::{Deserialize, Serialize};
+use std::sync::mpsc::{self, Sender, Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use rustls::ServerConfig;
+use sha2::{Sha256, Digest};
 use egui_phosphor as ep;
 
-/// How often the server reads for packets, in milliseconds. Setting this too low may cause 
-/// performance problems as it needs a lock on the app data.
+/// The most macros a user (or a single character) can save.
+pub const MAX_MACROS: usize = 50;
+
+/// How many characters a player is offered to choose from when the DM lets them make new ones.
+pub const NEW_CHARACTER_CHOICES: usize = 5;
+
+/// Once a player has picked enough characters that only this many choices are left, the rest are
+/// thrown away.
+pub const MIN_CHARACTER_CHOICES: usize = 3;
+
+/// How many chat messages are kept for a disconnected user to catch up on when they reconnect.
+pub const MAX_MISSED_MESSAGES: usize = 200;
+
+/// How often the server checks whether it has been asked to start hosting, and how often a
+/// headless server prints new logs, in milliseconds.
 pub const SERVER_UPDATE_CLOCK: u64 = 50;
 
+/// How often the autosave thread wakes up to store new chat messages and save if it was asked to.
+pub const AUTOSAVE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often the autosave thread checks for unsaved changes, unless it was asked to save. Every
+/// check serializes all of the data, which takes a while for a big campaign.
+pub const UNSAVED_CHECK_INTERVAL: Duration = Duration::from_secs(15);
+
+/// How many messages of the chat history are shown again when a campaign is loaded.
+pub const CHAT_HISTORY_LOADED: usize = 200;
+
+/// Why nothing can be saved or loaded when the campaign's store couldn't be opened.
+const NO_STORE: &str = "the campaign's saved data couldn't be opened";
+
 /// Runs the DM (server) application.
-pub fn run(prefs: AppPreferences) -> Result<(), eframe::Error> {
+pub fn run(prefs: AppPreferences, campaign: String) -> Result<(), eframe::Error> {
     let mut app_data = DMAppData::new();
+    app_data.campaign = campaign;
+    app_data.require_encryption = prefs.dm_require_encryption;
+    app_data.backup_count = prefs.dm_backup_count;
+    app_data.autosave_interval = prefs.dm_autosave_interval;
+    app_data.temp_state.remote.known_servers = prefs.known_servers.clone();
     app_data.load();
+    let seed = app_data.rng.seed();
+    app_data.log(ChatMessage::no_sender(format!("Dice seed for this session: {}", seed)).private());
     let data = Arc::new(Mutex::new(app_data));
 
-    let data_clone_1 = Arc::clone(&data);
-    std::thread::Builder::new().name(String::from("handle_streams")).spawn(move || {
-        handle_streams(data_clone_1);
-    }).unwrap();
-
-    let data_clone_2 = Arc::clone(&data);
-    std::thread::Builder::new().name(String::from("handle_connections")).spawn(move || {
-        handle_connections(data_clone_2);
-    }).unwrap();
+    start_server(&data);
+    start_autosave(&data);
+    announce_on_lan(Arc::clone(&data));
 
+    let title = format!("DM Automation Tool - {}", data.lock().unwrap().campaign);
     return eframe::run_native(
-        "DM Automation Tool", 
+        &title, 
         if let Some(p) = prefs.dm_window {
             p.to_native_options()
         } else {
@@ -72,90 +110,334 @@ pub fn run(prefs: AppPreferences) -> Result<(), eframe::Error> {
     );
 }
 
-/// A default, fallback socket address to use in case something went wrong. 
-const DEFAULT_IP: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 8080));
+/// Runs the server without a window, administered through commands typed into the terminal.
+/// Everything that would show up in the DM's chat is printed instead.
+pub fn run_headless(prefs: AppPreferences, port: u16, campaign: String) {
+    let mut app_data = DMAppData::new();
+    app_data.campaign = campaign;
+    app_data.headless = true;
+    app_data.require_encryption = prefs.dm_require_encryption;
+    app_data.backup_count = prefs.dm_backup_count;
+    app_data.autosave_interval = prefs.dm_autosave_interval;
+    app_data.load();
+    let seed = app_data.rng.seed();
+    app_data.log(ChatMessage::no_sender(format!("Dice seed for this session: {}", seed)).private());
+    if !app_data.roles.values().any(|role| *role == Role::CoDm) {
+        app_data.log(ChatMessage::no_sender("There are no co-DMs yet. Use /user <user> role codm to let someone run this server from the DM app.").private());
+    }
+    app_data.host_addr = Some(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port));
+    app_data.log(ChatMessage::no_sender(format!("Hosting the campaign \"{}\" on port {}. Type /help for commands, or /quit to save and exit.", app_data.campaign, port)).private());
+    let data = Arc::new(Mutex::new(app_data));
 
-/// Responsible for reading and handling packets, as well as handling existing connections.
-fn handle_streams(data: Arc<Mutex<DMAppData>>) {
-    loop {
-        std::thread::sleep(std::time::Duration::from_millis(SERVER_UPDATE_CLOCK));
-        let data = &mut *data.lock().unwrap();
-        let mut closed: Vec<usize> = Vec::new();
-        let mut packets: Vec<(ServerBoundPacket, SocketAddr)> = Vec::new();
-        let mut client_packets: Vec<ClientBoundPacket> = Vec::new();
-        for (i, (stream, buffer)) in data.streams.iter_mut().enumerate() {
-            let addr = stream.peer_addr().unwrap_or(DEFAULT_IP);
-            let mut reader = std::io::BufReader::new(&mut *stream);
-            let mut recieved: Vec<u8> = Vec::new();
-            recieved.append(buffer);
-            match reader.fill_buf() {
-                Ok(buf) => {
-                    if buf.is_empty() {
-                        continue;
-                    }
-                    recieved.append(&mut buf.to_vec());
-                },
-                Err(e) => {
-                    match e.kind() {
-                        ErrorKind::ConnectionReset |
-                        ErrorKind::ConnectionAborted => {
-                            closed.push(i);
-                            for (name, ip) in &data.connected_users {
-                                if *ip == addr {
-                                    let msg = ChatMessage::no_sender(format!("User \"{}\" has disconnected.", name)).blue();
-                                    data.logs.insert(0, msg.to_layout_job());
-                                    client_packets.push(ClientBoundPacket::ChatMessage(msg));
-                                }
-                            }
-                        },
-                        _ => {},
-                    }
-                    continue;
-                },
+    start_server(&data);
+    start_autosave(&data);
+    announce_on_lan(Arc::clone(&data));
+
+    let data_clone = Arc::clone(&data);
+    std::thread::Builder::new().name(String::from("print_logs")).spawn(move || {
+        let mut printed = 0;
+        loop {
+            std::thread::sleep(std::time::Duration::from_millis(SERVER_UPDATE_CLOCK));
+            let data = &mut *data_clone.lock().unwrap();
+            // logs are newest first, and multi-line output (like /help) is logged bottom up so it
+            // reads top down in the chat window, so print each batch in that same order
+            let new = data.logs.len().saturating_sub(printed);
+            for entry in &data.logs[..new] {
+                println!("{}", entry.job.text);
             }
+            printed = data.logs.len();
+        }
+    }).unwrap();
 
-            let mut iter = recieved.split_inclusive(|b| *b == 255);
-            while let Some(bytes) = iter.next() {
-                if bytes.ends_with(&[255]) {
-                    reader.consume(bytes.len());
-                    if bytes.len() < 2 {
-                        continue;
-                    }
-                    let msg = String::from_utf8_lossy(&bytes[..bytes.len() - 1]);
-                    match ron::from_str::<ServerBoundPacket>(&*msg) {
-                        Ok(packet) => {
-                            packets.push((packet, addr));
-                        },
-                        Err(_) => {},
-                    }
-                } else {
-                    *buffer = bytes.to_vec();
-                }
+    for line in std::io::stdin().lock().lines() {
+        let line = match line {
+            Ok(line) => line.trim().to_owned(),
+            Err(_) => break,
+        };
+        let data = &mut *data.lock().unwrap();
+        match line.as_str() {
+            "/quit" | "/exit" | "/stop" => break,
+            "" => {},
+            command if command.starts_with('/') => {
+                parse_command(data, command.to_owned());
+            },
+            _ => {
+                data.log(ChatMessage::server(line));
+            },
+        }
+    }
+    data.lock().unwrap().save();
+    println!("Saved. Goodbye!");
+}
+
+/// Starts the threads that accept and handle connections. Nothing is hosted until
+/// [`DMAppData::host_addr`] is set.
+pub fn start_server(data: &Arc<Mutex<DMAppData>>) {
+    let (events, receiver) = mpsc::channel();
+    let data_clone = Arc::clone(data);
+    std::thread::Builder::new().name(String::from("handle_events")).spawn(move || {
+        handle_events(data_clone, receiver);
+    }).unwrap();
+
+    let data_clone = Arc::clone(data);
+    std::thread::Builder::new().name(String::from("handle_connections")).spawn(move || {
+        handle_connections(data_clone, events);
+    }).unwrap();
+}
+
+/// Starts the thread that keeps track of unsaved changes, and saves them every
+/// [`DMAppData::autosave_interval`] or soon after [`DMAppData::request_autosave`].
+pub fn start_autosave(data: &Arc<Mutex<DMAppData>>) {
+    let data = Arc::clone(data);
+    std::thread::Builder::new().name(String::from("autosave")).spawn(move || {
+        let mut last_check = Instant::now();
+        loop {
+            std::thread::sleep(AUTOSAVE_CHECK_INTERVAL);
+            let data = &mut *data.lock().unwrap();
+            data.flush_chat();
+            if !data.autosave_requested && last_check.elapsed() < UNSAVED_CHECK_INTERVAL {
+                continue;
+            }
+            last_check = Instant::now();
+            data.check_unsaved();
+            if data.unsaved.any() && (data.autosave_requested || data.autosave_due()) {
+                data.save();
             }
+            data.autosave_requested = false;
         }
-        if !closed.is_empty() {
-            closed.sort();
-            closed.reverse();
-            for i in closed {
-                let (stream, _) = data.streams.remove(i);
-                if let Ok(addr) = stream.peer_addr() {
-                    data.connected_users.retain(|_, v| *v != addr);
+    }).unwrap();
+}
+
+/// Lets players on the LAN find the game while it is being hosted.
+fn announce_on_lan(data: Arc<Mutex<DMAppData>>) {
+    let result = discovery::announce(move || {
+        let data = data.lock().unwrap();
+        data.host_addr.map(|addr| Beacon::new(data.campaign_display_name(), addr.port(), data.require_encryption))
+    });
+    if let Err(e) = result {
+        eprintln!("Could not announce the game on the LAN: {}", e);
+    }
+}
+
+/// Responsible for handling everything that happens on existing connections. Sleeps until a
+/// connection has something to say, and only takes the lock on the app data to act on it.
+fn handle_events(data: Arc<Mutex<DMAppData>>, events: Receiver<NetEvent>) {
+    let password_worker = PasswordWorker::start();
+    let mut last_expiry_check = Instant::now();
+    loop {
+        let event = match events.recv_timeout(IDLE_CHECK_INTERVAL) {
+            Ok(event) => Some(event),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        match event {
+            Some(NetEvent { addr, id, kind: NetEventKind::Frame(payload) }) => {
+                let handshake_done = {
+                    let data = data.lock().unwrap();
+                    data.streams.iter().find(|c| c.id == id).map(|c| c.handshake_done)
+                };
+                match handshake_done {
+                    // it was dropped while this was on its way
+                    None => {},
+                    Some(false) => {
+                        let hello = decode_frame::<Handshake>(&payload);
+                        handle_handshake(&mut data.lock().unwrap(), id, addr, hello);
+                    },
+                    Some(true) => {
+                        // big packets take a while to decode, so do it before taking the lock
+                        match decode_frame::<ServerBoundPacket>(&payload) {
+                            Ok(packet) => {
+                                let allowed = within_rate_limits(&mut data.lock().unwrap(), id, addr, &packet);
+                                if allowed {
+                                    packet.handle_shared(&data, &password_worker, addr);
+                                }
+                            },
+                            Err(e) => {
+                                let data = &mut *data.lock().unwrap();
+                                // a broken client could send nothing else, so these count against it too
+                                if !strike(data, id, addr) {
+                                    let msg = ChatMessage::no_sender(format!("Could not read a packet from {}: {}", addr, e)).private().light_red();
+                                    data.logs.insert(0, msg.to_log_entry());
+                                    data.send_to_user_by_addr(ClientBoundPacket::ProtocolError(format!("The server could not read a packet: {}", e)), addr);
+                                }
+                            },
+                        }
+                    },
                 }
-            }
+            },
+            Some(NetEvent { addr, id, kind: NetEventKind::Closed(error) }) => {
+                let data = &mut *data.lock().unwrap();
+                connection_closed(data, id, addr, error);
+            },
+            None => {},
         }
-        for (packet, user) in packets {
-            if user != DEFAULT_IP {
-                packet.handle(data, user);
+        if last_expiry_check.elapsed() >= IDLE_CHECK_INTERVAL {
+            last_expiry_check = Instant::now();
+            let data = &mut *data.lock().unwrap();
+            drop_late_handshakes(data);
+            expire_sessions(data);
+        }
+    }
+}
+
+/// Answers a client's handshake, refusing the connection if it can't be used.
+fn handle_handshake(data: &mut DMAppData, id: u64, addr: SocketAddr, hello: Result<Handshake, String>) {
+    let require_encryption = data.require_encryption;
+    let connection = match data.streams.iter_mut().find(|c| c.id == id) {
+        Some(connection) => connection,
+        None => return,
+    };
+    // whatever the client sent, answer with our version so it can explain a mismatch
+    let refusal = match hello {
+        Ok(hello) => {
+            if !hello.is_compatible() {
+                Some((Handshake::current(), format!("client is running version {} (protocol {}), but the server is protocol {}", hello.app_version, hello.protocol_version, PROTOCOL_VERSION)))
+            } else if require_encryption && !connection.is_encrypted() {
+                Some((Handshake::refuse("This server only accepts encrypted connections. Turn on \"Encrypt connection\" and try again."), "client did not encrypt the connection".to_owned()))
+            } else {
+                None
             }
+        },
+        Err(e) => Some((Handshake::current(), format!("invalid handshake ({})", e))),
+    };
+    match refusal {
+        None => {
+            let _ = connection.send(&Handshake::current());
+            connection.handshake_done = true;
+        },
+        Some((reply, reason)) => {
+            let _ = connection.send(&reply);
+            connection.shutdown();
+            let msg = ChatMessage::no_sender(format!("Refused connection from {}: {}.", addr, reason)).private().light_red();
+            data.logs.insert(0, msg.to_log_entry());
+        },
+    }
+}
+
+/// Checks a packet against its connection's rate limits. Refused packets earn the connection a
+/// strike, and too many strikes get it disconnected.
+fn within_rate_limits(data: &mut DMAppData, id: u64, addr: SocketAddr, packet: &ServerBoundPacket) -> bool {
+    let class = packet.rate_class();
+    let mut allowed = data.rate_limits.entry(id).or_default().allow(class);
+    if allowed && matches!(packet, ServerBoundPacket::CreateAccount(..)) {
+        allowed = data.account_limits.entry(addr.ip()).or_insert_with(TokenBucket::account_creation).try_take();
+    }
+    if allowed {
+        return true;
+    }
+    if !strike(data, id, addr) {
+        data.send_to_user_by_addr(ClientBoundPacket::Error(ClientFacingError::RateLimited), addr);
+    }
+    false
+}
+
+/// Gives a connection a strike, disconnecting it if that makes too many. Returns true if it was
+/// disconnected.
+fn strike(data: &mut DMAppData, id: u64, addr: SocketAddr) -> bool {
+    let strikes = data.rate_limits.entry(id).or_default().strike();
+    if strikes < MAX_STRIKES {
+        return false;
+    }
+    let name = match data.get_username_by_addr(addr) {
+        Some(username) => {
+            // so they can't just resume the session
+            data.sessions.remove(&username);
+            format!("user \"{}\"", username)
+        },
+        None => addr.to_string(),
+    };
+    for connection in &data.streams {
+        if connection.id == id {
+            let _ = connection.send(&ClientBoundPacket::Error(ClientFacingError::Disconnected));
+            connection.shutdown();
+        }
+    }
+    data.log(ChatMessage::no_sender(format!("Disconnecting {} for sending too many packets.", name)).private().red());
+    true
+}
+
+/// Forgets a connection that has closed, keeping the user's session around in case they come
+/// back.
+fn connection_closed(data: &mut DMAppData, id: u64, addr: SocketAddr, error: Option<std::io::Error>) {
+    let before = data.streams.len();
+    data.streams.retain(|c| c.id != id);
+    data.rate_limits.remove(&id);
+    if data.streams.len() == before {
+        return;
+    }
+    if let Some(e) = error {
+        match e.kind() {
+            ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted => {},
+            _ => {
+                let msg = ChatMessage::no_sender(format!("Dropping connection from {}: {}", addr, e)).private().red();
+                data.logs.insert(0, msg.to_log_entry());
+            },
+        }
+    }
+    let mut client_packets = Vec::new();
+    for (name, ip) in &data.connected_users {
+        if *ip == addr {
+            let msg = match data.sessions.get_mut(name) {
+                Some(session) => {
+                    session.disconnected_at = Some(Instant::now());
+                    format!("User \"{}\" lost connection. Waiting for them to reconnect...", name)
+                },
+                None => format!("User \"{}\" has disconnected.", name),
+            };
+            let msg = ChatMessage::no_sender(msg).blue();
+            data.logs.insert(0, msg.to_log_entry());
+            client_packets.push(ClientBoundPacket::ChatMessage(msg));
+        }
+    }
+    data.connected_users.retain(|_, v| *v != addr);
+    for packet in client_packets {
+        data.send_to_all_players(packet);
+    }
+}
+
+/// Closes connections that didn't finish the handshake in time, so they can't be left open doing
+/// nothing. Their events are ignored once they are out of the list.
+fn drop_late_handshakes(data: &mut DMAppData) {
+    let mut late = Vec::new();
+    data.streams.retain(|connection| {
+        if !connection.handshake_done && connection.opened_at.elapsed() > HANDSHAKE_TIMEOUT {
+            late.push(connection.addr);
+            false
+        } else {
+            true
         }
-        for packet in client_packets {
-            data.send_to_all_players(packet);
+    });
+    for addr in late {
+        let msg = ChatMessage::no_sender(format!("Dropping connection from {}: no handshake in time.", addr)).private().light_red();
+        data.logs.insert(0, msg.to_log_entry());
+    }
+}
+
+/// Whether a new connection from `ip` is let in. Addresses that already have too many
+/// connections open are turned away.
+fn accepts_connection_from(data: &DMAppData, ip: IpAddr) -> bool {
+    data.streams.iter().filter(|connection| connection.addr.ip() == ip).count() < MAX_CONNECTIONS_PER_IP
+}
+
+/// Ends the sessions of users who didn't reconnect in time.
+fn expire_sessions(data: &mut DMAppData) {
+    data.account_limits.retain(|_, limits| !limits.is_full());
+    let mut expired = Vec::new();
+    for (name, session) in &data.sessions {
+        if session.disconnected_at.is_some_and(|t| t.elapsed() > RECONNECT_GRACE) {
+            expired.push(name.clone());
         }
     }
+    for name in expired {
+        data.sessions.remove(&name);
+        let msg = ChatMessage::no_sender(format!("User \"{}\" has disconnected.", name)).blue();
+        data.logs.insert(0, msg.to_log_entry());
+        data.send_to_all_players(ClientBoundPacket::ChatMessage(msg));
+    }
 }
 
 /// Responsible for handling new incoming connections.
-fn handle_connections(data: Arc<Mutex<DMAppData>>) {
+fn handle_connections(data: Arc<Mutex<DMAppData>>, events: Sender<NetEvent>) {
     let listener;
     loop {
         std::thread::sleep(std::time::Duration::from_millis(SERVER_UPDATE_CLOCK));
@@ -163,7 +445,21 @@ fn handle_connections(data: Arc<Mutex<DMAppData>>) {
         if let Some(addr) = data.host_addr {
             match TcpListener::bind(addr) {
                 Ok(l) => {
+                    // the OS picks the port if it was 0
+                    data.host_addr = Some(l.local_addr().unwrap_or(addr));
                     listener = l;
+                    if data.tls_config.is_none() {
+                        match server_tls_config() {
+                            Ok((config, fingerprint)) => {
+                                data.log(ChatMessage::no_sender(format!("Encryption is available. Certificate fingerprint: {}", fingerprint)).private().blue());
+                                data.tls_config = Some(config);
+                                data.tls_fingerprint = Some(fingerprint);
+                            },
+                            Err(e) => {
+                                data.log(ChatMessage::no_sender(format!("Could not set up encryption, connections will be unencrypted: {}", e)).private().red());
+                            },
+                        }
+                    }
                     break;
                 },
                 Err(e) => {
@@ -174,12 +470,18 @@ fn handle_connections(data: Arc<Mutex<DMAppData>>) {
         }
     }
     for stream in listener.incoming() {
+        // hold the lock until it's in the list, so its first events can't arrive before it does
         let data = &mut *data.lock().unwrap();
-        match stream {
-            Ok(s) => {
-                s.set_nonblocking(true).unwrap();
-                data.log(ChatMessage::no_sender(format!("Connection from user with ip: {:?}", s.peer_addr())).private().blue());
-                data.streams.push((s, Vec::new()));
+        if let Ok(stream) = &stream {
+            if stream.peer_addr().is_ok_and(|addr| !accepts_connection_from(data, addr.ip())) {
+                // dropping it closes it. Not logged, as a flood of them would bury the chat
+                continue;
+            }
+        }
+        match stream.and_then(|s| Connection::accept(s, data.tls_config.clone(), events.clone())) {
+            Ok(connection) => {
+                data.log(ChatMessage::no_sender(format!("Connection from user with ip: {}", connection.addr)).private().blue());
+                data.streams.push(connection);
             },
             Err(e) => {
                 data.log(ChatMessage::no_sender(format!("Connection error: {:?}", e)).private().red());
@@ -271,17 +573,61 @@ pub enum RegistryNode<T> {
     SubRegistry(HashMap<String, RegistryNode<T>>),
 }
 
-/// Saves a serializable value to disk.
-pub fn save_ron<S: Serialize>(obj: &S, dir: &str, file: &str) -> Result<(), ()> {
-    if let Ok(s) = ron::to_string(obj) {
-        let file = format!("{}/{}.ron", dir, file);
-        let path = Path::new(&file);
+/// Identifies the contents of each registry sent to players, so a client that already has one
+/// cached doesn't need it sent again. Registries are only read from disk when the server starts,
+/// so these never change while it is running.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct RegistryVersions {
+    pub classes: String,
+    pub proficiencies: String,
+    pub spells: String,
+}
+
+#[simple_enum]
+pub enum RegistryKind {
+    Classes,
+    Proficiencies,
+    Spells,
+}
+
+/// Hashes the files a registry was read from. They are sorted by path first, so the same files
+/// always give the same version no matter what order the directory lists them in.
+fn hash_files(mut files: Vec<(String, String)>) -> String {
+    files.sort();
+    let mut hasher = Sha256::new();
+    for (path, file) in &files {
+        hasher.update(path.as_bytes());
+        hasher.update([0]);
+        hasher.update(file.as_bytes());
+        hasher.update([0]);
+    }
+    hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Formats a duration roughly, like "40 seconds" or "3 minutes".
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs < 60 {
+        format!("{} second{}", secs, if secs == 1 { "" } else { "s" })
+    } else if secs < 60 * 60 {
+        let mins = secs / 60;
+        format!("{} minute{}", mins, if mins == 1 { "" } else { "s" })
+    } else {
+        let hours = secs / (60 * 60);
+        format!("{} hour{}", hours, if hours == 1 { "" } else { "s" })
+    }
+}
+
+/// Saves a serializable value to disk, in the directory for its kind of file inside `root`.
+pub fn save_ron<S: Serialize>(obj: &S, root: &Path, kind: FileKind, file: &str) -> Result<(), ()> {
+    if let Ok(s) = migration::to_string(kind, obj) {
+        let path = root.join(kind.dir()).join(format!("{}.ron", file));
         if let Some(parent) = path.parent() {
             if std::fs::create_dir_all(parent).is_err() {
                 return Err(());
             }
         }
-        if let Ok(_) = std::fs::write(path, s.as_bytes()) {
+        if let Ok(_) = write_atomic(&path, s.as_bytes()) {
             return Ok(());
         }
     }
@@ -291,9 +637,125 @@ pub fn save_ron<S: Serialize>(obj: &S, dir: &str, file: &str) -> Result<(), ()>
 /// The server's data that is saved to disk.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SaveData {
+    /// Which [`FileKind::Save`] version wrote this. Older ones are upgraded when loaded.
+    #[serde(default)]
+    pub version: u32,
+    /// Usernames and their Argon2 password hashes.
     pub known_users: HashMap<String, String>,
     pub user_data: HashMap<String, UserData>,
     pub parties: HashMap<String, Party>,
+    /// Users without the default [`Role::Player`] role.
+    #[serde(default)]
+    pub roles: HashMap<String, Role>,
+    /// Users allowed to run server commands remotely, from before there were roles. They are
+    /// loaded as co-DMs and never written back.
+    #[serde(default, skip_serializing)]
+    pub admins: HashSet<String>,
+    /// Users who may not log in.
+    #[serde(default)]
+    pub banned: HashSet<String>,
+    /// Refuse to create new accounts.
+    #[serde(default)]
+    pub registration_closed: bool,
+    /// Shown to players looking for games on the LAN.
+    #[serde(default)]
+    pub campaign_name: String,
+}
+
+impl SaveData {
+    /// Describes what would change if this data were replaced by `other`, one line per change.
+    pub fn describe_changes(&self, other: &SaveData) -> Vec<String> {
+        let mut changes = Vec::new();
+        let mut users: Vec<&String> = self.known_users.keys().chain(other.known_users.keys()).collect();
+        users.sort();
+        users.dedup();
+        for user in users {
+            match (self.known_users.get(user), other.known_users.get(user)) {
+                (Some(_), None) => {
+                    let characters = self.user_data.get(user).map_or(0, |user_data| user_data.characters.len());
+                    changes.push(format!("- User \"{}\" and their {} character(s) would be removed.", user, characters));
+                    continue;
+                },
+                (None, Some(_)) => {
+                    changes.push(format!("- User \"{}\" would be added back.", user));
+                },
+                (Some(old), Some(new)) if old != new => {
+                    changes.push(format!("- User \"{}\" would get their old password back.", user));
+                },
+                _ => {},
+            }
+            let empty = HashMap::new();
+            let old_characters = self.user_data.get(user).map_or(&empty, |user_data| &user_data.characters);
+            let new_characters = other.user_data.get(user).map_or(&empty, |user_data| &user_data.characters);
+            let mut names: Vec<&String> = old_characters.keys().chain(new_characters.keys()).collect();
+            names.sort();
+            names.dedup();
+            for name in names {
+                match (old_characters.get(name), new_characters.get(name)) {
+                    (Some(_), None) => changes.push(format!("- {} ({}) would be removed.", name, user)),
+                    (None, Some(_)) => changes.push(format!("- {} ({}) would be added back.", name, user)),
+                    (Some(old), Some(new)) => {
+                        if ron::to_string(old).ok() != ron::to_string(new).ok() {
+                            changes.push(format!("- {} ({}) would go from level {} with {} XP and {} HP to level {} with {} XP and {} HP.", name, user, old.level, old.xp, old.combat_stats.health.current_hp, new.level, new.xp, new.combat_stats.health.current_hp));
+                        }
+                    },
+                    (None, None) => {},
+                }
+            }
+        }
+        let mut parties: Vec<&String> = self.parties.keys().chain(other.parties.keys()).collect();
+        parties.sort();
+        parties.dedup();
+        for party in parties {
+            match (self.parties.get(party), other.parties.get(party)) {
+                (Some(_), None) => changes.push(format!("- Party \"{}\" would be removed.", party)),
+                (None, Some(_)) => changes.push(format!("- Party \"{}\" would be added back.", party)),
+                (Some(old), Some(new)) => {
+                    if old.members != new.members || old.temporary_xp != new.temporary_xp {
+                        changes.push(format!("- Party \"{}\" would go from {} to {} member(s).", party, old.members.len(), new.members.len()));
+                    }
+                },
+                (None, None) => {},
+            }
+        }
+        if self.roles != other.roles {
+            changes.push("- Some users' roles would change.".to_owned());
+        }
+        if self.banned != other.banned {
+            changes.push(format!("- The ban list would go from {} to {} user(s).", self.banned.len(), other.banned.len()));
+        }
+        if self.registration_closed != other.registration_closed {
+            changes.push(format!("- Registration would be {}.", if other.registration_closed { "closed" } else { "opened" }));
+        }
+        if self.campaign_name != other.campaign_name {
+            changes.push(format!("- The campaign would be renamed to \"{}\".", other.campaign_name));
+        }
+        changes
+    }
+}
+
+/// What a user is allowed to do on the server. Each role can do everything the ones before it
+/// can.
+#[simple_enum(display)]
+#[derive(PartialOrd, Ord)]
+pub enum Role {
+    /// spectator
+    Spectator,
+    /// player
+    Player,
+    /// co-DM
+    CoDm,
+}
+
+impl Role {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "spectator" => Some(Self::Spectator),
+            "player" => Some(Self::Player),
+            "codm" | "co-dm" => Some(Self::CoDm),
+            _ => None,
+        }
+    }
 }
 
 /// Information associated with a user, like their characters.
@@ -301,6 +763,17 @@ pub struct SaveData {
 pub struct UserData {
     pub characters: HashMap<String, PlayerCharacter>,
     pub notes: String,
+    /// Saved rolls shared by all of this user's characters, by name.
+    #[serde(default)]
+    pub macros: BTreeMap<String, String>,
+    /// SHA-256 hashes of the login tokens this user's clients have been given to remember them by.
+    /// Tokens hashed with Argon2 by older versions never match, so those clients log in with
+    /// their password once more.
+    #[serde(default)]
+    pub login_tokens: Vec<String>,
+    /// Characters the server rolled for this user that they can still pick from.
+    #[serde(default)]
+    pub pending_characters: Vec<PlayerCharacter>,
     #[serde(skip)]
     pub charsheet_tabs: HashMap<String, CharacterSheetTab>,
 }
@@ -310,14 +783,53 @@ impl UserData {
         Self {
             characters: HashMap::new(),
             notes: String::new(),
+            macros: BTreeMap::new(),
+            login_tokens: Vec::new(),
+            pending_characters: Vec::new(),
             charsheet_tabs: HashMap::new(),
         }
     }
 }
 
+/// A logged in user's session. It outlives their connection for [`RECONNECT_GRACE`] so they can
+/// reconnect without losing their place.
+pub struct Session {
+    pub token: String,
+    /// When the user's connection dropped, if it has.
+    pub disconnected_at: Option<Instant>,
+    /// Chat the user missed while disconnected, oldest first.
+    pub missed_messages: Vec<ChatMessage>,
+    /// The last version of each character sent to the user, so later updates only need to send
+    /// what changed.
+    pub sent_characters: HashMap<String, PlayerCharacter>,
+}
+
+impl Session {
+    pub fn new(token: String) -> Self {
+        Self {
+            token,
+            disconnected_at: None,
+            missed_messages: Vec::new(),
+            sent_characters: HashMap::new(),
+        }
+    }
+
+    /// Keeps a message for the user to catch up on, if they are currently disconnected.
+    pub fn record_missed(&mut self, msg: &ChatMessage) {
+        if self.disconnected_at.is_some() {
+            self.missed_messages.push(msg.clone());
+            if self.missed_messages.len() > MAX_MISSED_MESSAGES {
+                self.missed_messages.remove(0);
+            }
+        }
+    }
+}
+
 /// Superficial app state that is not saved.
 pub struct AppTempState {
     pub exit_without_saving: bool,
+    /// For running a headless server from this app.
+    pub remote: RemoteConsole,
     pub window_states: HashMap<String, bool>,
     pub chat: String,
     pub last_chat: String,
@@ -326,6 +838,10 @@ pub struct AppTempState {
     pub dice_roll: DiceRoll,
     pub dice_roll_advanced: bool,
     pub dice_roll_public: bool,
+    pub dice_expr: String,
+    pub dice_expr_error: Option<String>,
+    pub dice_odds: Option<(DiceRoll, Result<Distribution, String>)>,
+    pub dice_odds_target: i32,
     pub show_offline_users: bool,
     pub temp_enemy_type: Option<EnemyType>,
     pub temp_enemy_saves_preset: Option<(SavingThrowProgressionType, u8)>,
@@ -362,6 +878,7 @@ impl AppTempState {
     pub fn new() -> Self {
         Self {
             exit_without_saving: false,
+            remote: RemoteConsole::new(),
             window_states: HashMap::new(),
             chat: String::new(),
             last_chat: String::new(),
@@ -370,6 +887,10 @@ impl AppTempState {
             dice_roll: DiceRoll::simple(1, 20),
             dice_roll_advanced: false,
             dice_roll_public: true,
+            dice_expr: String::new(),
+            dice_expr_error: None,
+            dice_odds: None,
+            dice_odds_target: 10,
             show_offline_users: false,
             temp_enemy_type: None,
             temp_enemy_saves_preset: None,
@@ -408,12 +929,54 @@ impl AppTempState {
 pub struct DMAppData {
     pub host_port: u16,
     pub host_addr: Option<SocketAddr>,
+    /// Set once hosting starts, unless the certificate couldn't be loaded or created.
+    pub tls_config: Option<Arc<ServerConfig>>,
+    pub tls_fingerprint: Option<String>,
+    /// Refuse connections that aren't encrypted.
+    pub require_encryption: bool,
+    /// How many backups of the save file to keep. A new one is made every time it is saved, or less
+    /// often for a database (see [`CampaignStore::backup_interval`]).
+    pub backup_count: usize,
+    /// When the last backup was made, if there has been one since the app started.
+    pub last_backup: Option<Instant>,
+    /// How often the game saves on its own, in seconds. 0 turns autosaving off.
+    pub autosave_interval: u64,
+    /// Something important happened, so save on the next autosave check instead of waiting.
+    pub autosave_requested: bool,
+    pub last_save: Instant,
+    /// Fingerprints of the data as it was last saved or loaded.
+    pub saved: Fingerprints,
+    /// Updated by the autosave thread every [`UNSAVED_CHECK_INTERVAL`].
+    pub unsaved: UnsavedChanges,
     pub known_users: HashMap<String, String>,
     pub user_data: HashMap<String, UserData>,
     pub parties: HashMap<String, Party>,
     pub connected_users: HashMap<String, SocketAddr>,
-    pub logs: Vec<LayoutJob>,
-    pub streams: Vec<(TcpStream, Vec<u8>)>,
+    /// The rate limits of each connection, by [`Connection::id`].
+    pub rate_limits: HashMap<u64, ConnectionLimits>,
+    /// Limits on creating accounts, by IP address so reconnecting doesn't get around them.
+    pub account_limits: HashMap<IpAddr, TokenBucket>,
+    /// Sessions of logged in users by username, including any waiting for a reconnect.
+    pub sessions: HashMap<String, Session>,
+    /// Users without the default [`Role::Player`] role.
+    pub roles: HashMap<String, Role>,
+    /// Users who may not log in.
+    pub banned: HashSet<String>,
+    /// Refuse to create new accounts.
+    pub registration_closed: bool,
+    /// Shown to players looking for games on the LAN.
+    pub campaign_name: String,
+    /// The folder name of the loaded campaign. See [`campaign::dir`].
+    pub campaign: String,
+    /// Where the campaign is saved. Opened by [`DMAppData::load`], so it is `None` before then or
+    /// if it couldn't be opened.
+    pub store: Option<Box<dyn CampaignStore>>,
+    /// Chat messages logged since they were last added to the store's chat history.
+    pub pending_chat: Vec<ChatMessage>,
+    /// Running without a window, so there are no window preferences to save.
+    pub headless: bool,
+    pub logs: Vec<ChatLogEntry>,
+    pub streams: Vec<Connection>,
     pub temp_state: AppTempState,
     pub enemy_type_registry: Registry<EnemyType>,
     pub item_type_registry: Registry<ItemType>,
@@ -421,20 +984,44 @@ pub struct DMAppData {
     pub proficiency_registry: HashMap<String, Proficiency>,
     pub sorted_prof_list: Vec<(String, String)>,
     pub spell_registry: SpellRegistry,
+    pub registry_versions: RegistryVersions,
     pub prefs: WindowPreferences,
     pub map_registry: HashMap<String, String>,
     pub loaded_map: Option<(String, Map)>,
+    /// Every roll the DM makes draws from this, so a session can be replayed from its seed.
+    pub rng: DiceRng,
 }
 
 impl DMAppData {
     pub fn new() -> Self {
-        Self { 
+        let mut data = Self { 
             host_port: 8080,
             host_addr: None,
+            tls_config: None,
+            tls_fingerprint: None,
+            require_encryption: false,
+            backup_count: storage::DEFAULT_BACKUP_COUNT,
+            last_backup: None,
+            autosave_interval: storage::DEFAULT_AUTOSAVE_INTERVAL,
+            autosave_requested: false,
+            last_save: Instant::now(),
+            saved: Fingerprints::default(),
+            unsaved: UnsavedChanges::default(),
             known_users: HashMap::new(),
             user_data: HashMap::new(),
             parties: HashMap::new(),
             connected_users: HashMap::new(),
+            rate_limits: HashMap::new(),
+            account_limits: HashMap::new(),
+            sessions: HashMap::new(),
+            roles: HashMap::new(),
+            banned: HashSet::new(),
+            registration_closed: false,
+            campaign_name: String::new(),
+            campaign: DEFAULT_CAMPAIGN.to_owned(),
+            store: None,
+            pending_chat: Vec::new(),
+            headless: false,
             logs: Vec::new(),
             streams: Vec::new(),
             temp_state: AppTempState::new(),
@@ -444,27 +1031,75 @@ impl DMAppData {
             proficiency_registry: HashMap::new(),
             sorted_prof_list: Vec::new(),
             spell_registry: SpellRegistry::new(),
+            registry_versions: RegistryVersions::default(),
             prefs: WindowPreferences::new(),
             map_registry: HashMap::new(),
             loaded_map: None,
-        }
+            rng: DiceRng::new(),
+        };
+        data.saved = data.fingerprints();
+        data
     }
 
-    /// Reads data stored on disk, if it exists.
+    /// Reads data stored on disk, if it exists. The chat history is only shown the first time,
+    /// since after that it is already in the log.
     pub fn load(&mut self) {
-        if let Ok(mut file) = std::fs::read_to_string("savedata.ron") {
-            match ron::from_str::<SaveData>(&file) {
-                Ok(data) => {
-                    self.known_users = data.known_users;
-                    self.user_data = data.user_data;
-                    self.parties = data.parties;
-                },
-                // backs up the existing save data if we couldn't deserialize it
-                Err(e) => {
-                    file.push_str(&format!("\n\n/* error parsing save data:\n{}\n*/", e));
-                    let _ = std::fs::write(format!("backups/{}.ron", chrono::Local::now().format("%d-%m-%Y--%H-%M-%S")), file);
-                },
-            }
+        let first_load = self.store.is_none();
+        if !first_load {
+            // whatever was said so far belongs in the store being replaced
+            self.flush_chat();
+        }
+        match storage::open_store(&campaign::dir(&self.campaign)) {
+            Ok(store) => self.store = Some(store),
+            Err(e) => {
+                self.log(ChatMessage::no_sender(format!("Could not open the campaign's saved data, so nothing will be loaded or saved: {}.", e)).private().red());
+            },
+        }
+        if first_load {
+            self.load_chat_history();
+        }
+        let loaded = match &mut self.store {
+            Some(store) => store.load(),
+            None => Ok(None),
+        };
+        match loaded {
+            Ok(Some(loaded)) => {
+                // keeps a copy of the old data around, since older versions of the app can't read
+                // the new one, and not as a rotated backup so it is never thrown away
+                if loaded.version < FileKind::Save.version() {
+                    let old = match &mut self.store {
+                        Some(store) => store.saved_text().ok().flatten(),
+                        None => None,
+                    };
+                    let backup = self.campaign_path(storage::BACKUP_DIR).join(format!("before-upgrade-from-v{}-{}.ron", loaded.version, chrono::Local::now().format("%Y-%m-%d--%H-%M-%S")));
+                    if let Some(old) = old {
+                        let _ = write_atomic(&backup, old.as_bytes());
+                    }
+                    let mut msg = format!("Upgraded the saved data from version {} to {}. The old data was copied to {}.", loaded.version, FileKind::Save.version(), backup.display());
+                    for upgrade in &loaded.upgrades {
+                        msg.push_str(&format!("\n- {}", upgrade));
+                    }
+                    self.log(ChatMessage::no_sender(msg).private().blue());
+                }
+                self.apply_save_data(loaded.value);
+                self.saved = self.fingerprints();
+                self.check_unsaved();
+                self.hash_plaintext_passwords();
+            },
+            Ok(None) => {},
+            // backs up the existing save data if we couldn't deserialize it
+            Err(e) => {
+                let old = match &mut self.store {
+                    Some(store) => store.saved_text().ok().flatten(),
+                    None => None,
+                };
+                let backup = self.campaign_path(storage::BACKUP_DIR).join(format!("{}.ron", chrono::Local::now().format("%d-%m-%Y--%H-%M-%S")));
+                if let Some(mut old) = old {
+                    old.push_str(&format!("\n\n/* error parsing save data:\n{}\n*/", e));
+                    let _ = write_atomic(&backup, old.as_bytes());
+                }
+                self.log(ChatMessage::no_sender(format!("Could not read the saved data, so nothing was loaded: {}. It was copied to {}, and will be replaced the next time the game is saved.", e, backup.display())).private().red());
+            },
         }
         self.register_enemy_types();
         self.register_item_types();
@@ -475,68 +1110,142 @@ impl DMAppData {
     }
 
     fn register_enemy_types(&mut self) {
-        Self::read_dir_recursive("enemies", |path, s| {
-            if let Ok(enemy) = ron::from_str::<EnemyType>(&s) {
-                let _ = self.enemy_type_registry.register(path.strip_prefix("enemies\\").unwrap(), enemy);
-            }
+        self.read_registry(FileKind::Enemy, |data, path, enemy: EnemyType| {
+            let _ = data.enemy_type_registry.register(path, enemy);
         });
     }
 
     fn register_item_types(&mut self) {
-        Self::read_dir_recursive("items", |path, s| {
-            if let Ok(item) = ron::from_str::<ItemType>(&s) {
-                let _ = self.item_type_registry.register(path.strip_prefix("items\\").unwrap(), item);
-            }
+        self.read_registry(FileKind::Item, |data, path, item: ItemType| {
+            let _ = data.item_type_registry.register(path, item);
         });
     }
 
     fn register_classes(&mut self) {
-        Self::read_dir_recursive("classes", |path, s| {
-            if let Ok(class) = ron::from_str::<Class>(&s) {
-                let _ = self.class_registry.register(path.strip_prefix("classes\\").unwrap(), class);
-            }
+        let files = self.read_registry(FileKind::Class, |data, path, class: Class| {
+            let _ = data.class_registry.register(path, class);
         });
+        self.registry_versions.classes = hash_files(files);
     }
 
     fn register_spells(&mut self) {
-        Self::read_dir_recursive("spells", |path, s| {
-            if let Ok(spell) = ron::from_str::<Spell>(&s) {
-                let path = path.split(|c| c == '/' || c == '\\').last().unwrap_or("error").to_owned();
-                match spell.magic_type {
-                    MagicType::Arcane => {
-                        if (spell.spell_level as usize) < self.spell_registry.arcane.len() {
-                            self.spell_registry.arcane[spell.spell_level as usize].insert(path, spell);
-                        }
-                    },
-                    MagicType::Divine => {
-                        if (spell.spell_level as usize) < self.spell_registry.divine.len() {
-                            self.spell_registry.divine[spell.spell_level as usize].insert(path, spell);
-                        }
-                    },
-                }
+        let files = self.read_registry(FileKind::Spell, |data, path, spell: Spell| {
+            let path = path.split(|c| c == '/' || c == '\\').last().unwrap_or("error").to_owned();
+            match spell.magic_type {
+                MagicType::Arcane => {
+                    if (spell.spell_level as usize) < data.spell_registry.arcane.len() {
+                        data.spell_registry.arcane[spell.spell_level as usize].insert(path, spell);
+                    }
+                },
+                MagicType::Divine => {
+                    if (spell.spell_level as usize) < data.spell_registry.divine.len() {
+                        data.spell_registry.divine[spell.spell_level as usize].insert(path, spell);
+                    }
+                },
             }
         });
+        self.registry_versions.spells = hash_files(files);
     }
 
     fn register_profs(&mut self) {
         self.sorted_prof_list.clear();
-        Self::read_dir_recursive("proficiencies", |path, s| {
-            if let Ok(prof) = ron::from_str::<Proficiency>(&s) {
-                let path = path.split(|c| c == '/' || c == '\\').last().unwrap_or("error").to_owned();
-                self.sorted_prof_list.push((path.clone(), prof.name.clone()));
-                self.proficiency_registry.insert(path, prof);
-            }
+        let files = self.read_registry(FileKind::Proficiency, |data, path, prof: Proficiency| {
+            let path = path.split(|c| c == '/' || c == '\\').last().unwrap_or("error").to_owned();
+            data.sorted_prof_list.push((path.clone(), prof.name.clone()));
+            data.proficiency_registry.insert(path, prof);
         });
         self.sorted_prof_list.sort();
+        self.registry_versions.proficiencies = hash_files(files);
     }
 
     fn register_maps(&mut self) {
         self.map_registry.clear();
-        Self::read_dir_recursive("maps", |path, s| {
-            if let Ok(map) = ron::from_str::<Map>(&s) {
-                self.map_registry.insert(path.strip_prefix("maps\\").unwrap().to_owned(), map.name);
+        let maps = match &mut self.store {
+            Some(store) => store.list_maps(),
+            None => Vec::new(),
+        };
+        for (id, name) in maps {
+            match name {
+                Ok(name) => {
+                    self.map_registry.insert(id, name);
+                },
+                Err(e) => {
+                    self.log(ChatMessage::no_sender(format!("Could not load the map \"{}\": {}", id, e)).private().light_red());
+                },
             }
-        });
+        }
+    }
+
+    /// Shows the newest messages of the chat history, if the store keeps one.
+    fn load_chat_history(&mut self) {
+        let history = match &mut self.store {
+            Some(store) => store.recent_chat(CHAT_HISTORY_LOADED),
+            None => Ok(Vec::new()),
+        };
+        match history {
+            Ok(messages) => {
+                for message in messages {
+                    self.logs.insert(0, message.to_log_entry());
+                }
+            },
+            Err(e) => {
+                self.log(ChatMessage::no_sender(format!("Could not load the chat history: {}", e)).private().light_red());
+            },
+        }
+    }
+
+    /// Adds the chat messages logged since the last time to the store's chat history.
+    pub fn flush_chat(&mut self) {
+        if self.pending_chat.is_empty() {
+            return;
+        }
+        let messages = std::mem::take(&mut self.pending_chat);
+        let result = match &mut self.store {
+            Some(store) => store.append_chat(&messages),
+            None => Ok(()),
+        };
+        if let Err(e) = result {
+            // logging it normally would add it to the history too, and fail again
+            self.logs.insert(0, ChatMessage::no_sender(format!("Could not add {} message(s) to the chat history: {}", messages.len(), e)).private().light_red().to_log_entry());
+        }
+    }
+
+    /// Reads every file of a kind, upgrading any written by older versions, and passes each to
+    /// the provided function along with its path inside the kind's directory. The shared files
+    /// are read first, then the campaign's own, so it can override them. Files that can't be read
+    /// are reported to the DM. Returns all the files, for hashing.
+    fn read_registry<T, F>(&mut self, kind: FileKind, mut func: F) -> Vec<(String, String)>
+        where T: DeserializeOwned, F: FnMut(&mut Self, &str, T) {
+        let mut dirs = vec![PathBuf::from(kind.dir())];
+        let campaign_dir = self.campaign_path(kind.dir());
+        if !dirs.contains(&campaign_dir) {
+            dirs.push(campaign_dir);
+        }
+        let mut all_files = Vec::new();
+        for dir in dirs {
+            let mut files = Vec::new();
+            Self::read_dir_recursive(&dir, |path, s| {
+                files.push((path, s));
+            });
+            let prefix = dir.to_string_lossy().into_owned();
+            for (path, s) in &files {
+                // paths start with the directory, followed by either separator depending on the OS
+                let id = path.strip_prefix(&prefix).map_or(path.as_str(), |id| id.trim_start_matches(['/', '\\']));
+                match migration::from_str::<T>(kind, s) {
+                    Ok(loaded) => func(self, id, loaded.value),
+                    Err(e) => {
+                        self.log(ChatMessage::no_sender(format!("Could not load the {} \"{}.ron\": {}", kind, path, e)).private().light_red());
+                    },
+                }
+            }
+            all_files.append(&mut files);
+        }
+        all_files
+    }
+
+    /// Where a file or folder inside the loaded campaign is.
+    pub fn campaign_path(&self, path: impl AsRef<Path>) -> PathBuf {
+        campaign::dir(&self.campaign).join(path)
     }
 
     /// Reads through all files in a directory, as well as all sub-directories. If the files are 
@@ -571,95 +1280,437 @@ impl DMAppData {
         }
     }
 
-    /// Stores the app's data to disk.
-    pub fn save(&mut self) {
-        if let Some((file, map)) = &self.loaded_map {
-            let _ = save_ron(map, "maps", file);
+    /// Older save files stored passwords in plaintext. This hashes any that are left and
+    /// immediately saves, so the plaintext doesn't stick around on disk.
+    fn hash_plaintext_passwords(&mut self) {
+        let mut count = 0;
+        for (user, password) in &mut self.known_users {
+            if !is_hashed(password) {
+                match hash_password(password) {
+                    Ok(hash) => {
+                        *password = hash;
+                        count += 1;
+                    },
+                    Err(e) => {
+                        self.logs.insert(0, ChatMessage::no_sender(format!("Could not hash the password of user \"{}\": {}", user, e)).private().red().to_log_entry());
+                    },
+                }
+            }
         }
-        let mut file: File;
-        if let Ok(f) = File::options().write(true).truncate(true).open("savedata.ron") {
-            file = f;
-        } else {
-            file = File::create("savedata.ron").expect("Failed to create file");
+        if count > 0 {
+            self.save();
+            self.log(ChatMessage::no_sender(format!("Hashed {} plaintext password(s) from an older save file.", count)).private().blue());
         }
-        let save_data = SaveData {
+    }
+
+    /// Replaces the saved parts of the app's data.
+    fn apply_save_data(&mut self, data: SaveData) {
+        self.known_users = data.known_users;
+        self.user_data = data.user_data;
+        self.parties = data.parties;
+        self.roles = data.roles;
+        for admin in data.admins {
+            self.roles.insert(admin, Role::CoDm);
+        }
+        self.banned = data.banned;
+        self.registration_closed = data.registration_closed;
+        self.campaign_name = data.campaign_name;
+    }
+
+    /// The parts of the app's data that are saved to disk.
+    fn save_data(&self) -> SaveData {
+        SaveData {
+            version: FileKind::Save.version(),
             known_users: self.known_users.clone(),
             user_data: self.user_data.clone(),
             parties: self.parties.clone(),
+            roles: self.roles.clone(),
+            admins: HashSet::new(),
+            banned: self.banned.clone(),
+            registration_closed: self.registration_closed,
+            campaign_name: self.campaign_name.clone(),
+        }
+    }
+
+    /// Fingerprints of each part of the data as it is now.
+    fn fingerprints(&self) -> Fingerprints {
+        Fingerprints {
+            users: storage::fingerprint(&(&self.known_users, &self.user_data, &self.roles, &self.banned, self.registration_closed, &self.campaign_name)),
+            parties: storage::fingerprint(&self.parties),
+            map: storage::fingerprint(&self.loaded_map),
+        }
+    }
+
+    /// Works out which parts of the data have changed since they were saved.
+    pub fn check_unsaved(&mut self) {
+        self.unsaved = self.fingerprints().changes_since(&self.saved);
+    }
+
+    /// Saves on the next autosave check, for after something that would hurt to lose.
+    pub fn request_autosave(&mut self) {
+        self.autosave_requested = true;
+    }
+
+    /// Whether it has been long enough since the last save to save again on its own.
+    pub fn autosave_due(&self) -> bool {
+        self.autosave_interval > 0 && self.last_save.elapsed() >= Duration::from_secs(self.autosave_interval)
+    }
+
+    /// The map was just loaded or unloaded, so it has nothing unsaved.
+    fn map_saved(&mut self) {
+        self.saved.map = storage::fingerprint(&self.loaded_map);
+        self.unsaved.map = false;
+    }
+
+    /// Stores the app's data to disk, and keeps a backup of it if one is due.
+    pub fn save(&mut self) {
+        if self.save_without_backup() && self.backup_count > 0 && self.backup_due() {
+            if let Err(e) = self.backup_now() {
+                self.log(ChatMessage::no_sender(format!("Saved, but could not update the backups: {}", e)).private().light_red());
+            }
+        }
+    }
+
+    /// Saves like [`DMAppData::save`], but leaves the backups alone. Returns whether it saved.
+    fn save_without_backup(&mut self) -> bool {
+        self.flush_chat();
+        if let Some((file, map)) = &self.loaded_map {
+            let result = match &mut self.store {
+                Some(store) => store.save_map(file, map),
+                None => Err(NO_STORE.to_owned()),
+            };
+            match result {
+                Ok(_) => self.map_saved(),
+                Err(e) => self.log(ChatMessage::no_sender(format!("Could not save the map \"{}\": {}", file, e)).private().red()),
+            }
+        }
+        let fingerprints = self.fingerprints();
+        let save_data = self.save_data();
+        let result = match &mut self.store {
+            Some(store) => store.save(&save_data, fingerprints.changes_since(&self.saved)),
+            None => Err(NO_STORE.to_owned()),
         };
-        let save_data_str = ron::to_string(&save_data).unwrap();
-        file.write_all(save_data_str.as_bytes()).unwrap();
+        if let Err(e) = result {
+            self.log(ChatMessage::no_sender(format!("Could not save: {}. The previous save is unchanged.", e)).private().red());
+            return false;
+        }
+        self.saved.users = fingerprints.users;
+        self.saved.parties = fingerprints.parties;
+        self.check_unsaved();
+        self.last_save = Instant::now();
         if let Ok(s) = std::fs::read_to_string("preferences.ron") {
             if let Ok(mut prefs) = ron::from_str::<AppPreferences>(&s) {
-                prefs.dm_window = Some(self.prefs.clone());
-                let _ = std::fs::write("preferences.ron", ron::to_string(&prefs).unwrap_or(s));
+                // a headless server has no window, and can't change the other settings
+                if !self.headless {
+                    prefs.dm_window = Some(self.prefs.clone());
+                    prefs.known_servers = self.temp_state.remote.known_servers.clone();
+                    prefs.dm_require_encryption = self.require_encryption;
+                    prefs.dm_last_campaign = Some(self.campaign.clone());
+                }
+                prefs.dm_backup_count = self.backup_count;
+                prefs.dm_autosave_interval = self.autosave_interval;
+                let _ = write_atomic("preferences.ron", ron::to_string(&prefs).unwrap_or(s).as_bytes());
+            }
+        }
+        true
+    }
+
+    /// Whether enough time has passed since the last backup for saving to make another.
+    fn backup_due(&self) -> bool {
+        match (&self.store, self.last_backup) {
+            (Some(store), Some(last)) => last.elapsed() >= store.backup_interval(),
+            _ => true,
+        }
+    }
+
+    /// Backs up what is saved, however long it has been since the last backup. Returns the name of
+    /// the backup holding it, or `None` if backups are turned off.
+    pub fn backup_now(&mut self) -> Result<Option<String>, String> {
+        if self.backup_count == 0 {
+            return Ok(None);
+        }
+        let dir = self.campaign_path(storage::BACKUP_DIR);
+        let result = match &mut self.store {
+            Some(store) => store.backup(&dir, self.backup_count),
+            None => Err(NO_STORE.to_owned()),
+        };
+        if result.is_ok() {
+            self.last_backup = Some(Instant::now());
+        }
+        result
+    }
+
+    /// Replaces the saved data with a backup's, after saving and backing up the current data so the
+    /// restore can be undone. Everyone connected is sent their data again, and anyone whose account
+    /// doesn't exist in the backup is disconnected. Returns the name of the backup holding the data
+    /// from before, or `None` if backups are turned off. Nothing is restored if that backup fails.
+    pub fn restore_backup(&mut self, backup: SaveData) -> Result<Option<String>, String> {
+        // backups are copies of what is saved, so the current data has to be saved first
+        if !self.save_without_backup() {
+            return Err("the current data could not be saved".to_owned());
+        }
+        let before = self.backup_now()?;
+        self.apply_save_data(backup);
+        self.hash_plaintext_passwords();
+        let connected: Vec<(String, SocketAddr)> = self.connected_users.iter().map(|(user, addr)| (user.clone(), *addr)).collect();
+        for (user, addr) in connected {
+            if self.known_users.contains_key(&user) {
+                if let Some(session) = self.sessions.get_mut(&user) {
+                    session.sent_characters.clear();
+                }
+                sync_user(self, &user, addr);
+            } else {
+                disconnect_user(self, &user);
+            }
+        }
+        // the restored data is already in a backup
+        self.save_without_backup();
+        Ok(before)
+    }
+
+    /// Saves the currently loaded map to disc and unloads it.
+    pub fn save_and_unload_map(&mut self) {
+        if let Some((file, map)) = self.loaded_map.take() {
+            if let Err(e) = self.save_map(&file, &map) {
+                self.log(ChatMessage::no_sender(format!("Could not save the map \"{}\": {}", file, e)).private().red());
+            }
+            self.map_saved();
+        }
+    }
+
+    /// Stores a map in the campaign, without loading it.
+    pub fn save_map(&mut self, id: &str, map: &Map) -> Result<(), String> {
+        match &mut self.store {
+            Some(store) => store.save_map(id, map),
+            None => Err(NO_STORE.to_owned()),
+        }
+    }
+
+    /// Loads one of the campaign's maps, replacing the loaded one without saving it.
+    pub fn load_map(&mut self, id: &str) {
+        let map = match &mut self.store {
+            Some(store) => store.load_map(id),
+            None => Err(NO_STORE.to_owned()),
+        };
+        match map {
+            Ok(map) => {
+                self.loaded_map = Some((id.to_owned(), map));
+                self.map_saved();
+            },
+            Err(e) => {
+                self.log(ChatMessage::no_sender(format!("Could not load the map \"{}\": {}", id, e)).private().red());
+            },
+        }
+    }
+
+    /// Moves the campaign into a different kind of store, along with its maps and chat history.
+    /// The old files are moved into the backup folder rather than deleted. Returns anything the DM
+    /// should know about how it went.
+    pub fn convert_store(&mut self, kind: StoreKind) -> Result<String, String> {
+        match &self.store {
+            Some(store) if store.kind() == kind => return Err(format!("The campaign is already stored in {}.", kind)),
+            Some(_) => {},
+            None => return Err(format!("Could not convert the campaign: {}.", NO_STORE)),
+        }
+        self.save();
+        if self.unsaved.any() {
+            return Err("Could not save, so the campaign was not converted.".to_owned());
+        }
+        let dir = campaign::dir(&self.campaign);
+        let mut old = match self.store.take() {
+            Some(store) => store,
+            None => return Err(format!("Could not convert the campaign: {}.", NO_STORE)),
+        };
+        let new = storage::create_store(&dir, kind).and_then(|mut new| {
+            let everything = UnsavedChanges { users: true, parties: true, map: true };
+            new.save(&self.save_data(), everything)?;
+            for (id, _) in old.list_maps() {
+                let map = old.load_map(&id).map_err(|e| format!("could not read the map \"{}\": {}", id, e))?;
+                new.save_map(&id, &map)?;
+            }
+            new.append_chat(&old.recent_chat(usize::MAX)?)?;
+            Ok(new)
+        });
+        let new = match new {
+            Ok(new) => new,
+            Err(e) => {
+                if kind == StoreKind::Sqlite {
+                    let _ = std::fs::remove_file(dir.join(storage::DATABASE_FILE));
+                }
+                self.store = Some(old);
+                return Err(format!("Could not convert the campaign, so it is still stored in {}: {}.", self.store.as_ref().map_or(kind, |store| store.kind()), e));
+            },
+        };
+        let backups = self.campaign_path(storage::BACKUP_DIR);
+        let date = chrono::Local::now().format("%Y-%m-%d--%H-%M-%S");
+        let mut msg = format!("The campaign is now stored in {}.", kind);
+        match kind {
+            StoreKind::Sqlite => {
+                // the database is used over any RON files, so these only have to be moved to keep things tidy
+                let moved = backups.join(format!("before-database-{}", date));
+                let result = std::fs::create_dir_all(&moved)
+                    .and_then(|_| std::fs::rename(dir.join(storage::SAVE_FILE), moved.join(storage::SAVE_FILE)))
+                    .and_then(|_| if dir.join(FileKind::Map.dir()).is_dir() { std::fs::rename(dir.join(FileKind::Map.dir()), moved.join(FileKind::Map.dir())) } else { Ok(()) });
+                match result {
+                    Ok(_) => msg.push_str(&format!(" The RON files were moved to {}.", moved.display())),
+                    Err(e) => msg.push_str(&format!(" Could not move the RON files out of the way, but they won't be used anymore: {}.", e)),
+                }
+            },
+            StoreKind::Ron => {
+                // the database has to go, or it would be used instead of the RON files next time
+                drop(old);
+                let moved = backups.join(format!("before-ron-{}.sqlite", date));
+                let result = std::fs::create_dir_all(&backups).and_then(|_| std::fs::rename(dir.join(storage::DATABASE_FILE), &moved));
+                if let Err(e) = result {
+                    self.store = storage::open_store(&dir).ok();
+                    return Err(format!("Could not move the database out of the way, so the campaign is still stored in it: {}.", e));
+                }
+                msg.push_str(&format!(" The database was moved to {}, and the chat history stays in it since RON files don't keep one.", moved.display()));
+            },
+        }
+        self.store = Some(new);
+        Ok(msg)
+    }
+
+    /// Applies a closure to every active connection that has finished its handshake.
+    pub fn foreach_streams<F>(&mut self, mut func: F) 
+        where F: FnMut(&mut Connection) -> std::io::Result<()> {
+        for connection in self.streams.iter_mut() {
+            if connection.handshake_done {
+                let _ = func(connection);
+            }
+        }
+    }
+
+    /// Sends a packet to all connected users.
+    pub fn send_to_all_players(&mut self, packet: ClientBoundPacket) {
+        if let ClientBoundPacket::ChatMessage(msg) = &packet {
+            for session in self.sessions.values_mut() {
+                session.record_missed(msg);
+            }
+        }
+        if let Ok(frame) = encode_frame(&packet) {
+            let frame = frame.into();
+            self.foreach_streams(|connection| {
+                connection.send_frame(&frame)
+            });
+        }
+    }
+
+    /// Sends a packet to a user by their ip address. Use this if they do not have a username yet.
+    pub fn send_to_user_by_addr(&mut self, packet: ClientBoundPacket, user: SocketAddr) {
+        let packet = match packet {
+            ClientBoundPacket::UpdateCharacter(name, sheet) => {
+                match self.character_update(user, name, sheet) {
+                    Some(packet) => packet,
+                    None => return,
+                }
+            },
+            packet => packet,
+        };
+        for connection in &mut self.streams {
+            if connection.addr == user && connection.handshake_done {
+                let _ = connection.send(&packet);
+                return;
             }
         }
     }
 
-    /// Saves the currently loaded map to disc and unloads it.
-    pub fn save_and_unload_map(&mut self) {
-        if let Some((file, map)) = &self.loaded_map {
-            let _ = save_ron(map, "maps", file);
-            self.loaded_map = None;
-        }
+    /// Turns a character update into a patch with only what changed since the user was last sent
+    /// that character, or nothing at all if nothing did.
+    fn character_update(&mut self, user: SocketAddr, name: String, sheet: PlayerCharacter) -> Option<ClientBoundPacket> {
+        let session = match self.get_username_by_addr(user).and_then(|username| self.sessions.get_mut(&username)) {
+            Some(session) => session,
+            None => return Some(ClientBoundPacket::UpdateCharacter(name, sheet)),
+        };
+        let packet = match session.sent_characters.get(&name) {
+            Some(old) => {
+                let patch = CharacterPatch::diff(old, &sheet);
+                if patch.is_empty() {
+                    return None;
+                }
+                ClientBoundPacket::PatchCharacter(name.clone(), patch)
+            },
+            None => ClientBoundPacket::UpdateCharacter(name.clone(), sheet.clone()),
+        };
+        session.sent_characters.insert(name, sheet);
+        Some(packet)
     }
 
-    /// Applies a closure to every active tcp stream (connection).
-    pub fn foreach_streams<F>(&mut self, mut func: F) 
-        where F: FnMut(&mut TcpStream) -> std::io::Result<()> {
-        for (stream, _) in self.streams.iter_mut() {
-            match func(stream) {
-                Ok(_) => {},
-                Err(_) => {},
+    /// Sends a packet to a user by name.
+    pub fn send_to_user(&mut self, packet: ClientBoundPacket, user: String) {
+        if let Some(addr) = self.connected_users.get(&user).copied() {
+            self.send_to_user_by_addr(packet, addr);
+        } else if let ClientBoundPacket::ChatMessage(msg) = &packet {
+            if let Some(session) = self.sessions.get_mut(&user) {
+                session.record_missed(msg);
             }
         }
     }
 
-    /// Sends a packet to all connected users.
-    pub fn send_to_all_players(&mut self, packet: ClientBoundPacket) {
-        if let Ok(msg) = ron::to_string(&packet) {
-            self.foreach_streams(|stream| {
-                stream.write_all(msg.as_bytes())?;
-                stream.write_all(&[255])?;
-                stream.flush()?;
-                Ok(())
-            });      
-        }
+    /// Adds a character to a party and lets everyone know. Returns false if either doesn't exist.
+    pub fn join_party(&mut self, party_name: &str, user: &str, name: &str) -> bool {
+        let party = match self.parties.get_mut(party_name) {
+            Some(party) => party,
+            None => return false,
+        };
+        let sheet = match self.user_data.get_mut(user).and_then(|user_data| user_data.characters.get_mut(name)) {
+            Some(sheet) => sheet,
+            None => return false,
+        };
+        party.members.insert((user.to_owned(), name.to_owned()));
+        sheet.party = Some(party_name.to_owned());
+        let sheet = sheet.clone();
+        let color = party.color;
+        self.send_to_user(ClientBoundPacket::UpdateCharacter(name.to_owned(), sheet), user.to_owned());
+        self.log(ChatMessage::no_sender(format!("{} has joined {}!", name, party_name)).parties().color(color));
+        self.send_to_all_players(ClientBoundPacket::UpdateParties(self.parties.clone()));
+        true
     }
 
-    /// Sends a packet to a user by their ip address. Use this if they do not have a username yet.
-    pub fn send_to_user_by_addr(&mut self, packet: ClientBoundPacket, user: SocketAddr) {
-        if let Ok(msg) = ron::to_string(&packet) {
-            for (stream, _) in &mut self.streams {
-                if let Ok(addr) = stream.peer_addr() {
-                    if addr == user {
-                        let _ = stream.write_all(msg.as_bytes());
-                        let _ = stream.write_all(&[255]);
-                        let _ = stream.flush();
-                        return;
-                    }
-                }
+    /// The campaign name to show players, even if the DM hasn't given it one.
+    pub fn campaign_display_name(&self) -> String {
+        if self.campaign_name.trim().is_empty() {
+            if self.campaign == DEFAULT_CAMPAIGN {
+                "Unnamed campaign".to_owned()
+            } else {
+                self.campaign.clone()
             }
+        } else {
+            self.campaign_name.trim().to_owned()
         }
     }
 
-    /// Sends a packet to a user by name.
-    pub fn send_to_user(&mut self, packet: ClientBoundPacket, user: String) {
-        if let Ok(msg) = ron::to_string(&packet) {
-            if let Some(addr) = self.connected_users.get(&user) {
-                for (stream, _) in &mut self.streams {
-                    if let Ok(a) = stream.peer_addr() {
-                        if a == *addr {
-                            let _ = stream.write_all(msg.as_bytes());
-                            let _ = stream.write_all(&[255]);
-                            let _ = stream.flush();
-                            return;
-                        }
-                    }
-                }
+    /// The role of a user, which is [`Role::Player`] unless it has been changed.
+    pub fn role(&self, username: &str) -> Role {
+        self.roles.get(username).copied().unwrap_or(Role::Player)
+    }
+
+    /// Sends a user the response to their request and forgets it. Returns false if there is no
+    /// request at that index.
+    pub fn respond_to_request(&mut self, index: usize, approved: bool) -> bool {
+        if index >= self.temp_state.requests.len() {
+            return false;
+        }
+        let (user, request) = self.temp_state.requests.remove(index);
+        self.send_to_user(ClientBoundPacket::RespondToRequest(request, approved), user.clone());
+        if approved && request == Request::GenerateCharacters {
+            self.generate_characters(&user);
+        }
+        true
+    }
+
+    /// Rolls new characters for a user to pick from, unless they still have some left, and sends
+    /// them the choices.
+    pub fn generate_characters(&mut self, username: &str) {
+        let user_data = self.user_data.entry(username.to_owned()).or_insert_with(UserData::new);
+        if user_data.pending_characters.is_empty() {
+            for _ in 0..NEW_CHARACTER_CHOICES {
+                user_data.pending_characters.push(PlayerCharacter::random(&mut self.rng));
             }
         }
+        let pending = user_data.pending_characters.clone();
+        self.send_to_user(ClientBoundPacket::PendingCharacters(pending), username.to_owned());
     }
 
     /// Gets the first connected user with the specified ip address, or None.
@@ -674,9 +1725,18 @@ impl DMAppData {
 
     /// Sends a chat message to all users.
     pub fn log(&mut self, msg: ChatMessage) {
-        self.logs.insert(0, msg.to_layout_job());
+        self.logs.insert(0, msg.to_log_entry());
+        if self.store.is_some() {
+            self.pending_chat.push(msg.clone());
+        }
         if !msg.flags.private {
             self.send_to_all_players(ClientBoundPacket::ChatMessage(msg));
+        } else {
+            // co-DMs see everything the DM would
+            let co_dms: Vec<String> = self.roles.iter().filter(|(user, role)| **role == Role::CoDm && self.connected_users.contains_key(*user)).map(|(user, _)| user.clone()).collect();
+            for co_dm in co_dms {
+                self.send_to_user(ClientBoundPacket::ChatMessage(msg.clone()), co_dm);
+            }
         }
     }
 
@@ -887,9 +1947,47 @@ impl DMApp {
                     ui.menu_button("Port", |ui| {
                         ui.add(egui::DragValue::new(&mut data.host_port));
                     });
+                    ui.menu_button("Campaign name", |ui| {
+                        ui.add(egui::TextEdit::singleline(&mut data.campaign_name).hint_text("Shown to players on the LAN"));
+                    });
                 } else {
                     ui.label("Hosting...");
                 }
+                ui.checkbox(&mut data.require_encryption, "Require encryption")
+                    .on_hover_text("Refuse players who connect without encryption");
+                let mut registration_open = !data.registration_closed;
+                if ui.checkbox(&mut registration_open, "Allow new accounts")
+                    .on_hover_text("Let anyone who can reach the server create an account")
+                    .changed() {
+                    data.registration_closed = !registration_open;
+                }
+                if ui.button("Remote server").on_hover_text("Run a headless server from here").clicked() {
+                    Self::open_or_focus(tree, DMTab::RemoteServer);
+                    ui.close_menu();
+                }
+                if let Some(fingerprint) = &data.tls_fingerprint {
+                    if ui.button("Copy certificate fingerprint").on_hover_text(fingerprint).clicked() {
+                        ctx.output_mut(|output| output.copied_text = fingerprint.clone());
+                        ui.close_menu();
+                    }
+                }
+            });
+            ui.menu_button("Save", |ui| {
+                if ui.button("Save now").clicked() {
+                    data.save();
+                    ui.close_menu();
+                }
+                ui.horizontal(|ui| {
+                    ui.label("Autosave every");
+                    let mut minutes = data.autosave_interval / 60;
+                    if ui.add(egui::DragValue::new(&mut minutes).clamp_range(0..=120).suffix(" min")).changed() {
+                        data.autosave_interval = minutes * 60;
+                    }
+                }).response.on_hover_text("Also saves soon after combat ends, a character levels up or a new character is made. 0 only saves then.");
+                ui.horizontal(|ui| {
+                    ui.label("Backups to keep:");
+                    ui.add(egui::DragValue::new(&mut data.backup_count).clamp_range(0..=100));
+                }).response.on_hover_text("A backup is made every time the game is saved, or at most every 30 minutes for a database. Use /restore to load one.");
             });
             ui.menu_button("View", |ui| {
                 if ui.button("Chat").clicked() {
@@ -1000,8 +2098,20 @@ impl DMApp {
             }
             ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
                 if ui.add(egui::Button::new(format!("{}", ep::SIGN_OUT)).small().frame(false)).clicked() {
+                    // so the warning about unsaved changes is up to date
+                    data.check_unsaved();
                     data.temp_state.window_states.insert("exit_are_you_sure".to_owned(), true);
                 }
+                let saved_ago = format_duration(data.last_save.elapsed());
+                if data.unsaved.any() {
+                    let hover = format!("Unsaved changes to {}. Last saved {} ago. Click to save now.", data.unsaved.describe(), saved_ago);
+                    if ui.add(egui::Button::new(RichText::new(format!("{}*", ep::FLOPPY_DISK)).color(Color32::GOLD)).small().frame(false)).on_hover_text(hover).clicked() {
+                        data.save();
+                    }
+                } else {
+                    ui.label(RichText::new(ep::FLOPPY_DISK).weak())
+                        .on_hover_text(format!("Everything is saved. Last saved {} ago.", saved_ago));
+                }
             });
         });
     }
@@ -1016,7 +2126,12 @@ impl DMApp {
                     if ui.button(RichText::new("Save and exit").size(14.0)).clicked() {
                         frame.close();
                     }
-                    if ui.button(RichText::new("Exit without saving").size(14.0).color(ui.visuals().error_fg_color)).on_hover_text("Are you sure? Everything since the last save will be lost!").clicked() {
+                    let hover = if data.unsaved.any() {
+                        format!("Are you sure? Unsaved changes to {} will be lost!", data.unsaved.describe())
+                    } else {
+                        "Everything is already saved.".to_owned()
+                    };
+                    if ui.button(RichText::new("Exit without saving").size(14.0).color(ui.visuals().error_fg_color)).on_hover_text(hover).clicked() {
                         data.temp_state.exit_without_saving = true;
                         frame.close();
                     }
@@ -1036,23 +2151,20 @@ impl DMApp {
                 .resizable(false)
                 .vscroll(true)
                 .show(ctx, |ui| {
-                    let mut packets = Vec::new();
-                    data.temp_state.requests.retain(|(user, request)| {
+                    let mut response = None;
+                    for (i, (user, request)) in data.temp_state.requests.iter().enumerate() {
                         ui.horizontal(|ui| {
                             ui.label(format!("User \"{}\" requests to: {}", user, request));
                             if ui.small_button(RichText::new(format!("{}", egui_phosphor::CHECK)).color(Color32::GREEN)).clicked() {
-                                packets.push((ClientBoundPacket::RespondToRequest(*request, true), user.clone()));
-                                return false;
+                                response = Some((i, true));
                             }
                             if ui.small_button(RichText::new(format!("{}", egui_phosphor::X)).color(Color32::RED)).clicked() {
-                                packets.push((ClientBoundPacket::RespondToRequest(*request, false), user.clone()));
-                                return false;
+                                response = Some((i, false));
                             }
-                            true
-                        }).inner
-                    });
-                    for (packet, user) in packets {
-                        data.send_to_user(packet, user);
+                        });
+                    }
+                    if let Some((i, approved)) = response {
+                        data.respond_to_request(i, approved);
                     }
                 });
         }
@@ -1076,7 +2188,7 @@ pub fn chat(ui: &mut Ui, data: &mut DMAppData) {
         }
         for (i, log) in data.logs.iter().enumerate() {
             ui.with_layout(egui::Layout::top_down(egui::Align::Min), |ui| {
-                ui.label(log.clone());
+                chat_log_entry(ui, log);
                 if i == 0 {
                     ui.separator();
                 }
@@ -1090,13 +2202,7 @@ pub fn chat(ui: &mut Ui, data: &mut DMAppData) {
 
 pub fn parse_command(data: &mut DMAppData, mut command: String) {
     command.remove(0);
-    let mut in_quotes = false;
-    let mut tree = command.split(|c: char| {
-        if c == '\'' || c == '\"' {
-            in_quotes = !in_quotes;
-        }
-        !in_quotes && c.is_whitespace()
-    }).map(|s| s.trim_matches(|c| c == '\'' || c == '\"')).into_iter();
+    let mut tree = command_tokens(&command);
     if let Some(token) = tree.next() {
         match token {
             "user" => {
@@ -1105,21 +2211,92 @@ pub fn parse_command(data: &mut DMAppData, mut command: String) {
                         if let Some(token) = tree.next() {
                             match token {
                                 "kick" => {
-                                    if let Some(addr) = data.connected_users.get(username) {
-                                        let mut msg = "Error".to_owned();
-                                        for (stream, _) in &mut data.streams {
-                                            if let Ok(a) = stream.peer_addr() {
-                                                if *addr == a {
-                                                    msg = format!("Kicking user \"{}\".", username);
-                                                    let _ = stream.shutdown(std::net::Shutdown::Both);
-                                                }
-                                            }
-                                        }
-                                        data.log(ChatMessage::no_sender(msg).red());
+                                    if disconnect_user(data, username) {
+                                        data.log(ChatMessage::no_sender(format!("Kicking user \"{}\".", username)).red());
                                     } else {
                                         data.log(ChatMessage::no_sender(format!("The user \"{}\" is not connected.", username)).private().light_red());
                                     }
                                 },
+                                "ban" => {
+                                    data.banned.insert(username.to_owned());
+                                    disconnect_user(data, username);
+                                    data.log(ChatMessage::no_sender(format!("User \"{}\" has been banned.", username)).red());
+                                },
+                                "unban" => {
+                                    if data.banned.remove(username) {
+                                        data.log(ChatMessage::no_sender(format!("User \"{}\" is no longer banned.", username)).private().green());
+                                    } else {
+                                        data.log(ChatMessage::no_sender(format!("The user \"{}\" is not banned.", username)).private().light_red());
+                                    }
+                                },
+                                "rename" => {
+                                    match tree.next().map(|name| name.trim()) {
+                                        Some(new_name) if !new_name.is_empty() => {
+                                            if data.known_users.contains_key(new_name) {
+                                                data.log(ChatMessage::no_sender(format!("There is already a user called \"{}\".", new_name)).private().light_red());
+                                            } else if let Err(e) = validate_username(new_name) {
+                                                data.log(ChatMessage::no_sender(e.to_string()).private().light_red());
+                                            } else if in_fight(data, username) {
+                                                data.log(ChatMessage::no_sender(format!("The user \"{}\" can't be renamed during a fight they are in.", username)).private().light_red());
+                                            } else {
+                                                rename_user(data, username, new_name);
+                                                data.log(ChatMessage::no_sender(format!("User \"{}\" is now called \"{}\".", username, new_name)).private().green());
+                                            }
+                                        },
+                                        _ => {
+                                            data.log(ChatMessage::no_sender("You must specify a valid new name.").private().light_red());
+                                        },
+                                    }
+                                },
+                                "resetpw" => {
+                                    match tree.next() {
+                                        Some(password) if !password.is_empty() => {
+                                            match hash_password(password) {
+                                                Ok(hash) => {
+                                                    data.known_users.insert(username.to_owned(), hash);
+                                                    // remembered logins shouldn't outlive the old password
+                                                    if let Some(user_data) = data.user_data.get_mut(username) {
+                                                        user_data.login_tokens.clear();
+                                                    }
+                                                    disconnect_user(data, username);
+                                                    data.log(ChatMessage::no_sender(format!("The password of user \"{}\" has been reset.", username)).private().green());
+                                                },
+                                                Err(e) => {
+                                                    data.log(ChatMessage::no_sender(format!("Could not reset the password of user \"{}\": {}", username, e)).private().red());
+                                                },
+                                            }
+                                        },
+                                        _ => {
+                                            data.log(ChatMessage::no_sender("You must specify a new password.").private().light_red());
+                                        },
+                                    }
+                                },
+                                "delete" => {
+                                    if in_fight(data, username) {
+                                        data.log(ChatMessage::no_sender(format!("The user \"{}\" can't be deleted during a fight they are in.", username)).private().light_red());
+                                    } else {
+                                        disconnect_user(data, username);
+                                        data.known_users.remove(username);
+                                        data.user_data.remove(username);
+                                        data.roles.remove(username);
+                                        data.banned.remove(username);
+                                        data.temp_state.requests.retain(|(user, _)| user != username);
+                                        for party in data.parties.values_mut() {
+                                            party.members.retain(|(user, _)| user != username);
+                                        }
+                                        data.log(ChatMessage::no_sender(format!("User \"{}\" and all of their characters have been deleted.", username)).private().red());
+                                    }
+                                },
+                                "role" => {
+                                    match tree.next().map(Role::parse) {
+                                        Some(Some(role)) => {
+                                            set_role(data, username, role);
+                                        },
+                                        _ => {
+                                            data.log(ChatMessage::no_sender("You must specify a role: spectator, player or codm.").private().light_red());
+                                        },
+                                    }
+                                },
                                 t => {
                                     unknown_command(data, t);
                                 },
@@ -1155,6 +2332,196 @@ pub fn parse_command(data: &mut DMAppData, mut command: String) {
             "save" => {
                 data.save();
             },
+            "autosave" => {
+                match tree.next().map(|n| n.parse::<u64>()) {
+                    Some(Ok(minutes)) if minutes.checked_mul(60).is_none() => {
+                        data.log(ChatMessage::no_sender("That interval is too long.").private().light_red());
+                    },
+                    Some(Ok(minutes)) => {
+                        data.autosave_interval = minutes * 60;
+                        if minutes == 0 {
+                            data.log(ChatMessage::no_sender("Only autosaving after important events from now on.").private().green());
+                        } else {
+                            data.log(ChatMessage::no_sender(format!("Autosaving every {} minute(s) from now on.", minutes)).private().green());
+                        }
+                    },
+                    Some(Err(_)) => {
+                        data.log(ChatMessage::no_sender("Usage: /autosave <minutes>").private().light_red());
+                    },
+                    None => {
+                        data.check_unsaved();
+                        let every = if data.autosave_interval == 0 { "only after important events".to_owned() } else { format!("every {}", format_duration(Duration::from_secs(data.autosave_interval))) };
+                        let status = if data.unsaved.any() { format!("There are unsaved changes to {}.", data.unsaved.describe()) } else { "Everything is saved.".to_owned() };
+                        data.log(ChatMessage::no_sender(format!("{} Last saved {} ago, autosaving {}.", status, format_duration(data.last_save.elapsed()), every)).private());
+                    },
+                }
+            },
+            "backups" => {
+                match (tree.next(), tree.next().map(|n| n.parse::<usize>())) {
+                    (Some("keep"), Some(Ok(count))) => {
+                        data.backup_count = count;
+                        data.log(ChatMessage::no_sender(format!("Keeping the {} newest backups from now on.", count)).private().green());
+                    },
+                    (None, _) => {
+                        let backups = storage::list_backups(&data.campaign_path(storage::BACKUP_DIR));
+                        if backups.is_empty() {
+                            data.log(ChatMessage::no_sender("There are no backups yet. One is made every time the game is saved, or at most every 30 minutes for a database.").private());
+                        } else {
+                            for name in backups.iter().rev() {
+                                data.log(ChatMessage::no_sender(format!("- {}", name)).private());
+                            }
+                            data.log(ChatMessage::no_sender(format!("Backups, newest first (keeping {}):", data.backup_count)).private());
+                        }
+                    },
+                    _ => {
+                        data.log(ChatMessage::no_sender("Usage: /backups or /backups keep <count>").private().light_red());
+                    },
+                }
+            },
+            "restore" => {
+                match tree.next() {
+                    Some(name) => {
+                        let backup = storage::read_backup(&data.campaign_path(storage::BACKUP_DIR), name);
+                        match (backup, tree.next()) {
+                            (Ok(backup), Some("confirm")) => {
+                                match data.restore_backup(backup) {
+                                    Ok(Some(before)) => {
+                                        data.log(ChatMessage::no_sender(format!("Restored {}. The data from before is in the backup {}, if you want it back.", name, before)).private().green());
+                                    },
+                                    Ok(None) => {
+                                        data.log(ChatMessage::no_sender(format!("Restored {}. Backups are turned off, so the data from before was not kept.", name)).private().green());
+                                    },
+                                    Err(e) => {
+                                        data.log(ChatMessage::no_sender(format!("Could not back up the current data, so nothing was restored: {}", e)).private().light_red());
+                                    },
+                                }
+                            },
+                            (Ok(backup), _) => {
+                                let changes = data.save_data().describe_changes(&backup);
+                                if changes.is_empty() {
+                                    data.log(ChatMessage::no_sender(format!("{} is the same as the current data.", name)).private());
+                                } else {
+                                    data.log(ChatMessage::no_sender(format!("Run /restore {} confirm to restore it. The current data will be backed up first.", name)).private().strong());
+                                    for change in changes.iter().rev() {
+                                        data.log(ChatMessage::no_sender(change).private());
+                                    }
+                                    data.log(ChatMessage::no_sender(format!("Restoring {} would change:", name)).private());
+                                }
+                            },
+                            (Err(e), _) => {
+                                data.log(ChatMessage::no_sender(format!("Could not read the backup \"{}\": {}. Run /backups to see them all.", name, e)).private().light_red());
+                            },
+                        }
+                    },
+                    None => {
+                        data.log(ChatMessage::no_sender("You must specify a backup. Run /backups to see them all.").private().light_red());
+                    },
+                }
+            },
+            "storage" => {
+                match (tree.next(), tree.next()) {
+                    (Some("convert"), Some(kind)) => {
+                        let kind = match kind.to_lowercase().as_str() {
+                            "ron" => Some(StoreKind::Ron),
+                            "sqlite" | "database" => Some(StoreKind::Sqlite),
+                            _ => None,
+                        };
+                        match kind.map(|kind| data.convert_store(kind)) {
+                            Some(Ok(msg)) => data.log(ChatMessage::no_sender(msg).private().green()),
+                            Some(Err(e)) => data.log(ChatMessage::no_sender(e).private().light_red()),
+                            None => data.log(ChatMessage::no_sender("Usage: /storage convert <ron/sqlite>").private().light_red()),
+                        }
+                    },
+                    (None, _) => {
+                        match data.store.as_ref().map(|store| store.kind()) {
+                            Some(kind) => data.log(ChatMessage::no_sender(format!("The campaign \"{}\" is stored in {}.", data.campaign, kind)).private()),
+                            None => data.log(ChatMessage::no_sender(format!("The campaign \"{}\" isn't stored anywhere, since {}.", data.campaign, NO_STORE)).private().light_red()),
+                        }
+                    },
+                    _ => {
+                        data.log(ChatMessage::no_sender("Usage: /storage or /storage convert <ron/sqlite>").private().light_red());
+                    },
+                }
+            },
+            "role" => {
+                match tree.next() {
+                    None => {
+                        let mut roles: Vec<(String, Role)> = data.roles.iter().map(|(user, role)| (user.clone(), *role)).collect();
+                        roles.sort();
+                        for (user, role) in roles {
+                            data.log(ChatMessage::no_sender(format!("- {}: {}", user, role)).private());
+                        }
+                        data.log(ChatMessage::no_sender("List of all users who aren't players:").private());
+                    },
+                    Some(_) => {
+                        data.log(ChatMessage::no_sender("/role only lists roles. Change one with /user <user> role <spectator/player/codm>.").private().light_red());
+                    },
+                }
+            },
+            "campaign" => {
+                match tree.next() {
+                    Some(name) => {
+                        data.campaign_name = name.trim().to_owned();
+                        data.log(ChatMessage::no_sender(format!("The campaign is now called \"{}\".", data.campaign_display_name())).private().green());
+                    },
+                    None => {
+                        data.log(ChatMessage::no_sender(format!("The campaign \"{}\" is called \"{}\" on the LAN. Usage: /campaign <name>", data.campaign, data.campaign_display_name())).private());
+                    },
+                }
+            },
+            "registration" => {
+                match tree.next() {
+                    Some("open") => {
+                        data.registration_closed = false;
+                        data.log(ChatMessage::no_sender("Anyone can create an account now.").private().green());
+                    },
+                    Some("close") => {
+                        data.registration_closed = true;
+                        data.log(ChatMessage::no_sender("No new accounts can be created now.").private().green());
+                    },
+                    _ => {
+                        let state = if data.registration_closed { "closed" } else { "open" };
+                        data.log(ChatMessage::no_sender(format!("Registration is {}. Usage: /registration <open/close>", state)).private());
+                    },
+                }
+            },
+            "requests" => {
+                match (tree.next(), tree.next().map(|i| i.parse::<usize>())) {
+                    (None, _) => {
+                        if data.temp_state.requests.is_empty() {
+                            data.log(ChatMessage::no_sender("There are no pending requests.").private());
+                        } else {
+                            for (i, (user, request)) in data.temp_state.requests.clone().iter().enumerate().rev() {
+                                data.log(ChatMessage::no_sender(format!("{}: User \"{}\" requests to: {}", i + 1, user, request)).private());
+                            }
+                            data.log(ChatMessage::no_sender("List of all pending requests:").private());
+                        }
+                    },
+                    (Some(token @ ("approve" | "deny")), Some(Ok(i))) if i > 0 => {
+                        if data.respond_to_request(i - 1, token == "approve") {
+                            data.log(ChatMessage::no_sender(format!("Request {} has been {}.", i, if token == "approve" { "approved" } else { "denied" })).private().green());
+                        } else {
+                            data.log(ChatMessage::no_sender(format!("There is no request {}.", i)).private().light_red());
+                        }
+                    },
+                    _ => {
+                        data.log(ChatMessage::no_sender("Usage: /requests, /requests approve <number> or /requests deny <number>").private().light_red());
+                    },
+                }
+            },
+            "seed" => {
+                if let Some(token) = tree.next() {
+                    if let Ok(seed) = token.parse::<u64>() {
+                        data.rng.reseed(seed);
+                        data.log(ChatMessage::no_sender(format!("Reseeded dice with seed {}.", seed)).private().green());
+                    } else {
+                        data.log(ChatMessage::no_sender(format!("The token \"{}\" could not be interpreted as a seed (a whole number).", token)).private().light_red());
+                    }
+                } else {
+                    let seed = data.rng.seed();
+                    data.log(ChatMessage::no_sender(format!("Current dice seed: {}", seed)).private());
+                }
+            },
             "load" => {
                 data.load();
             },
@@ -1165,8 +2532,12 @@ pub fn parse_command(data: &mut DMAppData, mut command: String) {
                             if let Some(sheet) = user_data.characters.get_mut(name) {
                                 if let Some(token) = tree.next() {
                                     if let Ok(amount) = token.parse::<u32>() {
-                                        sheet.add_xp(amount);
+                                        let level = sheet.level;
+                                        sheet.add_xp(amount, &mut data.rng);
                                         let sheet = sheet.clone();
+                                        if sheet.level > level {
+                                            data.request_autosave();
+                                        }
                                         data.send_to_user(ClientBoundPacket::UpdateCharacter(name.to_owned(), sheet), user.to_owned());
                                         data.log(ChatMessage::no_sender("Added XP").private().green());
                                     } else {
@@ -1206,12 +2577,21 @@ pub fn parse_command(data: &mut DMAppData, mut command: String) {
                         },
                     }
                     if let Some(s) = s {
-                        match DiceRoll::from_notation(s) {
-                            Ok(roll) => {
+                        let character = match (tree.next(), tree.next()) {
+                            (Some(user), Some(name)) => Some((user, name)),
+                            (Some(_), None) => {
+                                data.log(ChatMessage::no_sender("You must specify a character as well as a user.").private().light_red());
+                                return;
+                            },
+                            _ => None,
+                        };
+                        match roll_expression(data, s, character) {
+                            Ok(result) => {
+                                let msg = ChatMessage::server(result.to_string()).dice_roll().with_rolls(result.rolls);
                                 if public {
-                                    data.log(ChatMessage::server(format!("{}", roll.roll())).dice_roll());
+                                    data.log(msg);
                                 } else {
-                                    data.log(ChatMessage::server(format!("{}", roll.roll())).private().dice_roll());
+                                    data.log(msg.private());
                                 }
                             },
                             Err(e) => {
@@ -1219,10 +2599,41 @@ pub fn parse_command(data: &mut DMAppData, mut command: String) {
                             },
                         }
                     } else {
-                        data.log(ChatMessage::no_sender("You must enter dice notation. Run /help roll for more info.").private().light_red());
+                        data.log(ChatMessage::no_sender("You must enter a dice expression. Run /help roll for more info.").private().light_red());
+                    }
+                } else {
+                    data.log(ChatMessage::no_sender("You must enter a dice expression. Run /help roll for more info.").private().light_red());
+                }
+            },
+            "odds" => {
+                if let Some(s) = tree.next() {
+                    let target = match tree.next().map(|t| t.parse::<i32>()) {
+                        Some(Ok(target)) => Some(target),
+                        Some(Err(_)) => {
+                            data.log(ChatMessage::no_sender("The target must be a whole number.").private().light_red());
+                            return;
+                        },
+                        None => None,
+                    };
+                    match DiceExpr::parse(s).and_then(|expr| Ok((expr.distribution(&|_| None)?, expr))) {
+                        Ok((dist, expr)) => {
+                            let mut msg = format!("Odds for {}:", expr);
+                            if let DiceExpr::Compare(..) = expr {
+                                msg.push_str(&format!("\nChance of success: {:.2}%", dist.at_least(1) * 100.0));
+                            } else {
+                                msg.push_str(&format!("\nMean: {:.2}, median: {}, range: {} to {}", dist.mean(), dist.median(), dist.min(), dist.max()));
+                                if let Some(target) = target {
+                                    msg.push_str(&format!("\nChance of {} or higher: {:.2}%", target, dist.at_least(target) * 100.0));
+                                }
+                            }
+                            data.log(ChatMessage::no_sender(msg).private().dice_roll());
+                        },
+                        Err(e) => {
+                            data.log(ChatMessage::no_sender(format!("Error: {}", e)).private().light_red());
+                        },
                     }
                 } else {
-                    data.log(ChatMessage::no_sender("You must enter dice notation. Run /help roll for more info.").private().light_red());
+                    data.log(ChatMessage::no_sender("You must enter a dice expression. Run /help odds for more info.").private().light_red());
                 }
             },
             "say" => {
@@ -1339,20 +2750,70 @@ pub fn parse_command(data: &mut DMAppData, mut command: String) {
             "help" => {
                 if let Some(token) = tree.next() {
                     match token {
+                        "odds" => {
+                            data.log(ChatMessage::no_sender("Examples: /odds 1d20+3 14, /odds \"1d20+3 >= 14\", /odds 4d6kh3").private());
+                            data.log(ChatMessage::no_sender("Calculates the exact odds of a dice expression (see /help roll) without rolling it: the mean, median and range, and the chance of rolling <target> or higher if given. For comparisons, the chance of success.").private());
+                            data.log(ChatMessage::no_sender("/odds <expression> <target>").private().strong());
+                        },
+                        "role" => {
+                            data.log(ChatMessage::no_sender("Co-DMs can log in to a headless server from the DM app (Network > Remote server), run commands there (except on other co-DMs' accounts and roles), approve requests and see all private messages. The commands they run are kept in the chat history of a campaign stored in a database. Players can only manage their own characters, and spectators can only watch public chat and combat.").private());
+                            data.log(ChatMessage::no_sender("Lists everyone who isn't a player. Change a user's role with /user <user> role <spectator/player/codm>.").private());
+                            data.log(ChatMessage::no_sender("/role").private().strong());
+                        },
+                        "user" => {
+                            data.log(ChatMessage::no_sender("Renaming or deleting a user isn't possible while they are in a fight. Deleting a user deletes all of their characters too.").private());
+                            data.log(ChatMessage::no_sender("Kicks a user, bans or unbans them, renames them, resets their password, deletes their account, or changes their role (see /help role).").private());
+                            data.log(ChatMessage::no_sender("/user <user> <kick/ban/unban/rename/resetpw/delete/role> <new name/password/role>").private().strong());
+                        },
+                        "campaign" => {
+                            data.log(ChatMessage::no_sender("Shows or changes the campaign name, which players see when looking for games on the LAN. Names containing spaces must be wrapped in \"quotes\".").private());
+                            data.log(ChatMessage::no_sender("/campaign <name>").private().strong());
+                        },
+                        "autosave" => {
+                            data.log(ChatMessage::no_sender("The game also saves soon after combat ends, a character levels up or a new character is made. An interval of 0 only saves then. Without an interval, shows what hasn't been saved yet.").private());
+                            data.log(ChatMessage::no_sender("Changes how often the game saves on its own.").private());
+                            data.log(ChatMessage::no_sender("/autosave <minutes>").private().strong());
+                        },
+                        "backups" => {
+                            data.log(ChatMessage::no_sender("A backup is made every time the game is saved, unless nothing changed. A campaign stored in a database is copied at most every 30 minutes instead. Once there are more than <count>, the oldest are deleted. A count of 0 turns backups off.").private());
+                            data.log(ChatMessage::no_sender("Lists the backups of the save file, or changes how many are kept.").private());
+                            data.log(ChatMessage::no_sender("/backups keep <count>").private().strong());
+                        },
+                        "restore" => {
+                            data.log(ChatMessage::no_sender("Without confirm, only shows what would change. The current data is backed up before it is replaced, so a restore can be undone by restoring that backup.").private());
+                            data.log(ChatMessage::no_sender("Replaces the saved data with a backup (see /backups).").private());
+                            data.log(ChatMessage::no_sender("/restore <backup> confirm").private().strong());
+                        },
+                        "storage" => {
+                            data.log(ChatMessage::no_sender("A database only writes what changed when saving, and keeps the chat history. RON files are easier to edit by hand. Converting saves first, copies everything over and moves the old files into the backups folder. RON files don't keep the chat history, so it stays in the old database.").private());
+                            data.log(ChatMessage::no_sender("Shows whether the campaign is stored in RON files or an SQLite database, or moves it to the other one.").private());
+                            data.log(ChatMessage::no_sender("/storage convert <ron/sqlite>").private().strong());
+                        },
+                        "registration" => {
+                            data.log(ChatMessage::no_sender("Opens or closes registration. While it is closed, nobody can create a new account.").private());
+                            data.log(ChatMessage::no_sender("/registration <open/close>").private().strong());
+                        },
+                        "requests" => {
+                            data.log(ChatMessage::no_sender("Lists the requests players are waiting on, or approves or denies one by its number in the list.").private());
+                            data.log(ChatMessage::no_sender("/requests <approve/deny> <number>").private().strong());
+                        },
+                        "seed" => {
+                            data.log(ChatMessage::no_sender("Every roll the DM makes (commands, combat, enemy HP, etc.) is drawn from the seeded dice RNG. Reseeding and then taking the same actions in the same order reproduces the same rolls.").private());
+                            data.log(ChatMessage::no_sender("Shows the current dice seed, or restarts the dice RNG from <seed> if given.").private());
+                            data.log(ChatMessage::no_sender("/seed <seed>").private().strong());
+                        },
                         "roll" => {
-                            data.log(ChatMessage::no_sender("min: Denotes a minimum value, inclusive or exclusive. A '>' symbol, optionally followed by a '=' symbol, then a value. Defaults to >=1.").private());
-                            data.log(ChatMessage::no_sender("X: How many dice to drop. Defaults to 1, and cannot be greater than N.").private());
-                            data.log(ChatMessage::no_sender("drop: An underscore ('_') followed by an 'h' or 'l'. Denotes to drop one or more dice, either highest or lowest.").private());
-                            data.log(ChatMessage::no_sender("A: The modifier value. Mandatory if <op> is present.").private());
-                            data.log(ChatMessage::no_sender("op: One of +, -, *, or /. Division is rounded normally by default, but append a 'u' or 'd' to the '/' to round up or down.").private());
-                            data.log(ChatMessage::no_sender("&: If present, apply the modifier to each die rather than the sum of all dice.").private());
-                            data.log(ChatMessage::no_sender("M: How many sides the dice have. Mandatory.").private());
-                            data.log(ChatMessage::no_sender("d: The literal letter \'d\'.").private());
-                            data.log(ChatMessage::no_sender("N: Number of dice to roll. Defaults to 1.").private());
-                            data.log(ChatMessage::no_sender("(Parentheses) denote optional values.").private());
-                            data.log(ChatMessage::no_sender("(N)dM(&)(<op>A)(<drop>(X))(<min>)").private().strong());
-                            data.log(ChatMessage::no_sender("Rolls dice using dice notation. <visibility> can be public/pub, private/priv, or absent (defaults private). <string> uses modified dice notation syntax:").private());
-                            data.log(ChatMessage::no_sender("/roll <visibility> <string>").private().strong());
+                            data.log(ChatMessage::no_sender("Single dice terms also accept the older notation: (N)dM(!)(rR)(&)(<op>A)(<drop/keep>)(s>=T)(<min>). '&' applies the modifier to each die, and <min> ('>' or '>=' then a value) sets a minimum result.").private());
+                            data.log(ChatMessage::no_sender("Expressions containing spaces must be wrapped in \"quotes\".").private());
+                            data.log(ChatMessage::no_sender("Names: STR, DEX, CON, INT, WIS and CHA give a character's attribute modifier. Requires <user> and <character>.").private());
+                            data.log(ChatMessage::no_sender("Comparisons: One of >, >=, <, <= or =. Only one is allowed, and the roll reports success or failure.").private());
+                            data.log(ChatMessage::no_sender("Operators: +, -, * (or x), and /. Division is rounded normally by default, but append a 'u' or 'd' to the '/' to round up or down. (Parentheses) group terms.").private());
+                            data.log(ChatMessage::no_sender("Success counting: s>=T (or s>T) directly after the dice counts how many dice meet the target instead of adding them. Without the 's', it is a comparison.").private());
+                            data.log(ChatMessage::no_sender("Dice modes: '!' explodes (rolls again on the highest face), rR rerolls R or lower, _hX/_lX drops and khX/klX keeps the X (default 1) highest or lowest dice.").private());
+                            data.log(ChatMessage::no_sender("Dice: (N)dM(!)(rR)(<drop/keep>)(s>=T). N dice with M sides, optionally followed by modes.").private());
+                            data.log(ChatMessage::no_sender("Examples: 2d6+1d4+3, 4d6kh3, (1d20+STR)>=14, 3d6/2, 1d20!, 2d6r1, 6d10s>=7").private().strong());
+                            data.log(ChatMessage::no_sender("Rolls a dice expression. <visibility> can be public/pub, private/priv, or absent (defaults private). <user> and <character> are optional.").private());
+                            data.log(ChatMessage::no_sender("/roll <visibility> <expression> <user> <character>").private().strong());
                         },
                         t => {
                             unknown_command(data, t);
@@ -1361,13 +2822,23 @@ pub fn parse_command(data: &mut DMAppData, mut command: String) {
                 } else {
                     let mut msg = "List of all known commands (run /help <command> for more info):".to_owned();
                     msg.push_str("\n- help");
-                    msg.push_str("\n- kick");
+                    msg.push_str("\n- user");
+                    msg.push_str("\n- registration");
+                    msg.push_str("\n- campaign");
                     msg.push_str("\n- known_users");
                     msg.push_str("\n- players");
                     msg.push_str("\n- save");
                     msg.push_str("\n- load");
+                    msg.push_str("\n- autosave");
+                    msg.push_str("\n- backups");
+                    msg.push_str("\n- restore");
+                    msg.push_str("\n- storage");
                     msg.push_str("\n- xp");
                     msg.push_str("\n- roll");
+                    msg.push_str("\n- odds");
+                    msg.push_str("\n- seed");
+                    msg.push_str("\n- role");
+                    msg.push_str("\n- requests");
                     data.log(ChatMessage::no_sender(msg).private());
                 }
             },
@@ -1380,6 +2851,148 @@ pub fn parse_command(data: &mut DMAppData, mut command: String) {
     }
 }
 
+/// Parses and rolls a dice expression. If a user and character are given, their attribute 
+/// modifiers can be used in the expression.
+pub fn roll_expression(data: &mut DMAppData, expr: &str, character: Option<(&str, &str)>) -> Result<ExprResult, String> {
+    let expr = DiceExpr::parse(expr)?;
+    match character {
+        Some((user, name)) => {
+            let sheet = data.user_data.get(user)
+                .ok_or(format!("The user \"{}\" doesn't appear to exist.", user))?
+                .characters.get(name)
+                .ok_or(format!("The character \"{}\" doesn't appear to exist.", name))?;
+            let attributes = sheet.combat_stats.attributes;
+            expr.evaluate(&|var| attributes.named_modifier(var), &mut data.rng)
+        },
+        None => expr.evaluate_simple(&mut data.rng),
+    }
+}
+
+/// Rolls one of a user's saved macros. A character's macros take priority over the user's own,
+/// and may use that character's attribute modifiers. If no character is given and the user only
+/// has one, that character is used.
+pub fn roll_macro(data: &mut DMAppData, username: &str, character: Option<&str>, name: &str) -> Result<ChatMessage, String> {
+    let user_data = data.user_data.get(username).ok_or("You don't have any saved data.".to_owned())?;
+    let character = match character {
+        Some(c) => Some(c.to_owned()),
+        None if user_data.characters.len() == 1 => user_data.characters.keys().next().cloned(),
+        None => None,
+    };
+    let sheet = match &character {
+        Some(c) => Some(user_data.characters.get(c).ok_or(format!("You don't have a character named \"{}\".", c))?),
+        None => None,
+    };
+    let expr = sheet.and_then(|s| s.macros.get(name))
+        .or(user_data.macros.get(name))
+        .ok_or(format!("There is no macro named \"{}\".", name))?;
+    let attributes = sheet.map(|s| s.combat_stats.attributes);
+    let result = DiceExpr::parse(expr)?.evaluate(&|var| attributes.and_then(|a| a.named_modifier(var)), &mut data.rng)?;
+    let who = character.map_or(String::new(), |c| format!(" ({})", c));
+    Ok(ChatMessage::player(username, format!("{}{}: {}", name, who, result)).dice_roll().with_rolls(result.rolls))
+}
+
+/// Checks that a macro can be saved.
+pub fn validate_macro(name: &str, expr: &str) -> Result<(), String> {
+    if name.trim().is_empty() || name.contains(char::is_whitespace) {
+        return Err("Macro names can't be empty or contain spaces.".to_owned());
+    }
+    if name.len() > 40 {
+        return Err("Macro names can be at most 40 characters long.".to_owned());
+    }
+    if expr.len() > 200 {
+        return Err("Macros can be at most 200 characters long.".to_owned());
+    }
+    let parsed = DiceExpr::parse(expr)?;
+    // saved macros are rolled for as long as they exist, so they have to always work
+    if !parsed.always_fits() {
+        return Err("That macro could roll a result too large to handle.".to_owned());
+    }
+    Ok(())
+}
+
+/// Closes a user's connection and ends their session, so they can't resume it. Returns false if
+/// they weren't connected.
+fn disconnect_user(data: &mut DMAppData, username: &str) -> bool {
+    data.sessions.remove(username);
+    match data.connected_users.remove(username) {
+        Some(addr) => {
+            for connection in &data.streams {
+                if connection.addr == addr {
+                    connection.shutdown();
+                }
+            }
+            true
+        },
+        None => false,
+    }
+}
+
+/// Gives a user a role and lets them know.
+fn set_role(data: &mut DMAppData, username: &str, role: Role) {
+    if role == Role::Player {
+        data.roles.remove(username);
+    } else {
+        data.roles.insert(username.to_owned(), role);
+    }
+    data.log(ChatMessage::no_sender(format!("User \"{}\" is now a {}.", username, role)).private().green());
+    data.send_to_user(ClientBoundPacket::ChatMessage(ChatMessage::no_sender(format!("You are now a {}.", role)).private().green()), username.to_owned());
+}
+
+/// Why a co-DM may not run a command, if they may not. Only the DM can change the account or role
+/// of another co-DM.
+pub fn refuse_co_dm_command(data: &DMAppData, co_dm: &str, command: &str) -> Option<String> {
+    let mut tokens = command_tokens(command.strip_prefix('/')?);
+    match (tokens.next(), tokens.next(), tokens.next()) {
+        (Some("user"), Some(target), Some(_)) if target != co_dm && data.role(target) == Role::CoDm => {
+            Some(format!("Only the DM can change the account or role of another co-DM, so \"{}\" was left alone.", target))
+        },
+        _ => None,
+    }
+}
+
+/// A command as it should be written to the chat history, without any password it contains.
+pub fn redact_command(command: &str) -> String {
+    let mut tokens = command_tokens(command.strip_prefix('/').unwrap_or(command));
+    match (tokens.next(), tokens.next(), tokens.next()) {
+        (Some("user"), Some(target), Some("resetpw")) => format!("/user \"{}\" resetpw <password>", target),
+        _ => command.to_owned(),
+    }
+}
+
+/// Whether any of a user's characters are in the loaded map's fight.
+fn in_fight(data: &DMAppData, username: &str) -> bool {
+    match &data.loaded_map {
+        Some((_, map)) => map.fight.as_ref().is_some_and(|fight| fight.combatants.iter().any(|(owner, _)| *owner == Owner::Player(username.to_owned()))),
+        None => false,
+    }
+}
+
+/// Moves everything belonging to a user over to a new name. They are disconnected first, so
+/// they have to log in again under it.
+fn rename_user(data: &mut DMAppData, old: &str, new: &str) {
+    disconnect_user(data, old);
+    if let Some(hash) = data.known_users.remove(old) {
+        data.known_users.insert(new.to_owned(), hash);
+    }
+    if let Some(user_data) = data.user_data.remove(old) {
+        data.user_data.insert(new.to_owned(), user_data);
+    }
+    if let Some(role) = data.roles.remove(old) {
+        data.roles.insert(new.to_owned(), role);
+    }
+    if data.banned.remove(old) {
+        data.banned.insert(new.to_owned());
+    }
+    for (user, _) in &mut data.temp_state.requests {
+        if user == old {
+            *user = new.to_owned();
+        }
+    }
+    for party in data.parties.values_mut() {
+        party.members = party.members.drain().map(|(user, character)| (if user == old { new.to_owned() } else { user }, character)).collect();
+    }
+}
+
 pub fn unknown_command(data: &mut DMAppData, token: impl Into<String>) {
     data.log(ChatMessage::no_sender(format!("Unknown command \"{}\".", token.into())).private().light_red());
 }
@@ -1401,6 +3014,9 @@ impl<'a, F: FnMut(DMTab, bool) + 'a> DMTabViewer<'a, F> {
                     data.temp_state.dice_roll.apply_modifier_to_all = false;
                     data.temp_state.dice_roll.drop = Drop::None;
                     data.temp_state.dice_roll.min_value = 1;
+                    data.temp_state.dice_roll.explode = false;
+                    data.temp_state.dice_roll.reroll_below = None;
+                    data.temp_state.dice_roll.success_target = None;
             }
             if data.temp_state.dice_roll_advanced {
                 dice_roll_editor(ui, &mut data.temp_state.dice_roll);
@@ -1408,19 +3024,86 @@ impl<'a, F: FnMut(DMTab, bool) + 'a> DMTabViewer<'a, F> {
                 dice_roll_editor_simple(ui, &mut data.temp_state.dice_roll);
             }
             ui.separator();
+            ui.horizontal(|ui| {
+                ui.label("Expression:");
+                let response = ui.text_edit_singleline(&mut data.temp_state.dice_expr)
+                    .on_hover_text("e.g. 2d6+1d4+3. Run /help roll for more info.");
+                if response.changed() {
+                    data.temp_state.dice_expr_error = None;
+                }
+                let enter = response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+                if (ui.button(RichText::new("Roll").strong()).clicked() || enter) && !data.temp_state.dice_expr.trim().is_empty() {
+                    match DiceExpr::parse(data.temp_state.dice_expr.trim()).and_then(|expr| expr.evaluate_simple(&mut data.rng)) {
+                        Ok(result) => {
+                            let msg = ChatMessage::server(result.to_string()).dice_roll().with_rolls(result.rolls);
+                            if data.temp_state.dice_roll_public {
+                                data.log(msg);
+                            } else {
+                                data.log(msg.private());
+                            }
+                        },
+                        Err(e) => {
+                            data.temp_state.dice_expr_error = Some(e);
+                        },
+                    }
+                }
+            });
+            if let Some(e) = &data.temp_state.dice_expr_error {
+                ui.colored_label(Color32::LIGHT_RED, e);
+            }
+            ui.separator();
             ui.horizontal(|ui| {
                 if ui.button(RichText::new("Roll!").strong()).clicked() {
-                    let r = roll(data.temp_state.dice_roll);
+                    let r = roll_detailed(data.temp_state.dice_roll, &mut data.rng);
+                    let msg = ChatMessage::server(format!("{}", r.total)).dice_roll().with_roll(r);
                     if data.temp_state.dice_roll_public {
-                        data.log(ChatMessage::server(format!("{}", r)).dice_roll());
+                        data.log(msg);
                     } else {
-                        data.log(ChatMessage::server(format!("{}", r)).private().dice_roll());
+                        data.log(msg.private());
                     }
                 }
                 let public = data.temp_state.dice_roll_public;
                 ui.checkbox(&mut data.temp_state.dice_roll_public, if public {format!("{}", ep::EYE)} else {format!("{}", ep::EYE_CLOSED)})
                     .on_hover_text(if public {"Roll public"} else {"Roll private"});
             });
+            ui.separator();
+            Self::dice_odds(ui, data);
+        });
+    }
+    fn dice_odds(ui: &mut Ui, data: &mut DMAppData) {
+        egui::CollapsingHeader::new("Odds").show(ui, |ui| {
+            let roll = data.temp_state.dice_roll;
+            if !matches!(&data.temp_state.dice_odds, Some((r, _)) if *r == roll) {
+                data.temp_state.dice_odds = Some((roll, Distribution::of_roll(&roll)));
+            }
+            ui.horizontal(|ui| {
+                ui.label("Target:");
+                ui.add(egui::DragValue::new(&mut data.temp_state.dice_odds_target));
+            });
+            let target = data.temp_state.dice_odds_target;
+            match &data.temp_state.dice_odds {
+                Some((_, Ok(dist))) => {
+                    ui.label(format!("Mean: {:.2}", dist.mean()));
+                    ui.label(format!("Median: {}", dist.median()));
+                    ui.label(format!("Chance of {} or higher: {:.2}%", target, dist.at_least(target) * 100.0));
+                    let bars = dist.probabilities.iter().map(|(value, p)| {
+                        let bar = egui::plot::Bar::new(*value as f64, *p * 100.0).width(0.9);
+                        if *value >= target {bar.fill(Color32::LIGHT_GREEN)} else {bar.fill(Color32::GRAY)}
+                    }).collect();
+                    egui::plot::Plot::new("dice_odds_plot")
+                        .height(160.0)
+                        .allow_drag(false)
+                        .allow_zoom(false)
+                        .allow_scroll(false)
+                        .show(ui, |plot_ui| {
+                            plot_ui.bar_chart(egui::plot::BarChart::new(bars).name("Chance (%)"));
+                        });
+                },
+                Some((_, Err(e))) => {
+                    ui.colored_label(Color32::LIGHT_RED, e);
+                },
+                None => {},
+            }
         });
     }
     fn player_list(&mut self, ui: &mut Ui) {
@@ -1475,8 +3158,7 @@ impl<'a, F: FnMut(DMTab, bool) + 'a> DMTabViewer<'a, F> {
     }
     fn player_character(&mut self, ui: &mut Ui, user: &String, name: &String) {
         let data = &mut *self.data;
-        let mut parties_changed = false;
-        let mut msg = None;
+        let mut join_party = None;
         if back_arrow(ui) {
             (self.callback)(DMTab::PlayerCharacter(user.clone(), name.clone()), false);
             (self.callback)(DMTab::Player(user.clone()), true);
@@ -1503,13 +3185,9 @@ impl<'a, F: FnMut(DMTab, bool) + 'a> DMTabViewer<'a, F> {
                                 ui.horizontal(|ui| {
                                     ui.label("Not a member of any party");
                                     ui.menu_button(RichText::new(format!("{}", ep::PLUS)).color(Color32::LIGHT_GREEN), |ui| {
-                                        for (party_name, party) in &mut data.parties {
+                                        for (party_name, party) in &data.parties {
                                             if ui.button(RichText::new(party_name).color(party.color)).clicked() {
-                                                party.members.insert((user.clone(), name.clone()));
-                                                sheet.party = Some(party_name.clone());
-                                                changed = true;
-                                                parties_changed = true;
-                                                msg = Some(ChatMessage::no_sender(format!("{} has joined {}!", name, party_name)).parties().color(party.color));
+                                                join_party = Some(party_name.clone());
                                                 ui.close_menu();
                                             }
                                         }
@@ -1985,11 +3663,8 @@ impl<'a, F: FnMut(DMTab, bool) + 'a> DMTabViewer<'a, F> {
         } else {
             ui.colored_label(ui.visuals().error_fg_color, "Something went wrong. This user doesn't appear to exist!");
         }
-        if let Some(msg) = msg {
-            data.log(msg);
-        }
-        if parties_changed {
-            data.send_to_all_players(ClientBoundPacket::UpdateParties(data.parties.clone()));
+        if let Some(party_name) = join_party {
+            data.join_party(&party_name, user, name);
         }
     }
     fn open_tab_button(&mut self, ui: &mut Ui, text: impl Into<WidgetText>, tab_to_open: DMTab, current_tab: DMTab) {
@@ -2897,7 +4572,7 @@ impl<'a, F: FnMut(DMTab, bool) + 'a> DMTabViewer<'a, F> {
             });
             ui.label(RichText::new("Hint: you can use \"/ \" to specify a folder.").weak().italics());
             if ui.button("Save").clicked() {
-                if let Ok(_) = save_ron(spell, "spells", data.temp_state.temp_spell_filename.trim()) {
+                if let Ok(_) = save_ron(spell, Path::new(""), FileKind::Spell, data.temp_state.temp_spell_filename.trim()) {
                     data.temp_state.temp_spell_filename = "spell".to_owned();
                     data.temp_state.temp_spell = None;
                     data.register_spells();
@@ -3302,7 +4977,7 @@ impl<'a, F: FnMut(DMTab, bool) + 'a> DMTabViewer<'a, F> {
                         }
                     }
                 }
-                if let Ok(_) = save_ron(class, "classes", data.temp_state.temp_class_filename.trim()) {
+                if let Ok(_) = save_ron(class, Path::new(""), FileKind::Class, data.temp_state.temp_class_filename.trim()) {
                     data.temp_state.temp_class_filename = "class".to_owned();
                     data.temp_state.temp_class = None;
                     data.temp_state.temp_class_profs.clear();
@@ -3610,6 +5285,7 @@ impl<'a, F: FnMut(DMTab, bool) + 'a> DMTabViewer<'a, F> {
                 }
                 if end_combat {
                     maybe_fight = None;
+                    data.request_autosave();
                 }
                 fight_cloned = maybe_fight;
             },
@@ -3712,7 +5388,10 @@ impl<'a, F: FnMut(DMTab, bool) + 'a> DMTabViewer<'a, F> {
         if ui.button("Create").clicked() {
             let mut map = Map::new();
             map.name = data.temp_state.temp_map_name.clone();
-            let _ = save_ron(&map, "maps", &data.temp_state.temp_map_filename);
+            let filename = data.temp_state.temp_map_filename.clone();
+            if let Err(e) = data.save_map(&filename, &map) {
+                data.log(ChatMessage::no_sender(format!("Could not create the map \"{}\": {}", filename, e)).private().red());
+            }
             data.temp_state.temp_map_filename = "map".to_owned();
             data.temp_state.temp_map_name.clear();
             data.register_maps();
@@ -3762,15 +5441,15 @@ impl<'a, F: FnMut(DMTab, bool) + 'a> DMTabViewer<'a, F> {
                     }
                 });
         } else {
+            let mut clicked = None;
             for (id, name) in &data.map_registry {
                 if ui.button(format!("Load: {}", name)).clicked() {
-                    if let Ok(s) = std::fs::read_to_string(format!("maps/{}.ron", id)) {
-                        if let Ok(map) = ron::from_str::<Map>(&s) {
-                            data.loaded_map = Some((id.clone(), map));
-                        }
-                    }
+                    clicked = Some(id.clone());
                 }
             }
+            if let Some(id) = clicked {
+                data.load_map(&id);
+            }
         }
     }
 }
@@ -3844,6 +5523,9 @@ impl<F: FnMut(DMTab, bool)> TabViewer for DMTabViewer<'_, F> {
             DMTab::Parties => {
                 self.parties(ui);
             },
+            DMTab::RemoteServer => {
+                self.data.temp_state.remote.show(ui);
+            },
             DMTab::MapViewer => {
                 self.map_viewer(ui);
             },
@@ -3883,6 +5565,7 @@ pub enum DMTab {
     Parties,
     MapViewer,
     MapCreator,
+    RemoteServer,
 }
 
 impl std::fmt::Display for DMTab {
@@ -3905,6 +5588,7 @@ impl std::fmt::Display for DMTab {
             Self::Chat => ep::CHAT_TEXT.to_owned(),
             Self::MapViewer => "Map Viewer".to_owned(),
             Self::MapCreator => "Map Creator".to_owned(),
+            Self::RemoteServer => "Remote Server".to_owned(),
             Self::PlayerCharacter(player, name) => format!("{} ({})", name, player),
             Self::Player(player) => format!("Player ({})", player),
         })
@@ -4159,7 +5843,7 @@ where
                     ui.menu_button("Add...", |ui| {
                         ui.set_max_width(180.0);
                         if let Some((path, enemy)) = enemy_viewer_callback(ui, &data.enemy_type_registry, room_id) {
-                            let e = Enemy::from_type(&enemy);
+                            let e = Enemy::from_type(&enemy, &mut data.rng);
                             room.enemies.entry(path).or_insert((enemy, Vec::new())).1.push(e);
                         }
                     });
@@ -4224,7 +5908,7 @@ where
                                     }
                                     if edit {
                                         if ui.add(egui::Button::new(ep::DICE_SIX).small().frame(false)).on_hover_text("Reroll max HP").clicked() {
-                                            enemy.combat_stats.health.max_hp = typ.hit_dice.roll();
+                                            enemy.combat_stats.health.max_hp = typ.hit_dice.roll(&mut data.rng);
                                             enemy.combat_stats.health.current_hp = enemy.combat_stats.health.max_hp as i32;
                                         }
                                     }
@@ -4717,6 +6401,7 @@ impl eframe::App for DMApp {
     }
     fn update(&mut self, ctx: &egui::Context, frame: &mut eframe::Frame) {
         let data = &mut *self.data.lock().unwrap();
+        data.temp_state.remote.poll();
         Self::chat_window(ctx, data, &mut self.tree);
         Self::requests_window(ctx, data);
         egui::TopBottomPanel::top("top_panel").show(ctx, |ui| {
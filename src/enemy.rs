@@ -1,9 +1,11 @@
 use std::{collections::HashSet, path::Path};
 
+use rand::Rng;
 use serde::{Serialize, Deserialize};
 use simple_enum_macro::simple_enum;
 
 use crate::{combat::{CombatantStats, DamageRoll}, dice::{DiceRoll, self, ModifierType, Drop}, character::{SavingThrows, Attributes}};
+use crate::migration::{self, FileKind};
 
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -12,21 +14,21 @@ pub struct Enemy {
 }
 
 impl Enemy {
-    pub fn from_type(typ: &EnemyType) -> Self {
+    pub fn from_type(typ: &EnemyType, rng: &mut impl Rng) -> Self {
         let mut s = Self {
             combat_stats: CombatantStats::empty(),
         };
-        s.combat_stats.attributes = Attributes::random();
+        s.combat_stats.attributes = Attributes::random(rng);
         let hp = match typ.hit_dice {
             EnemyHitDice::Standard(amount) => {
-                dice::roll(DiceRoll::new(amount, 8, 0, ModifierType::Add, false, Drop::None, 1))
+                dice::roll(DiceRoll::new(amount, 8, 0, ModifierType::Add, false, Drop::None, 1), rng)
             },
             EnemyHitDice::WithModifier(amount, modifier) => {
-                dice::roll(DiceRoll::new(amount, 8, modifier, ModifierType::Add, false, Drop::None, 1))
+                dice::roll(DiceRoll::new(amount, 8, modifier, ModifierType::Add, false, Drop::None, 1), rng)
             },
             EnemyHitDice::Special(mut roll) => {
                 roll.min_value = 1;
-                dice::roll(roll)
+                dice::roll(roll, rng)
             },
         };
         s.combat_stats.health.max_hp = hp as u32;
@@ -56,7 +58,7 @@ pub struct EnemyType {
 
 impl EnemyType {
     pub fn save(&self, file: &str) -> Result<(), ()> {
-        if let Ok(s) = ron::to_string(self) {
+        if let Ok(s) = migration::to_string(FileKind::Enemy, self) {
             let file = format!("enemies/{}.ron", file);
             let path = Path::new(&file);
             if let Some(parent) = path.parent() {
@@ -150,16 +152,16 @@ impl EnemyHitDice {
         }
     }
 
-    pub fn roll(&self) -> u32 {
+    pub fn roll(&self, rng: &mut impl Rng) -> u32 {
         match self {
             Self::Standard(amount) => {
-                DiceRoll::simple(*amount, 8).roll() as u32
+                DiceRoll::simple(*amount, 8).roll(rng) as u32
             },
             Self::WithModifier(amount, modifier) => {
-                DiceRoll::simple_modifier(*amount, 8, *modifier).roll() as u32
+                DiceRoll::simple_modifier(*amount, 8, *modifier).roll(rng) as u32
             },
             Self::Special(roll) => {
-                roll.roll() as u32
+                roll.roll(rng) as u32
             },
         }
     }
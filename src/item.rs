@@ -5,6 +5,7 @@ use serde::{Serialize, Deserialize};
 use simple_enum_macro::simple_enum;
 
 use crate::combat::DamageRoll;
+use crate::migration::{self, FileKind};
 
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -35,7 +36,7 @@ impl ItemType {
         }
     }
     pub fn save(&self, file: &str) -> Result<(), ()> {
-        if let Ok(s) = ron::to_string(self) {
+        if let Ok(s) = migration::to_string(FileKind::Item, self) {
             let file = format!("items/{}.ron", file);
             let path = Path::new(&file);
             if let Some(parent) = path.parent() {
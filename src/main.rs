@@ -1,6 +1,6 @@
 //#![windows_subsystem = "windows"]
 
-use std::{rc::Rc, cell::RefCell};
+use std::{rc::Rc, cell::RefCell, collections::HashMap};
 
 use eframe::NativeOptions;
 use serde::{Serialize, Deserialize};
@@ -11,6 +11,27 @@ pub mod dice;
 pub mod character;
 /// Packets sent between the server and client.
 pub mod packets;
+/// Running a headless server from the DM app.
+pub mod remote;
+/// A terminal client for playing without the GUI, or scripting fake players.
+pub mod bot;
+/// Password hashing and remembered logins.
+pub mod auth;
+/// Finding games hosted on the LAN.
+pub mod discovery;
+/// Limits on how often clients can send packets to the server.
+pub mod rate_limit;
+/// Where campaigns are stored, writing save files safely, and keeping backups of them.
+pub mod storage;
+/// Upgrading save and registry files written by older versions of the app.
+pub mod migration;
+/// Campaigns, each with its own save data, maps and registry overrides.
+pub mod campaign;
+/// Storing a campaign in an SQLite database instead of RON files.
+#[cfg(feature = "sqlite")]
+pub mod database;
+/// Framing and version handshake for the connection between server and client.
+pub mod network;
 /// Mortal Wounds Table automation.
 pub mod mortal_wounds;
 /// The DM (server-side) application.
@@ -35,11 +56,31 @@ pub mod proficiency;
 pub mod spell;
 pub mod party;
 pub mod map;
+#[cfg(test)]
+mod tests;
 
 fn main() -> Result<(), eframe::Error> {
+    let args: Vec<String> = std::env::args().collect();
+    if args.iter().any(|arg| arg == "--headless") {
+        let port = arg_value(&args, "--port").and_then(|p| p.parse().ok()).unwrap_or(8080);
+        let campaign = arg_value(&args, "--campaign").cloned().unwrap_or(campaign::DEFAULT_CAMPAIGN.to_owned());
+        if !campaign::exists(&campaign) {
+            eprintln!("There is no campaign called \"{}\". Create it from the DM app, or make a folder for it in {}/. The campaigns are: {}", campaign, campaign::CAMPAIGNS_DIR, campaign::list().join(", "));
+            std::process::exit(2);
+        }
+        dm_app::run_headless(load_preferences(), port, campaign);
+        return Ok(());
+    }
+    if args.iter().any(|arg| arg == "--bot") {
+        run_bot(&args);
+        return Ok(());
+    }
     // have to do some fuckery with interior mutability to store the button press between applications
     let is_dm: Rc<RefCell<Option<bool>>> = Rc::new(RefCell::new(None));
     let is_dm_clone = Rc::clone(&is_dm);
+    let campaign: Rc<RefCell<Option<String>>> = Rc::new(RefCell::new(None));
+    let campaign_clone = Rc::clone(&campaign);
+    let last_campaign = load_preferences().dm_last_campaign.filter(|c| campaign::exists(c)).unwrap_or(campaign::DEFAULT_CAMPAIGN.to_owned());
     let _ = eframe::run_native(
         "TTRPG Tool", 
         eframe::NativeOptions {
@@ -49,24 +90,24 @@ fn main() -> Result<(), eframe::Error> {
             ..Default::default()
         },
         Box::new(|_ctx| {
-            Box::new(StartupApp { is_dm: is_dm_clone })
+            Box::new(StartupApp {
+                is_dm: is_dm_clone,
+                campaign: campaign_clone,
+                picking_campaign: false,
+                campaigns: Vec::new(),
+                selected_campaign: last_campaign,
+                new_campaign: String::new(),
+                new_campaign_database: false,
+                error: None,
+            })
         })
     );
 
     if let Some(dm) = *is_dm.borrow() {
-        let prefs = if let Ok(s) = std::fs::read_to_string("preferences.ron") {
-            if let Ok(p) = ron::from_str::<AppPreferences>(&s) {
-                p
-            } else {
-                let _ = std::fs::write("preferences.ron", ron::to_string(&AppPreferences::default()).unwrap_or(String::new()));
-                AppPreferences::default()
-            }
-        } else {
-            let _ = std::fs::write("preferences.ron", ron::to_string(&AppPreferences::default()).unwrap_or(String::new()));
-            AppPreferences::default()
-        };
+        let prefs = load_preferences();
         if dm {
-            return dm_app::run(prefs);
+            let campaign = campaign.borrow_mut().take().unwrap_or(campaign::DEFAULT_CAMPAIGN.to_owned());
+            return dm_app::run(prefs, campaign);
         } else {
             return player_app::run(prefs);
         }
@@ -74,13 +115,128 @@ fn main() -> Result<(), eframe::Error> {
     Ok(())
 }
 
+/// The value given after a command line option, like the port in `--port 8080`.
+fn arg_value<'a>(args: &'a [String], name: &str) -> Option<&'a String> {
+    args.iter().position(|arg| arg == name).and_then(|i| args.get(i + 1))
+}
+
+/// Runs [`bot::run`] with the options given on the command line, exiting with an error code if
+/// it fails so scripts can tell.
+fn run_bot(args: &[String]) {
+    let (address, username) = match (arg_value(args, "--address"), arg_value(args, "--user")) {
+        (Some(address), Some(username)) => (address.clone(), username.clone()),
+        _ => {
+            eprintln!("Usage: --bot --address <ip:port> --user <name> [--password <password>] [--register] [--unencrypted] [--script <file>] [--auto]");
+            std::process::exit(2);
+        },
+    };
+    let password = match arg_value(args, "--password") {
+        Some(password) => password.clone(),
+        None => {
+            eprint!("Password: ");
+            let mut password = String::new();
+            let _ = std::io::stdin().read_line(&mut password);
+            password.trim_end_matches(['\r', '\n']).to_owned()
+        },
+    };
+    let options = bot::BotOptions {
+        address,
+        username,
+        password,
+        register: args.iter().any(|arg| arg == "--register"),
+        encrypt: !args.iter().any(|arg| arg == "--unencrypted"),
+        script: arg_value(args, "--script").cloned(),
+        autopilot: args.iter().any(|arg| arg == "--auto"),
+    };
+    if let Err(e) = bot::run(load_preferences(), options) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+/// Reads `preferences.ron`, replacing it with the defaults if it is missing or unreadable.
+fn load_preferences() -> AppPreferences {
+    if let Ok(s) = std::fs::read_to_string("preferences.ron") {
+        if let Ok(p) = ron::from_str::<AppPreferences>(&s) {
+            return p;
+        }
+    }
+    let _ = std::fs::write("preferences.ron", ron::to_string(&AppPreferences::default()).unwrap_or(String::new()));
+    AppPreferences::default()
+}
+
 struct StartupApp {
-    is_dm: Rc<RefCell<Option<bool>>>
+    is_dm: Rc<RefCell<Option<bool>>>,
+    /// The campaign the DM picked.
+    campaign: Rc<RefCell<Option<String>>>,
+    /// Chose to be the DM, and now has to pick a campaign.
+    picking_campaign: bool,
+    campaigns: Vec<String>,
+    selected_campaign: String,
+    new_campaign: String,
+    /// Store the new campaign in a database instead of RON files.
+    new_campaign_database: bool,
+    error: Option<String>,
+}
+
+impl StartupApp {
+    fn campaign_picker(&mut self, ui: &mut eframe::egui::Ui, frame: &mut eframe::Frame) {
+        ui.label("Campaign:");
+        eframe::egui::ScrollArea::vertical().max_height(120.0).show(ui, |ui| {
+            for name in &self.campaigns {
+                ui.selectable_value(&mut self.selected_campaign, name.clone(), name);
+            }
+        });
+        ui.separator();
+        ui.add(eframe::egui::TextEdit::singleline(&mut self.new_campaign).hint_text("New campaign name"));
+        if storage::database_available() {
+            ui.checkbox(&mut self.new_campaign_database, "Use a database")
+                .on_hover_text("Store the new campaign in an SQLite database, which only writes what changed when saving and keeps the chat history");
+        }
+        ui.horizontal(|ui| {
+            let mut result = None;
+            if ui.button("Create").on_hover_text("Make an empty campaign").clicked() {
+                let kind = if self.new_campaign_database { storage::StoreKind::Sqlite } else { storage::StoreKind::Ron };
+                result = Some(campaign::create(&self.new_campaign, kind));
+            }
+            if ui.button("Clone selected").on_hover_text("Make a campaign with a copy of the selected one's save data, maps and overrides").clicked() {
+                result = Some(campaign::clone(&self.selected_campaign, &self.new_campaign));
+            }
+            match result {
+                Some(Ok(_)) => {
+                    self.campaigns = campaign::list();
+                    self.selected_campaign = std::mem::take(&mut self.new_campaign);
+                    self.error = None;
+                },
+                Some(Err(e)) => self.error = Some(e),
+                None => {},
+            }
+        });
+        if let Some(error) = &self.error {
+            ui.label(eframe::egui::RichText::new(error).color(ui.visuals().error_fg_color));
+        }
+        ui.separator();
+        ui.horizontal(|ui| {
+            if ui.button("Back").clicked() {
+                self.picking_campaign = false;
+                self.error = None;
+            }
+            if ui.button(format!("Start {}", self.selected_campaign)).clicked() {
+                *self.campaign.borrow_mut() = Some(self.selected_campaign.clone());
+                *self.is_dm.borrow_mut() = Some(true);
+                frame.close();
+            }
+        });
+    }
 }
 
 impl eframe::App for StartupApp {
     fn update(&mut self, ctx: &eframe::egui::Context, frame: &mut eframe::Frame) {
         eframe::egui::CentralPanel::default().show(ctx, |ui| {
+            if self.picking_campaign {
+                self.campaign_picker(ui, frame);
+                return;
+            }
             ui.vertical_centered(|ui| {
                 ui.add_space(ui.available_height() / 2.5);
                 ui.label("I am a...");
@@ -90,9 +246,11 @@ impl eframe::App for StartupApp {
                     frame.close();
                 }
                 if ui.button("DM").clicked() {
-                    let mut borrow = self.is_dm.borrow_mut();
-                    *borrow = Some(true);
-                    frame.close();
+                    self.campaigns = campaign::list();
+                    if !self.campaigns.contains(&self.selected_campaign) {
+                        self.selected_campaign = campaign::DEFAULT_CAMPAIGN.to_owned();
+                    }
+                    self.picking_campaign = true;
                 }
             });
         });
@@ -104,7 +262,35 @@ pub struct AppPreferences {
     pub dm_window: Option<WindowPreferences>,
     pub player_window: Option<WindowPreferences>,
     pub player_last_ip: Option<String>,
-    pub player_login: Option<(String, String)>,
+    /// The username and login token to log in with automatically. Never the password itself.
+    #[serde(default)]
+    pub player_token: Option<(String, String)>,
+    /// Whether the player chose to connect without encryption.
+    #[serde(default)]
+    pub player_unencrypted: bool,
+    /// The certificate fingerprint of each server the player has connected to, by address.
+    #[serde(default)]
+    pub known_servers: HashMap<String, String>,
+    /// Whether the DM refuses unencrypted connections.
+    #[serde(default)]
+    pub dm_require_encryption: bool,
+    /// How many backups of the save file the DM keeps.
+    #[serde(default = "default_backup_count")]
+    pub dm_backup_count: usize,
+    /// How often the DM's game saves on its own, in seconds. 0 turns autosaving off.
+    #[serde(default = "default_autosave_interval")]
+    pub dm_autosave_interval: u64,
+    /// The campaign the DM played last, to pick it again at startup.
+    #[serde(default)]
+    pub dm_last_campaign: Option<String>,
+}
+
+fn default_backup_count() -> usize {
+    storage::DEFAULT_BACKUP_COUNT
+}
+
+fn default_autosave_interval() -> u64 {
+    storage::DEFAULT_AUTOSAVE_INTERVAL
 }
 
 impl Default for AppPreferences {
@@ -113,7 +299,13 @@ impl Default for AppPreferences {
             dm_window: None,
             player_window: None,
             player_last_ip: None,
-            player_login: None,
+            player_token: None,
+            player_unencrypted: false,
+            known_servers: HashMap::new(),
+            dm_require_encryption: false,
+            dm_backup_count: storage::DEFAULT_BACKUP_COUNT,
+            dm_autosave_interval: storage::DEFAULT_AUTOSAVE_INTERVAL,
+            dm_last_campaign: None,
         }
     }
 }
@@ -0,0 +1,566 @@
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use simple_enum_macro::simple_enum;
+
+/// The kinds of file that are versioned. Each has its own version, so changing one type only
+/// means upgrading the files of that kind.
+#[simple_enum]
+pub enum FileKind {
+    Save,
+    Class,
+    Enemy,
+    Item,
+    Proficiency,
+    Spell,
+    Map,
+}
+
+impl std::fmt::Display for FileKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Save => write!(f, "save file"),
+            Self::Class => write!(f, "class"),
+            Self::Enemy => write!(f, "enemy"),
+            Self::Item => write!(f, "item"),
+            Self::Proficiency => write!(f, "proficiency"),
+            Self::Spell => write!(f, "spell"),
+            Self::Map => write!(f, "map"),
+        }
+    }
+}
+
+impl FileKind {
+    /// The version files of this kind are written with. Files from before versioning count as
+    /// version 0.
+    ///
+    /// Whenever a type saved in these files changes in a way older files can't be read as, bump
+    /// its version here and add a step to [`MIGRATIONS`] that upgrades files from the old one.
+    pub fn version(&self) -> u32 {
+        match self {
+            Self::Save => 1,
+            Self::Class => 1,
+            Self::Enemy => 1,
+            Self::Item => 1,
+            Self::Proficiency => 1,
+            Self::Spell => 1,
+            Self::Map => 1,
+        }
+    }
+
+    /// The directory files of this kind are kept in. The save file is in the working directory.
+    pub fn dir(&self) -> &'static str {
+        match self {
+            Self::Save => "",
+            Self::Class => "classes",
+            Self::Enemy => "enemies",
+            Self::Item => "items",
+            Self::Proficiency => "proficiencies",
+            Self::Spell => "spells",
+            Self::Map => "maps",
+        }
+    }
+}
+
+/// One step of upgrading a file, from version `from` to `from + 1`.
+pub struct Migration {
+    pub kind: FileKind,
+    pub from: u32,
+    /// What changed, shown to the DM when a file is upgraded.
+    pub description: &'static str,
+    pub apply: fn(&mut RonValue) -> Result<(), String>,
+}
+
+/// Every upgrade step, in order. A version with no steps for a kind reads the same as the one
+/// before it, which is the case for going from unversioned files to version 1.
+///
+/// ## Example
+/// A field added to [`PlayerCharacter`](crate::character::PlayerCharacter) in version 2 of the
+/// save file:
+/// ```rust
+/// Migration {
+///     kind: FileKind::Save,
+///     from: 1,
+///     description: "characters have a nickname",
+///     apply: |save| {
+///         for user_data in save.field_mut("user_data")?.map_values_mut()? {
+///             for character in user_data.field_mut("characters")?.map_values_mut()? {
+///                 character.insert_field("nickname", RonValue::Ident("None".to_owned()))?;
+///             }
+///         }
+///         Ok(())
+///     },
+/// },
+/// ```
+pub const MIGRATIONS: &[Migration] = &[];
+
+/// A file that was read, and the version it was written with.
+pub struct Loaded<T> {
+    pub value: T,
+    pub version: u32,
+    /// The description of every upgrade step it went through.
+    pub upgrades: Vec<&'static str>,
+}
+
+/// Just enough of a versioned file to tell which version it is.
+#[derive(Deserialize)]
+struct VersionOnly {
+    #[serde(default)]
+    version: u32,
+}
+
+/// Reads a versioned file, upgrading it step by step first if it was written by an older version.
+pub fn from_str<T: DeserializeOwned>(kind: FileKind, s: &str) -> Result<Loaded<T>, String> {
+    let version = match ron::from_str::<VersionOnly>(s) {
+        Ok(v) => v.version,
+        // the full error says more about what is wrong with it
+        Err(_) => return Err(ron::from_str::<T>(s).err().map_or("not a valid file".to_owned(), |e| e.to_string())),
+    };
+    from_str_with(kind, s, version, MIGRATIONS)
+}
+
+fn from_str_with<T: DeserializeOwned>(kind: FileKind, s: &str, version: u32, migrations: &[Migration]) -> Result<Loaded<T>, String> {
+    let current = kind.version();
+    if version > current {
+        return Err(format!("it was written by a newer version of the app ({} version {}, but this one only reads up to {})", kind, version, current));
+    }
+    let steps: Vec<&Migration> = migrations.iter().filter(|m| m.kind == kind && m.from >= version && m.from < current).collect();
+    if steps.is_empty() {
+        return match ron::from_str::<T>(s) {
+            Ok(value) => Ok(Loaded { value, version, upgrades: Vec::new() }),
+            Err(e) => Err(e.to_string()),
+        };
+    }
+    let mut tree = RonValue::parse(s)?;
+    let mut upgrades = Vec::new();
+    for step in steps {
+        if let Err(e) = (step.apply)(&mut tree) {
+            return Err(format!("could not upgrade it from version {} ({}): {}", step.from, step.description, e));
+        }
+        upgrades.push(step.description);
+    }
+    let _ = tree.insert_field("version", RonValue::Literal(current.to_string()));
+    match ron::from_str::<T>(&tree.to_string()) {
+        Ok(value) => Ok(Loaded { value, version, upgrades }),
+        Err(e) => Err(format!("upgraded it from version {}, but then: {}", version, e)),
+    }
+}
+
+/// Writes a file with its version, for types that don't have a `version` field of their own.
+pub fn to_string<T: Serialize>(kind: FileKind, obj: &T) -> Result<String, String> {
+    let s = ron::to_string(obj).map_err(|e| e.to_string())?;
+    // structs are written as `(field:value,...)`, without their name
+    match s.strip_prefix('(') {
+        Some(rest) => Ok(format!("(version:{},{}", kind.version(), rest)),
+        None => Ok(s),
+    }
+}
+
+/// A RON value that keeps everything the typed representation needs, like enum variant and struct
+/// names, so old files can be changed without knowing what their types used to look like.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RonValue {
+    /// Numbers, strings and chars, exactly as written.
+    Literal(String),
+    /// A bare identifier, like `true`, `None` or a unit enum variant.
+    Ident(String),
+    /// `(a, b)`, or with a name, like `Some(a)` or an enum variant `Variant(a, b)`.
+    Tuple(Option<String>, Vec<RonValue>),
+    /// `(field: a)`, or with a name, like an enum variant `Variant(field: a)`.
+    Struct(Option<String>, Vec<(String, RonValue)>),
+    List(Vec<RonValue>),
+    Map(Vec<(RonValue, RonValue)>),
+}
+
+impl RonValue {
+    /// Parses a whole file. Any `#![enable(...)]` extensions at the start are not supported.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut parser = Parser { chars: s.chars().collect(), pos: 0 };
+        parser.skip_whitespace();
+        if parser.peek() == Some('#') {
+            return Err("RON extensions can't be upgraded".to_owned());
+        }
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        if parser.pos < parser.chars.len() {
+            return Err(parser.error("expected the end of the file"));
+        }
+        Ok(value)
+    }
+
+    /// The value of a struct's field.
+    pub fn field_mut(&mut self, name: &str) -> Result<&mut RonValue, String> {
+        match self {
+            Self::Struct(_, fields) => {
+                match fields.iter_mut().find(|(field, _)| field == name) {
+                    Some((_, value)) => Ok(value),
+                    None => Err(format!("there is no field \"{}\"", name)),
+                }
+            },
+            _ => Err(format!("expected a struct with the field \"{}\"", name)),
+        }
+    }
+
+    /// Adds a field to a struct, or replaces it if it is already there.
+    pub fn insert_field(&mut self, name: &str, value: RonValue) -> Result<(), String> {
+        match self {
+            Self::Struct(_, fields) => {
+                match fields.iter_mut().find(|(field, _)| field == name) {
+                    Some((_, old)) => *old = value,
+                    None => fields.push((name.to_owned(), value)),
+                }
+                Ok(())
+            },
+            // a struct with no fields is written as `()`
+            Self::Tuple(struct_name, values) if values.is_empty() => {
+                *self = Self::Struct(struct_name.take(), vec![(name.to_owned(), value)]);
+                Ok(())
+            },
+            _ => Err(format!("expected a struct to add the field \"{}\" to", name)),
+        }
+    }
+
+    /// Removes a field from a struct, returning its value if it was there.
+    pub fn remove_field(&mut self, name: &str) -> Result<Option<RonValue>, String> {
+        match self {
+            Self::Struct(_, fields) => {
+                match fields.iter().position(|(field, _)| field == name) {
+                    Some(i) => Ok(Some(fields.remove(i).1)),
+                    None => Ok(None),
+                }
+            },
+            _ => Err(format!("expected a struct to remove the field \"{}\" from", name)),
+        }
+    }
+
+    /// Renames a struct's field, if it is there.
+    pub fn rename_field(&mut self, from: &str, to: &str) -> Result<(), String> {
+        match self {
+            Self::Struct(_, fields) => {
+                for (field, _) in fields.iter_mut() {
+                    if field == from {
+                        *field = to.to_owned();
+                    }
+                }
+                Ok(())
+            },
+            _ => Err(format!("expected a struct with the field \"{}\"", from)),
+        }
+    }
+
+    /// The values of a map, like the characters of a user.
+    pub fn map_values_mut(&mut self) -> Result<impl Iterator<Item = &mut RonValue>, String> {
+        match self {
+            Self::Map(entries) => Ok(entries.iter_mut().map(|(_, value)| value)),
+            _ => Err("expected a map".to_owned()),
+        }
+    }
+
+    /// The items of a list.
+    pub fn list_mut(&mut self) -> Result<&mut Vec<RonValue>, String> {
+        match self {
+            Self::List(items) => Ok(items),
+            _ => Err("expected a list".to_owned()),
+        }
+    }
+}
+
+impl std::fmt::Display for RonValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Literal(s) | Self::Ident(s) => write!(f, "{}", s),
+            Self::Tuple(name, values) => {
+                if let Some(name) = name {
+                    write!(f, "{}", name)?;
+                }
+                write!(f, "(")?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                write!(f, ")")
+            },
+            Self::Struct(name, fields) => {
+                if let Some(name) = name {
+                    write!(f, "{}", name)?;
+                }
+                write!(f, "(")?;
+                for (i, (field, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}:{}", field, value)?;
+                }
+                write!(f, ")")
+            },
+            Self::List(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            },
+            Self::Map(entries) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in entries.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}:{}", key, value)?;
+                }
+                write!(f, "}}")
+            },
+        }
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn error(&self, msg: &str) -> String {
+        let line = self.chars[..self.pos.min(self.chars.len())].iter().filter(|c| **c == '\n').count() + 1;
+        format!("{} on line {}", msg, line)
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        self.skip_whitespace();
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected '{}'", c)))
+        }
+    }
+
+    /// Skips whitespace and comments.
+    fn skip_whitespace(&mut self) {
+        loop {
+            match (self.peek(), self.chars.get(self.pos + 1)) {
+                (Some(c), _) if c.is_whitespace() => self.pos += 1,
+                (Some('/'), Some('/')) => {
+                    while self.peek().is_some() && self.peek() != Some('\n') {
+                        self.pos += 1;
+                    }
+                },
+                (Some('/'), Some('*')) => {
+                    self.pos += 2;
+                    let mut depth = 1;
+                    while depth > 0 && self.pos < self.chars.len() {
+                        match (self.chars[self.pos], self.chars.get(self.pos + 1)) {
+                            ('/', Some('*')) => {
+                                depth += 1;
+                                self.pos += 2;
+                            },
+                            ('*', Some('/')) => {
+                                depth -= 1;
+                                self.pos += 2;
+                            },
+                            _ => self.pos += 1,
+                        }
+                    }
+                },
+                _ => break,
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<RonValue, String> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some('"') => self.quoted('"'),
+            Some('\'') => self.quoted('\''),
+            Some('[') => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                while !self.at_end_of(']')? {
+                    items.push(self.value()?);
+                    self.separator(']')?;
+                }
+                Ok(RonValue::List(items))
+            },
+            Some('{') => {
+                self.pos += 1;
+                let mut entries = Vec::new();
+                while !self.at_end_of('}')? {
+                    let key = self.value()?;
+                    self.expect(':')?;
+                    entries.push((key, self.value()?));
+                    self.separator('}')?;
+                }
+                Ok(RonValue::Map(entries))
+            },
+            Some('(') => self.parenthesized(None),
+            Some(c) if c.is_ascii_digit() || c == '-' || c == '+' || c == '.' => Ok(RonValue::Literal(self.number())),
+            Some(c) if c.is_alphabetic() || c == '_' => {
+                let ident = self.ident();
+                // raw and byte strings
+                match (ident.as_str(), self.peek()) {
+                    ("r" | "br", Some('"' | '#')) => return self.raw_string(ident),
+                    ("b", Some('"')) => return self.quoted('"').map(|s| RonValue::Literal(format!("b{}", s))),
+                    _ => {},
+                }
+                self.skip_whitespace();
+                if self.peek() == Some('(') {
+                    self.parenthesized(Some(ident))
+                } else {
+                    Ok(RonValue::Ident(ident))
+                }
+            },
+            Some(c) => Err(self.error(&format!("unexpected '{}'", c))),
+            None => Err(self.error("unexpected end of file")),
+        }
+    }
+
+    /// Checks for the end of a list, map, tuple or struct, consuming it if it is there.
+    fn at_end_of(&mut self, end: char) -> Result<bool, String> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(c) if c == end => {
+                self.pos += 1;
+                Ok(true)
+            },
+            Some(_) => Ok(false),
+            None => Err(self.error(&format!("expected '{}'", end))),
+        }
+    }
+
+    /// Consumes the comma after an item, unless it is the last one.
+    fn separator(&mut self, end: char) -> Result<(), String> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(',') => {
+                self.pos += 1;
+                Ok(())
+            },
+            Some(c) if c == end => Ok(()),
+            _ => Err(self.error(&format!("expected ',' or '{}'", end))),
+        }
+    }
+
+    /// A tuple or struct, which look the same until the first field name.
+    fn parenthesized(&mut self, name: Option<String>) -> Result<RonValue, String> {
+        self.pos += 1;
+        let start = self.pos;
+        self.skip_whitespace();
+        let is_struct = match self.peek() {
+            Some(c) if c.is_alphabetic() || c == '_' => {
+                let ident = self.ident();
+                self.skip_whitespace();
+                // tuples can hold things like `Some(x)` or `None`, but never `field:`
+                self.peek() == Some(':') && ident != "r" && ident != "b" && ident != "br"
+            },
+            _ => false,
+        };
+        self.pos = start;
+        if is_struct {
+            let mut fields = Vec::new();
+            while !self.at_end_of(')')? {
+                self.skip_whitespace();
+                let field = self.ident();
+                if field.is_empty() {
+                    return Err(self.error("expected a field name"));
+                }
+                self.expect(':')?;
+                fields.push((field, self.value()?));
+                self.separator(')')?;
+            }
+            Ok(RonValue::Struct(name, fields))
+        } else {
+            let mut values = Vec::new();
+            while !self.at_end_of(')')? {
+                values.push(self.value()?);
+                self.separator(')')?;
+            }
+            Ok(RonValue::Tuple(name, values))
+        }
+    }
+
+    fn ident(&mut self) -> String {
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            if c.is_alphanumeric() || c == '_' {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+        // raw identifiers, like `r#type`
+        if self.pos - start == 1 && self.chars[start] == 'r' && self.peek() == Some('#') && self.chars.get(self.pos + 1).is_some_and(|c| c.is_alphabetic() || *c == '_') {
+            self.pos += 1;
+            while let Some(c) = self.peek() {
+                if c.is_alphanumeric() || c == '_' {
+                    self.pos += 1;
+                } else {
+                    break;
+                }
+            }
+        }
+        self.chars[start..self.pos].iter().collect()
+    }
+
+    fn number(&mut self) -> String {
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            let exponent_sign = (c == '-' || c == '+') && self.pos > start && matches!(self.chars[self.pos - 1], 'e' | 'E') && !self.chars[start..self.pos].contains(&'x');
+            if c.is_alphanumeric() || c == '_' || c == '.' || exponent_sign || (self.pos == start && (c == '-' || c == '+')) {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+        self.chars[start..self.pos].iter().collect()
+    }
+
+    /// A string or char, kept with its quotes and escapes.
+    fn quoted(&mut self, quote: char) -> Result<RonValue, String> {
+        let start = self.pos;
+        self.pos += 1;
+        loop {
+            match self.peek() {
+                Some('\\') => self.pos += 2,
+                Some(c) if c == quote => {
+                    self.pos += 1;
+                    return Ok(RonValue::Literal(self.chars[start..self.pos].iter().collect()));
+                },
+                Some(_) => self.pos += 1,
+                None => return Err(self.error("unterminated string")),
+            }
+        }
+    }
+
+    /// A raw string like `r#"..."#`, after its prefix.
+    fn raw_string(&mut self, prefix: String) -> Result<RonValue, String> {
+        let start = self.pos;
+        let mut hashes = 0;
+        while self.peek() == Some('#') {
+            hashes += 1;
+            self.pos += 1;
+        }
+        self.expect('"')?;
+        loop {
+            match self.peek() {
+                Some('"') => {
+                    self.pos += 1;
+                    let closing = self.chars[self.pos..].iter().take(hashes).filter(|c| **c == '#').count();
+                    if closing == hashes {
+                        self.pos += hashes;
+                        let raw: String = self.chars[start..self.pos].iter().collect();
+                        return Ok(RonValue::Literal(format!("{}{}", prefix, raw)));
+                    }
+                },
+                Some(_) => self.pos += 1,
+                None => return Err(self.error("unterminated string")),
+            }
+        }
+    }
+}
@@ -1,3 +1,5 @@
+use rand::Rng;
+
 use crate::dice::{self, DiceRoll};
 
 
@@ -9,8 +11,8 @@ pub struct MortalWoundsResult {
 }
 
 impl MortalWoundsResult {
-    pub fn roll(modifiers: MortalWoundsModifiers) -> Self {
-        let mut total = dice::roll(DiceRoll::simple(1, 20)) as i32;
+    pub fn roll(modifiers: MortalWoundsModifiers, rng: &mut impl Rng) -> Self {
+        let mut total = dice::roll(DiceRoll::simple(1, 20), rng) as i32;
         total += modifiers.from_con;
         total += match modifiers.from_hit_dice {
             HitDiceValue::D4 => 0,
@@ -0,0 +1,579 @@
+use std::io::{prelude::*, ErrorKind};
+use std::collections::HashMap;
+use std::net::{TcpStream, SocketAddr, Shutdown, ToSocketAddrs};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Sender, Receiver};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use rustls::client::{ServerCertVerifier, ServerCertVerified};
+use rustls::{ServerConfig, ClientConfig, ServerConnection, ClientConnection, ServerName, Certificate, PrivateKey};
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+use sha2::{Sha256, Digest};
+
+/// The version of the wire protocol. Bump this whenever a packet changes in a way older builds
+/// can't read, so mismatched clients and servers refuse each other with a clear message instead of
+/// silently dropping packets.
+pub const PROTOCOL_VERSION: u32 = 7;
+/// The largest frame either side will accept, in bytes. Anything bigger means the stream is
+/// corrupt (or not speaking this protocol at all) and the connection is dropped.
+pub const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+/// The largest frame the server accepts from a client, in bytes. Clients never need to send
+/// anything near [`MAX_FRAME_SIZE`], so this keeps one from making the server buffer that much.
+pub const MAX_CLIENT_FRAME_SIZE: usize = 256 * 1024;
+/// How much can be waiting to be sent on a connection before it is dropped, in bytes. Otherwise a
+/// client that stops reading would have everything sent to it kept around forever.
+pub const MAX_QUEUED_BYTES: usize = 2 * MAX_FRAME_SIZE;
+/// How long a single connection attempt may take.
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+/// How long a client waits for the server to answer its handshake before giving up.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long the server holds on to a session after its connection drops, so the player can
+/// reconnect and pick up where they left off.
+pub const RECONNECT_GRACE: Duration = Duration::from_secs(120);
+/// How long the threads handling network events wait for one before waking up anyway to check
+/// for timeouts.
+pub const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// The size of the length prefix in front of every frame.
+const HEADER_SIZE: usize = 4;
+/// The first byte of a TLS handshake. A plaintext frame can never start with this, as the length
+/// it would encode is far above [`MAX_FRAME_SIZE`].
+const TLS_HANDSHAKE_BYTE: u8 = 0x16;
+/// Where the DM's self-signed certificate and its private key are kept.
+const TLS_CERT_PATH: &str = "tls/cert.der";
+const TLS_KEY_PATH: &str = "tls/key.der";
+/// The name the DM's certificate is issued to. Clients connect by IP, so this is never checked;
+/// the certificate is trusted by its fingerprint instead.
+const TLS_SERVER_NAME: &str = "dm-automation-tool";
+
+/// The first frame sent in each direction on a new connection. This is deliberately kept separate
+/// from the packet enums so it can be read by any build, no matter how much the packets change.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Handshake {
+    pub protocol_version: u32,
+    pub app_version: String,
+    /// Set by the server when it won't accept this connection, explaining why.
+    #[serde(default)]
+    pub refusal: Option<String>,
+}
+
+impl Handshake {
+    /// The handshake for this build.
+    pub fn current() -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            app_version: env!("CARGO_PKG_VERSION").to_owned(),
+            refusal: None,
+        }
+    }
+
+    /// The handshake for this build, refusing the connection for the given reason.
+    pub fn refuse(reason: impl Into<String>) -> Self {
+        Self {
+            refusal: Some(reason.into()),
+            ..Self::current()
+        }
+    }
+
+    pub fn is_compatible(&self) -> bool {
+        self.protocol_version == PROTOCOL_VERSION
+    }
+
+    /// A message explaining a version mismatch, from the point of view of whoever recieved `self`.
+    pub fn mismatch_message(&self, peer: &str) -> String {
+        format!(
+            "The {} is running version {} (protocol {}), but this is version {} (protocol {}). Please update so both match.",
+            peer,
+            self.app_version,
+            self.protocol_version,
+            env!("CARGO_PKG_VERSION"),
+            PROTOCOL_VERSION,
+        )
+    }
+}
+
+/// Serializes a packet into a frame: a big-endian `u32` length followed by that many bytes of RON.
+pub fn encode_frame<T: Serialize>(packet: &T) -> Result<Vec<u8>, String> {
+    let msg = ron::to_string(packet).map_err(|e| e.to_string())?;
+    if msg.len() > MAX_FRAME_SIZE {
+        return Err(format!("Packet of {} bytes exceeds the {} byte limit.", msg.len(), MAX_FRAME_SIZE));
+    }
+    let mut frame = Vec::with_capacity(HEADER_SIZE + msg.len());
+    frame.extend_from_slice(&(msg.len() as u32).to_be_bytes());
+    frame.extend_from_slice(msg.as_bytes());
+    Ok(frame)
+}
+
+/// Deserializes the payload of a frame recieved as a [`NetEventKind::Frame`].
+pub fn decode_frame<T: DeserializeOwned>(payload: &[u8]) -> Result<T, String> {
+    let msg = std::str::from_utf8(payload).map_err(|e| format!("Packet is not valid UTF-8: {}", e))?;
+    ron::from_str(msg).map_err(|e| e.to_string())
+}
+
+/// Loads the DM's certificate, generating a new self-signed one if there isn't one yet. Returns
+/// the TLS config and the certificate's fingerprint, which players can use to check they're
+/// talking to the right server.
+pub fn server_tls_config() -> Result<(Arc<ServerConfig>, String), String> {
+    let (cert, key) = match (std::fs::read(TLS_CERT_PATH), std::fs::read(TLS_KEY_PATH)) {
+        (Ok(cert), Ok(key)) => (cert, key),
+        _ => {
+            let (cert, key) = generate_certificate()?;
+            std::fs::create_dir_all("tls").map_err(|e| e.to_string())?;
+            std::fs::write(TLS_CERT_PATH, &cert).map_err(|e| e.to_string())?;
+            std::fs::write(TLS_KEY_PATH, &key).map_err(|e| e.to_string())?;
+            (cert, key)
+        },
+    };
+    tls_config_for(cert, key)
+}
+
+/// Like [`server_tls_config`], but with a new certificate that is never saved.
+pub fn temporary_tls_config() -> Result<(Arc<ServerConfig>, String), String> {
+    let (cert, key) = generate_certificate()?;
+    tls_config_for(cert, key)
+}
+
+/// Generates a self-signed certificate, returning it and its private key.
+fn generate_certificate() -> Result<(Vec<u8>, Vec<u8>), String> {
+    let generated = rcgen::generate_simple_self_signed(vec![TLS_SERVER_NAME.to_owned()]).map_err(|e| e.to_string())?;
+    let cert = generated.serialize_der().map_err(|e| e.to_string())?;
+    let key = generated.serialize_private_key_der();
+    Ok((cert, key))
+}
+
+fn tls_config_for(cert: Vec<u8>, key: Vec<u8>) -> Result<(Arc<ServerConfig>, String), String> {
+    let fingerprint = fingerprint(&cert);
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(vec![Certificate(cert)], PrivateKey(key))
+        .map_err(|e| e.to_string())?;
+    Ok((Arc::new(config), fingerprint))
+}
+
+/// The TLS config for clients. Any certificate is accepted here (as long as the server proves it
+/// holds the key); the client pins the fingerprint the first time it connects to a server and
+/// refuses to continue if it ever changes.
+pub fn client_tls_config() -> Arc<ClientConfig> {
+    Arc::new(ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(TrustOnFirstUse))
+        .with_no_client_auth())
+}
+
+/// Opens a connection to a server and starts the handshake. Everything the server sends arrives
+/// on the returned channel.
+pub fn connect(address: &str, encrypt: bool) -> std::io::Result<(Connection, Receiver<NetEvent>)> {
+    let addr = match address.to_socket_addrs()?.next() {
+        Some(addr) => addr,
+        None => return Err(std::io::Error::from(ErrorKind::AddrNotAvailable)),
+    };
+    let stream = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?;
+    let (events, receiver) = mpsc::channel();
+    let connection = if encrypt {
+        Connection::new_tls(stream, client_tls_config(), events)?
+    } else {
+        Connection::new(stream, events)?
+    };
+    connection.send(&Handshake::current())?;
+    Ok((connection, receiver))
+}
+
+/// Shown when a server's certificate doesn't match the pinned one.
+pub const CERTIFICATE_CHANGED: &str = "The server's certificate has changed since you last connected! Someone may be intercepting the connection. Only trust the new certificate if your DM confirms it.";
+
+/// The result of checking a server's certificate against the fingerprints pinned so far.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PinCheck {
+    /// It matches the pinned fingerprint.
+    Trusted,
+    /// We've never connected to this server before, so its fingerprint is now pinned.
+    FirstUse,
+    /// It doesn't match! Either the DM made a new certificate or someone is in the middle.
+    Changed,
+}
+
+/// Checks a server's certificate fingerprint against the one pinned for its address, pinning it
+/// if there isn't one yet.
+pub fn check_pin(known_servers: &mut HashMap<String, String>, server: &str, fingerprint: &str) -> PinCheck {
+    match known_servers.get(server) {
+        Some(pinned) if pinned == fingerprint => PinCheck::Trusted,
+        Some(_) => PinCheck::Changed,
+        None => {
+            known_servers.insert(server.to_owned(), fingerprint.to_owned());
+            PinCheck::FirstUse
+        },
+    }
+}
+
+/// A SHA-256 fingerprint of a certificate, formatted like `AB:CD:...`.
+pub fn fingerprint(cert: &[u8]) -> String {
+    Sha256::digest(cert).iter().map(|b| format!("{:02X}", b)).collect::<Vec<_>>().join(":")
+}
+
+/// See [`client_tls_config`].
+struct TrustOnFirstUse;
+
+impl ServerCertVerifier for TrustOnFirstUse {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+
+/// Something that happened on a connection, sent by its reader thread.
+#[derive(Debug)]
+pub struct NetEvent {
+    pub addr: SocketAddr,
+    /// The [`Connection::id`] of the connection it happened on.
+    pub id: u64,
+    pub kind: NetEventKind,
+}
+
+#[derive(Debug)]
+pub enum NetEventKind {
+    /// A complete frame arrived. Decode it with [`decode_frame`].
+    Frame(Vec<u8>),
+    /// The connection is gone, because of an error if there is one. Always the last event sent
+    /// for a connection.
+    Closed(Option<std::io::Error>),
+}
+
+/// What is actually carried over the TCP stream.
+enum Transport {
+    /// The server hasn't seen the first byte yet, so it doesn't know whether the client wants TLS.
+    Undecided(Arc<ServerConfig>),
+    Plain,
+    Tls(Box<rustls::Connection>),
+}
+
+impl Transport {
+    fn tls(mut tls: rustls::Connection) -> Self {
+        // each frame is encrypted whole before any of it is written, so TLS has to be able to
+        // hold all of it at once
+        tls.set_buffer_limit(None);
+        Self::Tls(Box::new(tls))
+    }
+}
+
+/// Work for a connection's writer thread.
+enum Command {
+    /// Sends an encoded frame.
+    Send(Arc<[u8]>),
+    /// Sends anything TLS wants to send on its own, like the rest of its handshake.
+    Flush,
+    /// Says goodbye and closes the socket.
+    Close,
+}
+
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(0);
+
+/// A handle to a TCP connection that sends and recieves length-prefixed frames. Each connection
+/// gets a reader thread, which blocks on the socket and passes complete frames to whoever owns the
+/// event channel, and a writer thread, which sends whatever is queued with [`Connection::send`].
+/// Nothing here ever waits on the network, so it is safe to use while holding the app data lock.
+/// Dropping the handle closes the connection.
+pub struct Connection {
+    pub addr: SocketAddr,
+    /// Unique to this connection, to tell its events apart from those of an older connection to
+    /// the same address.
+    pub id: u64,
+    /// Whether both sides have agreed on a protocol version. No packets other than the handshake
+    /// should be sent or handled until this is set.
+    pub handshake_done: bool,
+    pub opened_at: Instant,
+    /// Shared with the reader and writer threads. Only locked to encrypt or decrypt, never while
+    /// waiting on the socket.
+    transport: Arc<Mutex<Transport>>,
+    commands: Sender<Command>,
+    /// How many bytes of frames are waiting for the writer thread.
+    queued: Arc<AtomicUsize>,
+    /// Kept to close the socket when too much is queued, since the writer thread is stuck.
+    stream: TcpStream,
+}
+
+impl Connection {
+    /// Wraps an unencrypted connection.
+    pub fn new(stream: TcpStream, events: Sender<NetEvent>) -> std::io::Result<Self> {
+        Self::with_transport(stream, Transport::Plain, MAX_FRAME_SIZE, events)
+    }
+
+    /// Wraps a connection to a server, encrypting it with TLS.
+    pub fn new_tls(stream: TcpStream, config: Arc<ClientConfig>, events: Sender<NetEvent>) -> std::io::Result<Self> {
+        let name = ServerName::try_from(TLS_SERVER_NAME).map_err(|e| std::io::Error::new(ErrorKind::InvalidInput, e))?;
+        let tls = ClientConnection::new(config, name).map_err(std::io::Error::other)?;
+        Self::with_transport(stream, Transport::tls(tls.into()), MAX_FRAME_SIZE, events)
+    }
+
+    /// Wraps a connection accepted by the server. If a TLS config is given, the client may choose
+    /// to encrypt the connection. Frames bigger than [`MAX_CLIENT_FRAME_SIZE`] are refused.
+    pub fn accept(stream: TcpStream, tls: Option<Arc<ServerConfig>>, events: Sender<NetEvent>) -> std::io::Result<Self> {
+        let transport = match tls {
+            Some(config) => Transport::Undecided(config),
+            None => Transport::Plain,
+        };
+        Self::with_transport(stream, transport, MAX_CLIENT_FRAME_SIZE, events)
+    }
+
+    fn with_transport(stream: TcpStream, transport: Transport, max_frame_size: usize, events: Sender<NetEvent>) -> std::io::Result<Self> {
+        let addr = stream.peer_addr()?;
+        // frames are written whole, so there's nothing to gain by waiting to fill a packet
+        stream.set_nodelay(true)?;
+        let id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+        let transport = Arc::new(Mutex::new(transport));
+        let (commands, queue) = mpsc::channel();
+        let queued = Arc::new(AtomicUsize::new(0));
+
+        let read_stream = stream.try_clone()?;
+        let read_transport = Arc::clone(&transport);
+        let read_commands = commands.clone();
+        std::thread::Builder::new().name(format!("read {}", addr)).spawn(move || {
+            read_loop(read_stream, read_transport, read_commands, events, addr, id, max_frame_size);
+        })?;
+
+        let write_stream = stream.try_clone()?;
+        let write_transport = Arc::clone(&transport);
+        let write_queued = Arc::clone(&queued);
+        std::thread::Builder::new().name(format!("write {}", addr)).spawn(move || {
+            write_loop(write_stream, write_transport, queue, write_queued);
+        })?;
+
+        Ok(Self {
+            addr,
+            id,
+            handshake_done: false,
+            opened_at: Instant::now(),
+            transport,
+            commands,
+            queued,
+            stream,
+        })
+    }
+
+    /// Whether this connection is encrypted. Always `false` until the server knows what the
+    /// client wants.
+    pub fn is_encrypted(&self) -> bool {
+        matches!(*self.transport.lock().unwrap(), Transport::Tls(_))
+    }
+
+    /// The fingerprint of the server's certificate, once the TLS handshake is done. Only ever set
+    /// on the client side.
+    pub fn peer_fingerprint(&self) -> Option<String> {
+        match &*self.transport.lock().unwrap() {
+            Transport::Tls(tls) if matches!(**tls, rustls::Connection::Client(_)) => {
+                tls.peer_certificates().and_then(|certs| certs.first()).map(|cert| fingerprint(&cert.0))
+            },
+            _ => None,
+        }
+    }
+
+    /// Encodes a packet and queues it to be sent.
+    pub fn send<T: Serialize>(&self, packet: &T) -> std::io::Result<()> {
+        let frame = encode_frame(packet).map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))?;
+        self.send_frame(&frame.into())
+    }
+
+    /// Queues an already encoded frame. Useful when sending the same packet to many connections,
+    /// as they all share the one buffer. If more than [`MAX_QUEUED_BYTES`] would be waiting to be
+    /// sent, the connection is closed instead.
+    pub fn send_frame(&self, frame: &Arc<[u8]>) -> std::io::Result<()> {
+        if self.queued.fetch_add(frame.len(), Ordering::Relaxed) + frame.len() > MAX_QUEUED_BYTES {
+            // the reader thread notices and reports the connection as closed
+            let _ = self.stream.shutdown(Shutdown::Both);
+            return Err(std::io::Error::other("too much is waiting to be sent, as the other side stopped reading"));
+        }
+        self.commands.send(Command::Send(Arc::clone(frame))).map_err(|_| std::io::Error::from(ErrorKind::NotConnected))
+    }
+
+    /// Closes the connection once everything already queued has been sent.
+    pub fn shutdown(&self) {
+        let _ = self.commands.send(Command::Close);
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// Reads from the socket until it closes, sending every complete frame as an event.
+fn read_loop(mut stream: TcpStream, transport: Arc<Mutex<Transport>>, commands: Sender<Command>, events: Sender<NetEvent>, addr: SocketAddr, id: u64, max_frame_size: usize) {
+    let result = read_until_closed(&mut stream, &transport, &commands, &events, addr, id, max_frame_size);
+    let _ = stream.shutdown(Shutdown::Both);
+    let _ = events.send(NetEvent { addr, id, kind: NetEventKind::Closed(result.err()) });
+}
+
+fn read_until_closed(stream: &mut TcpStream, transport: &Mutex<Transport>, commands: &Sender<Command>, events: &Sender<NetEvent>, addr: SocketAddr, id: u64, max_frame_size: usize) -> std::io::Result<()> {
+    if !decide_transport(stream, transport)? {
+        return Ok(());
+    }
+    let mut incoming = Vec::new();
+    let mut chunk = [0u8; 16 * 1024];
+    loop {
+        let n = match stream.read(&mut chunk) {
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        let open = match &mut *transport.lock().unwrap() {
+            Transport::Tls(tls) => {
+                let open = decrypt(tls, &chunk[..n], &mut incoming)?;
+                // the handshake and key updates need answering without waiting for us to send
+                if tls.wants_write() {
+                    let _ = commands.send(Command::Flush);
+                }
+                open
+            },
+            _ => {
+                incoming.extend_from_slice(&chunk[..n]);
+                n != 0
+            },
+        };
+        for frame in split_frames(&mut incoming, max_frame_size)? {
+            if events.send(NetEvent { addr, id, kind: NetEventKind::Frame(frame) }).is_err() {
+                // nobody is listening anymore
+                return Ok(());
+            }
+        }
+        if !open {
+            return Ok(());
+        }
+    }
+}
+
+/// Peeks at the first byte from the client to decide whether it is starting a TLS handshake.
+/// Returns `false` if the client hung up without sending anything, and fails if it sends nothing
+/// within [`HANDSHAKE_TIMEOUT`].
+fn decide_transport(stream: &TcpStream, transport: &Mutex<Transport>) -> std::io::Result<bool> {
+    let config = match &*transport.lock().unwrap() {
+        Transport::Undecided(config) => Arc::clone(config),
+        _ => return Ok(true),
+    };
+    let mut first = [0u8; 1];
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let n = loop {
+        match stream.peek(&mut first) {
+            Ok(n) => break n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {},
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                return Err(std::io::Error::new(ErrorKind::TimedOut, "no handshake in time"));
+            },
+            Err(e) => return Err(e),
+        }
+    };
+    stream.set_read_timeout(None)?;
+    if n == 0 {
+        return Ok(false);
+    }
+    let decided = if first[0] == TLS_HANDSHAKE_BYTE {
+        let tls = ServerConnection::new(config).map_err(std::io::Error::other)?;
+        Transport::tls(tls.into())
+    } else {
+        Transport::Plain
+    };
+    *transport.lock().unwrap() = decided;
+    Ok(true)
+}
+
+/// Feeds bytes read from the socket to TLS and moves whatever it decrypts into `incoming`.
+/// Returns `false` once the other side has closed the connection.
+fn decrypt(tls: &mut rustls::Connection, mut data: &[u8], incoming: &mut Vec<u8>) -> std::io::Result<bool> {
+    loop {
+        tls.read_tls(&mut data)?;
+        tls.process_new_packets().map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))?;
+        match tls.reader().read_to_end(incoming) {
+            // a clean goodbye
+            Ok(_) => return Ok(false),
+            Err(e) => {
+                match e.kind() {
+                    ErrorKind::WouldBlock => {},
+                    // TLS reports the peer hanging up without saying goodbye this way
+                    ErrorKind::UnexpectedEof => return Ok(false),
+                    _ => return Err(e),
+                }
+            },
+        }
+        if data.is_empty() {
+            return Ok(true);
+        }
+    }
+}
+
+/// Removes every complete frame from the front of `incoming` and returns their payloads.
+/// Incomplete frames are kept until the rest arrives, so long as they are no bigger than
+/// `max_frame_size`. An error means the stream can't be recovered and the connection should be
+/// dropped.
+fn split_frames(incoming: &mut Vec<u8>, max_frame_size: usize) -> std::io::Result<Vec<Vec<u8>>> {
+    let mut frames = Vec::new();
+    let mut start = 0;
+    while incoming.len() - start >= HEADER_SIZE {
+        let mut header = [0u8; HEADER_SIZE];
+        header.copy_from_slice(&incoming[start..start + HEADER_SIZE]);
+        let len = u32::from_be_bytes(header) as usize;
+        if len > max_frame_size {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Incoming frame of {} bytes exceeds the {} byte limit.", len, max_frame_size),
+            ));
+        }
+        if incoming.len() - start - HEADER_SIZE < len {
+            break;
+        }
+        start += HEADER_SIZE;
+        frames.push(incoming[start..start + len].to_vec());
+        start += len;
+    }
+    incoming.drain(..start);
+    Ok(frames)
+}
+
+/// Writes everything queued for a connection until it is closed or the socket fails.
+fn write_loop(mut stream: TcpStream, transport: Arc<Mutex<Transport>>, commands: Receiver<Command>, queued: Arc<AtomicUsize>) {
+    for command in commands {
+        let closing = matches!(command, Command::Close);
+        let size = match &command {
+            Command::Send(frame) => frame.len(),
+            _ => 0,
+        };
+        let result = outgoing_bytes(&transport, command).and_then(|bytes| stream.write_all(&bytes));
+        queued.fetch_sub(size, Ordering::Relaxed);
+        if closing || result.is_err() {
+            break;
+        }
+    }
+    // wakes the reader thread up, so it reports the connection as closed
+    let _ = stream.shutdown(Shutdown::Both);
+}
+
+/// The bytes that should go out on the socket for a command, encrypted if need be.
+fn outgoing_bytes(transport: &Mutex<Transport>, command: Command) -> std::io::Result<Arc<[u8]>> {
+    match &mut *transport.lock().unwrap() {
+        Transport::Tls(tls) => {
+            match command {
+                Command::Send(frame) => tls.writer().write_all(&frame)?,
+                Command::Flush => {},
+                Command::Close => tls.send_close_notify(),
+            }
+            let mut out = Vec::new();
+            while tls.wants_write() {
+                tls.write_tls(&mut out)?;
+            }
+            Ok(out.into())
+        },
+        Transport::Undecided(_) | Transport::Plain => {
+            match command {
+                Command::Send(frame) => Ok(frame),
+                Command::Flush | Command::Close => Ok(Arc::from([])),
+            }
+        },
+    }
+}
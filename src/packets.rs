@@ -1,78 +1,128 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, BTreeMap};
 use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
 
 use egui::Color32;
 use serde::{Serialize, Deserialize};
 use simple_enum_macro::simple_enum;
-use crate::character::{PlayerCharacter, PlayerEquipSlot};
+use crate::auth::{MAX_LOGIN_TOKENS, PasswordWorker, hash_password, check_password, new_login_token, hash_token, verify_token, tokens_match};
+use crate::character::{PlayerCharacter, CharacterPatch, PlayerEquipSlot};
 use crate::class::Class;
 use crate::combat::{Combatant, SavingThrowType, PreRoundAction, MovementAction, AttackAction, Owner, TurnType};
-use crate::common_ui::ChatMessage;
-use crate::dm_app::{DMAppData, UserData, Registry};
+use crate::common_ui::{ChatMessage, MessageSender};
+use crate::dm_app::{DMAppData, UserData, Session, Registry, RegistryVersions, RegistryKind, RegistryNode, Role, MAX_MACROS, MIN_CHARACTER_CHOICES, roll_macro, validate_macro, parse_command, refuse_co_dm_command, redact_command};
 use crate::party::Party;
 use crate::player_app::{PlayerAppData, CombatState};
 use crate::proficiency::{Proficiency, ProficiencyInstance};
+use crate::rate_limit::RateClass;
 use crate::spell::SpellRegistry;
 
+/// The longest chat message a client can send, in bytes.
+pub const MAX_CHAT_MESSAGE_LENGTH: usize = 2000;
+
+/// The longest notes a client can save, for a player or one of their characters, in bytes.
+pub const MAX_NOTES_LENGTH: usize = 20_000;
+
+/// The longest username an account can have, in bytes.
+pub const MAX_USERNAME_LENGTH: usize = 32;
+
+/// Checks that a username could be given to an account, without checking whether it's taken.
+pub fn validate_username(username: &str) -> Result<(), ClientFacingError> {
+    if username.len() > MAX_USERNAME_LENGTH {
+        Err(ClientFacingError::UsernameTooLong)
+    } else if username.trim().is_empty() || username.trim() != username || username == "server" || username.chars().any(char::is_control) {
+        // "server" is how messages from the server are shown
+        Err(ClientFacingError::UsernameInvalid)
+    } else {
+        Ok(())
+    }
+}
+
 /// A packet sent from the server to a client.
 #[derive(Debug, Serialize, Deserialize)]
 pub enum ClientBoundPacket {
     /// Logs a message in the chat. Note that this may be called when a user sends a chat message,
     /// the server sends a chat message, or any time a message is sent to a client.
     ChatMessage(ChatMessage),
-    /// The result of a log in attempt.
-    LogInResult(bool),
-    /// The result of an attempt to create an account.
-    CreateAccountResult(bool, String, String),
+    /// The result of a log in attempt, with a new login token if the user asked to be remembered.
+    LogInResult(bool, Option<String>),
+    /// The result of an attempt to create an account, with the username.
+    CreateAccountResult(bool, String),
     /// The result of creating a new character.
     CreateNewCharacterResult(Result<(), ClientFacingError>, String),
-    /// Sent when a client's character is updated for whatever reason.
+    /// Sent when a client's character is updated for whatever reason. Only the first update for
+    /// a character is sent like this, the rest are sent as a [`Self::PatchCharacter`].
     UpdateCharacter(String, PlayerCharacter),
+    /// Sent when some of a character the client already has changes.
+    PatchCharacter(String, CharacterPatch),
     /// Sent when the player recieves their personal notes from the server upon login.
     UpdatePlayerNotes(String),
-    /// Sent to give the client a clone of the class registry.
-    UpdateClassRegistry(Registry<Class>),
-    /// Sent to give the client a clone of the proficiency registry.
-    UpdateProfRegistry(HashMap<String, Proficiency>),
-    /// Sent to give the client a clone of the spell registry.
-    UpdateSpellRegistry(SpellRegistry),
+    /// Sent upon login. The client asks for any registry it doesn't already have cached.
+    RegistryVersions(RegistryVersions),
+    /// Sent to give the client a clone of the class registry, with its version.
+    UpdateClassRegistry(String, Registry<Class>),
+    /// Sent to give the client a clone of the proficiency registry, with its version.
+    UpdateProfRegistry(String, HashMap<String, Proficiency>),
+    /// Sent to give the client a clone of the spell registry, with its version.
+    UpdateSpellRegistry(String, SpellRegistry),
     RespondToRequest(Request, bool),
+    /// The characters the server rolled for this user to pick from. Sent when they change, and
+    /// upon login if there are any.
+    PendingCharacters(Vec<PlayerCharacter>),
+    /// Sent when the user's own (not character) macros change, and upon login.
+    UpdateUserMacros(BTreeMap<String, String>),
     UpdateCombatState(Option<CombatState>),
     UpdateParties(HashMap<String, Party>),
+    /// Sent when the server could not read a packet from this client.
+    ProtocolError(String),
+    /// Sent when the server refuses a packet for a reason the user should know about.
+    Error(ClientFacingError),
+    /// Sent after logging in. The client can use the token to resume its session if the
+    /// connection drops.
+    SessionToken(String),
+    /// The result of trying to resume a session after reconnecting.
+    ResumeSessionResult(bool),
 }
 
 impl ClientBoundPacket {
     pub fn handle(self, data: &mut PlayerAppData) {
         match self {
             Self::ChatMessage(msg) => {
-                data.logs.insert(0, msg.to_layout_job());
+                data.logs.insert(0, msg.to_log_entry());
                 if data.unread_messages == 0 {
                     data.unread_msg_buffer = true;
                 }
                 data.unread_messages += 1;
             },
-            Self::LogInResult(success) => {
+            Self::LogInResult(success, token) => {
                 data.logged_in = success;
+                if success {
+                    data.password.clear();
+                    if token.is_some() {
+                        data.login_token = token;
+                    }
+                } else {
+                    data.login_token = None;
+                    data.login_error = Some("Incorrect username or password.".to_owned());
+                }
             },
-            Self::CreateAccountResult(success, username, password) => {
+            Self::CreateAccountResult(success, username) => {
                 if success {
                     data.username = username.clone();
-                    data.send_to_server(ServerBoundPacket::AttemptLogIn(username, password));
+                    data.send_to_server(ServerBoundPacket::AttemptLogIn(username, data.password.clone(), data.remember_me));
+                } else {
+                    data.login_error = Some("Could not create that account. The name may be taken.".to_owned());
                 }
             },
             Self::CreateNewCharacterResult(success, name) => {
                 if let Err(e) = success {
                     data.new_char_name_error = Some(e);
                 } else {
-                    if let Some(i) = data.picked_character {
-                        if i < data.new_characters.len() {
-                            data.new_characters.remove(i);
-                        }
-                    }
                     data.picked_character = None;
+                    data.picked_class = None;
                     data.new_char_name = None;
                     data.new_char_name_error = None;
-                    data.send_to_server(ServerBoundPacket::RequestCharacterUpdate(name, None));
+                    data.send_to_server(ServerBoundPacket::RequestCharacterUpdate(name));
                 }
             },
             Self::UpdateCharacter(name, mut character) => {
@@ -81,25 +131,81 @@ impl ClientBoundPacket {
                 }
                 data.characters.insert(name, character);
             },
+            Self::PatchCharacter(name, mut patch) => {
+                match data.characters.get_mut(&name) {
+                    Some(sheet) => {
+                        // notes are kept by the player, like in a full update
+                        patch.notes = None;
+                        patch.apply(sheet);
+                    },
+                    None => {
+                        data.send_to_server(ServerBoundPacket::RequestCharacterUpdate(name));
+                    },
+                }
+            },
             Self::UpdatePlayerNotes(notes) => {
                 data.notes = notes;
             },
-            Self::UpdateClassRegistry(registry) => {
+            Self::RegistryVersions(versions) => {
+                let cache = &data.registry_cache;
+                let classes = cache.classes.as_ref().filter(|(version, _)| *version == versions.classes).map(|(_, registry)| registry.clone());
+                let profs = cache.proficiencies.as_ref().filter(|(version, _)| *version == versions.proficiencies).map(|(_, profs)| profs.clone());
+                let spells = cache.spells.as_ref().filter(|(version, _)| *version == versions.spells).map(|(_, spells)| spells.clone());
+                let mut stale = Vec::new();
+                match classes {
+                    Some(registry) => data.class_registry = registry,
+                    None => stale.push(RegistryKind::Classes),
+                }
+                match profs {
+                    Some(profs) => data.set_prof_registry(profs),
+                    None => stale.push(RegistryKind::Proficiencies),
+                }
+                match spells {
+                    Some(spells) => data.spell_registry = spells,
+                    None => stale.push(RegistryKind::Spells),
+                }
+                if !stale.is_empty() {
+                    data.send_to_server(ServerBoundPacket::RequestRegistries(stale));
+                }
+            },
+            Self::UpdateClassRegistry(version, registry) => {
+                data.registry_cache.classes = Some((version, registry.clone()));
+                data.registry_cache.save();
                 data.class_registry = registry;
             },
-            Self::UpdateProfRegistry(profs) => {
-                for (id, prof) in  &profs {
-                    data.sorted_prof_list.push((id.clone(), prof.name.clone()));
-                }
-                data.sorted_prof_list.sort();
-                data.proficiency_registry = profs;
+            Self::UpdateProfRegistry(version, profs) => {
+                data.registry_cache.proficiencies = Some((version, profs.clone()));
+                data.registry_cache.save();
+                data.set_prof_registry(profs);
             },
-            Self::UpdateSpellRegistry(spells) => {
+            Self::UpdateSpellRegistry(version, spells) => {
+                data.registry_cache.spells = Some((version, spells.clone()));
+                data.registry_cache.save();
                 data.spell_registry = spells;
             },
             Self::RespondToRequest(request, approved) => {
                 data.requests.set_approval(request, approved);
             },
+            Self::Error(e) => {
+                if data.logged_in {
+                    data.logs.insert(0, ChatMessage::no_sender(e.to_string()).private().light_red().to_log_entry());
+                } else {
+                    data.login_error = Some(e.to_string());
+                }
+            },
+            Self::PendingCharacters(characters) => {
+                if !characters.is_empty() {
+                    data.requests.consume(Request::GenerateCharacters);
+                }
+                if data.picked_character.is_some_and(|i| i >= characters.len()) {
+                    data.picked_character = None;
+                    data.picked_class = None;
+                }
+                data.new_characters = characters;
+            },
+            Self::UpdateUserMacros(macros) => {
+                data.user_macros = macros;
+            },
             Self::UpdateCombatState(state) => {
                 if state.is_some() && data.combat_state.is_none() {
                     data.combat_just_started = true;
@@ -109,6 +215,22 @@ impl ClientBoundPacket {
             Self::UpdateParties(parties) => {
                 data.parties = parties;
             },
+            Self::ProtocolError(e) => {
+                data.log_local(ChatMessage::no_sender(e).red());
+            },
+            Self::SessionToken(token) => {
+                data.session_token = Some(token);
+            },
+            Self::ResumeSessionResult(success) => {
+                data.reconnecting = None;
+                if success {
+                    data.log_local(ChatMessage::no_sender("Reconnected to the server.").blue());
+                } else {
+                    data.logged_in = false;
+                    data.session_token = None;
+                    data.login_error = Some("Your session has expired. Please log in again.".to_owned());
+                }
+            },
         }
     }
 }
@@ -118,14 +240,21 @@ impl ClientBoundPacket {
 pub enum ServerBoundPacket {
     /// Sent when a user sends a message in chat.
     ChatMessage(ChatMessage),
-    /// Sent when a user tries to log in.
-    AttemptLogIn(String, String),
+    /// Sent when a user tries to log in with their password. If the flag is set, the server
+    /// responds with a token the client can remember instead of the password.
+    AttemptLogIn(String, String, bool),
+    /// Sent when a user tries to log in with a remembered login token.
+    LogInWithToken(String, String),
     /// Sent when a user creates a new account.
     CreateAccount(String, String),
-    /// Sent when a user generates a new character.
-    CreateNewCharacter(String, PlayerCharacter),
-    /// Sent to update a user's character sheet with data from the server.
-    RequestCharacterUpdate(String, Option<PlayerCharacter>),
+    /// Sent when a user picks one of their pending characters, with its new name, its index and
+    /// the registry path of its class.
+    CreateNewCharacter(String, usize, String),
+    /// Sent to get the whole of a character's sheet from the server.
+    RequestCharacterUpdate(String),
+    /// Sent when the player edits a character's notes, the only part of a sheet they can change
+    /// directly.
+    UpdateCharacterNotes(String, String),
     /// Sent when the player's personal notes change.
     UpdatePlayerNotes(String),
     /// Sent when a player tries to rearrange their inventory.
@@ -139,58 +268,145 @@ pub enum ServerBoundPacket {
     /// Sent when a player selects a new proficiency.
     PickNewProficiency(String, bool, String, Option<String>),
     MakeRequest(Request),
+    /// Sent to roll a saved macro, optionally for a specific character.
+    RollMacro(Option<String>, String),
+    /// Sent to save (or remove, if the expression is `None`) a macro. Saved on the character if
+    /// one is given, otherwise on the user.
+    SetMacro(Option<String>, String, Option<String>),
     MakePreRoundDeclaration(Combatant, PreRoundAction),
     DecideMovementAction(MovementAction),
     DecideAttackAction(AttackAction),
+    /// Sent when the client could not read a packet from the server.
+    ProtocolError(String),
+    /// Sent after reconnecting to pick up a session where it left off, instead of logging in.
+    ResumeSession(String),
+    /// Sent by a co-DM to run a server command (or chat as the server) remotely.
+    AdminCommand(String),
+    /// Sent after login for each registry the client doesn't have the current version of.
+    RequestRegistries(Vec<RegistryKind>),
 }
 
 impl ServerBoundPacket {
+    /// The role a user needs to send this packet, or None if it can be sent before logging in.
+    pub fn required_role(&self) -> Option<Role> {
+        match self {
+            Self::AttemptLogIn(..) | Self::LogInWithToken(..) | Self::CreateAccount(..) | Self::ResumeSession(..) | Self::ProtocolError(..) => None,
+            Self::RequestCharacterUpdate(..) | Self::RequestRegistries(..) => Some(Role::Spectator),
+            Self::ChatMessage(..) | Self::CreateNewCharacter(..) | Self::UpdateCharacterNotes(..) | Self::UpdatePlayerNotes(..) | Self::MoveInventoryItem(..) | Self::EquipInventoryItem(..)
+            | Self::UnequipInventoryItem(..) | Self::SavingThrow(..) | Self::PickNewProficiency(..) | Self::MakeRequest(..) | Self::RollMacro(..)
+            | Self::SetMacro(..) | Self::MakePreRoundDeclaration(..) | Self::DecideMovementAction(..) | Self::DecideAttackAction(..) => Some(Role::Player),
+            Self::AdminCommand(..) => Some(Role::CoDm),
+        }
+    }
+
+    /// Which rate limit this packet counts against.
+    pub fn rate_class(&self) -> RateClass {
+        match self {
+            Self::ChatMessage(..) | Self::RollMacro(..) | Self::AdminCommand(..) => RateClass::Chat,
+            Self::MakeRequest(..) => RateClass::Request,
+            Self::AttemptLogIn(..) | Self::LogInWithToken(..) | Self::CreateAccount(..) | Self::ResumeSession(..) => RateClass::LogIn,
+            _ => RateClass::Other,
+        }
+    }
+
+    /// Handles a packet like [`ServerBoundPacket::handle`], but only locks the server's data while
+    /// it is needed. Argon2 is slow on purpose, so passwords are checked and hashed on the
+    /// `worker`, and the DM and everyone else don't have to wait for them.
+    pub fn handle_shared(self, data: &Arc<Mutex<DMAppData>>, worker: &PasswordWorker, user: SocketAddr) {
+        match self {
+            Self::AttemptLogIn(username, password, remember) => {
+                let shared = Arc::clone(data);
+                let queued = worker.run(move || {
+                    // read once the job runs, in case an account queued just before is being created
+                    let hash = shared.lock().unwrap().known_users.get(&username).cloned();
+                    let correct = check_password(&password, hash.as_deref());
+                    finish_password_log_in(&mut shared.lock().unwrap(), username, hash, correct, remember, user);
+                });
+                if !queued {
+                    let data = &mut *data.lock().unwrap();
+                    data.send_to_user_by_addr(ClientBoundPacket::LogInResult(false, None), user);
+                    data.send_to_user_by_addr(ClientBoundPacket::Error(ClientFacingError::RateLimited), user);
+                }
+            },
+            Self::CreateAccount(username, password) => {
+                let username = username.trim().to_owned();
+                if refuse_account(&mut data.lock().unwrap(), &username, user) {
+                    return;
+                }
+                let shared = Arc::clone(data);
+                let name = username.clone();
+                let queued = worker.run(move || {
+                    let hash = hash_password(&password);
+                    finish_create_account(&mut shared.lock().unwrap(), name, hash, user);
+                });
+                if !queued {
+                    let data = &mut *data.lock().unwrap();
+                    data.send_to_user_by_addr(ClientBoundPacket::CreateAccountResult(false, username), user);
+                    data.send_to_user_by_addr(ClientBoundPacket::Error(ClientFacingError::RateLimited), user);
+                }
+            },
+            packet => packet.handle(&mut data.lock().unwrap(), user),
+        }
+    }
+
     pub fn handle(self, data: &mut DMAppData, user: SocketAddr) {
+        if let Some(required) = self.required_role() {
+            match data.get_username_by_addr(user) {
+                Some(username) if data.role(&username) >= required => {},
+                Some(username) => {
+                    data.send_to_user(ClientBoundPacket::ChatMessage(ChatMessage::no_sender(format!("You need to be a {} to do that.", required)).private().red()), username);
+                    return;
+                },
+                None => return,
+            }
+        }
         match self {
-            Self::ChatMessage(msg) => {
+            Self::ChatMessage(mut msg) => {
+                if msg.message.len() > MAX_CHAT_MESSAGE_LENGTH {
+                    data.send_to_user_by_addr(ClientBoundPacket::Error(ClientFacingError::MessageTooLong), user);
+                    return;
+                }
+                let username = match data.get_username_by_addr(user) {
+                    Some(username) => username,
+                    None => return,
+                };
+                // players speak only as themselves, and only the server rolls dice
+                msg.sender = MessageSender::Player(username);
+                msg.rolls.clear();
+                msg.flags.dice_roll = false;
+                msg.flags.combat = false;
                 data.log(msg);
                 if data.temp_state.unread_messages == 0 {
                     data.temp_state.unread_msg_buffer = true;
                 }
                 data.temp_state.unread_messages += 1;
             },
-            Self::AttemptLogIn(username, password) => {
-                if let Some(pw) = data.known_users.get(&username) {
-                    if pw == &password {
-                        data.log(ChatMessage::no_sender(format!("User \"{}\" has logged in!", &username)).blue());
-                        data.connected_users.insert(username.clone(), user);
-                        if !data.user_data.contains_key(&username) {
-                            data.user_data.insert(username.clone(), UserData::new());
-                        }
-                        data.send_to_user_by_addr(ClientBoundPacket::LogInResult(true), user);
-                        if let Some(user_data) = data.user_data.get(&username) {
-                            data.send_to_user_by_addr(ClientBoundPacket::UpdatePlayerNotes(user_data.notes.clone()), user);
-                        }
-                        if let Some(user_data) = data.user_data.get(&username) {
-                            for (name, character) in user_data.characters.clone() {
-                                data.send_to_user_by_addr(ClientBoundPacket::UpdateCharacter(name, character), user);
-                            }
+            Self::AttemptLogIn(username, password, remember) => {
+                let hash = data.known_users.get(&username).cloned();
+                let correct = check_password(&password, hash.as_deref());
+                finish_password_log_in(data, username, hash, correct, remember, user);
+            },
+            Self::LogInWithToken(username, token) => {
+                if let Some(user_data) = data.user_data.get(&username) {
+                    if data.known_users.contains_key(&username) && user_data.login_tokens.iter().any(|hash| verify_token(&token, hash)) {
+                        if refuse_if_banned(data, &username, user) {
+                            return;
                         }
-                        data.send_to_user_by_addr(ClientBoundPacket::UpdateClassRegistry(data.class_registry.clone()), user);
-                        data.send_to_user_by_addr(ClientBoundPacket::UpdateProfRegistry(data.proficiency_registry.clone()), user);
-                        data.send_to_user_by_addr(ClientBoundPacket::UpdateSpellRegistry(data.spell_registry.clone()), user);
-                        data.send_to_user_by_addr(ClientBoundPacket::UpdateParties(data.parties.clone()), user);
+                        log_in(data, username, user, None);
                         return;
                     }
                 }
-                data.send_to_user_by_addr(ClientBoundPacket::LogInResult(false), user);
+                data.send_to_user_by_addr(ClientBoundPacket::LogInResult(false, None), user);
             },
-            Self::CreateAccount(mut username, password) => {
-                username = username.trim().to_owned();
-                if data.known_users.contains_key(&username) || username == "server" || username.is_empty() {
-                    data.send_to_user_by_addr(ClientBoundPacket::CreateAccountResult(false, username, password), user);
-                } else {
-                    data.log(ChatMessage::no_sender(format!("User \"{}\" has been created.", &username)).private().blue());
-                    data.known_users.insert(username.clone(), password.clone());
-                    data.send_to_user_by_addr(ClientBoundPacket::CreateAccountResult(true, username, password), user);
+            Self::CreateAccount(username, password) => {
+                let username = username.trim().to_owned();
+                if refuse_account(data, &username, user) {
+                    return;
                 }
+                let hash = hash_password(&password);
+                finish_create_account(data, username, hash, user);
             },
-            Self::CreateNewCharacter(name, mut character) => {
+            Self::CreateNewCharacter(name, index, class_path) => {
                 if let Some(username) = data.get_username_by_addr(user) {
                     if let Some(user_data) = data.user_data.get_mut(&username) {
                         if user_data.characters.contains_key(&name) {
@@ -205,38 +421,75 @@ impl ServerBoundPacket {
                             data.send_to_user(ClientBoundPacket::CreateNewCharacterResult(Err(ClientFacingError::CharacterNameTooLong), name), username);
                             return;
                         }
-                        character.initialize();
+                        let candidate = match user_data.pending_characters.get(index) {
+                            Some(candidate) => candidate,
+                            None => {
+                                data.send_to_user(ClientBoundPacket::CreateNewCharacterResult(Err(ClientFacingError::CharacterUnavailable), name), username);
+                                return;
+                            },
+                        };
+                        let class = match data.class_registry.get(&class_path) {
+                            Some(RegistryNode::Value(class)) if candidate.can_be(class) => class.clone(),
+                            _ => {
+                                data.send_to_user(ClientBoundPacket::CreateNewCharacterResult(Err(ClientFacingError::ClassNotAllowed), name), username);
+                                return;
+                            },
+                        };
+                        let mut character = user_data.pending_characters.remove(index);
+                        if user_data.pending_characters.len() <= MIN_CHARACTER_CHOICES {
+                            user_data.pending_characters.clear();
+                        }
+                        let pending = user_data.pending_characters.clone();
+                        character.class = class;
+                        character.initialize(&mut data.rng);
                         if let Some(prof) = data.proficiency_registry.get("adventuring") {
                             character.add_prof("adventuring", ProficiencyInstance::from_prof(prof.clone(), None));
                         }
                         if let Some(arcane) = &mut character.arcane_spells {
-                            if let Some(spell) = data.spell_registry.random_arcane(0) {
+                            if let Some(spell) = data.spell_registry.random_arcane(0, &mut data.rng) {
                                 arcane.spell_repertoire[0].0.insert(spell);
                             }
                         }
                         user_data.characters.insert(name.clone(), character);
+                        data.send_to_user(ClientBoundPacket::PendingCharacters(pending), username.clone());
                         data.send_to_user(ClientBoundPacket::CreateNewCharacterResult(Ok(()), name), username);
+                        data.request_autosave();
                     }
                     
                 }
             },
-            Self::RequestCharacterUpdate(name, maybe_char) => {
+            Self::RequestCharacterUpdate(name) => {
+                if let Some(username) = data.get_username_by_addr(user) {
+                    // whatever the client had, it wants the whole sheet now
+                    if let Some(session) = data.sessions.get_mut(&username) {
+                        session.sent_characters.remove(&name);
+                    }
+                    if let Some(user_data) = data.user_data.get(&username) {
+                        if let Some(sheet) = user_data.characters.get(&name) {
+                            let sheet = sheet.clone();
+                            data.send_to_user(ClientBoundPacket::UpdateCharacter(name, sheet), username);
+                        }
+                    }
+                }
+            },
+            Self::UpdateCharacterNotes(name, notes) => {
+                if notes.len() > MAX_NOTES_LENGTH {
+                    data.send_to_user_by_addr(ClientBoundPacket::Error(ClientFacingError::NotesTooLong), user);
+                    return;
+                }
                 if let Some(username) = data.get_username_by_addr(user) {
                     if let Some(user_data) = data.user_data.get_mut(&username) {
                         if let Some(sheet) = user_data.characters.get_mut(&name) {
-                            if let Some(user_sheet) = maybe_char {
-                                sheet.notes = user_sheet.notes;
-                                let sheet = sheet.clone();
-                                data.send_to_user(ClientBoundPacket::UpdateCharacter(name, sheet), username);
-                            } else {
-                                let sheet = sheet.clone();
-                                data.send_to_user(ClientBoundPacket::UpdateCharacter(name, sheet), username);
-                            } 
+                            sheet.notes = notes;
                         }
                     }
                 }
             },
             Self::UpdatePlayerNotes(notes) => {
+                if notes.len() > MAX_NOTES_LENGTH {
+                    data.send_to_user_by_addr(ClientBoundPacket::Error(ClientFacingError::NotesTooLong), user);
+                    return;
+                }
                 if let Some(username) = data.get_username_by_addr(user) {
                     if let Some(user_data) = data.user_data.get_mut(&username) {
                         user_data.notes = notes;
@@ -284,7 +537,7 @@ impl ServerBoundPacket {
                 if let Some(username) = data.get_username_by_addr(user) {
                     if let Some(user_data) = data.user_data.get_mut(&username) {
                         if let Some(sheet) = user_data.characters.get_mut(&name) {
-                            if sheet.combat_stats.saving_throw(save) {
+                            if sheet.combat_stats.saving_throw(save, &mut data.rng) {
                                 data.log(ChatMessage::no_sender(format!("{} successfully made a saving throw against {}!", name, save)).dice_roll().color(Color32::LIGHT_GREEN));
                             } else {
                                 data.log(ChatMessage::no_sender(format!("{} failed a saving throw against {}!", name, save)).dice_roll().color(Color32::LIGHT_RED));
@@ -370,9 +623,63 @@ impl ServerBoundPacket {
             },
             Self::MakeRequest(request) => {
                 if let Some(username) = data.get_username_by_addr(user) {
+                    data.log(ChatMessage::no_sender(format!("User \"{}\" requests to: {}. Use /requests to answer.", username, request)).private());
                     data.temp_state.requests.push((username, request));
                 }
             },
+            Self::RollMacro(character, name) => {
+                if let Some(username) = data.get_username_by_addr(user) {
+                    match roll_macro(data, &username, character.as_deref(), &name) {
+                        Ok(msg) => {
+                            data.log(msg);
+                        },
+                        Err(e) => {
+                            data.send_to_user(ClientBoundPacket::ChatMessage(ChatMessage::no_sender(format!("Error: {}", e)).private().light_red()), username);
+                        },
+                    }
+                }
+            },
+            Self::SetMacro(character, name, expr) => {
+                if let Some(username) = data.get_username_by_addr(user) {
+                    if let Some(user_data) = data.user_data.get_mut(&username) {
+                        let macros = match &character {
+                            Some(c) => {
+                                match user_data.characters.get_mut(c) {
+                                    Some(sheet) => &mut sheet.macros,
+                                    None => return,
+                                }
+                            },
+                            None => &mut user_data.macros,
+                        };
+                        let result = match expr {
+                            Some(expr) => {
+                                validate_macro(&name, &expr).and_then(|_| {
+                                    if macros.len() >= MAX_MACROS && !macros.contains_key(&name) {
+                                        Err(format!("You can't save more than {} macros.", MAX_MACROS))
+                                    } else {
+                                        macros.insert(name, expr);
+                                        Ok(())
+                                    }
+                                })
+                            },
+                            None => {
+                                macros.remove(&name);
+                                Ok(())
+                            },
+                        };
+                        let packet = match &character {
+                            Some(c) => user_data.characters.get(c).map(|sheet| ClientBoundPacket::UpdateCharacter(c.clone(), sheet.clone())),
+                            None => Some(ClientBoundPacket::UpdateUserMacros(user_data.macros.clone())),
+                        };
+                        if let Err(e) = result {
+                            data.send_to_user(ClientBoundPacket::ChatMessage(ChatMessage::no_sender(format!("Error: {}", e)).private().light_red()), username.clone());
+                        }
+                        if let Some(packet) = packet {
+                            data.send_to_user(packet, username);
+                        }
+                    }
+                }
+            },
             Self::MakePreRoundDeclaration(combatant, action) => {
                 if let Some(username) = data.get_username_by_addr(user) {
                     if let Some((_, map)) = &mut data.loaded_map {
@@ -422,8 +729,201 @@ impl ServerBoundPacket {
                     });
                 }
             },
+            Self::ResumeSession(token) => {
+                let mut username = None;
+                for (name, session) in &data.sessions {
+                    if tokens_match(&session.token, &token) {
+                        username = Some(name.clone());
+                    }
+                }
+                match username {
+                    Some(username) => {
+                        // the old connection might not have noticed it's dead yet
+                        if let Some(old) = data.connected_users.insert(username.clone(), user) {
+                            for connection in &mut data.streams {
+                                if connection.addr == old && old != user {
+                                    connection.shutdown();
+                                }
+                            }
+                        }
+                        let missed = match data.sessions.get_mut(&username) {
+                            Some(session) => {
+                                session.disconnected_at = None;
+                                // updates sent just before the connection dropped may never have
+                                // arrived, so send whole sheets again
+                                session.sent_characters.clear();
+                                std::mem::take(&mut session.missed_messages)
+                            },
+                            None => Vec::new(),
+                        };
+                        data.send_to_user_by_addr(ClientBoundPacket::ResumeSessionResult(true), user);
+                        sync_user(data, &username, user);
+                        for msg in missed {
+                            data.send_to_user_by_addr(ClientBoundPacket::ChatMessage(msg), user);
+                        }
+                        data.log(ChatMessage::no_sender(format!("User \"{}\" has reconnected.", &username)).blue());
+                    },
+                    None => {
+                        data.send_to_user_by_addr(ClientBoundPacket::ResumeSessionResult(false), user);
+                    },
+                }
+            },
+            Self::AdminCommand(command) => {
+                if let Some(username) = data.get_username_by_addr(user) {
+                    // written to the chat history straight away, so there's a record of which co-DM did what
+                    data.log(ChatMessage::no_sender(format!("{} > {}", username, redact_command(&command))).private());
+                    if let Some(reason) = refuse_co_dm_command(data, &username, &command) {
+                        data.log(ChatMessage::no_sender(reason).private().light_red());
+                        data.flush_chat();
+                        return;
+                    }
+                    data.flush_chat();
+                    if command.starts_with('/') {
+                        parse_command(data, command);
+                    } else {
+                        data.log(ChatMessage::server(command));
+                    }
+                }
+            },
+            Self::RequestRegistries(kinds) => {
+                for kind in kinds {
+                    let packet = match kind {
+                        RegistryKind::Classes => ClientBoundPacket::UpdateClassRegistry(data.registry_versions.classes.clone(), data.class_registry.clone()),
+                        RegistryKind::Proficiencies => ClientBoundPacket::UpdateProfRegistry(data.registry_versions.proficiencies.clone(), data.proficiency_registry.clone()),
+                        RegistryKind::Spells => ClientBoundPacket::UpdateSpellRegistry(data.registry_versions.spells.clone(), data.spell_registry.clone()),
+                    };
+                    data.send_to_user_by_addr(packet, user);
+                }
+            },
+            Self::ProtocolError(e) => {
+                let name = data.get_username_by_addr(user).unwrap_or_else(|| user.to_string());
+                data.log(ChatMessage::no_sender(format!("Client \"{}\" could not read a packet: {}", name, e)).private().light_red());
+            },
+        }
+    }
+}
+
+/// Turns a banned user away, even though their credentials were right. Returns true if they
+/// were.
+fn refuse_if_banned(data: &mut DMAppData, username: &str, user: SocketAddr) -> bool {
+    if !data.banned.contains(username) {
+        return false;
+    }
+    data.send_to_user_by_addr(ClientBoundPacket::LogInResult(false, None), user);
+    data.send_to_user_by_addr(ClientBoundPacket::Error(ClientFacingError::Banned), user);
+    data.log(ChatMessage::no_sender(format!("Banned user \"{}\" tried to log in.", username)).private().light_red());
+    true
+}
+
+/// Logs in a user whose password was checked against `hash`, unless it was wrong or the password
+/// was changed while it was being checked.
+fn finish_password_log_in(data: &mut DMAppData, username: String, hash: Option<String>, correct: bool, remember: bool, user: SocketAddr) {
+    if !correct || hash.is_none() || data.known_users.get(&username) != hash.as_ref() {
+        data.send_to_user_by_addr(ClientBoundPacket::LogInResult(false, None), user);
+        return;
+    }
+    if refuse_if_banned(data, &username, user) {
+        return;
+    }
+    let mut token = None;
+    if remember {
+        let new_token = new_login_token();
+        let tokens = &mut data.user_data.entry(username.clone()).or_insert_with(UserData::new).login_tokens;
+        tokens.push(hash_token(&new_token));
+        if tokens.len() > MAX_LOGIN_TOKENS {
+            tokens.remove(0);
+        }
+        token = Some(new_token);
+    }
+    log_in(data, username, user, token);
+}
+
+/// Turns away an account that can't be created with this username. Returns true if it was.
+fn refuse_account(data: &mut DMAppData, username: &str, user: SocketAddr) -> bool {
+    if data.registration_closed {
+        data.send_to_user_by_addr(ClientBoundPacket::CreateAccountResult(false, username.to_owned()), user);
+        data.send_to_user_by_addr(ClientBoundPacket::Error(ClientFacingError::RegistrationClosed), user);
+        true
+    } else if data.known_users.contains_key(username) {
+        data.send_to_user_by_addr(ClientBoundPacket::CreateAccountResult(false, username.to_owned()), user);
+        data.send_to_user_by_addr(ClientBoundPacket::Error(ClientFacingError::UsernameTaken), user);
+        true
+    } else if let Err(e) = validate_username(username) {
+        data.send_to_user_by_addr(ClientBoundPacket::CreateAccountResult(false, username.to_owned()), user);
+        data.send_to_user_by_addr(ClientBoundPacket::Error(e), user);
+        true
+    } else {
+        false
+    }
+}
+
+/// Creates an account with a hashed password, checking the username again in case it was taken
+/// while the password was being hashed.
+fn finish_create_account(data: &mut DMAppData, username: String, hash: Result<String, String>, user: SocketAddr) {
+    if refuse_account(data, &username, user) {
+        return;
+    }
+    match hash {
+        Ok(hash) => {
+            data.log(ChatMessage::no_sender(format!("User \"{}\" has been created.", &username)).private().blue());
+            data.known_users.insert(username.clone(), hash);
+            data.send_to_user_by_addr(ClientBoundPacket::CreateAccountResult(true, username), user);
+        },
+        Err(e) => {
+            data.log(ChatMessage::no_sender(format!("Could not create user \"{}\": {}", &username, e)).private().red());
+            data.send_to_user_by_addr(ClientBoundPacket::CreateAccountResult(false, username), user);
+        },
+    }
+}
+
+/// Logs in a user whose credentials have already been checked and sends them everything they
+/// need.
+fn log_in(data: &mut DMAppData, username: String, user: SocketAddr, token: Option<String>) {
+    data.log(ChatMessage::no_sender(format!("User \"{}\" has logged in!", &username)).blue());
+    data.connected_users.insert(username.clone(), user);
+    if !data.user_data.contains_key(&username) {
+        data.user_data.insert(username.clone(), UserData::new());
+    }
+    let session_token = new_login_token();
+    data.sessions.insert(username.clone(), Session::new(session_token.clone()));
+    data.send_to_user_by_addr(ClientBoundPacket::LogInResult(true, token), user);
+    data.send_to_user_by_addr(ClientBoundPacket::SessionToken(session_token), user);
+    data.send_to_user_by_addr(ClientBoundPacket::RegistryVersions(data.registry_versions.clone()), user);
+    sync_user(data, &username, user);
+}
+
+/// Sends a user the current state of everything that is theirs: notes, macros, characters,
+/// parties and any fight they are in.
+pub fn sync_user(data: &mut DMAppData, username: &str, user: SocketAddr) {
+    if let Some(user_data) = data.user_data.get(username) {
+        data.send_to_user_by_addr(ClientBoundPacket::UpdatePlayerNotes(user_data.notes.clone()), user);
+    }
+    if let Some(user_data) = data.user_data.get(username) {
+        data.send_to_user_by_addr(ClientBoundPacket::UpdateUserMacros(user_data.macros.clone()), user);
+    }
+    if let Some(user_data) = data.user_data.get(username) {
+        for (name, character) in user_data.characters.clone() {
+            data.send_to_user_by_addr(ClientBoundPacket::UpdateCharacter(name, character), user);
+        }
+    }
+    if let Some(user_data) = data.user_data.get(username) {
+        if !user_data.pending_characters.is_empty() {
+            data.send_to_user_by_addr(ClientBoundPacket::PendingCharacters(user_data.pending_characters.clone()), user);
         }
     }
+    data.send_to_user_by_addr(ClientBoundPacket::UpdateParties(data.parties.clone()), user);
+    let fight = match &data.loaded_map {
+        Some((_, map)) => map.fight.clone(),
+        None => None,
+    };
+    match fight {
+        Some(fight) if fight.started && fight.combatants.iter().any(|(owner, _)| *owner == Owner::Player(username.to_owned())) => {
+            fight.update_specific_client(data, username.to_owned());
+        },
+        _ => {
+            data.send_to_user_by_addr(ClientBoundPacket::UpdateCombatState(None), user);
+        },
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -440,6 +940,14 @@ pub enum ClientFacingError {
     CharacterNameTaken,
     CharacterNameTooLong,
     CharacterNameInvalid,
+    CharacterUnavailable,
+    ClassNotAllowed,
+    MessageTooLong,
+    NotesTooLong,
+    RateLimited,
+    Disconnected,
+    Banned,
+    RegistrationClosed,
     Generic,
 }
 
@@ -453,6 +961,14 @@ impl std::fmt::Display for ClientFacingError {
             Self::CharacterNameTaken => "You already have a character with that name. Pick something else.",
             Self::CharacterNameTooLong => "That name is too long. Pick something shorter.",
             Self::CharacterNameInvalid => "That name is disallowed.",
+            Self::CharacterUnavailable => "That character is no longer available.",
+            Self::ClassNotAllowed => "This character can't be that class. Pick a different one.",
+            Self::MessageTooLong => "That message is too long.",
+            Self::NotesTooLong => "Those notes are too long to save. Move some of them somewhere else.",
+            Self::RateLimited => "You're doing that too often. Slow down a little.",
+            Self::Disconnected => "You have been disconnected for sending too much too quickly.",
+            Self::Banned => "You have been banned from this server.",
+            Self::RegistrationClosed => "This server isn't accepting new accounts right now.",
         })
     }
 }
@@ -3,29 +3,29 @@ use crate::{AppPreferences, WindowPreferences};
 use crate::character::{PlayerCharacter, Attr, PlayerEquipSlot};
 use crate::class::{Class, ClassDamageBonus, Cleaves, DivineValue, ArcaneValue};
 use crate::combat::{Combatant, SavingThrowType, MovementAction, AttackAction, PreRoundAction, SpecialManeuver};
-use crate::common_ui::{CharacterSheetTab, self, back_arrow, TabCallbackMode, ChatMessage, link_button};
+use crate::common_ui::{CharacterSheetTab, self, back_arrow, TabCallbackMode, ChatMessage, link_button, ChatLogEntry, chat_log_entry, command_tokens};
 use crate::dm_app::{Registry, RegistryNode};
+use crate::discovery::{Discovery, BEACON_INTERVAL};
 use crate::item::{WeaponDamage, MeleeDamage, ContainerStats};
 use crate::proficiency::Proficiency;
 use crate::spell::{Spell, SpellRegistry, MagicType};
 use eframe::egui::{self, RichText, Ui, WidgetText};
 use eframe::epaint::{Rgba, Color32};
-use egui::text::LayoutJob;
 use egui_dock::{TabViewer, Tree, DockArea, TabDestination, TabIndex};
 use egui_extras::{StripBuilder, Size};
 use serde::{Serialize, Deserialize};
 use simple_enum_macro::simple_enum;
 use thousands::Separable;
+use crate::network::{Connection, Handshake, NetEvent, NetEventKind, PinCheck, HANDSHAKE_TIMEOUT, RECONNECT_GRACE, IDLE_CHECK_INTERVAL, CERTIFICATE_CHANGED, decode_frame, connect, check_pin};
 use crate::packets::{ClientBoundPacket, ServerBoundPacket, ClientFacingError, Request};
-use std::collections::{HashMap, HashSet};
-use std::net::TcpStream;
-use std::io::{prelude::*, ErrorKind};
+use std::collections::{HashMap, HashSet, BTreeMap};
+use std::time::{Duration, Instant};
 use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
 use egui_phosphor as ep;
 
-/// How often to check for incoming packets, in milliseconds. Setting this too low may cause 
-/// performance problems due to acquiring a lock on a mutex.
-pub const CLIENT_UPDATE_CLOCK: u64 = 50;
+/// How long to wait between attempts to reconnect after losing the connection.
+pub const RECONNECT_INTERVAL: Duration = Duration::from_secs(2);
 
 pub fn run(prefs: AppPreferences) -> Result<(), eframe::Error> {
     eframe::run_native(
@@ -40,7 +40,7 @@ pub fn run(prefs: AppPreferences) -> Result<(), eframe::Error> {
                 ..Default::default()
             }
         }, 
-        Box::new(|ctx| {
+        Box::new(move |ctx| {
             let mut fonts = egui::FontDefinitions::default();
             egui_phosphor::add_to_fonts(&mut fonts);
             ctx.egui_ctx.set_fonts(fonts);
@@ -51,10 +51,12 @@ pub fn run(prefs: AppPreferences) -> Result<(), eframe::Error> {
                     if let Some(ip) = prefs.player_last_ip {
                         data.ip_address = ip;
                     }
-                    if let Some((username, password)) = prefs.player_login {
+                    data.encrypt = !prefs.player_unencrypted;
+                    data.known_servers = prefs.known_servers;
+                    if let Some((username, token)) = prefs.player_token {
                         data.remember_me = true;
                         data.username = username;
-                        data.password = password;
+                        data.login_token = Some(token);
                     }
                 }
                 app
@@ -65,21 +67,38 @@ pub fn run(prefs: AppPreferences) -> Result<(), eframe::Error> {
 pub struct PlayerAppData {
     pub window_states: HashMap<String, bool>,
     pub ip_address: String,
-    pub show_error: bool,
-    pub stream: Option<TcpStream>,
-    pub buffered_packet: Vec<u8>,
+    /// Why the last connection attempt failed or was dropped, if it was.
+    pub connect_error: Option<String>,
+    pub connection: Option<Connection>,
+    pub encrypt: bool,
+    /// Certificate fingerprints pinned the first time we connected to each server, by address.
+    pub known_servers: HashMap<String, String>,
+    /// Set when a server's certificate doesn't match the pinned one, so the player can choose to
+    /// trust the new one.
+    pub untrusted_fingerprint: Option<String>,
+    /// Listens for games hosted on the LAN, unless the port was already taken.
+    pub discovery: Option<Discovery>,
+    /// Lets us resume our session if the connection drops.
+    pub session_token: Option<String>,
+    /// When the connection dropped, if we are currently trying to get it back.
+    pub reconnecting: Option<Instant>,
     pub logged_in: bool,
-    pub logs: Vec<LayoutJob>,
+    pub logs: Vec<ChatLogEntry>,
     pub chat_box: String,
     pub unread_messages: u32,
     pub unread_msg_buffer: bool,
     pub username: String,
     pub password: String,
     pub remember_me: bool,
+    /// A token the server gave us to log in with instead of the password.
+    pub login_token: Option<String>,
+    pub login_error: Option<String>,
     pub class_registry: Registry<Class>,
     pub spell_registry: SpellRegistry,
     pub proficiency_registry: HashMap<String, Proficiency>,
     pub sorted_prof_list: Vec<(String, String)>,
+    /// The registries from the last server we logged in to, so they needn't be sent again.
+    pub registry_cache: RegistryCache,
     pub viewed_class: Option<String>,
     pub viewed_spell: Option<(MagicType, Option<(u8, Option<String>)>)>,
     pub viewed_prof: Option<String>,
@@ -88,6 +107,9 @@ pub struct PlayerAppData {
     pub characters: HashMap<String, PlayerCharacter>,
     pub character_window_tab_state: HashMap<String, CharacterSheetTab>,
     pub new_char_class: Option<Class>,
+    /// The registry path of the class picked for the new character, which is what the server
+    /// is told.
+    pub picked_class: Option<String>,
     pub new_char_name: Option<String>,
     pub notes: String,
     pub new_characters: Vec<PlayerCharacter>,
@@ -99,6 +121,9 @@ pub struct PlayerAppData {
     pub combat_just_started: bool,
     pub parties: HashMap<String, Party>,
     pub temp_party: (String, Color32),
+    pub user_macros: BTreeMap<String, String>,
+    pub new_macro_name: String,
+    pub new_macro_expr: String,
 }
 
 impl PlayerAppData {
@@ -106,9 +131,14 @@ impl PlayerAppData {
         Self {
             window_states: HashMap::new(),
             ip_address: String::new(),
-            show_error: false,
-            stream: None,
-            buffered_packet: Vec::new(),
+            connect_error: None,
+            connection: None,
+            encrypt: true,
+            known_servers: HashMap::new(),
+            untrusted_fingerprint: None,
+            discovery: Discovery::start().ok(),
+            session_token: None,
+            reconnecting: None,
             logged_in: false,
             logs: Vec::new(),
             chat_box: String::new(),
@@ -117,10 +147,13 @@ impl PlayerAppData {
             username: String::new(),
             password: String::new(),
             remember_me: false,
+            login_token: None,
+            login_error: None,
             class_registry: Registry::new(),
             spell_registry: SpellRegistry::new(),
             proficiency_registry: HashMap::new(),
             sorted_prof_list: Vec::new(),
+            registry_cache: RegistryCache::load(),
             viewed_class: None,
             viewed_spell: None,
             viewed_prof: None,
@@ -129,6 +162,7 @@ impl PlayerAppData {
             characters: HashMap::new(),
             character_window_tab_state: HashMap::new(),
             new_char_class: None,
+            picked_class: None,
             new_char_name: None,
             notes: String::new(),
             new_characters: Vec::new(),
@@ -140,29 +174,62 @@ impl PlayerAppData {
             combat_just_started: false,
             parties: HashMap::new(),
             temp_party: (String::new(), Color32::WHITE),
+            user_macros: BTreeMap::new(),
+            new_macro_name: String::new(),
+            new_macro_expr: String::new(),
         }
     }
 
-    pub fn write_to_stream(&mut self, msg: String) {
-        let func = |stream: &mut TcpStream, msg: String| -> std::io::Result<()> {
-            stream.write_all(msg.as_bytes())?;
-            stream.write_all(&[255])?;
-            stream.flush()?;
-            Ok(())
-        };
-        if let Some(stream) = self.stream.as_mut() {
-            match func(stream, msg) {
+    /// Replaces the proficiency registry, keeping the sorted list in step with it.
+    pub fn set_prof_registry(&mut self, profs: HashMap<String, Proficiency>) {
+        self.sorted_prof_list.clear();
+        for (id, prof) in &profs {
+            self.sorted_prof_list.push((id.clone(), prof.name.clone()));
+        }
+        self.sorted_prof_list.sort();
+        self.proficiency_registry = profs;
+    }
+
+    /// Shows a message in this client's chat only.
+    pub fn log_local(&mut self, msg: ChatMessage) {
+        self.logs.insert(0, msg.private().to_log_entry());
+    }
+
+    pub fn send_to_server(&mut self, packet: ServerBoundPacket) {
+        if let Some(connection) = &self.connection {
+            match connection.send(&packet) {
                 Ok(_) => {},
-                Err(_) => {
-                    self.stream = None;
+                Err(e) => {
+                    self.connection_lost(format!("Lost connection to the server: {}", e));
                 },
             }
         }
     }
 
-    pub fn send_to_server(&mut self, packet: ServerBoundPacket) {
-        if let Ok(msg) = ron::to_string(&packet) {
-            self.write_to_stream(msg);
+    /// Drops the connection to the server and returns to the connect screen, showing the reason.
+    pub fn disconnect(&mut self, reason: String) {
+        if let Some(connection) = self.connection.take() {
+            connection.shutdown();
+        }
+        self.logged_in = false;
+        self.session_token = None;
+        self.reconnecting = None;
+        self.connect_error = Some(reason);
+    }
+
+    /// Called when the connection drops unexpectedly. If we have a session, keeps everything as
+    /// it is and tries to reconnect in the background; otherwise it's the same as [`Self::disconnect`].
+    pub fn connection_lost(&mut self, reason: String) {
+        if !self.logged_in || self.session_token.is_none() {
+            self.disconnect(reason);
+            return;
+        }
+        if let Some(connection) = self.connection.take() {
+            connection.shutdown();
+        }
+        if self.reconnecting.is_none() {
+            self.reconnecting = Some(Instant::now());
+            self.log_local(ChatMessage::no_sender(format!("{}. Trying to reconnect...", reason)).light_red());
         }
     }
 
@@ -175,6 +242,30 @@ impl PlayerAppData {
     }
 }
 
+/// Registries recieved from a server, with their versions. Saved to `registry_cache.ron`.
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct RegistryCache {
+    pub classes: Option<(String, Registry<Class>)>,
+    pub proficiencies: Option<(String, HashMap<String, Proficiency>)>,
+    pub spells: Option<(String, SpellRegistry)>,
+}
+
+impl RegistryCache {
+    /// Reads the cache from disk. It's only a cache, so anything wrong with it just means
+    /// starting empty.
+    pub fn load() -> Self {
+        std::fs::read_to_string("registry_cache.ron").ok()
+            .and_then(|s| ron::from_str(&s).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self) {
+        if let Ok(s) = ron::to_string(self) {
+            let _ = std::fs::write("registry_cache.ron", s);
+        }
+    }
+}
+
 pub struct PlayerApp {
     pub data: Arc<Mutex<PlayerAppData>>,
     pub tree: Tree<PlayerTab>,
@@ -210,28 +301,62 @@ impl PlayerApp {
         data.window_states.insert("chat_window".to_owned(), temp_open);
     }
 
-    fn connect_screen(ctx: &egui::Context, data: &mut PlayerAppData) -> bool {
+    /// Shows the connect screen. Returns the new connection's events if the player connected.
+    fn connect_screen(ctx: &egui::Context, data: &mut PlayerAppData) -> Option<Receiver<NetEvent>> {
         egui::CentralPanel::default().show(ctx, |ui| {
             ui.vertical_centered(|ui| {
                 ui.add_space((ui.available_height() / 2.0) - 65.0);
                 ui.label(RichText::new("ACKS Player Tool").strong().size(30.0));
                 ui.add_space(10.0);
+                let mut join = false;
+                if let Some(discovery) = &data.discovery {
+                    // games come and go without any input to wake us up
+                    ctx.request_repaint_after(BEACON_INTERVAL);
+                    let servers = discovery.servers();
+                    if servers.is_empty() {
+                        ui.label(RichText::new("Looking for games on your network...").weak().italics());
+                    }
+                    for (address, beacon) in servers {
+                        if ui.button(format!("Join {} ({})", beacon.campaign, address)).clicked() {
+                            data.ip_address = address;
+                            if beacon.require_encryption {
+                                data.encrypt = true;
+                            }
+                            join = true;
+                        }
+                    }
+                    ui.add_space(10.0);
+                }
                 let res = ui.add(egui::TextEdit::singleline(&mut data.ip_address).hint_text(RichText::new("Enter IP...").weak().italics()));
-                if ui.button("Connect").clicked() || (res.lost_focus() && ctx.input(|i| i.key_pressed(egui::Key::Enter))) {
-                    if let Ok(stream) = TcpStream::connect(data.ip_address.trim()) {
-                        stream.set_nonblocking(true).unwrap();
-                        data.stream = Some(stream);
-                        return true;
-                    } else {
-                        data.show_error = true;
+                if ui.button("Connect").clicked() || join || (res.lost_focus() && ctx.input(|i| i.key_pressed(egui::Key::Enter))) {
+                    match connect(data.ip_address.trim(), data.encrypt) {
+                        Ok((connection, events)) => {
+                            data.connect_error = None;
+                            data.untrusted_fingerprint = None;
+                            data.connection = Some(connection);
+                            return Some(events);
+                        },
+                        Err(_) => {
+                            data.connect_error = Some("Could not connect to server.".to_owned());
+                        },
                     }
                 }
-                if data.show_error {
-                    ui.colored_label(Rgba::RED, "Could not connect to server.");
-                } else {
+                ui.checkbox(&mut data.encrypt, "Encrypt connection");
+                if let Some(e) = &data.connect_error {
+                    ui.colored_label(Rgba::RED, e);
+                }
+                if let Some(fingerprint) = data.untrusted_fingerprint.clone() {
+                    ui.label(RichText::new(format!("New fingerprint: {}", fingerprint)).small());
+                    if ui.button("Trust new certificate").clicked() {
+                        data.known_servers.insert(data.ip_address.trim().to_owned(), fingerprint);
+                        data.untrusted_fingerprint = None;
+                        data.connect_error = None;
+                    }
+                }
+                if data.connect_error.is_none() {
                     ui.label(RichText::new("Caution! Using this app will expose your IP address! This app is not a secure platform, do not store any sensitive information within!").color(Color32::YELLOW).small());
                 }
-                false
+                None
             }).inner
         }).inner
     }
@@ -250,8 +375,11 @@ impl PlayerApp {
                         ui.label("Username:");
                         let res1 = ui.text_edit_singleline(&mut data.username);
                         ui.label("Password:");
-                        let res2 = ui.text_edit_singleline(&mut data.password);
-                        ui.colored_label(Rgba::RED, "Warning! Username and password are NOT stored securely!");
+                        let hint = if data.login_token.is_some() {"Remembered"} else {""};
+                        let res2 = ui.add(egui::TextEdit::singleline(&mut data.password).password(true).hint_text(RichText::new(hint).weak().italics()));
+                        if let Some(e) = &data.login_error {
+                            ui.colored_label(Rgba::RED, e);
+                        }
                         let enter_pressed = ui.input(|i| i.key_pressed(egui::Key::Enter)) && (res1.lost_focus() || res2.lost_focus());
                         StripBuilder::new(ui)
                             .size(Size::remainder())
@@ -262,13 +390,22 @@ impl PlayerApp {
                                 strip.empty();
                                 strip.cell(|ui| {
                                     if ui.button("Log in").clicked() || enter_pressed {
-                                        data.send_to_server(ServerBoundPacket::AttemptLogIn(data.username.clone(), data.password.clone()));     
+                                        data.login_error = None;
+                                        match &data.login_token {
+                                            Some(token) if data.password.is_empty() => {
+                                                data.send_to_server(ServerBoundPacket::LogInWithToken(data.username.clone(), token.clone()));
+                                            },
+                                            _ => {
+                                                data.send_to_server(ServerBoundPacket::AttemptLogIn(data.username.clone(), data.password.clone(), data.remember_me));
+                                            },
+                                        }
                                     }
                                     ui.checkbox(&mut data.remember_me, "Remember me");
                                 });
                                 strip.empty();
                             });
                         if ui.button("Create account").clicked() {
+                            data.login_error = None;
                             data.send_to_server(ServerBoundPacket::CreateAccount(data.username.clone(), data.password.clone()));
                         }
                     });
@@ -338,13 +475,15 @@ pub fn chat(ui: &mut Ui, data: &mut PlayerAppData) {
     ui.with_layout(egui::Layout::top_down_justified(egui::Align::Min), |ui| {
         let response = ui.text_edit_singleline(&mut data.chat_box);
         if response.lost_focus() && ui.input(|input| input.key_pressed(egui::Key::Enter)) {
-            if !data.chat_box.trim().is_empty() {
+            if data.chat_box.starts_with('/') {
+                parse_command(data, data.chat_box.clone());
+            } else if !data.chat_box.trim().is_empty() {
                 data.send_to_server(ServerBoundPacket::ChatMessage(ChatMessage::player(data.username.clone(), data.chat_box.clone())));  
             }
             data.chat_box.clear();
         }
         for (i, log) in data.logs.iter().enumerate() {
-            ui.label(log.clone());
+            chat_log_entry(ui, log);
             if i == 0 {
                 ui.separator();
             }
@@ -352,6 +491,71 @@ pub fn chat(ui: &mut Ui, data: &mut PlayerAppData) {
     });
 }
 
+/// Handles the few chat commands that players can use.
+pub fn parse_command(data: &mut PlayerAppData, mut command: String) {
+    command.remove(0);
+    let mut tree = command_tokens(&command);
+    match tree.next() {
+        Some("m") => {
+            if let Some(name) = tree.next() {
+                let character = tree.next().map(|c| c.to_owned());
+                data.send_to_server(ServerBoundPacket::RollMacro(character, name.to_owned()));
+            } else {
+                data.log_local(ChatMessage::no_sender("You must specify a macro. Run /help for more info.").light_red());
+            }
+        },
+        Some("macro") => {
+            match tree.next() {
+                Some("add") => {
+                    match (tree.next(), tree.next()) {
+                        (Some(name), Some(expr)) => {
+                            let character = tree.next().map(|c| c.to_owned());
+                            data.send_to_server(ServerBoundPacket::SetMacro(character, name.to_owned(), Some(expr.to_owned())));
+                        },
+                        _ => {
+                            data.log_local(ChatMessage::no_sender("You must specify a name and a dice expression.").light_red());
+                        },
+                    }
+                },
+                Some("remove") => {
+                    if let Some(name) = tree.next() {
+                        let character = tree.next().map(|c| c.to_owned());
+                        data.send_to_server(ServerBoundPacket::SetMacro(character, name.to_owned(), None));
+                    } else {
+                        data.log_local(ChatMessage::no_sender("You must specify a macro.").light_red());
+                    }
+                },
+                Some("list") => {
+                    let mut msg = "Your macros:".to_owned();
+                    for (name, expr) in &data.user_macros {
+                        msg.push_str(&format!("\n- {}: {}", name, expr));
+                    }
+                    for (character, sheet) in &data.characters {
+                        for (name, expr) in &sheet.macros {
+                            msg.push_str(&format!("\n- {}: {} ({})", name, expr, character));
+                        }
+                    }
+                    data.log_local(ChatMessage::no_sender(msg));
+                },
+                _ => {
+                    data.log_local(ChatMessage::no_sender("Expected add, remove or list. Run /help for more info.").light_red());
+                },
+            }
+        },
+        Some("help") => {
+            let mut msg = "List of all commands:".to_owned();
+            msg.push_str("\n/m <macro> <character>: Rolls a saved macro. <character> is optional if you only have one.");
+            msg.push_str("\n/macro add <name> <expression> <character>: Saves a macro, e.g. /macro add longsword 1d8+STR. Saved for all your characters unless <character> is given. Character macros may use attribute modifiers (STR, DEX, etc.).");
+            msg.push_str("\n/macro remove <name> <character>: Removes a macro.");
+            msg.push_str("\n/macro list: Lists all of your macros.");
+            data.log_local(ChatMessage::no_sender(msg));
+        },
+        t => {
+            data.log_local(ChatMessage::no_sender(format!("Unknown command \"{}\". Run /help for a list of commands.", t.unwrap_or(""))).light_red());
+        },
+    }
+}
+
 pub struct PlayerTabViewer<'a, F: FnMut(PlayerTab, TabCallbackMode)> {
     pub callback: &'a mut F,
     pub data: &'a mut PlayerAppData,
@@ -465,6 +669,36 @@ impl<'a, F: FnMut(PlayerTab, TabCallbackMode) + 'a> PlayerTabViewer<'a, F> {
                                 }
                             });
                         });
+                        ui.separator();
+                        ui.label("Macros:")
+                            .on_hover_text("Saved rolls. They can use your attribute modifiers (e.g. 1d8+STR), and can also be rolled from chat with /m <name>.");
+                        let mut shown = Vec::new();
+                        for (macro_name, expr) in sheet.macros.iter().chain(data.user_macros.iter()) {
+                            if shown.contains(&macro_name) {
+                                continue;
+                            }
+                            shown.push(macro_name);
+                            let is_character_macro = sheet.macros.contains_key(macro_name);
+                            ui.horizontal(|ui| {
+                                if ui.button(macro_name).on_hover_text(expr).clicked() {
+                                    packets.push(ServerBoundPacket::RollMacro(Some(name.clone()), macro_name.clone()));
+                                }
+                                ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+                                    if ui.small_button(ep::TRASH).on_hover_text(if is_character_macro {"Remove"} else {"Remove (from all your characters)"}).clicked() {
+                                        packets.push(ServerBoundPacket::SetMacro(if is_character_macro {Some(name.clone())} else {None}, macro_name.clone(), None));
+                                    }
+                                });
+                            });
+                        }
+                        ui.horizontal(|ui| {
+                            ui.add(egui::TextEdit::singleline(&mut data.new_macro_name).hint_text("Name").desired_width(80.0));
+                            ui.add(egui::TextEdit::singleline(&mut data.new_macro_expr).hint_text("e.g. 1d8+STR").desired_width(100.0));
+                            if ui.small_button(ep::PLUS).on_hover_text("Save macro for this character").clicked() {
+                                packets.push(ServerBoundPacket::SetMacro(Some(name.clone()), data.new_macro_name.trim().to_owned(), Some(data.new_macro_expr.clone())));
+                                data.new_macro_name.clear();
+                                data.new_macro_expr.clear();
+                            }
+                        });
                     },
                     CharacterSheetTab::Class => {
                         ui.label(format!("Class: {}", sheet.class.name));
@@ -836,14 +1070,14 @@ impl<'a, F: FnMut(PlayerTab, TabCallbackMode) + 'a> PlayerTabViewer<'a, F> {
                     CharacterSheetTab::Notes => {
                         ui.centered_and_justified(|ui| {
                             if ui.text_edit_multiline(&mut sheet.notes).lost_focus() {
-                                packets.push(ServerBoundPacket::RequestCharacterUpdate(name.clone(), Some(sheet.clone())));
+                                packets.push(ServerBoundPacket::UpdateCharacterNotes(name.clone(), sheet.notes.clone()));
                             }
                         });
                     },
                 }
             });
             if update {
-                packets.push(ServerBoundPacket::RequestCharacterUpdate(name.clone(), Some(sheet.clone())));
+                packets.push(ServerBoundPacket::UpdateCharacterNotes(name.clone(), sheet.notes.clone()));
             }
         } else {
             ui.colored_label(ui.visuals().error_fg_color, "This character doesn't appear to exist!");
@@ -869,6 +1103,7 @@ impl<'a, F: FnMut(PlayerTab, TabCallbackMode) + 'a> PlayerTabViewer<'a, F> {
                                         if data.new_char_name.is_none() && data.picked_character.is_some() {
                                             if ui.button("Pick!").clicked() {
                                                 data.new_char_class = Some(class.clone());
+                                                data.picked_class = Some(path.clone());
                                                 go_back = true;
                                                 (self.callback)(PlayerTab::ClassViewer, TabCallbackMode::Remove);
                                             }
@@ -1021,26 +1256,10 @@ impl<'a, F: FnMut(PlayerTab, TabCallbackMode) + 'a> PlayerTabViewer<'a, F> {
                                     for (path, node) in reg {
                                         match node {
                                             RegistryNode::Value(class) => {
-                                                if class.race == sheet.race {
-                                                    let mut allowed = true;
-                                                    for req in &class.prime_reqs {
-                                                        if match *req {
-                                                            Attr::STR => sheet.combat_stats.attributes.strength,
-                                                            Attr::DEX => sheet.combat_stats.attributes.dexterity,
-                                                            Attr::CON => sheet.combat_stats.attributes.constitution,
-                                                            Attr::INT => sheet.combat_stats.attributes.intelligence,
-                                                            Attr::WIS => sheet.combat_stats.attributes.wisdom,
-                                                            Attr::CHA => sheet.combat_stats.attributes.charisma,
-                                                        } < 9 {
-                                                            allowed = false;
-                                                            break;
-                                                        }
-                                                    }
-                                                    if allowed {
-                                                        if ui.button(format!("View: {}", class.name)).clicked() {
-                                                            return Some(path.clone());
-                                                        } 
-                                                    }
+                                                if sheet.can_be(class) {
+                                                    if ui.button(format!("View: {}", class.name)).clicked() {
+                                                        return Some(path.clone());
+                                                    } 
                                                 }
                                             },
                                             RegistryNode::SubRegistry(sub) => {
@@ -1338,13 +1557,8 @@ impl<'a, F: FnMut(PlayerTab, TabCallbackMode) + 'a> PlayerTabViewer<'a, F> {
                 }
             } else {
                 match data.requests.consume(Request::GenerateCharacters) {
-                    Some(approved) => {
-                        if approved {
-                            for _ in 0..5 {
-                                data.new_characters.push(PlayerCharacter::random());
-                            }
-                        }
-                    },
+                    // if approved, the server rolls the characters and sends them itself
+                    Some(_) => {},
                     None => {
                         ui.label("Waiting for the DM to answer your request...");
                     },
@@ -1354,9 +1568,9 @@ impl<'a, F: FnMut(PlayerTab, TabCallbackMode) + 'a> PlayerTabViewer<'a, F> {
             ui.vertical_centered(|ui| {
                 if let Some(name) = &mut data.new_char_name {
                     if (ui.add(egui::TextEdit::singleline(name).hint_text("Give your new character a name...")).lost_focus() && ui.input(|input| input.key_pressed(egui::Key::Enter))) || ui.button("Ok").clicked() {
-                        if let Some(sheet) = data.new_characters.get(i) {
-                            let name = name.clone();
-                            data.send_to_server(ServerBoundPacket::CreateNewCharacter(name, sheet.clone()));
+                        if let Some(class_path) = &data.picked_class {
+                            let packet = ServerBoundPacket::CreateNewCharacter(name.clone(), i, class_path.clone());
+                            data.send_to_server(packet);
                         }
                     }
                     if let Some(e) = data.new_char_name_error {
@@ -1390,10 +1604,6 @@ impl<'a, F: FnMut(PlayerTab, TabCallbackMode) + 'a> PlayerTabViewer<'a, F> {
             });
         } else {
             ui.horizontal(|ui| {
-                if data.new_characters.len() <= 3 {
-                    data.new_characters.clear();
-                    return;
-                }
                 let mut picked = None;
                 for (i, sheet) in data.new_characters.iter().enumerate() {
                     ui.vertical(|ui| {
@@ -1713,10 +1923,15 @@ impl eframe::App for PlayerApp {
             if let Ok(mut prefs) = ron::from_str::<AppPreferences>(&s) {
                 prefs.player_window = Some(data.prefs.clone());
                 prefs.player_last_ip = Some(data.ip_address.clone());
-                if data.remember_me {
-                    prefs.player_login = Some((data.username.clone(), data.password.clone()));
-                } else {
-                    prefs.player_login = None;
+                prefs.player_unencrypted = !data.encrypt;
+                prefs.known_servers = data.known_servers.clone();
+                match &data.login_token {
+                    Some(token) if data.remember_me => {
+                        prefs.player_token = Some((data.username.clone(), token.clone()));
+                    },
+                    _ => {
+                        prefs.player_token = None;
+                    },
                 }
                 let _ = std::fs::write("preferences.ron", ron::to_string(&prefs).unwrap_or(s));
             }
@@ -1731,13 +1946,28 @@ impl eframe::App for PlayerApp {
         let pos = info.position.unwrap_or_default();
         data.prefs.pos = (pos.x, pos.y);
         data.prefs.size = (info.size.x, info.size.y);
-        if data.stream.is_none() {
-            if Self::connect_screen(ctx, data) {
-                let data_clone = Arc::clone(&self.data);
-                std::thread::spawn(move || {
-                    handle_packets(data_clone);
+        // while reconnecting, keep showing everything as it was
+        let reconnecting = data.reconnecting.is_some();
+        let handshake_done = match &data.connection {
+            Some(connection) => connection.handshake_done || reconnecting,
+            None if reconnecting => true,
+            None => {
+                if let Some(events) = Self::connect_screen(ctx, data) {
+                    let data_clone = Arc::clone(&self.data);
+                    std::thread::spawn(move || {
+                        handle_packets(data_clone, events);
+                    });
+                }
+                return;
+            },
+        };
+        if !handshake_done {
+            egui::CentralPanel::default().show(ctx, |ui| {
+                ui.vertical_centered(|ui| {
+                    ui.add_space((ui.available_height() / 2.0) - 10.0);
+                    ui.label(RichText::new("Connecting...").weak().italics());
                 });
-            }
+            });
             return;
         }
         if !data.logged_in {
@@ -1749,6 +1979,9 @@ impl eframe::App for PlayerApp {
             ui.vertical(|ui| {
                 ui.add_space(3.0);
                 Self::top_bar(ui, data, &mut self.tree);
+                if reconnecting {
+                    ui.colored_label(Color32::YELLOW, "Connection lost. Reconnecting...");
+                }
                 ui.add_space(2.0);
             });
         });
@@ -1817,58 +2050,139 @@ impl eframe::App for PlayerApp {
     }
 }
 
-fn handle_packets(data: Arc<Mutex<PlayerAppData>>) {
+/// Handles everything the server sends, and reconnects if the connection drops. Sleeps until
+/// there is something to do, and only takes the lock on the app data to act on it.
+fn handle_packets(data: Arc<Mutex<PlayerAppData>>, mut events: Receiver<NetEvent>) {
     loop {
-        std::thread::sleep(std::time::Duration::from_millis(CLIENT_UPDATE_CLOCK));
-        let data = &mut *data.lock().unwrap();
-        if let Some(stream) = &mut data.stream {
-            let mut reader = std::io::BufReader::new(stream);
-            let mut recieved: Vec<u8> = Vec::new();
-            recieved.append(&mut data.buffered_packet);
-            match reader.fill_buf() {
-                Ok(buf) => {
-                    if buf.is_empty() {
-                        continue;
+        // connecting can take a while, so don't hold the lock while doing it
+        let reconnect_to = {
+            let data = &mut *data.lock().unwrap();
+            match (&data.connection, data.reconnecting) {
+                (Some(_), _) => None,
+                (None, Some(since)) => {
+                    if since.elapsed() > RECONNECT_GRACE {
+                        data.disconnect("Could not reconnect to the server.".to_owned());
+                        break;
                     }
-                    recieved.append(&mut buf.to_vec());
+                    Some((data.ip_address.trim().to_owned(), data.encrypt))
                 },
-                Err(e) => {
-                    match e.kind() {
-                        ErrorKind::ConnectionAborted | ErrorKind::ConnectionRefused | ErrorKind::ConnectionReset => {
-                            data.stream = None;
-                            continue;
-                        },
-                        _ => {continue;},
+                (None, None) => break,
+            }
+        };
+        if let Some((address, encrypt)) = reconnect_to {
+            match connect(&address, encrypt) {
+                Ok((connection, new_events)) => {
+                    let data = &mut *data.lock().unwrap();
+                    if data.reconnecting.is_some() && data.connection.is_none() {
+                        data.connection = Some(connection);
+                        events = new_events;
                     }
                 },
+                Err(_) => {
+                    std::thread::sleep(RECONNECT_INTERVAL);
+                },
             }
-            
-            let mut packets = Vec::new();
-            let mut iter = recieved.split_inclusive(|b| *b == 255);
-            while let Some(bytes) = iter.next() {
-                if bytes.ends_with(&[255]) {
-                    reader.consume(bytes.len());
-                    if bytes.len() < 2 {
-                        continue;
-                    }
-                    let msg = String::from_utf8_lossy(&bytes[..bytes.len() - 1]);
-                    match ron::from_str::<ClientBoundPacket>(&*msg) {
+            continue;
+        }
+        match events.recv_timeout(IDLE_CHECK_INTERVAL) {
+            Ok(NetEvent { id, kind: NetEventKind::Frame(payload), .. }) => {
+                let handshake_done = match &data.lock().unwrap().connection {
+                    Some(connection) if connection.id == id => connection.handshake_done,
+                    // it was dropped while this was on its way
+                    _ => continue,
+                };
+                if handshake_done {
+                    // big packets take a while to decode, so do it before taking the lock
+                    let packet = decode_frame::<ClientBoundPacket>(&payload);
+                    let data = &mut *data.lock().unwrap();
+                    match packet {
                         Ok(packet) => {
-                            packets.push(packet);
+                            packet.handle(data);
+                        },
+                        Err(e) => {
+                            data.log_local(ChatMessage::no_sender(format!("Could not read a packet from the server: {}", e)).red());
+                            data.send_to_server(ServerBoundPacket::ProtocolError(e));
                         },
-                        Err(_) => {},
                     }
                 } else {
-                    data.buffered_packet = bytes.to_vec();
+                    handle_handshake(&mut data.lock().unwrap(), decode_frame::<Handshake>(&payload));
+                }
+            },
+            Ok(NetEvent { id, kind: NetEventKind::Closed(error), .. }) => {
+                let data = &mut *data.lock().unwrap();
+                if data.connection.as_ref().is_some_and(|c| c.id == id) {
+                    data.connection_lost(match error {
+                        Some(e) => format!("Lost connection to the server: {}", e),
+                        None => "The server closed the connection".to_owned(),
+                    });
                 }
+            },
+            Err(RecvTimeoutError::Timeout) => {},
+            // our connection is long gone, and whoever opened the current one is handling it
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        let data = &mut *data.lock().unwrap();
+        let timed_out = match &data.connection {
+            Some(connection) => !connection.handshake_done && connection.opened_at.elapsed() > HANDSHAKE_TIMEOUT,
+            None => continue,
+        };
+        if timed_out {
+            if data.reconnecting.is_some() {
+                data.connection_lost("The server did not answer".to_owned());
+            } else {
+                data.disconnect("The server did not answer the version handshake. It may be running an older version; please update so both match.".to_owned());
             }
-            for packet in packets {
-                packet.handle(data);
+        }
+    }
+}
+
+/// Handles the server's answer to our handshake, resuming our session if we were reconnecting.
+fn handle_handshake(data: &mut PlayerAppData, hello: Result<Handshake, String>) {
+    match hello {
+        Ok(hello) => {
+            if !hello.is_compatible() {
+                data.disconnect(hello.mismatch_message("server"));
+            } else if let Some(reason) = hello.refusal {
+                data.disconnect(reason);
+            } else if check_fingerprint(data) {
+                if let Some(connection) = &mut data.connection {
+                    connection.handshake_done = true;
+                }
+                if data.reconnecting.is_some() {
+                    if let Some(token) = data.session_token.clone() {
+                        data.send_to_server(ServerBoundPacket::ResumeSession(token));
+                    }
+                }
             }
-        } else {
-            break;
+        },
+        Err(_) => {
+            data.disconnect("The server sent an unexpected reply. It may be running an older version; please update so both match.".to_owned());
+        },
+    }
+}
+
+/// Compares the server's certificate against the one pinned for its address, pinning it if this
+/// is the first time connecting. Disconnects and returns `false` if it changed.
+fn check_fingerprint(data: &mut PlayerAppData) -> bool {
+    let fingerprint = match &data.connection {
+        Some(connection) => connection.peer_fingerprint(),
+        None => None,
+    };
+    if let Some(fingerprint) = fingerprint {
+        let server = data.ip_address.trim().to_owned();
+        match check_pin(&mut data.known_servers, &server, &fingerprint) {
+            PinCheck::Trusted => {},
+            PinCheck::FirstUse => {
+                data.log_local(ChatMessage::no_sender(format!("Connected to this server for the first time. Its certificate fingerprint is {}; ask your DM to check it matches.", fingerprint)).blue());
+            },
+            PinCheck::Changed => {
+                data.disconnect(CERTIFICATE_CHANGED.to_owned());
+                data.untrusted_fingerprint = Some(fingerprint);
+                return false;
+            },
         }
     }
+    true
 }
 
 pub struct Requests {
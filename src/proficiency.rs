@@ -4,6 +4,7 @@ use lazy_static::lazy_static;
 use serde::{Serialize, Deserialize};
 
 use crate::character::PlayerCharacter;
+use crate::migration::{self, FileKind};
 
 lazy_static! {
     pub static ref PROF_CODE_MAP: ProficiencyCodeMap = {
@@ -69,7 +70,7 @@ impl Proficiency {
     }
 
     pub fn save(&self, file: &str) -> Result<(), ()> {
-        if let Ok(s) = ron::to_string(self) {
+        if let Ok(s) = migration::to_string(FileKind::Proficiency, self) {
             let file = format!("proficiencies/{}.ron", file);
             let path = Path::new(&file);
             if let Some(parent) = path.parent() {
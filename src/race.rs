@@ -1,5 +1,7 @@
 use simple_enum_macro::simple_enum;
 
+use rand::Rng;
+
 use crate::{dice::{roll, DiceRoll}, character::Attributes};
 
 #[simple_enum(display)]
@@ -23,82 +25,82 @@ pub enum Race {
 }
 
 impl Race {
-    pub fn roll_attrs(&self) -> Attributes {
+    pub fn roll_attrs(&self, rng: &mut impl Rng) -> Attributes {
         match self {
             Self::Human => {
-                Attributes::random()
+                Attributes::random(rng)
             },
             Self::Dwarf => {
                 let simple = DiceRoll::simple(3, 6);
                 Attributes {
-                    strength: roll(simple) as u8,
-                    dexterity: roll(simple) as u8,
-                    constitution: roll(DiceRoll::simple_drop_lowest(4, 6)) as u8,
-                    intelligence: roll(simple) as u8,
-                    wisdom: roll(simple) as u8,
-                    charisma: roll(DiceRoll::simple_drop_highest(4, 6)) as u8,
+                    strength: roll(simple, rng) as u8,
+                    dexterity: roll(simple, rng) as u8,
+                    constitution: roll(DiceRoll::simple_drop_lowest(4, 6), rng) as u8,
+                    intelligence: roll(simple, rng) as u8,
+                    wisdom: roll(simple, rng) as u8,
+                    charisma: roll(DiceRoll::simple_drop_highest(4, 6), rng) as u8,
                 }
             },
             Self::Elf => {
                 let simple = DiceRoll::simple(3, 6);
                 Attributes {
-                    strength: roll(simple) as u8,
-                    dexterity: roll(simple) as u8,
-                    constitution: roll(DiceRoll::simple_drop_highest(4, 6)) as u8,
-                    intelligence: roll(DiceRoll::simple_drop_lowest(4, 6)) as u8,
-                    wisdom: roll(simple) as u8,
-                    charisma: roll(simple) as u8,
+                    strength: roll(simple, rng) as u8,
+                    dexterity: roll(simple, rng) as u8,
+                    constitution: roll(DiceRoll::simple_drop_highest(4, 6), rng) as u8,
+                    intelligence: roll(DiceRoll::simple_drop_lowest(4, 6), rng) as u8,
+                    wisdom: roll(simple, rng) as u8,
+                    charisma: roll(simple, rng) as u8,
                 }
             },
             Self::Gnome => {
                 Attributes {
-                    strength: DiceRoll::simple_drop_highest(4, 6).roll() as u8,
-                    dexterity: DiceRoll::simple(3, 6).roll() as u8,
-                    constitution: DiceRoll::simple_drop_lowest(4, 6).roll() as u8,
-                    intelligence: DiceRoll::simple_drop_lowest(4, 6).roll() as u8,
-                    wisdom: DiceRoll::simple(3, 6).roll() as u8,
-                    charisma: DiceRoll::simple(3, 6).roll() as u8,
+                    strength: DiceRoll::simple_drop_highest(4, 6).roll(rng) as u8,
+                    dexterity: DiceRoll::simple(3, 6).roll(rng) as u8,
+                    constitution: DiceRoll::simple_drop_lowest(4, 6).roll(rng) as u8,
+                    intelligence: DiceRoll::simple_drop_lowest(4, 6).roll(rng) as u8,
+                    wisdom: DiceRoll::simple(3, 6).roll(rng) as u8,
+                    charisma: DiceRoll::simple(3, 6).roll(rng) as u8,
                 }
             },
             Self::Nobiran => {
                 let r = DiceRoll::simple_drop_lowest(4, 6);
                 Attributes {
-                    strength: r.roll() as u8,
-                    dexterity: r.roll() as u8,
-                    constitution: r.roll() as u8,
-                    intelligence: r.roll() as u8,
-                    wisdom: r.roll() as u8,
-                    charisma: r.roll() as u8,
+                    strength: r.roll(rng) as u8,
+                    dexterity: r.roll(rng) as u8,
+                    constitution: r.roll(rng) as u8,
+                    intelligence: r.roll(rng) as u8,
+                    wisdom: r.roll(rng) as u8,
+                    charisma: r.roll(rng) as u8,
                 }
             },
             Self::Thrassian => {
                 Attributes {
-                    strength: DiceRoll::simple_drop_lowest(4, 6).roll() as u8,
-                    dexterity: DiceRoll::simple_drop_lowest(4, 6).roll() as u8,
-                    constitution: DiceRoll::simple_drop_lowest(4, 6).roll() as u8,
-                    intelligence: DiceRoll::simple(3, 6).roll() as u8,
-                    wisdom: DiceRoll::simple_drop_highest(4, 6).roll() as u8,
-                    charisma: DiceRoll::simple_drop_highest(4, 6).roll() as u8,
+                    strength: DiceRoll::simple_drop_lowest(4, 6).roll(rng) as u8,
+                    dexterity: DiceRoll::simple_drop_lowest(4, 6).roll(rng) as u8,
+                    constitution: DiceRoll::simple_drop_lowest(4, 6).roll(rng) as u8,
+                    intelligence: DiceRoll::simple(3, 6).roll(rng) as u8,
+                    wisdom: DiceRoll::simple_drop_highest(4, 6).roll(rng) as u8,
+                    charisma: DiceRoll::simple_drop_highest(4, 6).roll(rng) as u8,
                 }
             },
             Self::Zaharan => {
                 Attributes {
-                    strength: DiceRoll::simple(3, 6).roll() as u8,
-                    dexterity: DiceRoll::simple(3, 6).roll() as u8,
-                    constitution: DiceRoll::simple(3, 6).roll() as u8,
-                    intelligence: DiceRoll::simple_drop_lowest(4, 6).roll() as u8,
-                    wisdom: DiceRoll::simple_drop_lowest(4, 6).roll() as u8,
-                    charisma: DiceRoll::simple_drop_lowest(4, 6).roll() as u8,
+                    strength: DiceRoll::simple(3, 6).roll(rng) as u8,
+                    dexterity: DiceRoll::simple(3, 6).roll(rng) as u8,
+                    constitution: DiceRoll::simple(3, 6).roll(rng) as u8,
+                    intelligence: DiceRoll::simple_drop_lowest(4, 6).roll(rng) as u8,
+                    wisdom: DiceRoll::simple_drop_lowest(4, 6).roll(rng) as u8,
+                    charisma: DiceRoll::simple_drop_lowest(4, 6).roll(rng) as u8,
                 }
             },
             Self::Halfling => {
                 Attributes {
-                    strength: DiceRoll::simple(3, 6).roll() as u8,
-                    dexterity: DiceRoll::simple_drop_lowest(4, 6).roll() as u8,
-                    constitution: DiceRoll::simple(3, 6).roll() as u8,
-                    intelligence: DiceRoll::simple(3, 6).roll() as u8,
-                    wisdom: DiceRoll::simple(3, 6).roll() as u8,
-                    charisma: DiceRoll::simple(3, 6).roll() as u8,
+                    strength: DiceRoll::simple(3, 6).roll(rng) as u8,
+                    dexterity: DiceRoll::simple_drop_lowest(4, 6).roll(rng) as u8,
+                    constitution: DiceRoll::simple(3, 6).roll(rng) as u8,
+                    intelligence: DiceRoll::simple(3, 6).roll(rng) as u8,
+                    wisdom: DiceRoll::simple(3, 6).roll(rng) as u8,
+                    charisma: DiceRoll::simple(3, 6).roll(rng) as u8,
                 }
             }
         }
@@ -112,10 +114,10 @@ pub enum RaceTable {
 }
 
 impl RaceTable {
-    pub fn random_race(&self) -> Race {
+    pub fn random_race(&self, rng: &mut impl Rng) -> Race {
         match self {
             Self::StandardFantasy => {
-                match roll(DiceRoll::simple(1, 100)) {
+                match roll(DiceRoll::simple(1, 100), rng) {
                     ..=60 => Race::Human,
                     61..=70 => Race::Elf,
                     71..=85 => Race::Dwarf,
@@ -0,0 +1,124 @@
+use std::time::{Duration, Instant};
+
+/// How many packets a connection can have refused for going over its limits (or being unreadable)
+/// before it is disconnected.
+pub const MAX_STRIKES: u32 = 10;
+
+/// How long a connection has to stay within its limits for its strikes to be forgiven.
+pub const STRIKE_RESET: Duration = Duration::from_secs(60);
+
+/// How many connections a single IP address can have open at once. Each one takes two threads.
+pub const MAX_CONNECTIONS_PER_IP: usize = 8;
+
+/// Allows a burst of actions at once, then a steady rate after that. Each action takes a token,
+/// and tokens trickle back in over time up to the burst size.
+pub struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    per_second: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket that allows `burst` actions at once and `per_minute` on average after that.
+    pub fn new(burst: u32, per_minute: f64) -> Self {
+        Self {
+            capacity: burst as f64,
+            tokens: burst as f64,
+            per_second: per_minute / 60.0,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// The limit on new accounts from a single IP address.
+    pub fn account_creation() -> Self {
+        Self::new(3, 0.1)
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_second).min(self.capacity);
+        self.last_refill = now;
+    }
+
+    /// Takes a token if there is one. Returns false if the action should be refused.
+    pub fn try_take(&mut self) -> bool {
+        self.refill();
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Whether the bucket has refilled completely, in which case it can be forgotten.
+    pub fn is_full(&mut self) -> bool {
+        self.refill();
+        self.tokens >= self.capacity
+    }
+}
+
+/// Which of a connection's limits a packet counts against, on top of the limit on all packets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateClass {
+    Chat,
+    Request,
+    /// Logging in and creating accounts, which both hash a password and are slow on purpose.
+    LogIn,
+    Other,
+}
+
+/// The rate limits for a single connection to the server.
+pub struct ConnectionLimits {
+    packets: TokenBucket,
+    chat: TokenBucket,
+    requests: TokenBucket,
+    log_ins: TokenBucket,
+    strikes: u32,
+    last_strike: Instant,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ConnectionLimits {
+    pub fn new() -> Self {
+        Self {
+            packets: TokenBucket::new(100, 1200.0),
+            chat: TokenBucket::new(5, 30.0),
+            requests: TokenBucket::new(3, 6.0),
+            log_ins: TokenBucket::new(5, 10.0),
+            strikes: 0,
+            last_strike: Instant::now(),
+        }
+    }
+
+    /// Checks a packet against the limits, using them up if it is allowed.
+    pub fn allow(&mut self, class: RateClass) -> bool {
+        if !self.packets.try_take() {
+            return false;
+        }
+        match class {
+            RateClass::Chat => self.chat.try_take(),
+            RateClass::Request => self.requests.try_take(),
+            RateClass::LogIn => self.log_ins.try_take(),
+            RateClass::Other => true,
+        }
+    }
+
+    /// Records that a packet was refused, and returns how many strikes the connection now has.
+    pub fn strike(&mut self) -> u32 {
+        if self.last_strike.elapsed() >= STRIKE_RESET {
+            self.strikes = 0;
+        }
+        self.strikes += 1;
+        self.last_strike = Instant::now();
+        self.strikes
+    }
+
+}
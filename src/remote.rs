@@ -0,0 +1,251 @@
+use std::collections::HashMap;
+use std::sync::mpsc::{Receiver, TryRecvError};
+
+use eframe::egui::{self, Ui, RichText, Rgba};
+
+use crate::common_ui::{ChatMessage, ChatLogEntry, chat_log_entry};
+use crate::network::{Connection, Handshake, NetEvent, NetEventKind, PinCheck, HANDSHAKE_TIMEOUT, CERTIFICATE_CHANGED, decode_frame, connect, check_pin};
+use crate::packets::{ClientBoundPacket, ServerBoundPacket};
+
+/// A console in the DM app for running a headless server from afar. It logs in as a regular
+/// user, so that user must be made a co-DM on the server first (`/user <user> role codm`).
+pub struct RemoteConsole {
+    pub address: String,
+    pub username: String,
+    pub password: String,
+    pub encrypt: bool,
+    pub command: String,
+    pub connection: Option<Connection>,
+    /// Everything the server sends over `connection`.
+    pub events: Option<Receiver<NetEvent>>,
+    pub logged_in: bool,
+    pub logs: Vec<ChatLogEntry>,
+    pub error: Option<String>,
+    /// Certificate fingerprints pinned the first time we connected to each server, by address.
+    pub known_servers: HashMap<String, String>,
+    /// Set when a server's certificate doesn't match the pinned one.
+    pub untrusted_fingerprint: Option<String>,
+}
+
+impl Default for RemoteConsole {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RemoteConsole {
+    pub fn new() -> Self {
+        Self {
+            address: String::new(),
+            username: String::new(),
+            password: String::new(),
+            encrypt: true,
+            command: String::new(),
+            connection: None,
+            events: None,
+            logged_in: false,
+            logs: Vec::new(),
+            error: None,
+            known_servers: HashMap::new(),
+            untrusted_fingerprint: None,
+        }
+    }
+
+    fn log(&mut self, msg: ChatMessage) {
+        self.logs.insert(0, msg.to_log_entry());
+    }
+
+    pub fn disconnect(&mut self, reason: Option<String>) {
+        if let Some(connection) = self.connection.take() {
+            connection.shutdown();
+        }
+        self.events = None;
+        self.logged_in = false;
+        self.error = reason;
+    }
+
+    pub fn send(&mut self, packet: ServerBoundPacket) {
+        if let Some(connection) = &self.connection {
+            if let Err(e) = connection.send(&packet) {
+                self.disconnect(Some(format!("Lost connection to the server: {}", e)));
+            }
+        }
+    }
+
+    /// Handles anything the server sent since the last call. Call this every frame.
+    pub fn poll(&mut self) {
+        loop {
+            let event = match &self.events {
+                Some(events) => events.try_recv(),
+                None => return,
+            };
+            match event {
+                Ok(NetEvent { kind: NetEventKind::Frame(payload), .. }) => {
+                    self.handle_frame(payload);
+                },
+                Ok(NetEvent { kind: NetEventKind::Closed(error), .. }) => {
+                    self.disconnect(Some(match error {
+                        Some(e) => format!("Lost connection to the server: {}", e),
+                        None => "The server closed the connection.".to_owned(),
+                    }));
+                    return;
+                },
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    self.disconnect(Some("The server closed the connection.".to_owned()));
+                    return;
+                },
+            }
+        }
+        let timed_out = match &self.connection {
+            Some(connection) => !connection.handshake_done && connection.opened_at.elapsed() > HANDSHAKE_TIMEOUT,
+            None => return,
+        };
+        if timed_out {
+            self.disconnect(Some("The server did not answer the version handshake. It may be running an older version; please update so both match.".to_owned()));
+        }
+    }
+
+    fn handle_frame(&mut self, payload: Vec<u8>) {
+        let (handshake_done, fingerprint) = match &self.connection {
+            Some(connection) => (connection.handshake_done, connection.peer_fingerprint()),
+            None => return,
+        };
+        if !handshake_done {
+            match decode_frame::<Handshake>(&payload) {
+                Ok(hello) => {
+                    if !hello.is_compatible() {
+                        self.disconnect(Some(hello.mismatch_message("server")));
+                    } else if let Some(reason) = hello.refusal {
+                        self.disconnect(Some(reason));
+                    } else {
+                        if let Some(fingerprint) = fingerprint {
+                            let server = self.address.trim().to_owned();
+                            match check_pin(&mut self.known_servers, &server, &fingerprint) {
+                                PinCheck::Trusted => {},
+                                PinCheck::FirstUse => {
+                                    self.log(ChatMessage::no_sender(format!("Connected to this server for the first time. Its certificate fingerprint is {}.", fingerprint)).blue());
+                                },
+                                PinCheck::Changed => {
+                                    self.disconnect(Some(CERTIFICATE_CHANGED.to_owned()));
+                                    self.untrusted_fingerprint = Some(fingerprint);
+                                    return;
+                                },
+                            }
+                        }
+                        if let Some(connection) = &mut self.connection {
+                            connection.handshake_done = true;
+                        }
+                        self.send(ServerBoundPacket::AttemptLogIn(self.username.clone(), self.password.clone(), false));
+                    }
+                },
+                Err(_) => {
+                    self.disconnect(Some("The server sent an unexpected reply. It may be running an older version; please update so both match.".to_owned()));
+                },
+            }
+            return;
+        }
+        match decode_frame::<ClientBoundPacket>(&payload) {
+            Ok(ClientBoundPacket::ChatMessage(msg)) => {
+                self.logs.insert(0, msg.to_log_entry());
+            },
+            Ok(ClientBoundPacket::LogInResult(success, _)) => {
+                if success {
+                    self.logged_in = true;
+                    self.password.clear();
+                } else {
+                    self.disconnect(Some("Incorrect username or password.".to_owned()));
+                }
+            },
+            Ok(ClientBoundPacket::ProtocolError(e)) => {
+                self.log(ChatMessage::no_sender(e).red());
+            },
+            Ok(ClientBoundPacket::Error(e)) => {
+                self.log(ChatMessage::no_sender(e.to_string()).light_red());
+            },
+            // everything else is meant for the player app
+            Ok(_) => {},
+            Err(e) => {
+                self.log(ChatMessage::no_sender(format!("Could not read a packet from the server: {}", e)).red());
+            },
+        }
+    }
+
+    pub fn show(&mut self, ui: &mut Ui) {
+        if self.connection.is_none() {
+            self.connect_form(ui);
+            return;
+        }
+        if !self.logged_in {
+            ui.label(RichText::new("Connecting...").weak().italics());
+            return;
+        }
+        ui.horizontal(|ui| {
+            ui.label(format!("Connected to {} as {}", self.address.trim(), self.username));
+            if ui.button("Disconnect").clicked() {
+                self.disconnect(None);
+            }
+        });
+        ui.separator();
+        ui.with_layout(egui::Layout::top_down_justified(egui::Align::Min), |ui| {
+            let response = ui.add(egui::TextEdit::singleline(&mut self.command).hint_text(RichText::new("Command or message...").weak().italics()));
+            if response.lost_focus() && ui.input(|input| input.key_pressed(egui::Key::Enter)) {
+                if !self.command.trim().is_empty() {
+                    self.send(ServerBoundPacket::AdminCommand(self.command.trim().to_owned()));
+                }
+                self.command.clear();
+                response.request_focus();
+            }
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                for (i, log) in self.logs.iter().enumerate() {
+                    ui.with_layout(egui::Layout::top_down(egui::Align::Min), |ui| {
+                        chat_log_entry(ui, log);
+                    });
+                    if i >= 200 {
+                        break;
+                    }
+                }
+            });
+        });
+    }
+
+    fn connect_form(&mut self, ui: &mut Ui) {
+        ui.label("Run a headless server from here. The user must be a co-DM on that server.");
+        egui::Grid::new("remote_connect").num_columns(2).show(ui, |ui| {
+            ui.label("Address:");
+            ui.add(egui::TextEdit::singleline(&mut self.address).hint_text(RichText::new("Enter IP...").weak().italics()));
+            ui.end_row();
+            ui.label("Username:");
+            ui.text_edit_singleline(&mut self.username);
+            ui.end_row();
+            ui.label("Password:");
+            ui.add(egui::TextEdit::singleline(&mut self.password).password(true));
+            ui.end_row();
+        });
+        ui.checkbox(&mut self.encrypt, "Encrypt connection");
+        if ui.button("Connect").clicked() {
+            match connect(self.address.trim(), self.encrypt) {
+                Ok((connection, events)) => {
+                    self.connection = Some(connection);
+                    self.events = Some(events);
+                    self.error = None;
+                    self.untrusted_fingerprint = None;
+                },
+                Err(e) => {
+                    self.error = Some(format!("Could not connect to server: {}", e));
+                },
+            }
+        }
+        if let Some(e) = &self.error {
+            ui.colored_label(Rgba::RED, e);
+        }
+        if let Some(fingerprint) = self.untrusted_fingerprint.clone() {
+            ui.label(RichText::new(format!("New fingerprint: {}", fingerprint)).small());
+            if ui.button("Trust new certificate").clicked() {
+                self.known_servers.insert(self.address.trim().to_owned(), fingerprint);
+                self.untrusted_fingerprint = None;
+                self.error = None;
+            }
+        }
+    }
+}
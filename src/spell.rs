@@ -1,5 +1,6 @@
 use std::collections::HashMap;
 
+use rand::Rng;
 use serde::{Serialize, Deserialize};
 use simple_enum_macro::simple_enum;
 
@@ -120,13 +121,15 @@ impl SpellRegistry {
             arcane: array_macro::array![HashMap::new(); 9],
         }
     }
-    pub fn random_arcane(&self, level: u8) -> Option<String> {
+    pub fn random_arcane(&self, level: u8, rng: &mut impl Rng) -> Option<String> {
         if level < 9 {
             if self.arcane[level as usize].is_empty() {
                 None
             } else {
-                let spells: Vec<&String> = self.arcane[level as usize].keys().collect();
-                Some(spells[rand::random::<usize>() % spells.len()].clone())
+                // sorted so that the pick only depends on the rng, not on hashmap ordering
+                let mut spells: Vec<&String> = self.arcane[level as usize].keys().collect();
+                spells.sort();
+                Some(spells[rng.gen_range(0..spells.len())].clone())
             }
         } else {
             None
@@ -0,0 +1,385 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use serde::Serialize;
+use sha2::{Sha256, Digest};
+use simple_enum_macro::simple_enum;
+
+use crate::common_ui::ChatMessage;
+use crate::dm_app::SaveData;
+use crate::map::Map;
+use crate::migration::{self, FileKind, Loaded};
+
+/// Where backups of the save file are kept, inside the campaign's folder.
+pub const BACKUP_DIR: &str = "backups";
+
+/// How many backups are kept unless the DM picks a different number.
+pub const DEFAULT_BACKUP_COUNT: usize = 10;
+
+/// How often the game is saved on its own unless the DM picks a different interval, in seconds.
+pub const DEFAULT_AUTOSAVE_INTERVAL: u64 = 300;
+
+/// The save file of a campaign stored in RON files.
+pub const SAVE_FILE: &str = "savedata.ron";
+
+/// The database of a campaign stored in SQLite. A campaign with one is stored in it instead of in
+/// RON files.
+pub const DATABASE_FILE: &str = "campaign.sqlite";
+
+/// Every rotated backup's file name starts with this, so backups made for other reasons (like a
+/// save file that couldn't be read) are never thrown away.
+const BACKUP_PREFIX: &str = "savedata-";
+
+/// How long a campaign stored in SQLite waits after a backup before saving makes another. Each
+/// one copies the whole database, which would undo the point of only writing what changed.
+pub const DATABASE_BACKUP_INTERVAL: Duration = Duration::from_secs(30 * 60);
+
+/// Writes a file so that it is either completely replaced or left untouched, even if the program
+/// crashes or the disk fills up partway through. The contents go to a temporary file next to it,
+/// which is flushed to disk and then renamed over the original.
+pub fn write_atomic(path: impl AsRef<Path>, contents: &[u8]) -> std::io::Result<()> {
+    let path = path.as_ref();
+    if let Some(parent) = path.parent() {
+        if !parent.as_os_str().is_empty() {
+            std::fs::create_dir_all(parent)?;
+        }
+    }
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    let temp = PathBuf::from(temp);
+    let result = write_and_sync(&temp, contents).and_then(|_| std::fs::rename(&temp, path));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp);
+    }
+    result
+}
+
+fn write_and_sync(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut file = std::fs::File::create(path)?;
+    file.write_all(contents)?;
+    file.sync_all()
+}
+
+/// Stores a copy of the save data in `dir` as a new timestamped backup, then deletes the oldest backups so
+/// only `keep` are left. Nothing is written if the newest backup is already the same. Returns the
+/// name of the backup holding the save data, unless backups are turned off.
+pub fn backup(dir: &Path, contents: &[u8], keep: usize) -> std::io::Result<Option<String>> {
+    if keep == 0 {
+        return Ok(None);
+    }
+    if let Some(newest) = list_backups(dir).first() {
+        if std::fs::read(backup_path(dir, newest)).is_ok_and(|newest| newest == contents) {
+            return Ok(Some(newest.clone()));
+        }
+    }
+    let name = new_backup_name(dir);
+    write_atomic(dir.join(format!("{}.ron", name)), contents)?;
+    remove_old_backups(dir, keep)?;
+    Ok(Some(name))
+}
+
+/// A name for a new backup in `dir`, which sorts after the older ones.
+pub fn new_backup_name(dir: &Path) -> String {
+    let backups = list_backups(dir);
+    // a counter keeps two backups made in the same millisecond from sharing a name
+    let stamp = format!("{}{}", BACKUP_PREFIX, chrono::Local::now().format("%Y-%m-%d--%H-%M-%S-%3f"));
+    let mut name = stamp.clone();
+    let mut count = 1;
+    while backups.contains(&name) {
+        name = format!("{}-{:03}", stamp, count);
+        count += 1;
+    }
+    name
+}
+
+/// Deletes the oldest backups in `dir`, so only `keep` are left.
+pub fn remove_old_backups(dir: &Path, keep: usize) -> std::io::Result<()> {
+    for old in list_backups(dir).iter().skip(keep) {
+        std::fs::remove_file(backup_path(dir, old))?;
+    }
+    Ok(())
+}
+
+/// The names of all rotated backups in a directory, newest first. Backups of campaigns stored in
+/// SQLite are copies of the database, and the rest are RON.
+pub fn list_backups(dir: &Path) -> Vec<String> {
+    let mut names = Vec::new();
+    if let Ok(entries) = std::fs::read_dir(dir) {
+        for entry in entries.flatten() {
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            if let Some(name) = file_name.strip_suffix(".ron").or(file_name.strip_suffix(".sqlite")) {
+                if name.starts_with(BACKUP_PREFIX) {
+                    names.push(name.to_owned());
+                }
+            }
+        }
+    }
+    names.sort();
+    names.reverse();
+    names
+}
+
+/// Where the backup with the given name is. Anything that could reach outside the backup
+/// directory is stripped out, since the name may have been typed in.
+pub fn backup_path(dir: &Path, name: &str) -> PathBuf {
+    let name = name.trim_end_matches(".ron").trim_end_matches(".sqlite").replace(['/', '\\'], "").replace("..", "");
+    let database = dir.join(format!("{}.sqlite", name));
+    if database.exists() {
+        database
+    } else {
+        dir.join(format!("{}.ron", name))
+    }
+}
+
+/// Reads the saved data in a backup, whichever kind it is.
+pub fn read_backup(dir: &Path, name: &str) -> Result<SaveData, String> {
+    let path = backup_path(dir, name);
+    if path.extension().is_some_and(|extension| extension == "sqlite") {
+        return match open_database(&path)?.load()? {
+            Some(loaded) => Ok(loaded.value),
+            None => Err("there is no saved data in it".to_owned()),
+        };
+    }
+    let s = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    migration::from_str::<SaveData>(FileKind::Save, &s).map(|loaded| loaded.value)
+}
+
+/// A fingerprint of how a value would be saved, to tell whether it changed since it was last saved.
+pub fn fingerprint<S: Serialize>(obj: &S) -> String {
+    let mut hasher = Sha256::new();
+    if let Ok(s) = ron::to_string(obj) {
+        hasher.update(s.as_bytes());
+    }
+    hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Fingerprints of each part of the saved data.
+#[derive(Debug, Clone, Default)]
+pub struct Fingerprints {
+    /// Accounts, their characters, and the server's settings.
+    pub users: String,
+    pub parties: String,
+    pub map: String,
+}
+
+impl Fingerprints {
+    /// Which parts differ from the saved ones.
+    pub fn changes_since(&self, saved: &Fingerprints) -> UnsavedChanges {
+        UnsavedChanges {
+            users: self.users != saved.users,
+            parties: self.parties != saved.parties,
+            map: self.map != saved.map,
+        }
+    }
+}
+
+/// Which parts of the saved data have changed since they were last saved.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UnsavedChanges {
+    pub users: bool,
+    pub parties: bool,
+    pub map: bool,
+}
+
+impl UnsavedChanges {
+    pub fn any(&self) -> bool {
+        self.users || self.parties || self.map
+    }
+
+    /// Lists what changed, like "users and the map".
+    pub fn describe(&self) -> String {
+        let mut parts = Vec::new();
+        if self.users {
+            parts.push("users");
+        }
+        if self.parties {
+            parts.push("parties");
+        }
+        if self.map {
+            parts.push("the map");
+        }
+        match parts.split_last() {
+            Some((last, [])) => last.to_string(),
+            Some((last, rest)) => format!("{} and {}", rest.join(", "), last),
+            None => "nothing".to_owned(),
+        }
+    }
+}
+
+/// The ways a campaign can be stored.
+#[simple_enum]
+pub enum StoreKind {
+    /// A RON file for the save data, and one for each map. Rewritten whole on every save.
+    Ron,
+    /// An SQLite database, with rows for each user, character, party and map, and the chat
+    /// history. Only what changed is written.
+    Sqlite,
+}
+
+impl std::fmt::Display for StoreKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Ron => write!(f, "RON files"),
+            Self::Sqlite => write!(f, "an SQLite database"),
+        }
+    }
+}
+
+/// Where a campaign's save data, maps and chat history are kept.
+pub trait CampaignStore: Send {
+    fn kind(&self) -> StoreKind;
+
+    /// The saved data, upgraded if it was saved by an older version of the app, or `None` if
+    /// nothing has been saved yet.
+    fn load(&mut self) -> Result<Option<Loaded<SaveData>>, String>;
+
+    /// The saved data as RON, exactly as it is stored, to keep a copy of before it is upgraded or
+    /// when it can't be loaded.
+    fn saved_text(&mut self) -> Result<Option<String>, String>;
+
+    /// Replaces the saved data. Only the parts in `unsaved` have changed since the last save or
+    /// load, so the rest don't need writing again.
+    fn save(&mut self, data: &SaveData, unsaved: UnsavedChanges) -> Result<(), String>;
+
+    /// The id and name of every map, or why it couldn't be read.
+    fn list_maps(&mut self) -> Vec<(String, Result<String, String>)>;
+
+    fn load_map(&mut self, id: &str) -> Result<Map, String>;
+
+    fn save_map(&mut self, id: &str, map: &Map) -> Result<(), String>;
+
+    /// Copies what is saved into a new rotated backup in `dir`, then deletes the oldest backups so
+    /// only `keep` are left. Returns the name of the backup holding the saved data, unless backups
+    /// are turned off.
+    fn backup(&mut self, dir: &Path, keep: usize) -> Result<Option<String>, String>;
+
+    /// How long to wait after a backup before saving makes another.
+    fn backup_interval(&self) -> Duration;
+
+    /// Adds messages to the end of the chat history, if this store keeps one.
+    fn append_chat(&mut self, messages: &[ChatMessage]) -> Result<(), String>;
+
+    /// The newest `count` messages of the chat history, oldest first.
+    fn recent_chat(&mut self, count: usize) -> Result<Vec<ChatMessage>, String>;
+}
+
+/// Opens the store of the campaign in `dir`, whichever kind it is.
+pub fn open_store(dir: &Path) -> Result<Box<dyn CampaignStore>, String> {
+    if dir.join(DATABASE_FILE).exists() {
+        open_database(&dir.join(DATABASE_FILE))
+    } else {
+        Ok(Box::new(RonStore::new(dir)))
+    }
+}
+
+/// Makes a new, empty store for the campaign in `dir`.
+pub fn create_store(dir: &Path, kind: StoreKind) -> Result<Box<dyn CampaignStore>, String> {
+    match kind {
+        StoreKind::Ron => Ok(Box::new(RonStore::new(dir))),
+        StoreKind::Sqlite => {
+            if dir.join(DATABASE_FILE).exists() {
+                return Err(format!("{} already exists", dir.join(DATABASE_FILE).display()));
+            }
+            open_database(&dir.join(DATABASE_FILE))
+        },
+    }
+}
+
+#[cfg(feature = "sqlite")]
+fn open_database(path: &Path) -> Result<Box<dyn CampaignStore>, String> {
+    Ok(Box::new(crate::database::SqliteStore::open(path)?))
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn open_database(_path: &Path) -> Result<Box<dyn CampaignStore>, String> {
+    Err("this campaign is stored in a database, but this copy of the app was built without the \"sqlite\" feature".to_owned())
+}
+
+/// Whether this copy of the app can store campaigns in a database.
+pub fn database_available() -> bool {
+    cfg!(feature = "sqlite")
+}
+
+/// Stores a campaign like it always has been, in RON files in the campaign's folder.
+pub struct RonStore {
+    dir: PathBuf,
+}
+
+impl RonStore {
+    pub fn new(dir: &Path) -> Self {
+        Self { dir: dir.to_owned() }
+    }
+
+    fn map_path(&self, id: &str) -> PathBuf {
+        self.dir.join(FileKind::Map.dir()).join(format!("{}.ron", id))
+    }
+}
+
+impl CampaignStore for RonStore {
+    fn kind(&self) -> StoreKind {
+        StoreKind::Ron
+    }
+
+    fn load(&mut self) -> Result<Option<Loaded<SaveData>>, String> {
+        match self.saved_text()? {
+            Some(s) => migration::from_str::<SaveData>(FileKind::Save, &s).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn saved_text(&mut self) -> Result<Option<String>, String> {
+        match std::fs::read_to_string(self.dir.join(SAVE_FILE)) {
+            Ok(s) => Ok(Some(s)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    fn save(&mut self, data: &SaveData, _unsaved: UnsavedChanges) -> Result<(), String> {
+        let s = ron::to_string(data).map_err(|e| e.to_string())?;
+        // the old save file is only replaced once the new one is completely written
+        write_atomic(self.dir.join(SAVE_FILE), s.as_bytes()).map_err(|e| e.to_string())
+    }
+
+    fn list_maps(&mut self) -> Vec<(String, Result<String, String>)> {
+        let mut maps = Vec::new();
+        let dir = self.dir.join(FileKind::Map.dir());
+        let prefix = dir.to_string_lossy().into_owned();
+        crate::dm_app::DMAppData::read_dir_recursive(&dir, |path, s| {
+            // paths start with the directory, followed by either separator depending on the OS
+            let id = path.strip_prefix(&prefix).map_or(path.as_str(), |id| id.trim_start_matches(['/', '\\'])).to_owned();
+            maps.push((id, migration::from_str::<Map>(FileKind::Map, &s).map(|loaded| loaded.value.name)));
+        });
+        maps
+    }
+
+    fn load_map(&mut self, id: &str) -> Result<Map, String> {
+        let s = std::fs::read_to_string(self.map_path(id)).map_err(|e| e.to_string())?;
+        migration::from_str::<Map>(FileKind::Map, &s).map(|loaded| loaded.value)
+    }
+
+    fn save_map(&mut self, id: &str, map: &Map) -> Result<(), String> {
+        let s = migration::to_string(FileKind::Map, map)?;
+        write_atomic(self.map_path(id), s.as_bytes()).map_err(|e| e.to_string())
+    }
+
+    fn backup(&mut self, dir: &Path, keep: usize) -> Result<Option<String>, String> {
+        // the save file is copied as it is, so nothing has to be serialized again
+        match self.saved_text()? {
+            Some(s) => backup(dir, s.as_bytes(), keep).map_err(|e| e.to_string()),
+            None => Ok(None),
+        }
+    }
+
+    fn backup_interval(&self) -> Duration {
+        Duration::ZERO
+    }
+
+    fn append_chat(&mut self, _messages: &[ChatMessage]) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn recent_chat(&mut self, _count: usize) -> Result<Vec<ChatMessage>, String> {
+        Ok(Vec::new())
+    }
+}
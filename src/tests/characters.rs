@@ -0,0 +1,100 @@
+use crate::character::PlayerCharacter;
+use crate::class::Class;
+use crate::race::Race;
+use crate::dm_app::{RegistryNode, NEW_CHARACTER_CHOICES};
+use crate::packets::{ClientBoundPacket, ServerBoundPacket, ClientFacingError, Request};
+
+use super::{TestServer, TestClient};
+
+/// Asks for new characters and has the DM approve, returning the characters to pick from.
+fn roll_characters(server: &TestServer, client: &TestClient) -> Vec<PlayerCharacter> {
+    client.send(ServerBoundPacket::MakeRequest(Request::GenerateCharacters));
+    server.wait_until("the request to arrive", |data| !data.temp_state.requests.is_empty());
+    assert!(server.with_data(|data| data.respond_to_request(0, true)));
+    let approved = client.expect("the DM's answer", |packet| match packet {
+        ClientBoundPacket::RespondToRequest(Request::GenerateCharacters, approved) => Some(approved),
+        _ => None,
+    });
+    assert!(approved);
+    client.expect("the rolled characters", |packet| match packet {
+        ClientBoundPacket::PendingCharacters(pending) => Some(pending),
+        _ => None,
+    })
+}
+
+/// Registers a class with no prime requisites for characters of the given race.
+fn register_class(server: &TestServer, path: &str, race: Race) {
+    let mut class = Class::default();
+    class.name = path.to_owned();
+    class.race = race;
+    server.with_data(|data| data.class_registry.tree.insert(path.to_owned(), RegistryNode::Value(class)));
+}
+
+#[test]
+fn create_character() {
+    let server = TestServer::start();
+    let client = server.connect_as("alice", "hunter2");
+    let pending = roll_characters(&server, &client);
+    assert_eq!(pending.len(), NEW_CHARACTER_CHOICES);
+    register_class(&server, "fighter", pending[0].race);
+
+    client.send(ServerBoundPacket::CreateNewCharacter("Aria".to_owned(), 0, "fighter".to_owned()));
+    let remaining = client.expect("the remaining characters", |packet| match packet {
+        ClientBoundPacket::PendingCharacters(pending) => Some(pending),
+        _ => None,
+    });
+    assert_eq!(remaining.len(), NEW_CHARACTER_CHOICES - 1);
+    let result = client.expect("the new character", |packet| match packet {
+        ClientBoundPacket::CreateNewCharacterResult(result, name) => Some((result, name)),
+        _ => None,
+    });
+    assert_eq!(result, (Ok(()), "Aria".to_owned()));
+
+    client.send(ServerBoundPacket::RequestCharacterUpdate("Aria".to_owned()));
+    let sheet = client.expect("the character sheet", |packet| match packet {
+        ClientBoundPacket::UpdateCharacter(name, sheet) if name == "Aria" => Some(sheet),
+        _ => None,
+    });
+    assert_eq!(sheet.class.name, "fighter");
+    assert_eq!(sheet.race, pending[0].race);
+    server.with_data(|data| {
+        assert!(data.user_data["alice"].characters.contains_key("Aria"));
+        // a new character shouldn't have to wait for the next autosave
+        assert!(data.autosave_requested);
+    });
+}
+
+#[test]
+fn character_must_qualify_for_class() {
+    let server = TestServer::start();
+    let client = server.connect_as("alice", "hunter2");
+    let pending = roll_characters(&server, &client);
+    let other_race = if pending[0].race == Race::Human { Race::Dwarf } else { Race::Human };
+    register_class(&server, "other_race", other_race);
+
+    client.send(ServerBoundPacket::CreateNewCharacter("Aria".to_owned(), 0, "other_race".to_owned()));
+    let result = client.expect("the refusal", |packet| match packet {
+        ClientBoundPacket::CreateNewCharacterResult(result, _) => Some(result),
+        _ => None,
+    });
+    assert_eq!(result, Err(ClientFacingError::ClassNotAllowed));
+    server.with_data(|data| {
+        assert!(data.user_data["alice"].characters.is_empty());
+        assert_eq!(data.user_data["alice"].pending_characters.len(), NEW_CHARACTER_CHOICES);
+    });
+}
+
+#[test]
+fn cannot_pick_a_character_that_was_not_rolled() {
+    let server = TestServer::start();
+    let client = server.connect_as("alice", "hunter2");
+    let pending = roll_characters(&server, &client);
+    register_class(&server, "fighter", pending[0].race);
+
+    client.send(ServerBoundPacket::CreateNewCharacter("Aria".to_owned(), NEW_CHARACTER_CHOICES, "fighter".to_owned()));
+    let result = client.expect("the refusal", |packet| match packet {
+        ClientBoundPacket::CreateNewCharacterResult(result, _) => Some(result),
+        _ => None,
+    });
+    assert_eq!(result, Err(ClientFacingError::CharacterUnavailable));
+}
@@ -0,0 +1,121 @@
+use crate::character::PlayerCharacter;
+use crate::combat::{Fight, Owner, Combatant, PreRoundAction, MovementAction, TurnType};
+use crate::dm_app::{DMAppData, UserData};
+use crate::map::Map;
+use crate::packets::{ClientBoundPacket, ServerBoundPacket};
+use crate::player_app::{CombatState, CombatRoundState};
+
+use super::{TestServer, TestClient};
+
+/// Gives a user a freshly rolled character.
+fn give_character(data: &mut DMAppData, user: &str, name: &str) -> Combatant {
+    let character = PlayerCharacter::random(&mut data.rng);
+    data.user_data.entry(user.to_owned()).or_insert_with(UserData::new).characters.insert(name.to_owned(), character);
+    Combatant::pc(user.to_owned(), name.to_owned())
+}
+
+/// Starts a fight between the given player characters, as the DM would.
+fn start_fight(data: &mut DMAppData, combatants: &[&Combatant]) {
+    let mut fight = Fight::new();
+    fight.started = true;
+    for combatant in combatants {
+        if let Combatant::PC { user, .. } = combatant {
+            fight.combatants.insert((Owner::Player(user.clone()), (*combatant).clone()));
+        }
+    }
+    fight.update_clients(data);
+    let mut map = Map::new();
+    map.fight = Some(fight);
+    data.loaded_map = Some(("test".to_owned(), map));
+}
+
+/// Starts the next round of the fight, as the DM would.
+fn start_round(data: &mut DMAppData) {
+    let mut fight = data.loaded_map.as_mut().and_then(|(_, map)| map.fight.take()).unwrap();
+    fight.start_round(data);
+    data.loaded_map.as_mut().unwrap().1.fight = Some(fight);
+}
+
+fn expect_combat_state(client: &TestClient) -> CombatState {
+    client.expect("the combat state", |packet| match packet {
+        ClientBoundPacket::UpdateCombatState(state) => state,
+        _ => None,
+    })
+}
+
+#[test]
+fn combat_round() {
+    let server = TestServer::start();
+    let alice = server.connect_as("alice", "hunter2");
+    let bob = server.connect_as("bob", "hunter2");
+    let (aria, brom) = server.with_data(|data| {
+        let aria = give_character(data, "alice", "Aria");
+        let brom = give_character(data, "bob", "Brom");
+        start_fight(data, &[&aria, &brom]);
+        (aria, brom)
+    });
+
+    let state = expect_combat_state(&alice);
+    assert_eq!(state.round_state, CombatRoundState::PreRound);
+    assert_eq!(state.your_combatants.keys().collect::<Vec<_>>(), vec![&aria]);
+    let state = expect_combat_state(&bob);
+    assert_eq!(state.round_state, CombatRoundState::PreRound);
+    assert_eq!(state.your_combatants.keys().collect::<Vec<_>>(), vec![&brom]);
+
+    // nobody can declare for someone else's character
+    alice.send(ServerBoundPacket::MakePreRoundDeclaration(brom.clone(), PreRoundAction::FullRetreat));
+    alice.send(ServerBoundPacket::MakePreRoundDeclaration(aria.clone(), PreRoundAction::FightingWithdrawal));
+    let state = expect_combat_state(&alice);
+    assert_eq!(state.your_combatants.get(&aria), Some(&PreRoundAction::FightingWithdrawal));
+    server.with_data(|data| {
+        let fight = data.loaded_map.as_ref().unwrap().1.fight.as_ref().unwrap();
+        assert_eq!(fight.declarations.keys().collect::<Vec<_>>(), vec![&aria]);
+    });
+
+    server.with_data(start_round);
+    let alice_state = expect_combat_state(&alice);
+    let bob_state = expect_combat_state(&bob);
+    // initiative is rolled, so either could go first
+    let (first, first_state, first_combatant, other, other_state) = if alice_state.round_state == CombatRoundState::NotYourTurn {
+        (&bob, bob_state, &brom, &alice, alice_state)
+    } else {
+        (&alice, alice_state, &aria, &bob, bob_state)
+    };
+    assert_eq!(first_state.round_state, CombatRoundState::MovementAction { combatant: first_combatant.clone(), waiting_for_approval: false, temp_action: MovementAction::None });
+    assert_eq!(other_state.round_state, CombatRoundState::NotYourTurn);
+
+    // only whoever's turn it is gets to decide
+    other.send(ServerBoundPacket::DecideMovementAction(MovementAction::Run));
+    first.send(ServerBoundPacket::DecideMovementAction(MovementAction::Move));
+    server.wait_until("the movement action", |data| {
+        let fight = data.loaded_map.as_ref().and_then(|(_, map)| map.fight.as_ref());
+        match fight.and_then(|fight| fight.current_turn.as_ref()) {
+            Some((_, TurnType::Movement { player_action, .. })) => player_action.is_some(),
+            _ => false,
+        }
+    });
+    server.with_data(|data| {
+        let fight = data.loaded_map.as_ref().unwrap().1.fight.as_ref().unwrap();
+        assert_eq!(fight.get_current_actor(), *first_combatant);
+        assert!(matches!(fight.current_turn, Some((0, TurnType::Movement { player_action: Some(MovementAction::Move), .. }))));
+    });
+}
+
+#[test]
+fn reconnecting_player_gets_combat_state() {
+    let server = TestServer::start();
+    let alice = server.connect_as("alice", "hunter2");
+    let aria = server.with_data(|data| {
+        let aria = give_character(data, "alice", "Aria");
+        start_fight(data, &[&aria]);
+        aria
+    });
+    expect_combat_state(&alice);
+    drop(alice);
+
+    let alice = server.connect(false);
+    alice.send(ServerBoundPacket::AttemptLogIn("alice".to_owned(), "hunter2".to_owned(), false));
+    let state = expect_combat_state(&alice);
+    assert_eq!(state.round_state, CombatRoundState::PreRound);
+    assert!(state.your_combatants.contains_key(&aria));
+}
@@ -0,0 +1,168 @@
+use crate::dice::{BinaryOp, CompareOp, DiceExpr, DiceRng, DiceRoll, Distribution, Drop, MAX_DICE_SIDES, MAX_EXPLOSIONS};
+use crate::dm_app::validate_macro;
+
+fn parse(s: &str) -> DiceExpr {
+    match DiceExpr::parse(s) {
+        Ok(expr) => expr,
+        Err(e) => panic!("could not parse {}: {}", s, e),
+    }
+}
+
+fn value_of(s: &str) -> i32 {
+    parse(s).evaluate_simple(&mut DiceRng::seeded(1)).unwrap().value
+}
+
+/// Checks that a distribution gives the expected chance of each result.
+fn assert_odds(dist: &Distribution, expected: &[(i32, f64)]) {
+    assert_eq!(dist.probabilities.len(), expected.len(), "{:?}", dist);
+    for (value, p) in expected {
+        let actual = dist.probabilities.get(value).copied().unwrap_or(0.0);
+        assert!((actual - p).abs() < 1e-9, "P({}) was {}, expected {}", value, actual, p);
+    }
+}
+
+#[test]
+fn expressions_follow_precedence() {
+    assert_eq!(value_of("2+3*4"), 14);
+    assert_eq!(value_of("(2+3)*4"), 20);
+    assert_eq!(value_of("10-2-3"), 5);
+    assert_eq!(value_of("-2x-3"), 6);
+    assert_eq!(value_of("7/2"), 4);
+    assert_eq!(value_of("7/d2"), 3);
+    assert_eq!(value_of("7/u3"), 3);
+    assert_eq!(value_of("3 >= 2"), 1);
+    assert_eq!(value_of("3 < 2"), 0);
+    assert_eq!(parse("1d20+STR"), DiceExpr::Binary(Box::new(DiceExpr::Roll(DiceRoll::simple(1, 20))), BinaryOp::Add, Box::new(DiceExpr::Variable("STR".to_owned()))));
+}
+
+#[test]
+fn bad_expressions_are_refused() {
+    for s in ["", "0d6", "1d0", "1001d6", "1d1000001", "1d3000000000", "99999999999", "(1d6", "1d6 > 2 > 1", "1d6+", "4d6_h4", "4d6kh5", "1d6r6", "1d1!", "1d6s>", "2/0"] {
+        let result = DiceExpr::parse(s).and_then(|expr| expr.evaluate_simple(&mut DiceRng::seeded(1)));
+        assert!(result.is_err(), "{} should be refused, but gave {:?}", s, result);
+    }
+    assert!(DiceExpr::parse(format!("1d{}", MAX_DICE_SIDES)).is_ok());
+    assert_eq!(parse("2147483647+1").evaluate_simple(&mut DiceRng::seeded(1)).unwrap_err(), "The result is too large.");
+}
+
+#[test]
+fn comparisons_and_success_counting() {
+    // without the 's', a target after the dice is a comparison
+    let expr = parse("2d6>=7");
+    assert!(matches!(expr, DiceExpr::Compare(_, CompareOp::GreaterOrEqual, _)));
+    let result = parse("1d20>=14").evaluate_simple(&mut DiceRng::seeded(1)).unwrap();
+    assert!(result.success.is_some());
+
+    match parse("6d10s>=7") {
+        DiceExpr::Roll(roll) => {
+            assert_eq!(roll.success_target, Some(7));
+            assert_eq!(roll.min_value, 0);
+        },
+        expr => panic!("expected a roll, got {:?}", expr),
+    }
+    match parse("6d10s>7") {
+        DiceExpr::Roll(roll) => assert_eq!(roll.success_target, Some(8)),
+        expr => panic!("expected a roll, got {:?}", expr),
+    }
+    let mut rng = DiceRng::seeded(7);
+    for _ in 0..100 {
+        let value = parse("6d10s>=7").evaluate_simple(&mut rng).unwrap().value;
+        assert!((0..=6).contains(&value));
+    }
+}
+
+#[test]
+fn dice_modes() {
+    let mut rng = DiceRng::seeded(3);
+    for _ in 0..100 {
+        let result = DiceRoll::from_notation("4d6kh3").unwrap().roll_detailed(&mut rng);
+        assert_eq!(result.raw.len(), 4);
+        assert_eq!(result.dropped.len(), 1);
+        assert!(result.dropped[0] <= *result.raw.iter().min().unwrap() || result.raw.iter().filter(|r| **r < result.dropped[0]).count() == 0);
+
+        let result = DiceRoll::from_notation("3d6r2").unwrap().roll_detailed(&mut rng);
+        assert!(result.raw.iter().all(|r| *r > 2));
+        assert!(result.rerolled.iter().all(|r| *r <= 2));
+
+        let result = DiceRoll::from_notation("2d2!").unwrap().roll_detailed(&mut rng);
+        assert!(result.raw.len() <= 2 + MAX_EXPLOSIONS as usize);
+        // every die that rolled the highest face added another
+        assert_eq!(result.raw.len(), 2 + result.raw.iter().filter(|r| **r == 2).count().min(MAX_EXPLOSIONS as usize));
+    }
+}
+
+#[test]
+fn notation_round_trips() {
+    for s in ["1d6", "2d8+3", "1d6&+1", "3d6/u2_l", "3d6/d2_h2", "2d8x3>=4", "1d6-1>=-3", "4d6!r1&+2kh3s>=5", "5d10kl2"] {
+        let roll = DiceRoll::from_notation(s).unwrap();
+        assert_eq!(roll.to_notation(), s);
+        assert_eq!(DiceRoll::from_notation(roll.to_notation()).unwrap(), roll);
+    }
+    let roll = DiceRoll::from_notation("4d6s>3").unwrap();
+    assert_eq!(roll.to_notation(), "4d6s>=4");
+    assert_eq!(roll.drop, Drop::None);
+}
+
+#[test]
+fn display_round_trips() {
+    for s in ["2d6 + 1d4 + 3", "4d6kh3", "(1d20 + STR) >= 14", "3d6 /u 2", "1d20!", "2d6r1", "6d10s>=7", "-(1d4 x 2)", "1d6&+1", "d8"] {
+        let expr = parse(s);
+        assert_eq!(parse(&expr.to_string()), expr, "{} was written as {}", s, expr);
+    }
+    assert_eq!(parse("1d6&+1").to_string(), "1d6&+1");
+}
+
+#[test]
+fn distributions() {
+    let d6 = parse("1d6").distribution(&|_| None).unwrap();
+    assert_odds(&d6, &[(1, 1.0 / 6.0), (2, 1.0 / 6.0), (3, 1.0 / 6.0), (4, 1.0 / 6.0), (5, 1.0 / 6.0), (6, 1.0 / 6.0)]);
+    assert!((d6.mean() - 3.5).abs() < 1e-9);
+
+    let two_d6 = parse("2d6").distribution(&|_| None).unwrap();
+    assert!((two_d6.probabilities[&7] - 6.0 / 36.0).abs() < 1e-9);
+    assert_eq!((two_d6.min(), two_d6.max(), two_d6.median()), (2, 12, 7));
+
+    // the well known average of rolling 4d6 and dropping the lowest
+    let stats = parse("4d6kh3").distribution(&|_| None).unwrap();
+    assert!((stats.mean() - 15869.0 / 1296.0).abs() < 1e-9);
+    assert_eq!(parse("4d6_l").distribution(&|_| None).unwrap(), stats);
+
+    assert_odds(&parse("2d6s>=4").distribution(&|_| None).unwrap(), &[(0, 0.25), (1, 0.5), (2, 0.25)]);
+    assert_odds(&parse("1d4r1").distribution(&|_| None).unwrap(), &[(2, 1.0 / 3.0), (3, 1.0 / 3.0), (4, 1.0 / 3.0)]);
+    assert!((parse("1d20 >= 11").distribution(&|_| None).unwrap().at_least(1) - 0.5).abs() < 1e-9);
+    assert_odds(&parse("1d4 + STR").distribution(&|_| Some(2)).unwrap(), &[(3, 0.25), (4, 0.25), (5, 0.25), (6, 0.25)]);
+
+    // an exploding d6 averages 3.5 * 6/5, less the tiny chance of exploding past the limit
+    let exploding = parse("1d6!").distribution(&|_| None).unwrap();
+    assert!((exploding.mean() - 4.2).abs() < 1e-6);
+    assert!((exploding.probabilities.values().sum::<f64>() - 1.0).abs() < 1e-9);
+}
+
+#[test]
+fn huge_odds_are_refused_quickly() {
+    for s in [format!("1d{}", MAX_DICE_SIDES), "1000d1000".to_owned(), "100d100kh50".to_owned(), "1d100000 x 1d100000".to_owned()] {
+        assert!(parse(&s).distribution(&|_| None).is_err(), "{} should have too many outcomes", s);
+    }
+}
+
+#[test]
+fn results_never_overflow() {
+    let mut rng = DiceRng::seeded(1);
+    assert_eq!(DiceRoll::from_notation("1000d1000000&x2000000000").unwrap().roll(&mut rng), i32::MAX);
+    // rolls from files aren't limited like typed ones
+    let mut roll = DiceRoll::simple(2, u32::MAX);
+    roll.modifier = i32::MAX;
+    assert!(roll.roll(&mut rng) > 0);
+
+    assert!(parse("1000d1000000").always_fits());
+    assert!(!parse("1000d1000000 x 1000d1000000").always_fits());
+    assert!(validate_macro("attack", "1d20+STR").is_ok());
+    assert!(validate_macro("big", "1000d1000000 x 1000").is_err());
+    assert!(validate_macro("huge", "1000d2000000000").is_err());
+}
+
+#[test]
+fn odds_never_overflow() {
+    assert_eq!(parse("-(0-2147483647-1)").distribution(&|_| None).unwrap_err(), "The result is too large.");
+    assert!(parse("-(1d6-7)").distribution(&|_| None).is_ok());
+}
@@ -0,0 +1,276 @@
+use std::io::Read;
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, mpsc};
+use std::time::Duration;
+
+use crate::common_ui::{ChatMessage, MessageSender};
+use crate::dice::RollResult;
+use crate::dm_app::Role;
+use crate::network::{Connection, Handshake, NetEvent, NetEventKind, MAX_QUEUED_BYTES};
+use crate::packets::{ClientBoundPacket, ServerBoundPacket, ClientFacingError, MAX_USERNAME_LENGTH};
+use crate::rate_limit::{MAX_STRIKES, MAX_CONNECTIONS_PER_IP};
+
+use super::TestServer;
+
+#[test]
+fn create_account_and_log_in() {
+    let server = TestServer::start();
+    let client = server.connect(false);
+    client.send(ServerBoundPacket::CreateAccount("alice".to_owned(), "hunter2".to_owned()));
+    assert!(matches!(client.recv(), Some(ClientBoundPacket::CreateAccountResult(true, name)) if name == "alice"));
+
+    client.send(ServerBoundPacket::AttemptLogIn("alice".to_owned(), "hunter2".to_owned(), true));
+    let token = client.expect("the log in result", |packet| match packet {
+        ClientBoundPacket::LogInResult(success, token) => Some((success, token)),
+        _ => None,
+    });
+    assert!(token.0, "Could not log in.");
+    assert!(token.1.is_some(), "No login token was sent, even though one was asked for.");
+    assert!(matches!(client.recv(), Some(ClientBoundPacket::SessionToken(_))));
+    assert!(matches!(client.recv(), Some(ClientBoundPacket::RegistryVersions(_))));
+    server.with_data(|data| assert!(data.connected_users.contains_key("alice")));
+}
+
+#[test]
+fn log_in_with_remembered_token() {
+    let server = TestServer::start();
+    let client = server.connect(false);
+    client.send(ServerBoundPacket::CreateAccount("alice".to_owned(), "hunter2".to_owned()));
+    client.send(ServerBoundPacket::AttemptLogIn("alice".to_owned(), "hunter2".to_owned(), true));
+    let token = client.expect("the login token", |packet| match packet {
+        ClientBoundPacket::LogInResult(true, token) => token,
+        _ => None,
+    });
+    // only a hash of the token is kept
+    let stored = server.with_data(|data| data.user_data["alice"].login_tokens.clone());
+    assert_eq!(stored.len(), 1);
+    assert_ne!(stored[0], token);
+    drop(client);
+    server.wait_until("the connection to drop", |data| data.sessions.get("alice").is_some_and(|session| session.disconnected_at.is_some()));
+
+    let client = server.connect(false);
+    client.send(ServerBoundPacket::LogInWithToken("alice".to_owned(), "0".repeat(token.len())));
+    assert!(matches!(client.recv(), Some(ClientBoundPacket::LogInResult(false, None))));
+    client.send(ServerBoundPacket::LogInWithToken("alice".to_owned(), token));
+    let success = client.expect("the log in result", |packet| match packet {
+        ClientBoundPacket::LogInResult(success, _) => Some(success),
+        _ => None,
+    });
+    assert!(success);
+}
+
+#[test]
+fn log_in_encrypted() {
+    let server = TestServer::start();
+    let client = server.connect(true);
+    assert!(client.connection.is_encrypted());
+    client.send(ServerBoundPacket::CreateAccount("alice".to_owned(), "hunter2".to_owned()));
+    assert!(matches!(client.recv(), Some(ClientBoundPacket::CreateAccountResult(true, _))));
+    client.send(ServerBoundPacket::AttemptLogIn("alice".to_owned(), "hunter2".to_owned(), false));
+    let logged_in = client.expect("the log in result", |packet| match packet {
+        ClientBoundPacket::LogInResult(success, _) => Some(success),
+        _ => None,
+    });
+    assert!(logged_in);
+}
+
+#[test]
+fn wrong_password_is_refused() {
+    let server = TestServer::start();
+    let client = server.connect(false);
+    client.send(ServerBoundPacket::CreateAccount("alice".to_owned(), "hunter2".to_owned()));
+    assert!(matches!(client.recv(), Some(ClientBoundPacket::CreateAccountResult(true, _))));
+    client.send(ServerBoundPacket::AttemptLogIn("alice".to_owned(), "hunter3".to_owned(), false));
+    assert!(matches!(client.recv(), Some(ClientBoundPacket::LogInResult(false, None))));
+    client.send(ServerBoundPacket::AttemptLogIn("bob".to_owned(), "hunter2".to_owned(), false));
+    assert!(matches!(client.recv(), Some(ClientBoundPacket::LogInResult(false, None))));
+    server.with_data(|data| assert!(data.connected_users.is_empty()));
+}
+
+#[test]
+fn taken_username_is_refused() {
+    let server = TestServer::start();
+    let _alice = server.connect_as("alice", "hunter2");
+    let client = server.connect(false);
+    client.send(ServerBoundPacket::CreateAccount("alice".to_owned(), "password".to_owned()));
+    assert!(matches!(client.recv(), Some(ClientBoundPacket::CreateAccountResult(false, _))));
+}
+
+#[test]
+fn bad_usernames_are_refused() {
+    let server = TestServer::start();
+    for (username, error) in [("server", ClientFacingError::UsernameInvalid), ("al\nice", ClientFacingError::UsernameInvalid), ("", ClientFacingError::UsernameInvalid), (&"a".repeat(MAX_USERNAME_LENGTH + 1), ClientFacingError::UsernameTooLong)] {
+        // every attempt comes from the same address, which may only create a few accounts
+        server.with_data(|data| data.account_limits.clear());
+        let client = server.connect(false);
+        client.send(ServerBoundPacket::CreateAccount(username.to_owned(), "hunter2".to_owned()));
+        assert!(matches!(client.recv(), Some(ClientBoundPacket::CreateAccountResult(false, _))), "{:?} was not refused", username);
+        assert!(matches!(client.recv(), Some(ClientBoundPacket::Error(e)) if e == error));
+    }
+}
+
+#[test]
+fn closed_registration_is_refused() {
+    let server = TestServer::start();
+    server.with_data(|data| data.registration_closed = true);
+    let client = server.connect(false);
+    client.send(ServerBoundPacket::CreateAccount("alice".to_owned(), "hunter2".to_owned()));
+    assert!(matches!(client.recv(), Some(ClientBoundPacket::CreateAccountResult(false, _))));
+    assert!(matches!(client.recv(), Some(ClientBoundPacket::Error(ClientFacingError::RegistrationClosed))));
+}
+
+#[test]
+fn banned_user_is_refused() {
+    let server = TestServer::start();
+    let client = server.connect(false);
+    client.send(ServerBoundPacket::CreateAccount("mallory".to_owned(), "hunter2".to_owned()));
+    assert!(matches!(client.recv(), Some(ClientBoundPacket::CreateAccountResult(true, _))));
+    server.with_data(|data| data.banned.insert("mallory".to_owned()));
+    client.send(ServerBoundPacket::AttemptLogIn("mallory".to_owned(), "hunter2".to_owned(), false));
+    assert!(matches!(client.recv(), Some(ClientBoundPacket::LogInResult(false, None))));
+    assert!(matches!(client.recv(), Some(ClientBoundPacket::Error(ClientFacingError::Banned))));
+}
+
+#[test]
+fn packets_before_log_in_are_ignored() {
+    let server = TestServer::start();
+    let client = server.connect(false);
+    client.send(ServerBoundPacket::ChatMessage(ChatMessage::no_sender("hello?")));
+    client.send(ServerBoundPacket::CreateAccount("alice".to_owned(), "hunter2".to_owned()));
+    // the chat message would have come first if it had been let through
+    assert!(matches!(client.recv(), Some(ClientBoundPacket::CreateAccountResult(true, _))));
+}
+
+#[test]
+fn spectators_cannot_chat() {
+    let server = TestServer::start();
+    let client = server.connect_as("alice", "hunter2");
+    server.with_data(|data| data.roles.insert("alice".to_owned(), Role::Spectator));
+    client.send(ServerBoundPacket::ChatMessage(ChatMessage::no_sender("hello")));
+    let msg = client.expect("the refusal", |packet| match packet {
+        ClientBoundPacket::ChatMessage(msg) if msg.message.starts_with("You need to be") => Some(msg.message),
+        _ => None,
+    });
+    assert_eq!(msg, "You need to be a player to do that.");
+}
+
+#[test]
+fn chat_is_sent_as_whoever_is_logged_in() {
+    let server = TestServer::start();
+    let client = server.connect_as("alice", "hunter2");
+    let mut msg = ChatMessage::player("bob", "I rolled a 20!").dice_roll();
+    msg.rolls.push(RollResult::new("1d20"));
+    client.send(ServerBoundPacket::ChatMessage(msg));
+    let msg = client.expect("the chat message", |packet| match packet {
+        ClientBoundPacket::ChatMessage(msg) if msg.message == "I rolled a 20!" => Some(msg),
+        _ => None,
+    });
+    assert!(matches!(msg.sender, MessageSender::Player(name) if name == "alice"));
+    assert!(msg.rolls.is_empty());
+    assert!(!msg.flags.dice_roll);
+}
+
+#[test]
+fn resume_session_after_reconnecting() {
+    let server = TestServer::start();
+    let client = server.connect(false);
+    client.send(ServerBoundPacket::CreateAccount("alice".to_owned(), "hunter2".to_owned()));
+    client.send(ServerBoundPacket::AttemptLogIn("alice".to_owned(), "hunter2".to_owned(), false));
+    let token = client.expect("the session token", |packet| match packet {
+        ClientBoundPacket::SessionToken(token) => Some(token),
+        _ => None,
+    });
+    drop(client);
+    server.wait_until("the connection to drop", |data| data.sessions.get("alice").is_some_and(|session| session.disconnected_at.is_some()));
+
+    let client = server.connect(false);
+    client.send(ServerBoundPacket::ResumeSession("not a token".to_owned()));
+    assert!(matches!(client.recv(), Some(ClientBoundPacket::ResumeSessionResult(false))));
+    client.send(ServerBoundPacket::ResumeSession(token));
+    assert!(matches!(client.recv(), Some(ClientBoundPacket::ResumeSessionResult(true))));
+    server.with_data(|data| assert!(data.sessions.get("alice").is_some_and(|session| session.disconnected_at.is_none())));
+}
+
+#[test]
+fn flooding_chat_gets_disconnected() {
+    let server = TestServer::start();
+    let client = server.connect_as("alice", "hunter2");
+    for i in 0..20 {
+        client.send(ServerBoundPacket::ChatMessage(ChatMessage::no_sender(format!("spam {}", i))));
+    }
+    client.expect_closed();
+    server.wait_until("alice to be disconnected", |data| !data.connected_users.contains_key("alice"));
+}
+
+#[test]
+fn unreadable_packets_count_as_strikes() {
+    let server = TestServer::start();
+    let client = server.connect_as("alice", "hunter2");
+    for _ in 0..MAX_STRIKES {
+        // a handshake is never a valid packet once the handshake is done
+        client.connection.send(&Handshake::current()).unwrap();
+    }
+    client.expect_closed();
+    // strikes belong to the connection, so others from the same address aren't punished
+    let bob = server.connect_as("bob", "hunter2");
+    bob.send(ServerBoundPacket::ChatMessage(ChatMessage::no_sender("hi")));
+    bob.expect("bob's message", |packet| match packet {
+        ClientBoundPacket::ChatMessage(msg) if msg.message == "hi" => Some(()),
+        _ => None,
+    });
+}
+
+#[test]
+fn clients_that_stop_reading_are_dropped() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    // never read from, so everything sent piles up
+    let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (stream, _) = listener.accept().unwrap();
+    let (events, closed) = mpsc::channel();
+    let connection = Connection::new(stream, events).unwrap();
+    let frame: Arc<[u8]> = vec![0; 1024 * 1024].into();
+    assert!((0..2 * MAX_QUEUED_BYTES / frame.len()).any(|_| connection.send_frame(&frame).is_err()));
+    assert!(matches!(closed.recv_timeout(Duration::from_secs(10)), Ok(NetEvent { kind: NetEventKind::Closed(_), .. })));
+}
+
+#[test]
+fn idle_connections_are_limited() {
+    let server = TestServer::start();
+    let mut streams = Vec::new();
+    for _ in 0..MAX_CONNECTIONS_PER_IP {
+        streams.push(TcpStream::connect(server.addr).unwrap());
+    }
+    server.wait_until("the connections to be accepted", |data| data.streams.len() == MAX_CONNECTIONS_PER_IP);
+    let extra = TcpStream::connect(server.addr).unwrap();
+    extra.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    assert_eq!((&extra).read(&mut [0; 16]).unwrap(), 0);
+    // none of them sent a handshake
+    server.wait_until("the connections to be dropped", |data| data.streams.is_empty());
+}
+
+#[test]
+fn co_dms_cannot_change_other_co_dms() {
+    let server = TestServer::start();
+    let alice = server.connect_as("alice", "hunter2");
+    let _bob = server.connect_as("bob", "hunter2");
+    let password = server.with_data(|data| {
+        data.roles.insert("alice".to_owned(), Role::CoDm);
+        data.roles.insert("bob".to_owned(), Role::CoDm);
+        data.known_users["bob"].clone()
+    });
+    alice.send(ServerBoundPacket::AdminCommand("/user bob resetpw secret".to_owned()));
+    alice.send(ServerBoundPacket::AdminCommand("/user bob role player".to_owned()));
+    for _ in 0..2 {
+        alice.expect("the refusal", |packet| match packet {
+            ClientBoundPacket::ChatMessage(msg) if msg.message.starts_with("Only the DM") => Some(()),
+            _ => None,
+        });
+    }
+    server.with_data(|data| {
+        assert_eq!(data.role("bob"), Role::CoDm);
+        assert_eq!(data.known_users["bob"], password);
+        // passwords typed into commands never reach the log
+        assert!(data.logs.iter().any(|entry| entry.job.text.contains("alice > /user \"bob\" resetpw")));
+        assert!(!data.logs.iter().any(|entry| entry.job.text.contains("secret")));
+    });
+}
@@ -0,0 +1,94 @@
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::character::PlayerCharacter;
+use crate::dice::DiceRng;
+use crate::dm_app::DMAppData;
+use crate::enemy::EnemyType;
+use crate::item::ItemType;
+use crate::migration::{self, FileKind, RonValue};
+use crate::proficiency::Proficiency;
+
+/// Checks that going through a [`RonValue`] doesn't change what a file reads as. Sets are written
+/// in whatever order they iterate in, so this compares how the tree writes what `ron` wrote
+/// instead of comparing values.
+fn assert_round_trip<T: Serialize + DeserializeOwned>(s: &str) {
+    let tree = match RonValue::parse(s) {
+        Ok(tree) => tree,
+        Err(e) => panic!("could not parse {}: {}", s, e),
+    };
+    let value = match ron::from_str::<T>(&tree.to_string()) {
+        Ok(value) => value,
+        Err(e) => panic!("could not read {} after parsing it: {}", s, e),
+    };
+    let written = ron::to_string(&value).unwrap();
+    assert_eq!(RonValue::parse(&written).unwrap().to_string(), written);
+}
+
+#[test]
+fn registry_files_round_trip() {
+    let mut count = 0;
+    DMAppData::read_dir_recursive("enemies", |_, s| {
+        assert_round_trip::<EnemyType>(&s);
+        count += 1;
+    });
+    DMAppData::read_dir_recursive("items", |_, s| {
+        assert_round_trip::<ItemType>(&s);
+        count += 1;
+    });
+    DMAppData::read_dir_recursive("proficiencies", |_, s| {
+        assert_round_trip::<Proficiency>(&s);
+        count += 1;
+    });
+    assert!(count > 0);
+}
+
+#[test]
+fn characters_round_trip() {
+    let mut rng = DiceRng::new();
+    for _ in 0..20 {
+        let character = PlayerCharacter::random(&mut rng);
+        assert_round_trip::<PlayerCharacter>(&ron::to_string(&character).unwrap());
+    }
+}
+
+#[test]
+fn files_are_written_with_their_version() {
+    let s = std::fs::read_to_string("enemies/rat.ron").unwrap();
+    let rat = migration::from_str::<EnemyType>(FileKind::Enemy, &s).unwrap();
+    assert_eq!(rat.version, 0);
+    assert!(rat.upgrades.is_empty());
+
+    let written = migration::to_string(FileKind::Enemy, &rat.value).unwrap();
+    assert!(written.starts_with(&format!("(version:{},", FileKind::Enemy.version())));
+    let reread = migration::from_str::<EnemyType>(FileKind::Enemy, &written).unwrap();
+    assert_eq!(reread.version, FileKind::Enemy.version());
+    assert_eq!(reread.value.name, "Rat");
+}
+
+#[test]
+fn newer_files_are_refused() {
+    let s = std::fs::read_to_string("enemies/rat.ron").unwrap();
+    let newer = s.replacen('(', &format!("(version:{},", FileKind::Enemy.version() + 1), 1);
+    let error = migration::from_str::<EnemyType>(FileKind::Enemy, &newer).err().unwrap();
+    assert!(error.contains("newer version"), "{}", error);
+}
+
+#[test]
+fn tree_edits() {
+    let mut tree = RonValue::parse("(name: \"Aria\", level: 3, class: Fighter, spells: [], stats: (), pos: Some((1, -2.5e-3)))").unwrap();
+    tree.rename_field("level", "rank").unwrap();
+    tree.insert_field("nickname", RonValue::Ident("None".to_owned())).unwrap();
+    tree.field_mut("stats").unwrap().insert_field("str", RonValue::Literal("10".to_owned())).unwrap();
+    tree.field_mut("spells").unwrap().list_mut().unwrap().push(RonValue::Literal("r#\"Fireball\"#".to_owned()));
+    assert_eq!(tree.remove_field("class").unwrap(), Some(RonValue::Ident("Fighter".to_owned())));
+    assert!(tree.field_mut("class").is_err());
+    assert_eq!(tree.to_string(), "(name:\"Aria\",rank:3,spells:[r#\"Fireball\"#],stats:(str:10),pos:Some((1,-2.5e-3)),nickname:None)");
+    // and it is still valid RON
+    assert_eq!(RonValue::parse(&tree.to_string()).unwrap(), tree);
+}
+
+#[test]
+fn parse_errors_say_where() {
+    let error = RonValue::parse("(\n  name: \"Aria\",\n  level: ,\n)").err().unwrap();
+    assert!(error.ends_with("on line 3"), "{}", error);
+}
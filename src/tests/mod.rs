@@ -0,0 +1,140 @@
+//! Tests that start a real server on an ephemeral localhost port and talk to it with scripted
+//! clients, asserting on the packets they get back. Tests of saved files and dice expressions are in
+//! here too.
+
+use std::net::SocketAddr;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::dm_app::{DMAppData, start_server};
+use crate::network::{self, Connection, NetEvent, NetEventKind, Handshake, decode_frame};
+use crate::packets::{ClientBoundPacket, ServerBoundPacket};
+
+mod login;
+mod characters;
+mod combat;
+mod parties;
+mod saving;
+mod migration;
+mod dice;
+
+/// How long to wait for the server before failing a test.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A server hosted on `127.0.0.1` with nothing loaded from or saved to disk.
+pub struct TestServer {
+    pub data: Arc<Mutex<DMAppData>>,
+    pub addr: SocketAddr,
+}
+
+impl TestServer {
+    pub fn start() -> Self {
+        let mut data = DMAppData::new();
+        data.headless = true;
+        // a throwaway certificate, so the tests never write one to disk
+        let (config, fingerprint) = network::temporary_tls_config().unwrap();
+        data.tls_config = Some(config);
+        data.tls_fingerprint = Some(fingerprint);
+        data.host_addr = Some(SocketAddr::from(([127, 0, 0, 1], 0)));
+        let data = Arc::new(Mutex::new(data));
+        start_server(&data);
+        let mut server = Self { data, addr: SocketAddr::from(([127, 0, 0, 1], 0)) };
+        server.wait_until("the server to start hosting", |data| data.host_addr.is_some_and(|addr| addr.port() != 0));
+        server.addr = server.with_data(|data| data.host_addr.unwrap());
+        server
+    }
+
+    /// Runs `f` with the server's data locked, as if the DM did something.
+    pub fn with_data<R>(&self, f: impl FnOnce(&mut DMAppData) -> R) -> R {
+        f(&mut self.data.lock().unwrap())
+    }
+
+    /// Waits for the server's data to satisfy `f`, for things the server doesn't answer.
+    pub fn wait_until(&self, what: &str, mut f: impl FnMut(&mut DMAppData) -> bool) {
+        let start = Instant::now();
+        while !self.with_data(&mut f) {
+            if start.elapsed() > TIMEOUT {
+                panic!("Timed out waiting for {}.", what);
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Connects a new client and finishes the version handshake.
+    pub fn connect(&self, encrypt: bool) -> TestClient {
+        let (connection, events) = network::connect(&self.addr.to_string(), encrypt).unwrap();
+        let client = TestClient { connection, events };
+        let hello = client.next_frame().unwrap_or_else(|| panic!("The server closed the connection during the handshake."));
+        let hello = decode_frame::<Handshake>(&hello).unwrap();
+        assert!(hello.is_compatible(), "The server refused the handshake: {:?}", hello);
+        client
+    }
+
+    /// Connects a client and logs in as a new user.
+    pub fn connect_as(&self, username: &str, password: &str) -> TestClient {
+        let client = self.connect(false);
+        client.send(ServerBoundPacket::CreateAccount(username.to_owned(), password.to_owned()));
+        let created = client.expect("the account to be created", |packet| match packet {
+            ClientBoundPacket::CreateAccountResult(success, _) => Some(success),
+            _ => None,
+        });
+        assert!(created, "Could not create {}.", username);
+        client.send(ServerBoundPacket::AttemptLogIn(username.to_owned(), password.to_owned(), false));
+        let logged_in = client.expect("the log in result", |packet| match packet {
+            ClientBoundPacket::LogInResult(success, _) => Some(success),
+            _ => None,
+        });
+        assert!(logged_in, "Could not log in as {}.", username);
+        client
+    }
+}
+
+/// A fake client that sends whatever packets it is told to.
+pub struct TestClient {
+    pub connection: Connection,
+    events: Receiver<NetEvent>,
+}
+
+impl TestClient {
+    pub fn send(&self, packet: ServerBoundPacket) {
+        self.connection.send(&packet).unwrap();
+    }
+
+    /// The next frame from the server, or `None` if the connection closed.
+    fn next_frame(&self) -> Option<Vec<u8>> {
+        match self.events.recv_timeout(TIMEOUT) {
+            Ok(NetEvent { kind: NetEventKind::Frame(payload), .. }) => Some(payload),
+            Ok(NetEvent { kind: NetEventKind::Closed(_), .. }) | Err(RecvTimeoutError::Disconnected) => None,
+            Err(RecvTimeoutError::Timeout) => panic!("Timed out waiting for the server."),
+        }
+    }
+
+    /// The next packet from the server, or `None` if the connection closed.
+    pub fn recv(&self) -> Option<ClientBoundPacket> {
+        self.next_frame().map(|payload| decode_frame(&payload).unwrap())
+    }
+
+    /// Skips packets until `f` picks one out, returning what it returned.
+    pub fn expect<T>(&self, what: &str, mut f: impl FnMut(ClientBoundPacket) -> Option<T>) -> T {
+        let start = Instant::now();
+        loop {
+            if start.elapsed() > TIMEOUT {
+                panic!("Timed out waiting for {}.", what);
+            }
+            match self.recv() {
+                Some(packet) => {
+                    if let Some(result) = f(packet) {
+                        return result;
+                    }
+                },
+                None => panic!("The connection closed while waiting for {}.", what),
+            }
+        }
+    }
+
+    /// Skips packets until the server closes the connection.
+    pub fn expect_closed(&self) {
+        while self.recv().is_some() {}
+    }
+}
@@ -0,0 +1,40 @@
+use crate::character::PlayerCharacter;
+use crate::dm_app::UserData;
+use crate::packets::ClientBoundPacket;
+use crate::party::Party;
+
+use super::TestServer;
+
+#[test]
+fn join_party() {
+    let server = TestServer::start();
+    let alice = server.connect_as("alice", "hunter2");
+    let bob = server.connect_as("bob", "hunter2");
+    server.with_data(|data| {
+        let character = PlayerCharacter::random(&mut data.rng);
+        data.user_data.entry("alice".to_owned()).or_insert_with(UserData::new).characters.insert("Aria".to_owned(), character);
+        data.parties.insert("The Company".to_owned(), Party::new());
+        assert!(data.join_party("The Company", "alice", "Aria"));
+        assert!(!data.join_party("The Company", "alice", "Nobody"));
+        assert!(!data.join_party("Nowhere", "alice", "Aria"));
+    });
+
+    let sheet = alice.expect("the updated character", |packet| match packet {
+        ClientBoundPacket::UpdateCharacter(name, sheet) if name == "Aria" => Some(sheet),
+        _ => None,
+    });
+    assert_eq!(sheet.party.as_deref(), Some("The Company"));
+    let msg = bob.expect("the announcement", |packet| match packet {
+        ClientBoundPacket::ChatMessage(msg) if msg.message.contains("has joined") => Some(msg.message),
+        _ => None,
+    });
+    assert_eq!(msg, "Aria has joined The Company!");
+    // everyone sees who is in which party
+    for client in [&alice, &bob] {
+        let parties = client.expect("the parties", |packet| match packet {
+            ClientBoundPacket::UpdateParties(parties) => Some(parties),
+            _ => None,
+        });
+        assert!(parties["The Company"].members.contains(&("alice".to_owned(), "Aria".to_owned())));
+    }
+}
@@ -0,0 +1,230 @@
+use crate::party::Party;
+use crate::storage::UnsavedChanges;
+
+use super::TestServer;
+
+#[test]
+fn unsaved_changes_are_tracked() {
+    let server = TestServer::start();
+    let unsaved = server.with_data(|data| {
+        data.check_unsaved();
+        data.unsaved
+    });
+    assert_eq!(unsaved, UnsavedChanges::default());
+
+    let _alice = server.connect_as("alice", "hunter2");
+    let unsaved = server.with_data(|data| {
+        data.check_unsaved();
+        data.unsaved
+    });
+    assert_eq!(unsaved, UnsavedChanges { users: true, parties: false, map: false });
+    assert_eq!(unsaved.describe(), "users");
+
+    let unsaved = server.with_data(|data| {
+        data.parties.insert("The Company".to_owned(), Party::new());
+        data.check_unsaved();
+        data.unsaved
+    });
+    assert_eq!(unsaved, UnsavedChanges { users: true, parties: true, map: false });
+    assert_eq!(unsaved.describe(), "users and parties");
+}
+
+/// A database in a fresh temporary folder, which is deleted when dropped.
+#[cfg(feature = "sqlite")]
+struct TempDatabase {
+    dir: std::path::PathBuf,
+}
+
+#[cfg(feature = "sqlite")]
+impl TempDatabase {
+    fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("dm_automation_tool-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        Self { dir }
+    }
+
+    fn open(&self) -> crate::database::SqliteStore {
+        crate::database::SqliteStore::open(&self.dir.join(crate::storage::DATABASE_FILE)).unwrap()
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl Drop for TempDatabase {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn database_round_trip() {
+    use std::collections::{HashMap, HashSet};
+
+    use crate::character::PlayerCharacter;
+    use crate::common_ui::ChatMessage;
+    use crate::dice::DiceRng;
+    use crate::dm_app::{Role, SaveData, UserData};
+    use crate::map::Map;
+    use crate::migration::{self, FileKind};
+    use crate::storage::CampaignStore;
+
+    let database = TempDatabase::new("database_round_trip");
+    let mut store = database.open();
+    assert!(store.load().unwrap().is_none());
+
+    let mut rng = DiceRng::new();
+    let mut alice = UserData::new();
+    alice.characters.insert("Aria".to_owned(), PlayerCharacter::random(&mut rng));
+    alice.characters.insert("Brom".to_owned(), PlayerCharacter::random(&mut rng));
+    let mut data = SaveData {
+        version: FileKind::Save.version(),
+        known_users: HashMap::from([("alice".to_owned(), "hash".to_owned())]),
+        user_data: HashMap::from([("alice".to_owned(), alice)]),
+        parties: HashMap::from([("The Company".to_owned(), Party::new())]),
+        roles: HashMap::from([("alice".to_owned(), Role::CoDm)]),
+        admins: HashSet::new(),
+        // names can be banned before they have an account
+        banned: HashSet::from(["mallory".to_owned()]),
+        registration_closed: true,
+        campaign_name: "The \"Lost\" Mine".to_owned(),
+    };
+    let everything = UnsavedChanges { users: true, parties: true, map: true };
+    store.save(&data, everything).unwrap();
+    drop(store);
+
+    let mut store = database.open();
+    let loaded = store.load().unwrap().unwrap().value;
+    assert_eq!(loaded.known_users, data.known_users);
+    assert_eq!(loaded.roles, data.roles);
+    assert_eq!(loaded.banned, data.banned);
+    assert_eq!(loaded.registration_closed, data.registration_closed);
+    assert_eq!(loaded.campaign_name, data.campaign_name);
+    assert_eq!(loaded.parties.keys().collect::<Vec<_>>(), vec!["The Company"]);
+    let mut characters: Vec<&String> = loaded.user_data["alice"].characters.keys().collect();
+    characters.sort();
+    assert_eq!(characters, vec!["Aria", "Brom"]);
+    assert!(!loaded.user_data.contains_key("mallory"));
+    // the rows put back together are what older versions' data is upgraded from
+    let text = store.saved_text().unwrap().unwrap();
+    let from_text = migration::from_str::<SaveData>(FileKind::Save, &text).unwrap().value;
+    assert_eq!(from_text.known_users, data.known_users);
+    assert_eq!(from_text.user_data["alice"].characters.len(), 2);
+
+    // parts that aren't marked as changed aren't written
+    data.user_data.get_mut("alice").unwrap().characters.remove("Brom");
+    data.parties.clear();
+    store.save(&data, UnsavedChanges { users: false, parties: true, map: false }).unwrap();
+    let loaded = database.open().load().unwrap().unwrap().value;
+    assert_eq!(loaded.user_data["alice"].characters.len(), 2);
+    assert!(loaded.parties.is_empty());
+    store.save(&data, UnsavedChanges { users: true, parties: false, map: false }).unwrap();
+    let loaded = database.open().load().unwrap().unwrap().value;
+    assert_eq!(loaded.user_data["alice"].characters.keys().collect::<Vec<_>>(), vec!["Aria"]);
+
+    let mut map = Map::new();
+    map.name = "Cellar".to_owned();
+    store.save_map("dungeons/cellar", &map).unwrap();
+    let maps = store.list_maps();
+    assert_eq!(maps.len(), 1);
+    assert_eq!(maps[0].0, "dungeons/cellar");
+    assert_eq!(maps[0].1, Ok("Cellar".to_owned()));
+    assert_eq!(store.load_map("dungeons/cellar").unwrap().name, "Cellar");
+    assert!(store.load_map("attic").is_err());
+
+    store.append_chat(&[ChatMessage::no_sender("one"), ChatMessage::no_sender("two")]).unwrap();
+    store.append_chat(&[ChatMessage::no_sender("three")]).unwrap();
+    let chat: Vec<String> = store.recent_chat(2).unwrap().into_iter().map(|msg| msg.message).collect();
+    assert_eq!(chat, vec!["two", "three"]);
+}
+
+#[test]
+fn restoring_keeps_the_data_from_before() {
+    use crate::dm_app::SaveData;
+    use crate::storage::{self, StoreKind};
+
+    let dir = std::env::temp_dir().join(format!("dm_automation_tool-restore-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let server = TestServer::start();
+    let read_backup = |name: &str| -> SaveData {
+        storage::read_backup(&dir.join(storage::BACKUP_DIR), name).unwrap()
+    };
+    let (first, before, backups) = server.with_data(|data| {
+        // an absolute path, so the campaign's files go in the temporary folder
+        data.campaign = dir.to_string_lossy().into_owned();
+        data.store = Some(storage::create_store(&dir, StoreKind::Ron).unwrap());
+        data.parties.insert("First".to_owned(), Party::new());
+        data.save();
+        let first = storage::list_backups(&data.campaign_path(storage::BACKUP_DIR))[0].clone();
+        // saved and changed again within the same second as the first backup
+        data.parties.insert("Second".to_owned(), Party::new());
+        data.save();
+        data.parties.insert("Unsaved".to_owned(), Party::new());
+        let before = data.restore_backup(read_backup(&first)).unwrap().unwrap();
+        (first, before, storage::list_backups(&data.campaign_path(storage::BACKUP_DIR)))
+    });
+    assert_eq!(server.with_data(|data| data.parties.len()), 1);
+    // one backup for each save and one from before restoring, and none for the restored data
+    assert_eq!(backups.len(), 3);
+    assert_eq!(backups[0], before);
+    assert_eq!(backups[2], first);
+    assert_eq!(read_backup(&backups[1]).parties.len(), 2);
+    let mut parties: Vec<String> = read_backup(&before).parties.into_keys().collect();
+    parties.sort();
+    assert_eq!(parties, vec!["First", "Second", "Unsaved"]);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn databases_are_backed_up_less_often() {
+    use crate::storage::{self, StoreKind};
+
+    let dir = std::env::temp_dir().join(format!("dm_automation_tool-database-backups-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let server = TestServer::start();
+    let (backups, before) = server.with_data(|data| {
+        data.campaign = dir.to_string_lossy().into_owned();
+        data.store = Some(storage::create_store(&dir, StoreKind::Sqlite).unwrap());
+        data.parties.insert("First".to_owned(), Party::new());
+        data.save();
+        // too soon after the first backup to make another
+        data.parties.insert("Second".to_owned(), Party::new());
+        data.save();
+        let backups = storage::list_backups(&data.campaign_path(storage::BACKUP_DIR));
+        let restored = storage::read_backup(&data.campaign_path(storage::BACKUP_DIR), &backups[0]).unwrap();
+        let before = data.restore_backup(restored).unwrap().unwrap();
+        (backups, before)
+    });
+    assert_eq!(backups.len(), 1);
+    assert!(storage::backup_path(&dir.join(storage::BACKUP_DIR), &backups[0]).ends_with(format!("{}.sqlite", backups[0])));
+    assert_eq!(server.with_data(|data| data.parties.len()), 1);
+    let before = storage::read_backup(&dir.join(storage::BACKUP_DIR), &before).unwrap();
+    assert_eq!(before.parties.len(), 2);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn reloading_keeps_the_chat_history_once() {
+    use crate::common_ui::ChatMessage;
+    use crate::storage::{self, StoreKind};
+
+    let dir = std::env::temp_dir().join(format!("dm_automation_tool-reload-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    storage::create_store(&dir, StoreKind::Sqlite).unwrap().append_chat(&[ChatMessage::no_sender("earlier")]).unwrap();
+    let server = TestServer::start();
+    let (earlier, later, history) = server.with_data(|data| {
+        data.campaign = dir.to_string_lossy().into_owned();
+        data.load();
+        data.log(ChatMessage::no_sender("later"));
+        data.load();
+        data.load();
+        let count = |text: &str| data.logs.iter().filter(|entry| entry.job.text.contains(text)).count();
+        let history: Vec<String> = data.store.as_mut().unwrap().recent_chat(10).unwrap().into_iter().map(|msg| msg.message).collect();
+        (count("earlier"), count("later"), history)
+    });
+    assert_eq!((earlier, later), (1, 1));
+    // what was said before reloading went into the history rather than being lost
+    assert!(history.contains(&"later".to_owned()));
+    let _ = std::fs::remove_dir_all(&dir);
+}